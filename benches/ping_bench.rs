@@ -0,0 +1,128 @@
+//! Loopback benchmarks of the ping path, as a baseline to spot regressions.
+//!
+//! Run with `cargo bench --bench ping_bench`. Every benchmark pings a server on an
+//! in-process endpoint over localhost, without relays or discovery, so no network or
+//! external daemon is involved. Each group binds a fresh server and client and tears them
+//! down once done, so connections and state left over from one group don't skew the next.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use iroh::{protocol::Router, Endpoint, NodeAddr, RelayMode};
+use iroh_ping::{Ping, ALPN_V1};
+use tokio::runtime::Runtime;
+
+/// A server and a client endpoint for a single benchmark group, both on localhost.
+struct Pair {
+    router: Router,
+    addr: NodeAddr,
+    client: Endpoint,
+}
+
+impl Pair {
+    async fn new() -> Self {
+        let ep = local_endpoint().await;
+        let addrs: Vec<_> = ep
+            .bound_sockets()
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()))
+            .collect();
+        let addr = NodeAddr::from_parts(ep.node_id(), None, addrs);
+        let router = Ping::new()
+            .with_log_connections(false)
+            .register(Router::builder(ep))
+            .spawn();
+        let client = local_endpoint().await;
+        Self {
+            router,
+            addr,
+            client,
+        }
+    }
+
+    async fn shutdown(self) {
+        self.client.close().await;
+        self.router.shutdown().await.expect("shutdown");
+    }
+}
+
+/// Binds an endpoint that talks over localhost only.
+async fn local_endpoint() -> Endpoint {
+    Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await
+        .expect("bind endpoint")
+}
+
+/// (a) a ping including the handshake of a new connection
+fn cold(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime");
+    let pair = rt.block_on(Pair::new());
+    let ping = Ping::new();
+    c.bench_function("ping with connect", |b| {
+        b.to_async(&rt).iter(|| async {
+            ping.ping_once(&pair.client, pair.addr.clone())
+                .await
+                .expect("ping")
+        })
+    });
+    rt.block_on(pair.shutdown());
+}
+
+/// (b) a ping on an established connection
+fn warm(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime");
+    let pair = rt.block_on(Pair::new());
+    let ping = Ping::new();
+    let conn = rt
+        .block_on(pair.client.connect(pair.addr.clone(), ALPN_V1))
+        .expect("connect");
+    c.bench_function("ping_on_conn", |b| {
+        b.to_async(&rt)
+            .iter(|| async { ping.ping_on_conn(&conn).await.expect("ping") })
+    });
+    conn.close(0u32.into(), b"bye!");
+    rt.block_on(pair.shutdown());
+}
+
+/// (c) `ping_n`, with the handshake amortized over its pings, and (d) pipelined pings,
+/// both in pings per second
+fn throughput(c: &mut Criterion) {
+    let rt = Runtime::new().expect("tokio runtime");
+    let pair = rt.block_on(Pair::new());
+    let ping = Ping::new();
+    let conn = rt
+        .block_on(pair.client.connect(pair.addr.clone(), ALPN_V1))
+        .expect("connect");
+    let mut group = c.benchmark_group("throughput");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(20));
+    group.throughput(Throughput::Elements(1000));
+    group.bench_function("ping_n(1000)", |b| {
+        b.to_async(&rt).iter(|| async {
+            ping.ping_n(&pair.client, pair.addr.clone(), 1000)
+                .await
+                .expect("ping_n")
+        })
+    });
+    group.throughput(Throughput::Elements(100));
+    group.bench_function("ping_pipelined(100)", |b| {
+        b.to_async(&rt).iter(|| async {
+            ping.ping_pipelined(&conn, 100)
+                .await
+                .expect("ping_pipelined")
+        })
+    });
+    group.finish();
+    conn.close(0u32.into(), b"bye!");
+    rt.block_on(pair.shutdown());
+}
+
+criterion_group!(benches, cold, warm, throughput);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+//! Feeds arbitrary requests to the ping handler, which must never panic on them.
+//!
+//! Every input is sent as a single stream on a new localhost connection, and the handler
+//! for that connection is awaited, so a panic in it fails the run. Errors are fine, the
+//! handler may refuse or reset whatever it can't make sense of.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use iroh::{protocol::ProtocolHandler, Endpoint, NodeAddr, RelayMode, Watcher};
+use iroh_ping::{Ping, ALPN};
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::Runtime;
+
+/// A small payload limit, so oversized requests are cheap to produce.
+const MAX_PAYLOAD: usize = 64;
+
+struct Setup {
+    rt: Runtime,
+    server: Endpoint,
+    client: Endpoint,
+    addr: NodeAddr,
+    ping: Ping,
+}
+
+fn setup() -> &'static Setup {
+    static SETUP: OnceLock<Setup> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        let rt = Runtime::new().expect("tokio runtime");
+        let (server, client, addr) = rt.block_on(async {
+            let bind = || {
+                Endpoint::builder()
+                    .relay_mode(RelayMode::Disabled)
+                    .alpns(vec![ALPN.to_vec()])
+                    .bind()
+            };
+            let server = bind().await.expect("bind server");
+            let client = bind().await.expect("bind client");
+            let addr = server.node_addr().initialized().await.expect("server addr");
+            (server, client, addr)
+        });
+        let ping = Ping::new()
+            .with_max_payload(MAX_PAYLOAD)
+            .with_log_connections(false);
+        Setup {
+            rt,
+            server,
+            client,
+            addr,
+            ping,
+        }
+    })
+}
+
+fuzz_target!(|request: &[u8]| {
+    let Setup {
+        rt,
+        server,
+        client,
+        addr,
+        ping,
+    } = setup();
+    rt.block_on(async {
+        let handler = {
+            let server = server.clone();
+            let ping = ping.clone();
+            tokio::spawn(async move {
+                let incoming = server.accept().await.expect("server endpoint open");
+                let Ok(conn) = incoming.await else {
+                    return;
+                };
+                // refusing the request is fine, only a panic is not
+                ping.accept(conn).await.ok();
+            })
+        };
+
+        let conn = client.connect(addr.clone(), ALPN).await.expect("connect");
+        if let Ok((mut send, mut recv)) = conn.open_bi().await {
+            send.write_all(request).await.ok();
+            send.finish().ok();
+            // at most a version byte, a tag, a frame header and the payload come back
+            recv.read_to_end(2 * MAX_PAYLOAD + 64).await.ok();
+        }
+        conn.close(0u32.into(), b"bye!");
+
+        if let Err(err) = handler.await {
+            std::panic::resume_unwind(err.into_panic());
+        }
+    });
+});
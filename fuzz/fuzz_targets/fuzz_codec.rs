@@ -0,0 +1,43 @@
+//! Decodes arbitrary bytes as ping frames, which must fail cleanly or round trip.
+//!
+//! Frames whose length prefix exceeds the limit must be rejected as too large, whatever
+//! follows the prefix.
+
+#![no_main]
+
+use iroh_ping::{CodecError, PingRequest, PongResponse};
+use libfuzzer_sys::fuzz_target;
+
+/// Largest frame body accepted, small so the limit is hit often.
+const MAX_LEN: usize = 64;
+
+fuzz_target!(|frame: &[u8]| {
+    let announced = frame
+        .first_chunk::<4>()
+        .map(|prefix| u32::from_be_bytes(*prefix) as usize);
+    let oversized = announced.is_some_and(|len| len > MAX_LEN);
+
+    match PingRequest::decode(frame, MAX_LEN) {
+        Ok(request) => {
+            assert!(!oversized);
+            let mut buf = bytes::BytesMut::new();
+            request.encode(&mut buf);
+            assert_eq!(&buf[..], frame);
+        }
+        Err(CodecError::TooLarge { len, max_len }) => {
+            assert!(oversized);
+            assert_eq!((Some(len), max_len), (announced, MAX_LEN));
+        }
+        Err(_) => assert!(!oversized),
+    }
+
+    match PongResponse::decode(frame, MAX_LEN) {
+        Ok(response) => {
+            let mut buf = bytes::BytesMut::new();
+            response.encode(&mut buf);
+            assert_eq!(&buf[..], frame);
+        }
+        Err(CodecError::TooLarge { .. }) => assert!(oversized),
+        Err(_) => assert!(!oversized),
+    }
+});
@@ -0,0 +1,188 @@
+use iroh::{
+    endpoint::Connecting,
+    protocol::{AcceptError, ProtocolHandler},
+    Endpoint,
+};
+use std::time::Duration;
+
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use crate::{Ping, ALPN, ALPN_DATAGRAM, ALPN_V1};
+
+/// Handle to the accept loop started by [`Ping::spawn_accept_loop`].
+///
+/// Dropping the handle leaves the loop running until the endpoint closes.
+#[derive(Debug)]
+pub struct AcceptLoopHandle {
+    cancel: CancellationToken,
+    /// tells the loop to close the connections it accepted instead of waiting for them
+    force: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl AcceptLoopHandle {
+    /// Stop accepting connections and wait for the connections already accepted to end.
+    ///
+    /// Those end once their clients close them, or when the endpoint closes.
+    pub async fn shutdown(self) {
+        self.cancel.cancel();
+        // the loop never panics on its own, and is only aborted at runtime shutdown
+        self.task.await.ok();
+    }
+
+    /// Stop accepting connections and give the connections already accepted up to
+    /// `timeout` to end, then close those still open.
+    ///
+    /// Pings in flight during the grace period are still answered. Returns early once the
+    /// last connection ended, and whether that happened within `timeout`.
+    pub async fn shutdown_with_timeout(mut self, timeout: Duration) -> bool {
+        self.cancel.cancel();
+        if tokio::time::timeout(timeout, &mut self.task).await.is_ok() {
+            return true;
+        }
+        self.force.cancel();
+        self.task.await.ok();
+        false
+    }
+}
+
+impl Ping {
+    /// Answer pings on an endpoint without a [`Router`](iroh::protocol::Router).
+    ///
+    /// Spawns a task accepting incoming connections on `endpoint`, handling every
+    /// connection on its own task. Connections with another ALPN than [`Ping::alpn`] or
+    /// [`ALPN_DATAGRAM`], or [`ALPN`] if [`Ping::alpn`] is [`ALPN_V1`], are refused, so the
+    /// endpoint must be bound with these among its ALPNs and should not serve any other
+    /// protocol. A failing connection does not stop
+    /// the loop, which runs until [`AcceptLoopHandle::shutdown`] is called or the endpoint
+    /// closes.
+    pub fn spawn_accept_loop(self, endpoint: Endpoint) -> AcceptLoopHandle {
+        let cancel = CancellationToken::new();
+        let force = CancellationToken::new();
+        let task = tokio::spawn(accept_loop(self, endpoint, cancel.clone(), force.clone()));
+        AcceptLoopHandle {
+            cancel,
+            force,
+            task,
+        }
+    }
+}
+
+async fn accept_loop(
+    ping: Ping,
+    endpoint: Endpoint,
+    cancel: CancellationToken,
+    force: CancellationToken,
+) {
+    let mut handlers = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                let Ok(connecting) = incoming.accept() else {
+                    continue;
+                };
+                let ping = ping.clone();
+                handlers.spawn(async move {
+                    if let Err(err) = handle(&ping, connecting).await {
+                        tracing::warn!(%err, "ping connection failed");
+                    }
+                });
+            }
+            // reap finished handlers, so they don't pile up in a long running loop
+            Some(_) = handlers.join_next(), if !handlers.is_empty() => {}
+        }
+    }
+    let drain = async { while handlers.join_next().await.is_some() {} };
+    if force.run_until_cancelled(drain).await.is_none() {
+        // aborting a handler drops its connection, which closes it
+        handlers.shutdown().await;
+    }
+}
+
+async fn handle(ping: &Ping, mut connecting: Connecting) -> Result<(), AcceptError> {
+    let alpn = connecting.alpn().await.map_err(AcceptError::from_err)?;
+    let legacy = alpn == ALPN && ping.alpn == ALPN_V1;
+    if alpn != ping.alpn && alpn != ALPN_DATAGRAM && !legacy {
+        // dropping the handshake refuses the connection
+        return Ok(());
+    }
+    let connection = connecting.await?;
+    ping.accept(connection).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_spawn_accept_loop() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        ep.set_alpns(vec![ALPN_V1.to_vec()]);
+        let addr = test_utils::local_addr(&ep);
+        let server = Ping::new();
+        let handle = server.clone().spawn_accept_loop(ep.clone());
+
+        let client = test_utils::local_endpoint().await?;
+        for _ in 0..2 {
+            Ping::new().ping_once(&client, addr.clone()).await?;
+        }
+        client.close().await;
+        assert_eq!(server.metrics().pings_recv.get(), 2);
+
+        handle.shutdown().await;
+        assert_eq!(server.metrics().pings_in_flight.get(), 0);
+        ep.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_with_timeout() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        ep.set_alpns(vec![ALPN_V1.to_vec()]);
+        let addr = test_utils::local_addr(&ep);
+        let server = Ping::new();
+        let handle = server.clone().spawn_accept_loop(ep.clone());
+
+        // a ping that is still being sent when the shutdown starts
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"\x01PING").await?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let shutdown = tokio::spawn(handle.shutdown_with_timeout(Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!shutdown.is_finished());
+
+        // is still answered, and the shutdown returns as soon as its connection ends
+        send.write_all(b"hi").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(64).await?, b"\x01PONGhi");
+        conn.close(0u32.into(), b"bye!");
+        assert!(shutdown.await?);
+        assert_eq!(server.metrics().pings_recv.get(), 1);
+
+        // a connection that outlives the grace period is closed
+        let handle = server.clone().spawn_accept_loop(ep.clone());
+        let conn = client.connect(addr, ALPN_V1).await?;
+        Ping::new().ping_on_conn(&conn).await?;
+        assert!(
+            !handle
+                .shutdown_with_timeout(Duration::from_millis(100))
+                .await
+        );
+        conn.closed().await;
+        assert_eq!(server.metrics().pings_in_flight.get(), 0);
+
+        client.close().await;
+        ep.close().await;
+
+        Ok(())
+    }
+}
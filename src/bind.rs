@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+
+use iroh::{endpoint::Builder, Endpoint, RelayMode};
+
+use crate::PingError;
+
+/// Bind an endpoint to a specific local address, e.g. to ping from or serve pings on a
+/// particular interface and port.
+///
+/// `builder` carries the rest of the endpoint configuration. The address sets the socket of
+/// its IP family, the endpoint still binds a socket of the other family on a random port.
+/// Port 0 picks a random port, read it back from [`Endpoint::bound_sockets`].
+///
+/// Iroh quietly falls back to a random port if the requested one is taken, this fails with
+/// [`PingError::BindPortTaken`] instead, so firewall rules for the port keep matching.
+pub async fn bind_endpoint(builder: Builder, addr: SocketAddr) -> Result<Endpoint, PingError> {
+    let builder = match addr {
+        SocketAddr::V4(addr) => builder.bind_addr_v4(addr),
+        SocketAddr::V6(addr) => builder.bind_addr_v6(addr),
+    };
+    let endpoint = builder.bind().await.map_err(|source| PingError::Bind {
+        addr,
+        source: Box::new(source),
+    })?;
+    if addr.port() != 0 {
+        let bound = endpoint
+            .bound_sockets()
+            .into_iter()
+            .find(|bound| bound.is_ipv4() == addr.is_ipv4());
+        if let Some(bound) = bound.filter(|bound| bound.port() != addr.port()) {
+            endpoint.close().await;
+            return Err(PingError::BindPortTaken { addr, bound });
+        }
+    }
+    Ok(endpoint)
+}
+
+/// An endpoint builder without discovery or relays, for networks without internet access.
+///
+/// Nodes are then only reachable at the socket addresses their [`NodeAddr`](iroh::NodeAddr)
+/// carries, which must be exchanged some other way, e.g. in a ticket. Ping them with
+/// [`Ping::ping_direct`](crate::Ping::ping_direct) or any other ping taking an address.
+pub fn no_discovery_builder() -> Builder {
+    Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .clear_discovery()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use iroh::{protocol::Router, NodeAddr};
+
+    use super::*;
+    use crate::{Ping, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_bind_endpoint() -> anyhow::Result<()> {
+        let local = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let builder = Endpoint::builder().relay_mode(RelayMode::Disabled);
+        let ep = bind_endpoint(builder, local).await?;
+        let bound = ep
+            .bound_sockets()
+            .into_iter()
+            .find(SocketAddr::is_ipv4)
+            .expect("an IPv4 socket");
+        assert_eq!(bound.ip(), local.ip());
+        assert_ne!(bound.port(), 0);
+        let addr = NodeAddr::from_parts(ep.node_id(), None, [bound]);
+        let _router = Router::builder(ep).accept(ALPN_V1, Ping::new()).spawn();
+
+        let builder = Endpoint::builder().relay_mode(RelayMode::Disabled);
+        let client = bind_endpoint(builder, local).await?;
+        Ping::new().ping_once(&client, addr).await?;
+
+        // a port somebody else holds
+        let taken = UdpSocket::bind(local)?;
+        let builder = Endpoint::builder().relay_mode(RelayMode::Disabled);
+        let err = bind_endpoint(builder, taken.local_addr()?)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::BindPortTaken { .. }), "{err:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_discovery() -> anyhow::Result<()> {
+        let server = no_discovery_builder().bind().await?;
+        let client = no_discovery_builder().bind().await?;
+        // hand the server's addresses to the client, no discovery service involved
+        let node_id = server.node_id();
+        let addrs = server
+            .bound_sockets()
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()))
+            .collect();
+        let _router = Router::builder(server).accept(ALPN_V1, Ping::new()).spawn();
+
+        Ping::new().ping_direct(&client, node_id, addrs).await?;
+
+        // without addresses it has no way to find the server
+        let err = Ping::new()
+            .ping_direct(&client, node_id, Vec::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::Unreachable { .. }), "{err:?}");
+
+        Ok(())
+    }
+}
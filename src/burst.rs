@@ -0,0 +1,415 @@
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use iroh::{endpoint::Connection, Endpoint, NodeAddr};
+use tokio::task::JoinSet;
+
+use crate::{Ping, PingError, PingStats, TransportStats};
+
+/// Outcome of [`Ping::ping_burst`].
+#[derive(Debug)]
+pub struct BurstReport {
+    /// the result of every ping, in the order they were sent
+    pub results: Vec<Result<Duration, PingError>>,
+    /// sequence numbers of the successful pings, in the order their answers arrived
+    pub completion_order: Vec<u32>,
+    /// statistics over the burst
+    pub stats: PingStats,
+}
+
+impl BurstReport {
+    /// Number of pings answered after a ping that was sent later.
+    pub fn reordered(&self) -> usize {
+        count_reordered(&self.completion_order)
+    }
+}
+
+/// Counts the sequence numbers in `order` that come after a higher one.
+pub(crate) fn count_reordered(order: &[u32]) -> usize {
+    let mut highest = None;
+    let mut reordered = 0;
+    for &seq in order {
+        if highest.is_some_and(|highest| seq < highest) {
+            reordered += 1;
+        } else {
+            highest = Some(seq);
+        }
+    }
+    reordered
+}
+
+/// Outcome of [`Ping::ping_flood`].
+#[derive(Debug, Clone)]
+pub struct FloodStats {
+    /// number of pings sent, answered or not
+    pub total_pings: u64,
+    /// number of pings answered
+    pub successful: u64,
+    /// number of pings that failed
+    pub failed: u64,
+    /// bytes sent and received in UDP datagrams on the connection during the flood
+    pub total_bytes: u64,
+    /// how long the flood actually ran
+    pub elapsed: Duration,
+    /// answered pings per second
+    pub pings_per_second: f64,
+    /// bytes sent and received per second
+    pub bytes_per_second: f64,
+    /// statistics over the answered pings
+    pub rtt_stats: PingStats,
+}
+
+impl FloodStats {
+    /// Pings per second a single ping at a time could reach, given the average round
+    /// trip time.
+    pub fn sequential_max(&self) -> f64 {
+        let avg = self.rtt_stats.avg().as_secs_f64();
+        if avg == 0.0 {
+            return 0.0;
+        }
+        1.0 / avg
+    }
+
+    /// Estimate of how many pings were in flight on average, comparing the measured pings
+    /// per second to [`FloodStats::sequential_max`].
+    ///
+    /// Falls short of the concurrency asked for when the node or the connection can't keep
+    /// up, e.g. because the node limits the number of streams.
+    pub fn effective_concurrency(&self) -> f64 {
+        self.pings_per_second * self.rtt_stats.avg().as_secs_f64()
+    }
+}
+
+impl Ping {
+    /// Send `n` pings at once over a single connection, one stream each.
+    ///
+    /// The pings are numbered from 0 in the order they were started. The server answers
+    /// them concurrently too (see [`Ping::with_max_concurrent_streams`]), so this shows how
+    /// pings queue up when many are in flight. Every ping is bounded by the timeout (see
+    /// [`Ping::with_timeout`]), as is establishing the connection. Fails only if the
+    /// connection cannot be established, failed pings are recorded in the report and
+    /// counted in the metrics. Does not close the endpoint.
+    pub async fn ping_burst(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        n: usize,
+    ) -> Result<BurstReport, PingError> {
+        let conn = self.connect_timeout(endpoint, addr).await?;
+
+        let mut tasks = JoinSet::new();
+        for seq in 0..n as u32 {
+            let conn = conn.clone();
+            let ping = self.clone();
+            tasks.spawn(async move {
+                // times out on its own, and counts in the metrics either way
+                let res = ping
+                    .ping_on_conn_version(&conn, ping.version_on(&conn), &mut BytesMut::new())
+                    .await;
+                (seq, res.map(|(rtt, _version)| rtt))
+            });
+        }
+
+        let mut results: Vec<Option<Result<Duration, PingError>>> = (0..n).map(|_| None).collect();
+        let mut completion_order = Vec::with_capacity(n);
+        while let Some(res) = tasks.join_next().await {
+            // the tasks are never aborted, so this only fails if a ping panicked
+            let (seq, res) = res.expect("ping task panicked");
+            if res.is_ok() {
+                completion_order.push(seq);
+            }
+            results[seq as usize] = Some(res);
+        }
+
+        self.close_conn(&conn);
+
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|res| res.expect("every ping completed"))
+            .collect();
+        let mut stats = PingStats::with_samples();
+        for res in &results {
+            stats.record(res);
+        }
+        Ok(BurstReport {
+            results,
+            completion_order,
+            stats,
+        })
+    }
+
+    /// Ping a node as fast as possible for `duration`, to load test it.
+    ///
+    /// Keeps `concurrency` pings, but at least one, in flight at any time over a single
+    /// connection, each on its own stream, which the server answers concurrently up to
+    /// [`Ping::with_max_concurrent_streams`]. Every ping is bounded by the timeout, and one
+    /// timing out before the time is up counts as failed. Pings still in flight when the
+    /// time is up are dropped without being counted, and the connection is closed. Fails
+    /// only if the connection cannot be established within the timeout. Does not close
+    /// the endpoint.
+    ///
+    /// The pings count towards [`Ping::metrics`] as they complete, so those can be watched
+    /// while the flood runs.
+    pub async fn ping_flood(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        duration: Duration,
+        concurrency: usize,
+    ) -> Result<FloodStats, PingError> {
+        let conn = self.connect_timeout(endpoint, addr).await?;
+
+        let before = TransportStats::from_conn(&conn);
+        let start = Instant::now();
+        let deadline = tokio::time::Instant::from_std(start + duration);
+        let mut tasks = JoinSet::new();
+        for _ in 0..concurrency.max(1) {
+            let ping = self.clone();
+            let conn = conn.clone();
+            tasks.spawn(async move {
+                let mut stats = PingStats::default();
+                let mut version = ping.version_on(&conn);
+                let mut buf = BytesMut::new();
+                loop {
+                    // each ping is bounded by the timeout as well, whichever comes first: a
+                    // ping timing out on its own is a failure, one cut off by the deadline
+                    // is not counted
+                    let res = tokio::time::timeout_at(
+                        deadline,
+                        ping.ping_on_conn_version(&conn, version, &mut buf),
+                    );
+                    match res.await {
+                        Err(_deadline) => break,
+                        Ok(Ok((rtt, negotiated))) => {
+                            version = negotiated;
+                            stats.record_rtt(rtt);
+                        }
+                        Ok(Err(_)) => {
+                            stats.record_loss();
+                            // no point in hammering a connection that is gone
+                            if conn.close_reason().is_some() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                stats
+            });
+        }
+
+        let mut rtt_stats = PingStats::default();
+        while let Some(stats) = tasks.join_next().await {
+            // the tasks are never aborted, so this only fails if a ping panicked
+            rtt_stats.merge(&stats.expect("ping task panicked"));
+        }
+        let elapsed = start.elapsed();
+        let transport = TransportStats::from_conn(&conn).delta(&before);
+        self.close_conn(&conn);
+
+        let total_bytes = transport.bytes_sent + transport.bytes_received;
+        let per_second = |n: u64| {
+            if elapsed.is_zero() {
+                0.0
+            } else {
+                n as f64 / elapsed.as_secs_f64()
+            }
+        };
+        Ok(FloodStats {
+            total_pings: rtt_stats.sent(),
+            successful: rtt_stats.received(),
+            failed: rtt_stats.lost(),
+            total_bytes,
+            elapsed,
+            pings_per_second: per_second(rtt_stats.received()),
+            bytes_per_second: per_second(total_bytes),
+            rtt_stats,
+        })
+    }
+
+    /// Send `count` pings at once over an existing connection, one stream each.
+    ///
+    /// Returns the round trip times in the order the answers arrived, each with the index
+    /// of the stream it was sent on. Unlike [`Ping::ping_n_on_conn`], no ping waits for the
+    /// answer to the previous one, and the server answers the streams concurrently, up to
+    /// [`Ping::with_max_concurrent_streams`], so comparing the two shows what queueing the
+    /// pings costs. Fails on the first ping that fails, and stops the others then. Does not
+    /// close the connection.
+    pub async fn ping_pipelined(
+        &self,
+        conn: &Connection,
+        count: usize,
+    ) -> Result<Vec<(usize, Duration)>, PingError> {
+        let mut tasks = JoinSet::new();
+        for index in 0..count {
+            let ping = self.clone();
+            let conn = conn.clone();
+            tasks.spawn(async move {
+                let (rtt, _version) = ping
+                    .ping_on_conn_version(&conn, ping.version_on(&conn), &mut BytesMut::new())
+                    .await?;
+                Ok::<_, PingError>((index, rtt))
+            });
+        }
+
+        let mut rtts = Vec::with_capacity(count);
+        while let Some(res) = tasks.join_next().await {
+            // the tasks are only aborted when dropped, so this only fails if a ping panicked
+            rtts.push(res.expect("ping task panicked")?);
+        }
+        Ok(rtts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::protocol::{AcceptError, ProtocolHandler};
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1, PROTOCOL_VERSION};
+
+    /// Accepts streams but never answers them.
+    #[derive(Debug, Clone)]
+    struct Mute;
+
+    impl ProtocolHandler for Mute {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let mut streams = Vec::new();
+            while let Ok(bi) = connection.accept_bi().await {
+                streams.push(bi);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reordered() {
+        let report = BurstReport {
+            results: Vec::new(),
+            completion_order: vec![0, 2, 1, 3, 5, 4, 6],
+            stats: PingStats::default(),
+        };
+        assert_eq!(report.reordered(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ping_burst() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, server.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        let report = ping_client.ping_burst(&client, addr, 32).await?;
+        assert!(report.results.iter().all(Result::is_ok));
+        assert_eq!(report.completion_order.len(), 32);
+        assert_eq!(report.stats.received(), 32);
+        assert_eq!(ping_client.metrics().pings_sent.get(), 32);
+        assert_eq!(server.metrics().pings_recv.get(), 32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_burst_unanswered() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Mute).await?;
+        let (_other, _, client) = test_utils::local_pair().await?;
+        // long enough for the handshake, which the timeout bounds as well
+        let ping = Ping::new().with_timeout(Duration::from_secs(3));
+        let report = ping.ping_burst(&client, addr, 4).await?;
+        assert_eq!(report.results.len(), 4);
+        assert!(
+            report
+                .results
+                .iter()
+                .all(|res| matches!(res, Err(PingError::Timeout { .. }))),
+            "{report:?}"
+        );
+        assert!(report.completion_order.is_empty());
+        assert_eq!(report.stats.lost(), 4);
+        assert_eq!(ping.metrics().pings_failed.get(), 4);
+        assert_eq!(ping.metrics().pings_timed_out.get(), 4);
+        assert_eq!(ping.metrics().pings_sent.get(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_flood() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr, client) = test_utils::local_pair_with(server.clone()).await?;
+        let ping = Ping::new();
+        let res = ping
+            .ping_flood(&client, addr, Duration::from_millis(500), 8)
+            .await?;
+        assert!(res.successful > 0, "{res:?}");
+        assert_eq!(res.failed, 0, "{res:?}");
+        assert_eq!(res.total_pings, res.successful);
+        assert_eq!(res.rtt_stats.received(), res.successful);
+        assert_eq!(ping.metrics().pings_sent.get(), res.successful);
+        assert!(res.total_bytes > 0, "{res:?}");
+        assert!(res.pings_per_second > 0.0 && res.bytes_per_second > 0.0);
+        assert!(res.sequential_max() > 0.0);
+        assert!(res.effective_concurrency() > 0.0);
+        assert!(server.metrics().pings_recv.get() >= res.successful);
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_flood_unanswered() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Mute).await?;
+        let (_other, _, client) = test_utils::local_pair().await?;
+        let ping = Ping::new().with_timeout(Duration::from_secs(3));
+        let res = ping
+            .ping_flood(&client, addr, Duration::from_secs(5), 2)
+            .await?;
+        // the first ping of each worker times out, the second is cut off by the deadline
+        assert_eq!(res.successful, 0, "{res:?}");
+        assert_eq!(res.failed, 2, "{res:?}");
+        assert_eq!(ping.metrics().pings_failed.get(), 2);
+        assert_eq!(ping.metrics().pings_timed_out.get(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pipelined() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, server.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        let rtts = ping.ping_pipelined(&conn, 16).await?;
+        let mut indices: Vec<_> = rtts.iter().map(|(index, _)| *index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..16).collect::<Vec<_>>());
+        assert_eq!(ping.metrics().pings_sent.get(), 16);
+        assert_eq!(server.metrics().pings_recv.get(), 16);
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pipelined_next_to_reset_stream() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        // a ping the client gives up on within its length prefix
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(&[PROTOCOL_VERSION, b'P', b'I', b'N', b'G', 0])
+            .await?;
+        send.reset(0u32.into())?;
+        let rtts = Ping::new().ping_pipelined(&conn, 8).await?;
+        assert_eq!(rtts.len(), 8);
+        assert!(conn.close_reason().is_none());
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadToEndError, RecvStream, SendStream},
+    protocol::AcceptError,
+};
+
+use crate::{
+    codec::{self, CapsFrame},
+    fallback_version, read_to_end_into, reject, Negotiated, Ping, PingError, PingServerMode,
+    ERR_INVALID_REQUEST,
+};
+
+/// Oldest protocol version with capability negotiation.
+const CAPS_VERSION: u8 = 6;
+
+/// Capability of answering datagram pings, see [`Ping::with_datagrams`].
+pub const CAP_DATAGRAMS: u64 = 1;
+/// Capability of answering ping streams, see [`Ping::open_stream`].
+pub const CAP_STREAMS: u64 = 1 << 1;
+/// Capability of answering pings on unidirectional streams, see [`Ping::ping_uni`].
+pub const CAP_UNI: u64 = 1 << 2;
+/// Capability of throughput transfers, see [`Ping::throughput`].
+pub const CAP_THROUGHPUT: u64 = 1 << 3;
+/// Capability of refusing pings with an [`ErrorResponse`](crate::ErrorResponse).
+pub const CAP_ERROR_RESPONSES: u64 = 1 << 4;
+/// Capability of answering [`FLAG_SERVER_INFO`](crate::FLAG_SERVER_INFO).
+pub const CAP_SERVER_INFO: u64 = 1 << 5;
+/// Capability of answering [`FLAG_OBSERVED_ADDR`](crate::FLAG_OBSERVED_ADDR).
+pub const CAP_OBSERVED_ADDR: u64 = 1 << 6;
+/// Capability of answering [`FLAG_REVERSE_PING`](crate::FLAG_REVERSE_PING).
+pub const CAP_REVERSE_PING: u64 = 1 << 7;
+/// Capability of pushing heartbeats, see [`Ping::subscribe_heartbeats`].
+pub const CAP_HEARTBEATS: u64 = 1 << 8;
+/// Capability of dialing clients back, see [`Ping::with_dial_back`].
+pub const CAP_DIAL_BACK: u64 = 1 << 9;
+
+/// All capabilities this crate knows, which is what clients ask for.
+const CAP_ALL: u64 = CAP_DATAGRAMS
+    | CAP_STREAMS
+    | CAP_UNI
+    | CAP_THROUGHPUT
+    | CAP_ERROR_RESPONSES
+    | CAP_SERVER_INFO
+    | CAP_OBSERVED_ADDR
+    | CAP_REVERSE_PING
+    | CAP_HEARTBEATS
+    | CAP_DIAL_BACK;
+
+/// What a server supports, as agreed on with [`Ping::negotiate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    /// the protocol version agreed on
+    pub version: u8,
+    /// the supported features, as `CAP_*` bits like [`CAP_DATAGRAMS`]
+    pub features: u64,
+    /// the largest payload the server accepts, `None` if it is too old to say
+    pub max_payload: Option<usize>,
+    /// the largest throughput transfer the server moves, `None` if it is too old to say
+    pub max_transfer: Option<u64>,
+}
+
+impl Capabilities {
+    /// Whether the server supports all of `features`, as `CAP_*` bits.
+    pub fn supports(&self, features: u64) -> bool {
+        self.features & features == features
+    }
+
+    /// whether the server answers datagram pings
+    pub fn datagrams(&self) -> bool {
+        self.supports(CAP_DATAGRAMS)
+    }
+
+    /// Capabilities of a server too old to negotiate, going by its protocol version only.
+    ///
+    /// Features requested by flags may have been added without a new version, so they are
+    /// left out.
+    fn inferred(version: u8) -> Self {
+        let mut features = CAP_DATAGRAMS | CAP_THROUGHPUT;
+        if version >= 3 {
+            features |= CAP_STREAMS;
+        }
+        if version >= 4 {
+            features |= CAP_UNI;
+        }
+        if version >= 5 {
+            features |= CAP_ERROR_RESPONSES;
+        }
+        Self {
+            version,
+            features,
+            max_payload: None,
+            max_transfer: None,
+        }
+    }
+}
+
+impl Ping {
+    /// Ask the server at the other end of `conn` what it supports, see [`Capabilities`].
+    ///
+    /// The client sends `WANT` and a framed [`CapsFrame`] listing every feature it knows,
+    /// and the server answers with `CAPS` and those of them it supports, plus its limits.
+    /// Servers too old for that are judged by the protocol version they speak. Takes a
+    /// round trip, more if the versions need to be agreed on. Does not close the
+    /// connection.
+    pub async fn negotiate(&self, conn: &Connection) -> Result<Capabilities, PingError> {
+        let negotiate = async {
+            let mut version = self.max_version;
+            loop {
+                if version < CAPS_VERSION {
+                    return Ok(Capabilities::inferred(version));
+                }
+                match request(conn, version).await? {
+                    Negotiated::Done(caps) => return Ok(caps),
+                    Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, negotiate)
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+    }
+
+    /// The capabilities this node offers when answering, out of [`CAP_ALL`].
+    fn capabilities(&self) -> u64 {
+        if self.server_mode == PingServerMode::Echo {
+            return 0;
+        }
+        let mut features = CAP_ALL;
+        if !self.datagrams {
+            features &= !CAP_DATAGRAMS;
+        }
+        if !self.share_observed_addr {
+            features &= !CAP_OBSERVED_ADDR;
+        }
+        if self.dial_back.is_none() || self.endpoint.is_none() {
+            features &= !CAP_DIAL_BACK;
+        }
+        features
+    }
+}
+
+/// Sends a `WANT` in `version` and reads the server's `CAPS`.
+async fn request(conn: &Connection, version: u8) -> Result<Negotiated<Capabilities>, PingError> {
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let nonce = rand::random();
+    let mut buf = BytesMut::new();
+    buf.put_u8(version);
+    buf.put_slice(b"WANT");
+    CapsFrame {
+        features: CAP_ALL,
+        max_payload: 0,
+        max_transfer: 0,
+        nonce,
+    }
+    .encode(&mut buf);
+    send.write_all(&buf)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let response = recv
+        .read_to_end(1 + 4 + codec::LEN_PREFIX + CapsFrame::HEADER_LEN)
+        .await
+        .map_err(|source| PingError::Read { source })?;
+    match response.split_first() {
+        Some((&v, [])) if v != version => Ok(Negotiated::Fallback(v)),
+        Some((&v, rest)) if v == version && rest.starts_with(b"CAPS") => {
+            let frame =
+                CapsFrame::decode(&rest[4..]).map_err(|source| PingError::Decode { source })?;
+            if frame.nonce != nonce {
+                return Err(PingError::NonceMismatch {
+                    expected: nonce,
+                    got: frame.nonce,
+                });
+            }
+            Ok(Negotiated::Done(Capabilities {
+                version,
+                // a newer server can't support what we didn't ask for
+                features: frame.features & CAP_ALL,
+                max_payload: Some(usize::try_from(frame.max_payload).unwrap_or(usize::MAX)),
+                max_transfer: Some(frame.max_transfer),
+            }))
+        }
+        _ => Err(PingError::InvalidResponse { response }),
+    }
+}
+
+/// Answers a `WANT` in `version` with the capabilities of `ping` out of those asked for.
+pub(crate) async fn handle_caps(
+    ping: &Ping,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    if version < CAPS_VERSION {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    }
+    match read_to_end_into(&mut recv, buf, codec::LEN_PREFIX + CapsFrame::HEADER_LEN).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    let Ok(request) = CapsFrame::decode(buf) else {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    };
+    buf.clear();
+    buf.put_u8(version);
+    buf.put_slice(b"CAPS");
+    CapsFrame {
+        features: request.features & ping.capabilities(),
+        max_payload: ping.max_payload as u64,
+        max_transfer: ping.max_transfer,
+        nonce: request.nonce,
+    }
+    .encode(buf);
+    send.write_all(buf).await.map_err(AcceptError::from_err)?;
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, PingSession, ALPN_V1, PROTOCOL_VERSION};
+
+    #[tokio::test]
+    async fn test_negotiate() -> anyhow::Result<()> {
+        let server = Ping::new().with_max_payload(512);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        let caps = ping.negotiate(&conn).await?;
+        assert_eq!(caps.version, PROTOCOL_VERSION);
+        assert_eq!(caps.features, CAP_ALL & !CAP_DIAL_BACK);
+        assert_eq!(caps.max_payload, Some(512));
+        assert!(caps.datagrams());
+
+        // older servers are judged by their version
+        let old = Ping::new().with_max_version(CAPS_VERSION - 1);
+        let caps = old.negotiate(&conn).await?;
+        assert_eq!(caps, Capabilities::inferred(CAPS_VERSION - 1));
+        assert!(caps.supports(CAP_STREAMS | CAP_UNI));
+        assert!(!caps.supports(CAP_REVERSE_PING));
+        assert_eq!(caps.max_payload, None);
+
+        // the connection is good for pings afterwards
+        ping.ping_on_conn(&conn).await?;
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_datagrams_away() -> anyhow::Result<()> {
+        let server = Ping::new().with_datagrams(false);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        let mut session = PingSession::new();
+        assert_eq!(session.capabilities(), None);
+        let caps = session.negotiate(&ping, &conn).await?;
+        assert!(!caps.datagrams());
+        assert!(caps.supports(CAP_STREAMS));
+        assert_eq!(session.capabilities(), Some(&caps));
+
+        let err = session.ping_datagram(&ping, &conn).await.unwrap_err();
+        assert!(matches!(err, PingError::DatagramsUnsupported), "{err:?}");
+        // refused before sending anything, and not counted as a lost ping
+        assert_eq!(conn.stats().frame_tx.datagram, 0);
+        assert_eq!(session.sent(), 0);
+
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+}
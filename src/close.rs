@@ -0,0 +1,160 @@
+use std::fmt;
+
+use iroh::endpoint::{Connection, ConnectionError, ReadError, ReadToEndError, VarInt, WriteError};
+
+use crate::PingError;
+
+/// Application error codes either side closes a ping connection with.
+///
+/// A side that receives something it can't handle closes the connection with one of
+/// these and a short reason, and fails locally with a typed error. The other side
+/// translates the code into a [`PingError::Rejected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// done pinging, the code of every regular close
+    Ok,
+    /// the peer sent something that breaks the protocol
+    BadRequest,
+    /// the peer asked for a protocol version that doesn't exist
+    UnsupportedVersion,
+    /// the closing side failed on its own
+    Internal,
+}
+
+impl CloseCode {
+    /// the code on the wire
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Ok => 0,
+            Self::BadRequest => 1,
+            Self::UnsupportedVersion => 2,
+            Self::Internal => 3,
+        }
+    }
+
+    /// the close code for a code on the wire, if it is one of ours
+    pub fn from_code(code: VarInt) -> Option<Self> {
+        [
+            Self::Ok,
+            Self::BadRequest,
+            Self::UnsupportedVersion,
+            Self::Internal,
+        ]
+        .into_iter()
+        .find(|close| VarInt::from(close.code()) == code)
+    }
+
+    /// Close `conn` with this code and `reason`.
+    pub(crate) fn close(self, conn: &Connection, reason: &[u8]) {
+        conn.close(self.code().into(), reason);
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ok => "ok",
+            Self::BadRequest => "bad request",
+            Self::UnsupportedVersion => "unsupported version",
+            Self::Internal => "internal error",
+        })
+    }
+}
+
+/// Closes `conn` with [`CloseCode::BadRequest`] if `err` means the peer broke the
+/// protocol, leaving it open otherwise.
+pub(crate) fn close_on_violation(conn: &Connection, err: &PingError) {
+    let reason: &[u8] = match err {
+        PingError::InvalidResponse { .. } | PingError::Decode { .. } => b"invalid response",
+        PingError::NonceMismatch { .. } => b"nonce mismatch",
+        PingError::IntegrityMismatch { .. } => b"corrupted payload",
+        _ => return,
+    };
+    CloseCode::BadRequest.close(conn, reason);
+}
+
+/// Turns an error caused by the peer closing the connection with one of our
+/// [`CloseCode`]s, other than [`CloseCode::Ok`], into [`PingError::Rejected`].
+pub(crate) fn translate(err: PingError) -> PingError {
+    let conn_err = match &err {
+        PingError::Connection { source }
+        | PingError::Read {
+            source: ReadToEndError::Read(ReadError::ConnectionLost(source)),
+        }
+        | PingError::Write {
+            source: WriteError::ConnectionLost(source),
+        } => source,
+        _ => return err,
+    };
+    let ConnectionError::ApplicationClosed(close) = conn_err else {
+        return err;
+    };
+    match CloseCode::from_code(close.error_code) {
+        Some(CloseCode::Ok) | None => err,
+        Some(code) => PingError::Rejected {
+            code,
+            reason: String::from_utf8_lossy(&close.reason).into_owned(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::endpoint::ApplicationClose;
+
+    use super::*;
+
+    fn closed(code: u32, reason: &'static [u8]) -> ConnectionError {
+        ConnectionError::ApplicationClosed(ApplicationClose {
+            error_code: code.into(),
+            reason: reason.into(),
+        })
+    }
+
+    #[test]
+    fn test_from_code() {
+        for code in [
+            CloseCode::Ok,
+            CloseCode::BadRequest,
+            CloseCode::UnsupportedVersion,
+            CloseCode::Internal,
+        ] {
+            assert_eq!(CloseCode::from_code(code.code().into()), Some(code));
+        }
+        assert_eq!(CloseCode::from_code(42u32.into()), None);
+    }
+
+    #[test]
+    fn test_translate() {
+        let err = translate(PingError::Connection {
+            source: closed(CloseCode::Internal.code(), b"oops"),
+        });
+        assert!(
+            matches!(&err, PingError::Rejected { code: CloseCode::Internal, reason } if reason == "oops"),
+            "{err:?}"
+        );
+        assert_eq!(err.to_string(), "server rejected: internal error (oops)");
+
+        let err = translate(PingError::Write {
+            source: WriteError::ConnectionLost(closed(CloseCode::BadRequest.code(), b"")),
+        });
+        assert!(
+            matches!(
+                err,
+                PingError::Rejected {
+                    code: CloseCode::BadRequest,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        // regular closes and foreign codes stay as they are
+        for code in [CloseCode::Ok.code(), 42] {
+            let err = translate(PingError::Connection {
+                source: closed(code, b"bye!"),
+            });
+            assert!(matches!(err, PingError::Connection { .. }), "{err:?}");
+        }
+    }
+}
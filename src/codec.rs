@@ -0,0 +1,989 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{BufMut, BytesMut};
+use snafu::Snafu;
+
+/// Size of the length prefix in front of every frame.
+pub const LEN_PREFIX: usize = 4;
+
+/// Size of the nonce a client puts in every request, see [`PingRequest::nonce`].
+pub const NONCE_LEN: usize = 16;
+
+/// Random bytes a client puts in a request, which the server copies into its response.
+pub type Nonce = [u8; NONCE_LEN];
+
+/// Flag of a [`PingRequest`] asking the server to include a [`ServerInfo`] in its response,
+/// and of a [`PongResponse`] carrying one.
+pub const FLAG_SERVER_INFO: u64 = 1;
+
+/// Flag of a [`PingRequest`] asking the server to include an [`ObservedAddr`] in its
+/// response, and of a [`PongResponse`] carrying one.
+pub const FLAG_OBSERVED_ADDR: u64 = 2;
+
+/// Flag of a [`PingRequest`] asking the server to ping the client back after its response,
+/// and of a [`PongResponse`] promising to.
+///
+/// The server then opens a bidirectional stream of its own for the reverse ping, which
+/// measures the path in the other direction, see [`Ping::with_reverse_ping`].
+///
+/// [`Ping::with_reverse_ping`]: crate::Ping::with_reverse_ping
+pub const FLAG_REVERSE_PING: u64 = 4;
+
+/// Errors decoding a frame.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[non_exhaustive]
+pub enum CodecError {
+    /// The length prefix announces a frame larger than allowed.
+    #[snafu(display("frame of {len} bytes exceeds the limit of {max_len} bytes"))]
+    TooLarge { len: usize, max_len: usize },
+    /// The frame ended before all of it arrived, or before all its fields.
+    #[snafu(display("truncated frame, expected {expected} bytes but got {got}"))]
+    Truncated { expected: usize, got: usize },
+    /// There are more bytes than the length prefix announced.
+    #[snafu(display("{extra} trailing bytes after the frame"))]
+    TrailingBytes { extra: usize },
+    /// A text field is not valid UTF-8.
+    #[snafu(display("text field is not valid UTF-8"))]
+    InvalidUtf8,
+    /// An address field holds an IP version other than 4 or 6.
+    #[snafu(display("unknown IP version {version}"))]
+    UnknownIpVersion { version: u8 },
+}
+
+/// Decodes a length prefix, returning the length of the frame body behind it.
+///
+/// Fails if the body would be larger than `max_len`, so a reader can reject a frame before
+/// buffering any of it.
+pub fn frame_len(prefix: [u8; LEN_PREFIX], max_len: usize) -> Result<usize, CodecError> {
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > max_len {
+        return Err(CodecError::TooLarge { len, max_len });
+    }
+    Ok(len)
+}
+
+/// Splits a whole frame, length prefix included, into its body.
+fn frame_body(frame: &[u8], max_len: usize) -> Result<&[u8], CodecError> {
+    let Some((prefix, body)) = frame.split_first_chunk::<LEN_PREFIX>() else {
+        return Err(CodecError::Truncated {
+            expected: LEN_PREFIX,
+            got: frame.len(),
+        });
+    };
+    let len = frame_len(*prefix, max_len)?;
+    match body.len() {
+        got if got < len => Err(CodecError::Truncated {
+            expected: LEN_PREFIX + len,
+            got: frame.len(),
+        }),
+        got if got > len => Err(CodecError::TrailingBytes { extra: got - len }),
+        _ => Ok(body),
+    }
+}
+
+/// Appends a frame to `buf`, with the integer fields and the nonce first and then whatever
+/// `rest` writes.
+fn encode_frame(
+    buf: &mut BytesMut,
+    fields: &[u64],
+    nonce: &Nonce,
+    rest: impl FnOnce(&mut BytesMut),
+) {
+    let start = buf.len();
+    buf.put_u32(0);
+    for field in fields {
+        buf.put_u64(*field);
+    }
+    buf.put_slice(nonce);
+    rest(buf);
+    // now that the body is written, fill in its length
+    let len = (buf.len() - start - LEN_PREFIX) as u32;
+    buf[start..start + LEN_PREFIX].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Reads `N` integer fields and the nonce from the front of a frame body, returning them
+/// and the payload behind.
+fn decode_fields<const N: usize>(body: &[u8]) -> Result<([u64; N], Nonce, &[u8]), CodecError> {
+    let header_len = N * 8 + NONCE_LEN;
+    if body.len() < header_len {
+        return Err(CodecError::Truncated {
+            expected: LEN_PREFIX + header_len,
+            got: LEN_PREFIX + body.len(),
+        });
+    }
+    let (fields, rest) = body.split_at(N * 8);
+    let (nonce, payload) = rest.split_at(NONCE_LEN);
+    let mut out = [0u64; N];
+    for (out, field) in out.iter_mut().zip(fields.chunks_exact(8)) {
+        *out = u64::from_be_bytes(field.try_into().expect("chunks of 8"));
+    }
+    Ok((out, nonce.try_into().expect("split at NONCE_LEN"), payload))
+}
+
+/// Body of a `PING` request in protocol version 2, see [`PROTOCOL_VERSION`].
+///
+/// Frames are a four byte big endian length prefix followed by the body, which holds the
+/// integer fields as big endian `u64`s in declaration order, then the nonce, then the
+/// payload.
+///
+/// [`PROTOCOL_VERSION`]: crate::PROTOCOL_VERSION
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingRequest<'a> {
+    /// chosen by the client, to match the response to the request
+    pub seq: u64,
+    /// client clock when sending, in microseconds since the unix epoch
+    pub sent_at_us: u64,
+    /// what to include in the response, see [`FLAG_SERVER_INFO`]
+    pub flags: u64,
+    /// random bytes the server must copy into its response, so a canned reply is noticed
+    pub nonce: Nonce,
+    /// bytes to echo
+    pub payload: &'a [u8],
+}
+
+impl<'a> PingRequest<'a> {
+    /// Size of the body without the payload.
+    pub const HEADER_LEN: usize = 24 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let fields = [self.seq, self.sent_at_us, self.flags];
+        encode_frame(buf, &fields, &self.nonce, |buf| buf.put_slice(self.payload));
+    }
+
+    /// Decode a whole frame, length prefix included, whose body is at most `max_len` bytes.
+    pub fn decode(frame: &'a [u8], max_len: usize) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, max_len)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &'a [u8]) -> Result<Self, CodecError> {
+        let ([seq, sent_at_us, flags], nonce, payload) = decode_fields(body)?;
+        Ok(Self {
+            seq,
+            sent_at_us,
+            flags,
+            nonce,
+            payload,
+        })
+    }
+}
+
+/// Body of a `PONG` response in protocol version 2, see [`PingRequest`] for the framing.
+///
+/// If its flags contain [`FLAG_SERVER_INFO`], the nonce is followed by the server info:
+/// the uptime in microseconds and the pings served as `u64`s, the protocol version as a
+/// byte, and the crate version as a byte holding its length and then the text. If they
+/// contain [`FLAG_OBSERVED_ADDR`], the observed address comes next: a byte that is 1 if
+/// the connection goes through the relay, a byte holding the IP version or 0 for no
+/// address, then the IP address and the port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PongResponse<'a> {
+    /// the sequence number of the request
+    pub seq: u64,
+    /// the client clock of the request, echoed
+    pub sent_at_us: u64,
+    /// server clock when the request arrived, in microseconds since the unix epoch
+    pub received_at_us: u64,
+    /// Microseconds the server spent between reading the request and writing the response.
+    ///
+    /// Subtracting this from the round trip time leaves the time spent in the network.
+    pub processing_us: u64,
+    /// what the response includes, see [`FLAG_SERVER_INFO`]
+    pub flags: u64,
+    /// the nonce of the request, copied
+    pub nonce: Nonce,
+    /// about the server, if the request asked for it
+    pub server_info: Option<ServerInfo>,
+    /// how the server sees the client, if the request asked for it
+    pub observed_addr: Option<ObservedAddr>,
+    /// the payload of the request, echoed
+    pub payload: &'a [u8],
+}
+
+impl<'a> PongResponse<'a> {
+    /// Size of the body without the server info, the observed address and the payload.
+    pub const HEADER_LEN: usize = 40 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    ///
+    /// The server info is only written if the flags contain [`FLAG_SERVER_INFO`], and
+    /// then with empty fields if there is none, likewise the observed address.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let fields = [
+            self.seq,
+            self.sent_at_us,
+            self.received_at_us,
+            self.processing_us,
+            self.flags,
+        ];
+        encode_frame(buf, &fields, &self.nonce, |buf| {
+            if self.flags & FLAG_SERVER_INFO != 0 {
+                let info = self.server_info.clone().unwrap_or_default();
+                info.encode(buf);
+            }
+            if self.flags & FLAG_OBSERVED_ADDR != 0 {
+                self.observed_addr.unwrap_or_default().encode(buf);
+            }
+            buf.put_slice(self.payload);
+        });
+    }
+
+    /// Decode a whole frame, length prefix included, whose body is at most `max_len` bytes.
+    pub fn decode(frame: &'a [u8], max_len: usize) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, max_len)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &'a [u8]) -> Result<Self, CodecError> {
+        let ([seq, sent_at_us, received_at_us, processing_us, flags], nonce, rest) =
+            decode_fields(body)?;
+        let (server_info, rest) = if flags & FLAG_SERVER_INFO != 0 {
+            let (info, rest) = ServerInfo::decode(rest)?;
+            (Some(info), rest)
+        } else {
+            (None, rest)
+        };
+        let (observed_addr, payload) = if flags & FLAG_OBSERVED_ADDR != 0 {
+            let (addr, payload) = ObservedAddr::decode(rest)?;
+            (Some(addr), payload)
+        } else {
+            (None, rest)
+        };
+        Ok(Self {
+            seq,
+            sent_at_us,
+            received_at_us,
+            processing_us,
+            flags,
+            nonce,
+            server_info,
+            observed_addr,
+            payload,
+        })
+    }
+}
+
+/// How a server sees the client of a connection, see [`Ping::with_observed_addr`].
+///
+/// This is the client's address after any NATs on the way, as STUN would report it.
+///
+/// [`Ping::with_observed_addr`]: crate::Ping::with_observed_addr
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ObservedAddr {
+    /// the address the client's packets arrive from, `None` if they only come through the
+    /// relay or the server doesn't know
+    pub addr: Option<SocketAddr>,
+    /// whether the connection goes through the relay, alone or next to the address
+    pub via_relay: bool,
+}
+
+impl ObservedAddr {
+    /// Largest encoded size.
+    pub const MAX_LEN: usize = 2 + 16 + 2;
+
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.via_relay.into());
+        encode_addr(buf, self.addr);
+    }
+
+    /// Decodes the address from the front of `body`, returning it and the rest.
+    fn decode(body: &[u8]) -> Result<(Self, &[u8]), CodecError> {
+        let truncated = |expected| CodecError::Truncated {
+            expected,
+            got: body.len(),
+        };
+        let Some((via_relay, rest)) = body.split_first() else {
+            return Err(truncated(2));
+        };
+        let (addr, rest) = decode_addr(rest).map_err(|err| match err {
+            CodecError::Truncated { expected, .. } => truncated(1 + expected),
+            err => err,
+        })?;
+        let observed = Self {
+            addr,
+            via_relay: *via_relay != 0,
+        };
+        Ok((observed, rest))
+    }
+}
+
+/// Appends an optional address as its IP version, 0 for none, the IP and the port.
+fn encode_addr(buf: &mut BytesMut, addr: Option<SocketAddr>) {
+    match addr {
+        None => buf.put_u8(0),
+        Some(SocketAddr::V4(addr)) => {
+            buf.put_u8(4);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        Some(SocketAddr::V6(addr)) => {
+            buf.put_u8(6);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+    }
+}
+
+/// Decodes an address written by [`encode_addr`] from the front of `body`, returning it
+/// and the rest.
+fn decode_addr(body: &[u8]) -> Result<(Option<SocketAddr>, &[u8]), CodecError> {
+    let truncated = |expected| CodecError::Truncated {
+        expected,
+        got: body.len(),
+    };
+    let Some((ip_version, rest)) = body.split_first() else {
+        return Err(truncated(1));
+    };
+    let ip_len = match ip_version {
+        0 => return Ok((None, rest)),
+        4 => 4,
+        6 => 16,
+        &version => return Err(CodecError::UnknownIpVersion { version }),
+    };
+    if rest.len() < ip_len + 2 {
+        return Err(truncated(1 + ip_len + 2));
+    }
+    let (ip, rest) = rest.split_at(ip_len);
+    let (port, rest) = rest.split_at(2);
+    let ip = match ip_len {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).expect("4 bytes")),
+        _ => IpAddr::from(<[u8; 16]>::try_from(ip).expect("16 bytes")),
+    };
+    let port = u16::from_be_bytes(port.try_into().expect("2 bytes"));
+    Ok((Some(SocketAddr::new(ip, port)), rest))
+}
+
+/// Why a server refused a request it understood, see [`ErrorResponse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// the client sends more pings than the server answers, it should slow down
+    RateLimited,
+    /// the server doesn't answer this client
+    Unauthorized,
+    /// the payload exceeds the server's limit
+    PayloadTooLarge,
+    /// a reason from a newer server, by its code on the wire
+    Other(u64),
+}
+
+impl RejectReason {
+    /// the code on the wire
+    pub fn code(self) -> u64 {
+        match self {
+            Self::RateLimited => 1,
+            Self::Unauthorized => 2,
+            Self::PayloadTooLarge => 3,
+            Self::Other(code) => code,
+        }
+    }
+
+    /// the reason for a code on the wire
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            1 => Self::RateLimited,
+            2 => Self::Unauthorized,
+            3 => Self::PayloadTooLarge,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited => f.write_str("rate limited"),
+            Self::Unauthorized => f.write_str("unauthorized"),
+            Self::PayloadTooLarge => f.write_str("payload too large"),
+            Self::Other(code) => write!(f, "reason {code}"),
+        }
+    }
+}
+
+/// Body of an `EROR` response in protocol version 5, see [`PingRequest`] for the framing.
+///
+/// A server answers a `PING` with this instead of a `PONG` to refuse it without closing
+/// the stream or the connection. The fields are the sequence number, the reason code, the
+/// retry hint in microseconds and the server's payload limit in bytes, followed by the
+/// nonce. The hint and the limit are zero if there is none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorResponse {
+    /// the sequence number of the request
+    pub seq: u64,
+    /// why the request was refused
+    pub reason: RejectReason,
+    /// how long the client should wait before asking again, if the server knows
+    pub retry_after: Option<Duration>,
+    /// the largest payload the server accepts, if it refused a payload as too large
+    pub limit: Option<u64>,
+    /// the nonce of the request, copied
+    pub nonce: Nonce,
+}
+
+impl ErrorResponse {
+    /// Size of the body.
+    pub const HEADER_LEN: usize = 32 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    ///
+    /// A retry hint below a microsecond is rounded up, so it isn't lost.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let retry_after_us = self
+            .retry_after
+            .map_or(0, |retry_after| (retry_after.as_micros() as u64).max(1));
+        let fields = [
+            self.seq,
+            self.reason.code(),
+            retry_after_us,
+            self.limit.unwrap_or(0),
+        ];
+        encode_frame(buf, &fields, &self.nonce, |_| {});
+    }
+
+    /// Decode a whole frame, length prefix included.
+    pub fn decode(frame: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, Self::HEADER_LEN)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &[u8]) -> Result<Self, CodecError> {
+        let ([seq, reason, retry_after_us, limit], nonce, rest) = decode_fields(body)?;
+        if !rest.is_empty() {
+            return Err(CodecError::TrailingBytes { extra: rest.len() });
+        }
+        Ok(Self {
+            seq,
+            reason: RejectReason::from_code(reason),
+            retry_after: (retry_after_us != 0).then(|| Duration::from_micros(retry_after_us)),
+            limit: (limit != 0).then_some(limit),
+            nonce,
+        })
+    }
+}
+
+/// Body of a `CAPS` request or answer, in protocol version 6.
+///
+/// The client lists the features it wants, and the server answers with those of them it
+/// supports, plus its limits, see [`Ping::negotiate`]. The fields are the feature bits,
+/// the largest payload and the largest transfer, both zero in requests, followed by the
+/// nonce.
+///
+/// [`Ping::negotiate`]: crate::Ping::negotiate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapsFrame {
+    /// the features, as `CAP_*` bits
+    pub features: u64,
+    /// the largest payload the server accepts
+    pub max_payload: u64,
+    /// the largest throughput transfer the server moves
+    pub max_transfer: u64,
+    /// a nonce the answer repeats
+    pub nonce: Nonce,
+}
+
+impl CapsFrame {
+    /// Size of the body.
+    pub const HEADER_LEN: usize = 24 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let fields = [self.features, self.max_payload, self.max_transfer];
+        encode_frame(buf, &fields, &self.nonce, |_| {});
+    }
+
+    /// Decode a whole frame, length prefix included.
+    pub fn decode(frame: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, Self::HEADER_LEN)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &[u8]) -> Result<Self, CodecError> {
+        let ([features, max_payload, max_transfer], nonce, rest) = decode_fields(body)?;
+        if !rest.is_empty() {
+            return Err(CodecError::TrailingBytes { extra: rest.len() });
+        }
+        Ok(Self {
+            features,
+            max_payload,
+            max_transfer,
+            nonce,
+        })
+    }
+}
+
+/// Body of a `BEAT` request and its acknowledgement, in protocol version 7.
+///
+/// The client asks for a heartbeat every `interval` for `duration`, and the server
+/// acknowledges with what it granted within its limits, see [`Ping::subscribe_heartbeats`].
+/// The fields are the interval and the duration in microseconds, followed by the nonce.
+///
+/// [`Ping::subscribe_heartbeats`]: crate::Ping::subscribe_heartbeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription {
+    /// time between two heartbeats
+    pub interval: Duration,
+    /// how long to send heartbeats for
+    pub duration: Duration,
+    /// chosen by the client, copied into the acknowledgement and every heartbeat
+    pub nonce: Nonce,
+}
+
+impl Subscription {
+    /// Size of the body.
+    pub const HEADER_LEN: usize = 16 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    ///
+    /// Durations beyond what fits into microseconds are capped.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let micros = |d: Duration| u64::try_from(d.as_micros()).unwrap_or(u64::MAX);
+        let fields = [micros(self.interval), micros(self.duration)];
+        encode_frame(buf, &fields, &self.nonce, |_| {});
+    }
+
+    /// Decode a whole frame, length prefix included.
+    pub fn decode(frame: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, Self::HEADER_LEN)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &[u8]) -> Result<Self, CodecError> {
+        let ([interval_us, duration_us], nonce, rest) = decode_fields(body)?;
+        if !rest.is_empty() {
+            return Err(CodecError::TrailingBytes { extra: rest.len() });
+        }
+        Ok(Self {
+            interval: Duration::from_micros(interval_us),
+            duration: Duration::from_micros(duration_us),
+            nonce,
+        })
+    }
+}
+
+/// Body of a `DIAL` request in protocol version 8.
+///
+/// The client asks the server to open a connection of its own back to the client and
+/// ping it there, see [`Ping::dial_back`]. The body is the nonce followed by the addresses
+/// to dial, each as its IP version, 4 or 6, the IP and the port. Without addresses the
+/// server dials the one it sees the client at.
+///
+/// [`Ping::dial_back`]: crate::Ping::dial_back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialRequest {
+    /// the client's addresses to dial, at most [`DialRequest::MAX_ADDRS`]
+    pub addrs: Vec<SocketAddr>,
+    /// a nonce the answer repeats
+    pub nonce: Nonce,
+}
+
+impl DialRequest {
+    /// Most addresses a request may carry.
+    pub const MAX_ADDRS: usize = 8;
+
+    /// Largest size of the body.
+    pub const MAX_LEN: usize = NONCE_LEN + Self::MAX_ADDRS * (1 + 16 + 2);
+
+    /// Append the frame to `buf`.
+    ///
+    /// Addresses beyond [`DialRequest::MAX_ADDRS`] are left out.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        encode_frame(buf, &[], &self.nonce, |buf| {
+            for addr in self.addrs.iter().take(Self::MAX_ADDRS) {
+                encode_addr(buf, Some(*addr));
+            }
+        });
+    }
+
+    /// Decode a whole frame, length prefix included.
+    pub fn decode(frame: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, Self::MAX_LEN)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &[u8]) -> Result<Self, CodecError> {
+        let ([], nonce, mut rest) = decode_fields(body)?;
+        let mut addrs = Vec::new();
+        while !rest.is_empty() {
+            match decode_addr(rest)? {
+                (Some(addr), tail) => {
+                    addrs.push(addr);
+                    rest = tail;
+                }
+                (None, _) => return Err(CodecError::UnknownIpVersion { version: 0 }),
+            }
+        }
+        Ok(Self { addrs, nonce })
+    }
+}
+
+/// Body of a `DIAL` answer in protocol version 8, see [`DialRequest`].
+///
+/// The fields are 1 if the server reached the client and 0 if not, and the round trip
+/// time of its ping in microseconds, zero if it failed, followed by the nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialResult {
+    /// round trip time of the server's ping, `None` if it couldn't reach the client
+    pub rtt: Option<Duration>,
+    /// the nonce of the request, copied
+    pub nonce: Nonce,
+}
+
+impl DialResult {
+    /// Size of the body.
+    pub const HEADER_LEN: usize = 16 + NONCE_LEN;
+
+    /// Append the frame to `buf`.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let fields = match self.rtt {
+            Some(rtt) => [1, u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX)],
+            None => [0, 0],
+        };
+        encode_frame(buf, &fields, &self.nonce, |_| {});
+    }
+
+    /// Decode a whole frame, length prefix included.
+    pub fn decode(frame: &[u8]) -> Result<Self, CodecError> {
+        Self::decode_body(frame_body(frame, Self::HEADER_LEN)?)
+    }
+
+    /// Decode a frame body, after its length prefix was checked with [`frame_len`].
+    pub fn decode_body(body: &[u8]) -> Result<Self, CodecError> {
+        let ([reached, rtt_us], nonce, rest) = decode_fields(body)?;
+        if !rest.is_empty() {
+            return Err(CodecError::TrailingBytes { extra: rest.len() });
+        }
+        Ok(Self {
+            rtt: (reached != 0).then(|| Duration::from_micros(rtt_us)),
+            nonce,
+        })
+    }
+}
+
+/// What a server reports about itself when asked, see [`Ping::with_server_info`].
+///
+/// [`Ping::with_server_info`]: crate::Ping::with_server_info
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerInfo {
+    /// version of this crate the server runs, at most 255 bytes long
+    pub crate_version: String,
+    /// newest protocol version the server speaks
+    pub protocol_version: u8,
+    /// time since the server's [`Ping`](crate::Ping) was created
+    pub uptime: Duration,
+    /// pings the server answered before this one
+    pub pings_served: u64,
+}
+
+impl ServerInfo {
+    /// Largest encoded size.
+    pub const MAX_LEN: usize = 18 + u8::MAX as usize;
+
+    fn encode(&self, buf: &mut BytesMut) {
+        let version = self.crate_version.as_bytes();
+        let version = &version[..version.len().min(u8::MAX as usize)];
+        buf.put_u64(self.uptime.as_micros() as u64);
+        buf.put_u64(self.pings_served);
+        buf.put_u8(self.protocol_version);
+        buf.put_u8(version.len() as u8);
+        buf.put_slice(version);
+    }
+
+    /// Decodes the info from the front of `body`, returning it and the rest.
+    fn decode(body: &[u8]) -> Result<(Self, &[u8]), CodecError> {
+        let truncated = |expected| CodecError::Truncated {
+            expected,
+            got: body.len(),
+        };
+        let Some((fixed, rest)) = body.split_first_chunk::<18>() else {
+            return Err(truncated(18));
+        };
+        let uptime_us = u64::from_be_bytes(fixed[..8].try_into().expect("8 bytes"));
+        let pings_served = u64::from_be_bytes(fixed[8..16].try_into().expect("8 bytes"));
+        let protocol_version = fixed[16];
+        let version_len = fixed[17] as usize;
+        if rest.len() < version_len {
+            return Err(truncated(18 + version_len));
+        }
+        let (version, rest) = rest.split_at(version_len);
+        let crate_version = std::str::from_utf8(version)
+            .map_err(|_| CodecError::InvalidUtf8)?
+            .to_string();
+        let info = Self {
+            crate_version,
+            protocol_version,
+            uptime: Duration::from_micros(uptime_us),
+            pings_served,
+        };
+        Ok((info, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = BytesMut::new();
+        let request = PingRequest {
+            seq: 7,
+            sent_at_us: 1_700_000_000_000_000,
+            flags: FLAG_SERVER_INFO,
+            nonce: [0xab; NONCE_LEN],
+            payload: b"hello",
+        };
+        request.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + PingRequest::HEADER_LEN + 5);
+        assert_eq!(PingRequest::decode(&buf, 1024), Ok(request));
+
+        buf.clear();
+        let response = PongResponse {
+            seq: 7,
+            sent_at_us: request.sent_at_us,
+            received_at_us: request.sent_at_us + 1,
+            processing_us: 12,
+            flags: 0,
+            nonce: request.nonce,
+            server_info: None,
+            observed_addr: None,
+            payload: &[],
+        };
+        response.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + PongResponse::HEADER_LEN);
+        assert_eq!(PongResponse::decode(&buf, 1024), Ok(response.clone()));
+
+        buf.clear();
+        let response = PongResponse {
+            flags: FLAG_SERVER_INFO,
+            server_info: Some(ServerInfo {
+                crate_version: "1.2.3".to_string(),
+                protocol_version: 2,
+                uptime: Duration::from_secs(3600),
+                pings_served: 42,
+            }),
+            payload: b"hello",
+            ..response
+        };
+        response.encode(&mut buf);
+        assert_eq!(PongResponse::decode(&buf, 1024), Ok(response));
+        // the text must be valid
+        let version_at = buf.len() - 5 - 5;
+        buf[version_at] = 0xff;
+        assert_eq!(
+            PongResponse::decode(&buf, 1024),
+            Err(CodecError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn test_caps_frame() {
+        let mut buf = BytesMut::new();
+        let frame = CapsFrame {
+            features: 0b1011,
+            max_payload: 1024,
+            max_transfer: u64::MAX,
+            nonce: [0xcd; NONCE_LEN],
+        };
+        frame.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + CapsFrame::HEADER_LEN);
+        assert_eq!(CapsFrame::decode(&buf), Ok(frame));
+
+        buf.put_u8(0);
+        assert!(CapsFrame::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_subscription() {
+        let mut buf = BytesMut::new();
+        let subscription = Subscription {
+            interval: Duration::from_secs(5),
+            duration: Duration::from_secs(600),
+            nonce: [0x5b; NONCE_LEN],
+        };
+        subscription.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + Subscription::HEADER_LEN);
+        assert_eq!(Subscription::decode(&buf), Ok(subscription));
+    }
+
+    #[test]
+    fn test_dial_frames() {
+        let mut buf = BytesMut::new();
+        let request = DialRequest {
+            addrs: vec![
+                "192.0.2.1:4433".parse().unwrap(),
+                "[2001:db8::1]:4433".parse().unwrap(),
+            ],
+            nonce: [0xd1; NONCE_LEN],
+        };
+        request.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + NONCE_LEN + 7 + 19);
+        assert_eq!(DialRequest::decode(&buf), Ok(request));
+
+        // no addresses asks for the observed one
+        buf.clear();
+        let request = DialRequest {
+            addrs: Vec::new(),
+            nonce: [0xd2; NONCE_LEN],
+        };
+        request.encode(&mut buf);
+        assert_eq!(DialRequest::decode(&buf), Ok(request));
+        buf.put_u8(0);
+        assert!(DialRequest::decode(&buf).is_err());
+
+        for rtt in [Some(Duration::from_micros(1234)), None] {
+            buf.clear();
+            let result = DialResult {
+                rtt,
+                nonce: [0xd3; NONCE_LEN],
+            };
+            result.encode(&mut buf);
+            assert_eq!(buf.len(), LEN_PREFIX + DialResult::HEADER_LEN);
+            assert_eq!(DialResult::decode(&buf), Ok(result));
+        }
+    }
+
+    #[test]
+    fn test_observed_addr() {
+        let mut buf = BytesMut::new();
+        for observed_addr in [
+            ObservedAddr {
+                addr: Some("192.0.2.1:4433".parse().unwrap()),
+                via_relay: false,
+            },
+            ObservedAddr {
+                addr: Some("[2001:db8::1]:4433".parse().unwrap()),
+                via_relay: true,
+            },
+            ObservedAddr {
+                addr: None,
+                via_relay: true,
+            },
+        ] {
+            buf.clear();
+            let response = PongResponse {
+                seq: 7,
+                sent_at_us: 1,
+                received_at_us: 2,
+                processing_us: 3,
+                flags: FLAG_SERVER_INFO | FLAG_OBSERVED_ADDR,
+                nonce: [0xab; NONCE_LEN],
+                server_info: Some(ServerInfo::default()),
+                observed_addr: Some(observed_addr),
+                payload: b"hello",
+            };
+            response.encode(&mut buf);
+            assert_eq!(PongResponse::decode(&buf, 1024), Ok(response));
+        }
+
+        // an address in an IP version that doesn't exist
+        let version_at = buf.len() - 5 - 1;
+        buf[version_at] = 5;
+        assert_eq!(
+            PongResponse::decode(&buf, 1024),
+            Err(CodecError::UnknownIpVersion { version: 5 })
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut buf = BytesMut::new();
+        let response = ErrorResponse {
+            seq: 7,
+            reason: RejectReason::RateLimited,
+            retry_after: Some(Duration::from_millis(250)),
+            limit: None,
+            nonce: [0xab; NONCE_LEN],
+        };
+        response.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + ErrorResponse::HEADER_LEN);
+        assert_eq!(ErrorResponse::decode(&buf), Ok(response));
+
+        // no hint, and a reason only newer servers know
+        buf.clear();
+        let response = ErrorResponse {
+            reason: RejectReason::Other(42),
+            retry_after: None,
+            ..response
+        };
+        response.encode(&mut buf);
+        assert_eq!(ErrorResponse::decode(&buf), Ok(response));
+        assert_eq!(response.reason.to_string(), "reason 42");
+
+        // a payload refused as too large carries the limit, next to any hint
+        buf.clear();
+        let response = ErrorResponse {
+            reason: RejectReason::PayloadTooLarge,
+            retry_after: Some(Duration::from_secs(1)),
+            limit: Some(4096),
+            ..response
+        };
+        response.encode(&mut buf);
+        assert_eq!(buf.len(), LEN_PREFIX + ErrorResponse::HEADER_LEN);
+        assert_eq!(ErrorResponse::decode(&buf), Ok(response));
+
+        for reason in [
+            RejectReason::RateLimited,
+            RejectReason::Unauthorized,
+            RejectReason::PayloadTooLarge,
+        ] {
+            assert_eq!(RejectReason::from_code(reason.code()), reason);
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut buf = BytesMut::new();
+        PingRequest {
+            seq: 1,
+            sent_at_us: 2,
+            flags: 0,
+            nonce: [3; NONCE_LEN],
+            payload: b"abc",
+        }
+        .encode(&mut buf);
+        for len in 0..buf.len() {
+            let err = PingRequest::decode(&buf[..len], 1024).unwrap_err();
+            assert!(
+                matches!(err, CodecError::Truncated { .. }),
+                "{len}: {err:?}"
+            );
+        }
+        let mut long = buf.to_vec();
+        long.push(0);
+        assert_eq!(
+            PingRequest::decode(&long, 1024),
+            Err(CodecError::TrailingBytes { extra: 1 })
+        );
+
+        // a well framed body too short for the fields
+        let frame = [0, 0, 0, 3, 1, 2, 3];
+        let err = PongResponse::decode(&frame, 1024).unwrap_err();
+        assert!(matches!(err, CodecError::Truncated { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn test_oversized_length_prefix() {
+        // rejected from the prefix alone, without the body
+        assert_eq!(
+            frame_len(u32::MAX.to_be_bytes(), 1024),
+            Err(CodecError::TooLarge {
+                len: u32::MAX as usize,
+                max_len: 1024
+            })
+        );
+        assert_eq!(
+            PingRequest::decode(&[0, 0, 4, 1], 1024),
+            Err(CodecError::TooLarge {
+                len: 1025,
+                max_len: 1024
+            })
+        );
+        assert_eq!(frame_len(1024u32.to_be_bytes(), 1024), Ok(1024));
+    }
+}
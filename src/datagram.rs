@@ -0,0 +1,289 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use iroh::endpoint::{Connection, SendDatagramError};
+
+use crate::{
+    burst::count_reordered, exchange, fallback_version, mtu::HEADER_LEN, Ping, PingError, PingStats,
+};
+
+/// Size of the payload of a datagram in a burst: its sequence number, and when it was sent.
+const BURST_PAYLOAD: usize = 4 + 8;
+
+/// Outcome of [`Ping::ping_datagram_burst`].
+#[derive(Debug, Clone)]
+pub struct DatagramReport {
+    /// round trip time of every ping in the order they were sent, `None` if it was lost
+    pub rtts: Vec<Option<Duration>>,
+    /// sequence numbers of the answered pings, in the order their answers arrived
+    pub arrival_order: Vec<u32>,
+    /// statistics over the burst, counting lost pings as lost
+    pub stats: PingStats,
+}
+
+impl DatagramReport {
+    /// Number of pings that weren't answered in time.
+    pub fn lost(&self) -> usize {
+        self.rtts.iter().filter(|rtt| rtt.is_none()).count()
+    }
+
+    /// Percentage of pings that weren't answered in time.
+    pub fn loss_pct(&self) -> f64 {
+        self.stats.loss_pct()
+    }
+
+    /// Number of answers that arrived after the answer to a ping sent later.
+    pub fn reordered(&self) -> usize {
+        count_reordered(&self.arrival_order)
+    }
+}
+
+impl Ping {
+    /// Send a single ping as a QUIC datagram over an existing connection.
+    ///
+    /// Datagrams skip the stream setup and retransmissions of a regular ping, which makes
+    /// this closer to an ICMP ping: a lost ping or answer simply shows as a
+    /// [`PingError::Timeout`]. Each ping carries a sequence number, so late answers to
+    /// earlier datagram pings on the connection are skipped. The connection may have been
+    /// established for [`ALPN_V1`](crate::ALPN_V1) or [`ALPN_DATAGRAM`](crate::ALPN_DATAGRAM).
+    /// Does not close the connection.
+    pub async fn ping_datagram(&self, conn: &Connection) -> Result<Duration, PingError> {
+        self.wait_for_turn().await;
+        let seq = self.datagram_seq.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        tokio::time::timeout(self.timeout, datagram_exchange(conn, seq, self.max_version))
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+            .map_err(|err| self.failed(err))?;
+        self.metrics.pings_sent.inc();
+        Ok(start.elapsed())
+    }
+
+    /// Send `count` pings as QUIC datagrams over an existing connection, back to back, and
+    /// report which got answered.
+    ///
+    /// Unlike stream pings, which QUIC retransmits until they arrive, datagrams show loss
+    /// and reordering on the path. Each datagram carries its sequence number, numbered
+    /// from 0, and when it was sent. Answers are collected until all arrived, or until
+    /// `window` passed after sending the last datagram, any still missing then count as
+    /// lost. Datagrams that don't fit the send buffer are dropped before they are sent,
+    /// which shows as loss as well, so very large bursts overstate it.
+    ///
+    /// Fails with [`PingError::DatagramsUnsupported`] if either side of the connection
+    /// has datagrams disabled, or they are too small for a ping. Does not close the
+    /// connection.
+    pub async fn ping_datagram_burst(
+        &self,
+        conn: &Connection,
+        count: usize,
+        window: Duration,
+    ) -> Result<DatagramReport, PingError> {
+        let max_size = conn
+            .max_datagram_size()
+            .ok_or(PingError::DatagramsUnsupported)?;
+        if max_size < HEADER_LEN + BURST_PAYLOAD {
+            return Err(PingError::DatagramsUnsupported);
+        }
+        // Settle on a protocol version with a regular ping first, there is no room to
+        // fall back halfway through the burst.
+        let version = tokio::time::timeout(self.timeout, exchange(conn, &[], self.max_version))
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+            .map_err(|err| self.failed(err))?;
+
+        let mut rtts = vec![None; count];
+        let mut arrival_order = Vec::with_capacity(count);
+        let start = Instant::now();
+        let sending = async {
+            for seq in 0..count as u32 {
+                self.wait_for_turn().await;
+                let mut request = Vec::with_capacity(HEADER_LEN + BURST_PAYLOAD);
+                request.push(version);
+                request.extend_from_slice(b"PING");
+                request.extend_from_slice(&seq.to_be_bytes());
+                request.extend_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+                conn.send_datagram(request.into()).map_err(datagram_error)?;
+            }
+            tokio::time::sleep(window).await;
+            Ok(())
+        };
+        let receiving = receive_burst(conn, version, start, &mut rtts, &mut arrival_order);
+        tokio::select! {
+            res = sending => res?,
+            res = receiving => res?,
+        }
+
+        let mut stats = PingStats::with_samples();
+        for rtt in &rtts {
+            match rtt {
+                Some(rtt) => stats.record_rtt(*rtt),
+                None => stats.record_loss(),
+            }
+        }
+        self.metrics.pings_sent.inc_by(stats.received());
+        self.metrics.pings_failed.inc_by(stats.lost());
+        Ok(DatagramReport {
+            rtts,
+            arrival_order,
+            stats,
+        })
+    }
+}
+
+/// Records the answers to a burst of datagram pings sent since `start`, until all of them
+/// arrived.
+async fn receive_burst(
+    conn: &Connection,
+    version: u8,
+    start: Instant,
+    rtts: &mut [Option<Duration>],
+    arrival_order: &mut Vec<u32>,
+) -> Result<(), PingError> {
+    while arrival_order.len() < rtts.len() {
+        let response = conn
+            .read_datagram()
+            .await
+            .map_err(|source| PingError::Connection { source })?;
+        let [v, b'P', b'O', b'N', b'G', payload @ ..] = &response[..] else {
+            continue;
+        };
+        let Ok(payload) = <[u8; BURST_PAYLOAD]>::try_from(payload) else {
+            continue;
+        };
+        let (seq, sent_us) = payload.split_at(4);
+        let seq = u32::from_be_bytes(seq.try_into().expect("4 bytes"));
+        let sent_us = u64::from_be_bytes(sent_us.try_into().expect("8 bytes"));
+        // skip answers to other pings, and duplicates
+        match rtts.get_mut(seq as usize) {
+            Some(rtt @ None) if *v == version => {
+                let sent = Duration::from_micros(sent_us);
+                *rtt = Some(start.elapsed().saturating_sub(sent));
+                arrival_order.push(seq);
+            }
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
+/// Turns a failure to send a datagram into a [`PingError`].
+fn datagram_error(source: SendDatagramError) -> PingError {
+    match source {
+        SendDatagramError::UnsupportedByPeer | SendDatagramError::Disabled => {
+            PingError::DatagramsUnsupported
+        }
+        source => PingError::SendDatagram { source },
+    }
+}
+
+/// Sends a datagram ping and waits for its answer, falling back to an older protocol
+/// version if the server asks for one.
+async fn datagram_exchange(conn: &Connection, seq: u32, mut version: u8) -> Result<(), PingError> {
+    loop {
+        let mut request = Vec::with_capacity(HEADER_LEN + 4);
+        request.push(version);
+        request.extend_from_slice(b"PING");
+        request.extend_from_slice(&seq.to_be_bytes());
+        conn.send_datagram(request.clone().into())
+            .map_err(datagram_error)?;
+
+        loop {
+            let response = conn
+                .read_datagram()
+                .await
+                .map_err(|source| PingError::Connection { source })?;
+            match &response[..] {
+                [offered] => {
+                    version = fallback_version(version, *offered)?;
+                    break;
+                }
+                [v, b'P', b'O', b'N', b'G', payload @ ..]
+                    if *v == version && payload == &request[HEADER_LEN..] =>
+                {
+                    return Ok(());
+                }
+                // an answer to an earlier ping, or something else entirely
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::{endpoint::TransportConfig, Endpoint, RelayMode};
+
+    use super::*;
+    use crate::{test_utils, ALPN_DATAGRAM, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_ping_datagram() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new();
+        let conn = client.connect(addr.clone(), ALPN_DATAGRAM).await?;
+        for _ in 0..3 {
+            ping.ping_datagram(&conn).await?;
+        }
+        conn.close(0u32.into(), b"bye!");
+
+        // compare both kinds of pings on the same connection
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let stream = ping.ping_n_on_conn(&conn, 5).await?;
+        let mut datagram = PingStats::with_samples();
+        for _ in 0..5 {
+            datagram.record_rtt(ping.ping_datagram(&conn).await?);
+        }
+        assert_eq!(stream.received(), 5);
+        assert_eq!(datagram.received(), 5);
+        assert_eq!(ping.metrics().pings_sent.get(), 13);
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_datagram_burst() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new();
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let report = ping
+            .ping_datagram_burst(&conn, 100, Duration::from_secs(1))
+            .await?;
+        assert_eq!(report.lost(), 0);
+        assert_eq!(report.loss_pct(), 0.0);
+        assert_eq!(report.reordered(), 0);
+        assert_eq!(report.arrival_order, (0..100).collect::<Vec<_>>());
+        assert!(report.rtts.iter().all(|rtt| rtt.is_some()));
+        assert_eq!(report.stats.received(), 100);
+        conn.close(0u32.into(), b"bye!");
+
+        // a client that has datagrams disabled
+        let mut transport = TransportConfig::default();
+        transport.datagram_receive_buffer_size(None);
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .transport_config(transport)
+            .bind()
+            .await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let err = ping
+            .ping_datagram_burst(&conn, 1, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::DatagramsUnsupported), "{err:?}");
+        let err = ping.ping_datagram(&conn).await.unwrap_err();
+        assert!(matches!(err, PingError::DatagramsUnsupported), "{err:?}");
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadToEndError, RecvStream, SendStream},
+    protocol::{AcceptError, Router},
+    Endpoint, NodeAddr, NodeId,
+};
+
+use crate::{
+    codec::{self, DialRequest, DialResult},
+    fallback_version,
+    rate::TokenBucket,
+    read_to_end_into, reject, ErrorResponse, Negotiated, Ping, PingError, RejectReason,
+    ERR_INVALID_REQUEST,
+};
+
+/// Oldest protocol version with dial-back requests.
+const DIAL_VERSION: u8 = 8;
+
+/// Whether a server could reach us on a connection of its own, see [`Ping::dial_back`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DialBackReport {
+    /// round trip time of the server's ping, `None` if the dial-back failed
+    pub rtt: Option<Duration>,
+}
+
+impl DialBackReport {
+    /// whether the server reached us
+    pub fn reachable(&self) -> bool {
+        self.rtt.is_some()
+    }
+}
+
+impl Ping {
+    /// Ask the server at the other end of `conn` to dial us back on a new connection and
+    /// ping us there, to find out whether others can reach us, see [`DialBackReport`].
+    ///
+    /// An outgoing ping succeeding says little about incoming connections, e.g. behind a
+    /// NAT. The client sends `DIAL` and a framed [`DialRequest`] with `addrs`, and the
+    /// server dials our node at those, or at the address it sees us at if `addrs` is empty,
+    /// then answers with `DIAL` and a framed [`DialResult`]. Our endpoint has to serve
+    /// pings for the server's ping to be answered, e.g. with [`Ping::serve`], otherwise the
+    /// report says the dial-back failed.
+    ///
+    /// Servers only dial back if they allow it, see [`Ping::with_dial_back`], others refuse
+    /// with [`PingError::RequestRejected`], as do servers over their rate limit. Fails with
+    /// [`PingError::DialBackUnsupported`] if the server is too old for dial-backs. The
+    /// server gives up dialing after its own timeout, so this waits for twice ours. Does
+    /// not close the connection.
+    pub async fn dial_back(
+        &self,
+        conn: &Connection,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<DialBackReport, PingError> {
+        let dial_back = async {
+            let mut version = self.max_version;
+            loop {
+                if version < DIAL_VERSION {
+                    return Err(PingError::DialBackUnsupported { version });
+                }
+                match request(conn, version, &addrs).await? {
+                    Negotiated::Done(report) => return Ok(report),
+                    Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+                }
+            }
+        };
+        let timeout = self.timeout.saturating_mul(2);
+        tokio::time::timeout(timeout, dial_back)
+            .await
+            .unwrap_or(Err(PingError::Timeout { timeout }))
+    }
+
+    /// Allow clients to have us dial them back, at most `max_dials_per_second` times per
+    /// second across all connections and clones, see [`Ping::dial_back`].
+    ///
+    /// Off by default, since it makes us connect wherever a client asks, though only ever
+    /// to the node of the client asking. Requests beyond the limit are refused with a
+    /// [`PingError::RequestRejected`] telling when to retry. Dialing back needs the
+    /// endpoint, so only servers set up with [`Ping::register`] or [`Ping::serve`] do.
+    ///
+    /// # Panics
+    ///
+    /// If `max_dials_per_second` is zero.
+    pub fn with_dial_back(mut self, max_dials_per_second: u32) -> Self {
+        assert!(max_dials_per_second > 0, "the rate limit must be positive");
+        let rate = f64::from(max_dials_per_second);
+        self.dial_back = Some(Arc::new(TokenBucket::new(rate, rate)));
+        self
+    }
+
+    /// Serve pings on `endpoint` and return the router doing it, so the same endpoint can
+    /// both ping and answer pings, e.g. the ones of a dial-back.
+    ///
+    /// Shorthand for [`Ping::register`] on a router with no other protocols. Dropping the
+    /// router stops serving.
+    pub fn serve(&self, endpoint: Endpoint) -> Router {
+        self.register(Router::builder(endpoint)).spawn()
+    }
+}
+
+/// Sends a `DIAL` in `version` and reads the server's answer.
+async fn request(
+    conn: &Connection,
+    version: u8,
+    addrs: &[SocketAddr],
+) -> Result<Negotiated<DialBackReport>, PingError> {
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let nonce = rand::random();
+    let mut buf = BytesMut::new();
+    buf.put_u8(version);
+    buf.put_slice(b"DIAL");
+    DialRequest {
+        addrs: addrs.to_vec(),
+        nonce,
+    }
+    .encode(&mut buf);
+    send.write_all(&buf)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let limit = 1 + 4 + codec::LEN_PREFIX + DialResult::HEADER_LEN.max(ErrorResponse::HEADER_LEN);
+    let response = recv
+        .read_to_end(limit)
+        .await
+        .map_err(|source| PingError::Read { source })?;
+    let check_nonce = |got| {
+        if got != nonce {
+            return Err(PingError::NonceMismatch {
+                expected: nonce,
+                got,
+            });
+        }
+        Ok(())
+    };
+    match response.split_first() {
+        Some((&v, [])) if v != version => Ok(Negotiated::Fallback(v)),
+        Some((&v, rest)) if v == version && rest.starts_with(b"DIAL") => {
+            let result =
+                DialResult::decode(&rest[4..]).map_err(|source| PingError::Decode { source })?;
+            check_nonce(result.nonce)?;
+            Ok(Negotiated::Done(DialBackReport { rtt: result.rtt }))
+        }
+        Some((&v, rest)) if v == version && rest.starts_with(b"EROR") => {
+            let error =
+                ErrorResponse::decode(&rest[4..]).map_err(|source| PingError::Decode { source })?;
+            check_nonce(error.nonce)?;
+            Err(PingError::RequestRejected {
+                reason: error.reason,
+                retry_after: error.retry_after,
+            })
+        }
+        _ => Err(PingError::InvalidResponse { response }),
+    }
+}
+
+/// Answers a `DIAL` in `version` from `node_id` by dialing it back, if `ping` allows that.
+pub(crate) async fn handle_dial(
+    ping: &Ping,
+    node_id: NodeId,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    if version < DIAL_VERSION {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    }
+    match read_to_end_into(&mut recv, buf, codec::LEN_PREFIX + DialRequest::MAX_LEN).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    let Ok(request) = DialRequest::decode(buf) else {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    };
+
+    let admitted = match &ping.dial_back {
+        None => Err((RejectReason::Unauthorized, None)),
+        Some(bucket) => bucket
+            .try_take()
+            .map_err(|retry_after| (RejectReason::RateLimited, Some(retry_after))),
+    };
+    buf.clear();
+    buf.put_u8(version);
+    match admitted {
+        Ok(()) => {
+            let rtt = dial(ping, node_id, request.addrs).await;
+            tracing::debug!(reachable = rtt.is_some(), "dialed_back");
+            buf.put_slice(b"DIAL");
+            DialResult {
+                rtt,
+                nonce: request.nonce,
+            }
+            .encode(buf);
+        }
+        Err((reason, retry_after)) => {
+            buf.put_slice(b"EROR");
+            ErrorResponse {
+                seq: 0,
+                reason,
+                retry_after,
+                limit: None,
+                nonce: request.nonce,
+            }
+            .encode(buf);
+        }
+    }
+    send.write_all(buf).await.map_err(AcceptError::from_err)?;
+    send.finish()?;
+    Ok(())
+}
+
+/// Pings `node_id` on a new connection from our endpoint, at `addrs` or where we see it,
+/// returning the round trip time if that worked.
+async fn dial(ping: &Ping, node_id: NodeId, mut addrs: Vec<SocketAddr>) -> Option<Duration> {
+    let endpoint = ping.endpoint.as_ref()?;
+    if addrs.is_empty() {
+        addrs.extend(ping.observed(node_id).addr);
+    }
+    let addr = NodeAddr::from_parts(node_id, None, addrs);
+    let pinger = Ping::new()
+        .with_alpn(ping.alpn.clone())
+        .with_timeout(ping.timeout);
+    pinger.ping_once(endpoint, addr).await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_dial_back() -> anyhow::Result<()> {
+        let server = Ping::new().with_dial_back(10);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        // the client answers pings on its endpoint too
+        let client_router = Ping::new().serve(client.clone());
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        let report = ping.dial_back(&conn, Vec::new()).await?;
+        assert!(report.reachable(), "{report:?}");
+        assert!(report.rtt.unwrap() > Duration::ZERO);
+
+        conn.close(0u32.into(), b"bye!");
+        client_router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dial_back_without_handler() -> anyhow::Result<()> {
+        let server = Ping::new()
+            .with_dial_back(10)
+            .with_timeout(Duration::from_secs(2));
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        let report = Ping::new().dial_back(&conn, Vec::new()).await?;
+        assert_eq!(report, DialBackReport { rtt: None });
+        assert!(!report.reachable());
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dial_back_refused() -> anyhow::Result<()> {
+        // servers don't dial back unless they allow it
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let err = Ping::new().dial_back(&conn, Vec::new()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::RequestRejected {
+                    reason: RejectReason::Unauthorized,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        // and only as often as they allow
+        let server = Ping::new().with_dial_back(1);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let _client_router = Ping::new().serve(client.clone());
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        assert!(ping.dial_back(&conn, Vec::new()).await?.reachable());
+        let err = ping.dial_back(&conn, Vec::new()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::RequestRejected {
+                    reason: RejectReason::RateLimited,
+                    retry_after: Some(_),
+                }
+            ),
+            "{err:?}"
+        );
+
+        // older servers can't dial back
+        let old = Ping::new().with_max_version(DIAL_VERSION - 1);
+        let err = old.dial_back(&conn, Vec::new()).await.unwrap_err();
+        assert!(
+            matches!(err, PingError::DialBackUnsupported { version } if version == DIAL_VERSION - 1),
+            "{err:?}"
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+}
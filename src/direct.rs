@@ -0,0 +1,83 @@
+use std::{net::SocketAddr, time::Duration};
+
+use iroh::{Endpoint, NodeAddr, NodeId};
+
+use crate::{Ping, PingError};
+
+impl Ping {
+    /// Ping a node at known socket addresses, without discovery or relays.
+    ///
+    /// Meant for networks where neither is available, so the endpoint needs no discovery
+    /// service or relay configured. Fails with [`PingError::Unreachable`] if no connection
+    /// can be established through any of `addrs` within the timeout (see
+    /// [`Ping::with_timeout`]), or if there are none. Does not close the endpoint.
+    pub async fn ping_direct(
+        &self,
+        endpoint: &Endpoint,
+        node_id: NodeId,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Duration, PingError> {
+        if addrs.is_empty() {
+            return Err(PingError::Unreachable { addrs });
+        }
+        let addr = NodeAddr::from_parts(node_id, None, addrs.iter().copied());
+        match self.ping_once(endpoint, addr).await {
+            Err(
+                PingError::Connect { .. }
+                | PingError::NodeUnreachable { .. }
+                | PingError::Timeout { .. },
+            ) => Err(PingError::Unreachable { addrs }),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh::{protocol::Router, RelayMode, SecretKey};
+
+    use super::*;
+    use crate::ALPN_V1;
+
+    #[tokio::test]
+    async fn test_ping_direct() -> anyhow::Result<()> {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = ep.node_id();
+        let addrs = ep
+            .bound_sockets()
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()))
+            .collect();
+        let _router = Router::builder(ep).accept(ALPN_V1, Ping::new()).spawn();
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        Ping::new().ping_direct(&client, node_id, addrs).await?;
+
+        // nobody listens there, which only shows once the timeout passes
+        let ping = Ping::new().with_timeout(Duration::from_secs(1));
+        let bogus = SecretKey::generate(rand::rngs::OsRng).public();
+        let addrs = vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1)];
+        let err = ping
+            .ping_direct(&client, bogus, addrs.clone())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, PingError::Unreachable { addrs: a } if *a == addrs),
+            "{err:?}"
+        );
+
+        let err = ping.ping_direct(&client, bogus, vec![]).await.unwrap_err();
+        assert!(matches!(err, PingError::Unreachable { .. }), "{err:?}");
+
+        Ok(())
+    }
+}
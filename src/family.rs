@@ -0,0 +1,175 @@
+use std::net::SocketAddr;
+
+use iroh::{endpoint::ConnectionType, Endpoint, NodeAddr, NodeId, Watcher};
+
+use crate::PingError;
+
+/// Which IP family to ping over, see [`Ping::with_ip_family`](crate::Ping::with_ip_family).
+///
+/// On dual-stack hosts the family can make a difference in reachability and latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IpFamily {
+    /// whatever the endpoint picks
+    #[default]
+    Any,
+    /// Dial only the node's IPv4 addresses if it has any, and all its addresses otherwise.
+    PreferIpv4,
+    /// Dial only the node's IPv6 addresses if it has any, and all its addresses otherwise.
+    PreferIpv6,
+    /// Only ping over a direct IPv4 path, never through the relay.
+    Ipv4Only,
+    /// Only ping over a direct IPv6 path, never through the relay.
+    Ipv6Only,
+}
+
+impl IpFamily {
+    /// Whether an address of the given family may be used, for [`IpFamily::Ipv4Only`] and
+    /// [`IpFamily::Ipv6Only`] only that family, for the others any.
+    pub fn allows(self, addr: SocketAddr) -> bool {
+        match self.required() {
+            Some(ipv4) => addr.is_ipv4() == ipv4,
+            None => true,
+        }
+    }
+
+    /// For the families that must be used, whether it is IPv4.
+    fn required(self) -> Option<bool> {
+        match self {
+            Self::Ipv4Only => Some(true),
+            Self::Ipv6Only => Some(false),
+            Self::Any | Self::PreferIpv4 | Self::PreferIpv6 => None,
+        }
+    }
+
+    /// Narrows `addr` to the parts the node may be dialed at.
+    ///
+    /// Fails with [`PingError::IpFamilyUnavailable`] if a family is required but `addr`
+    /// has no direct address in it.
+    pub(crate) fn narrow(self, addr: NodeAddr) -> Result<NodeAddr, PingError> {
+        let in_family = |ipv4: bool| -> Vec<SocketAddr> {
+            addr.direct_addresses
+                .iter()
+                .copied()
+                .filter(|addr| addr.is_ipv4() == ipv4)
+                .collect()
+        };
+        match self {
+            Self::Any => Ok(addr),
+            Self::PreferIpv4 | Self::PreferIpv6 => {
+                let preferred = in_family(self == Self::PreferIpv4);
+                if preferred.is_empty() {
+                    return Ok(addr);
+                }
+                Ok(NodeAddr::from_parts(
+                    addr.node_id,
+                    addr.relay_url,
+                    preferred,
+                ))
+            }
+            Self::Ipv4Only | Self::Ipv6Only => {
+                let addrs = in_family(self == Self::Ipv4Only);
+                if addrs.is_empty() {
+                    return Err(PingError::IpFamilyUnavailable { family: self });
+                }
+                Ok(NodeAddr::from_parts(addr.node_id, None, addrs))
+            }
+        }
+    }
+
+    /// Waits until the endpoint's path to the node is a direct one in the required family,
+    /// if there is one.
+    ///
+    /// The endpoint may know other addresses of the node from earlier contact, so dialing
+    /// only addresses in the family doesn't guarantee the path uses it.
+    pub(crate) async fn wait_for_path(
+        self,
+        endpoint: &Endpoint,
+        node_id: NodeId,
+    ) -> Result<(), PingError> {
+        let Some(ipv4) = self.required() else {
+            return Ok(());
+        };
+        let unavailable = || PingError::IpFamilyUnavailable { family: self };
+        let mut conn_type = endpoint.conn_type(node_id).ok_or_else(unavailable)?;
+        let mut current = conn_type.get().map_err(|_| unavailable())?;
+        while !matches!(current, ConnectionType::Direct(addr) if addr.is_ipv4() == ipv4) {
+            current = conn_type.updated().await.map_err(|_| unavailable())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::{test_utils, Ping};
+
+    #[test]
+    fn test_narrow() {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1);
+        let relay: iroh::RelayUrl = "https://relay.example".parse().unwrap();
+        let addr = NodeAddr::from_parts(node_id, Some(relay.clone()), [v4, v6]);
+
+        assert_eq!(IpFamily::Any.narrow(addr.clone()).unwrap(), addr);
+        assert_eq!(
+            IpFamily::PreferIpv6.narrow(addr.clone()).unwrap(),
+            NodeAddr::from_parts(node_id, Some(relay.clone()), [v6])
+        );
+        assert_eq!(
+            IpFamily::Ipv4Only.narrow(addr.clone()).unwrap(),
+            NodeAddr::from_parts(node_id, None, [v4])
+        );
+
+        // preferring a family the node doesn't have keeps the rest
+        let v4_only = NodeAddr::from_parts(node_id, Some(relay), [v4]);
+        assert_eq!(
+            IpFamily::PreferIpv6.narrow(v4_only.clone()).unwrap(),
+            v4_only
+        );
+        let err = IpFamily::Ipv6Only.narrow(v4_only).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::IpFamilyUnavailable {
+                    family: IpFamily::Ipv6Only
+                }
+            ),
+            "{err:?}"
+        );
+
+        assert!(IpFamily::Ipv4Only.allows(v4));
+        assert!(!IpFamily::Ipv4Only.allows(v6));
+        assert!(IpFamily::PreferIpv4.allows(v6));
+    }
+
+    #[tokio::test]
+    async fn test_ping_ipv4_only() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new().with_ip_family(IpFamily::Ipv4Only);
+        let (res, conn, _stats) = ping.ping_keep(&client, addr.clone()).await?;
+        assert!(
+            res.remote_addr.is_some_and(|addr| addr.is_ipv4()),
+            "{res:?}"
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        // the test server is only reachable over IPv4
+        let ping = Ping::new().with_ip_family(IpFamily::Ipv6Only);
+        let err = ping.ping_once(&client, addr).await.unwrap_err();
+        assert!(
+            matches!(err, PingError::IpFamilyUnavailable { .. }),
+            "{err:?}"
+        );
+        assert_eq!(ping.metrics().pings_failed.get(), 1);
+        client.close().await;
+
+        Ok(())
+    }
+}
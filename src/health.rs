@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use iroh::{
+    endpoint::{ConnectError, ConnectWithOptsError, ConnectionError, TransportErrorCode},
+    Endpoint, NodeAddr, NodeId,
+};
+
+use crate::{Ping, PingError};
+
+/// TLS alert sent when the peers share no application protocol (RFC 7301).
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// Outcome of [`Ping::check`].
+#[derive(Debug)]
+pub enum Health {
+    /// the node answered within the time budget
+    Reachable {
+        /// round trip time of the ping
+        rtt: Duration,
+    },
+    /// the node did not answer properly within the time budget
+    Unreachable {
+        /// why the check failed
+        reason: UnreachableReason,
+        /// the underlying error, if the ping failed before the budget ran out
+        error: Option<PingError>,
+    },
+}
+
+impl Health {
+    /// whether the node answered within the time budget
+    pub fn is_reachable(&self) -> bool {
+        matches!(self, Health::Reachable { .. })
+    }
+}
+
+/// Why a node was deemed unreachable by [`Ping::check`], or a ping failed with
+/// [`PingError::NodeUnreachable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableReason {
+    /// the node did not answer within the time budget
+    Timeout,
+    /// the node is up, but does not speak the ping protocol
+    WrongAlpn,
+    /// the connection could not be established
+    ConnectFailed,
+    /// the node answered, but not with a valid response
+    BadResponse,
+    /// no address of the node could be found, so it was never dialed
+    NotDiscovered,
+}
+
+impl Ping {
+    /// Whether a node answers a ping within `budget`.
+    ///
+    /// This is meant for liveness probes: it never takes much longer than `budget`, and
+    /// any failure, including malformed responses, counts as unreachable. Does not close
+    /// the endpoint.
+    pub async fn is_reachable(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        budget: Duration,
+    ) -> bool {
+        self.check(endpoint, addr, budget).await.is_reachable()
+    }
+
+    /// Like [`Ping::is_reachable`], but reports why a node is unreachable.
+    pub async fn check(&self, endpoint: &Endpoint, addr: NodeAddr, budget: Duration) -> Health {
+        match tokio::time::timeout(budget, self.ping_once(endpoint, addr)).await {
+            Ok(Ok(rtt)) => Health::Reachable { rtt },
+            Ok(Err(err)) => Health::Unreachable {
+                reason: reason(&err),
+                error: Some(err),
+            },
+            Err(_) => Health::Unreachable {
+                reason: UnreachableReason::Timeout,
+                error: None,
+            },
+        }
+    }
+}
+
+/// Turns a failure to connect to `node` into [`PingError::NodeUnreachable`], unless the
+/// node answered and refused our ALPN, or it failed before dialing for other reasons than
+/// a missing address, which stay a [`PingError::Connect`].
+pub(crate) fn connect_failed(node: NodeId, source: ConnectError) -> PingError {
+    let reason = match &source {
+        ConnectError::Connect { source: err, .. }
+            if matches!(err.as_ref(), ConnectWithOptsError::NoAddress { .. }) =>
+        {
+            UnreachableReason::NotDiscovered
+        }
+        _ if alpn_refused(&source) => return PingError::Connect { source },
+        ConnectError::Connection { source: err, .. } => match err.as_ref() {
+            ConnectionError::TimedOut => UnreachableReason::Timeout,
+            _ => UnreachableReason::ConnectFailed,
+        },
+        _ => return PingError::Connect { source },
+    };
+    PingError::NodeUnreachable {
+        node,
+        reason,
+        source,
+    }
+}
+
+/// Whether the node answered, but refused the ALPN we connected with in the handshake.
+pub(crate) fn alpn_refused(source: &ConnectError) -> bool {
+    match source {
+        ConnectError::Connection { source, .. } => matches!(
+            source.as_ref(),
+            ConnectionError::ConnectionClosed(close)
+                if close.error_code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL)
+        ),
+        _ => false,
+    }
+}
+
+/// What went wrong, for the message of [`PingError::NodeUnreachable`].
+pub(crate) fn describe(reason: UnreachableReason) -> &'static str {
+    match reason {
+        UnreachableReason::NotDiscovered => {
+            "could not discover its address, the ticket may be stale or the node offline"
+        }
+        UnreachableReason::Timeout => "discovered its address, but connecting timed out",
+        UnreachableReason::WrongAlpn => "it does not speak the ping protocol",
+        UnreachableReason::BadResponse => "it did not answer properly",
+        UnreachableReason::ConnectFailed => "discovered its address, but the connection failed",
+    }
+}
+
+fn reason(err: &PingError) -> UnreachableReason {
+    match err {
+        PingError::Timeout { .. } => UnreachableReason::Timeout,
+        PingError::NodeUnreachable { reason, .. } => *reason,
+        PingError::Connect { source } if alpn_refused(source) => UnreachableReason::WrongAlpn,
+        PingError::Connect { .. } | PingError::Unreachable { .. } => {
+            UnreachableReason::ConnectFailed
+        }
+        _ => UnreachableReason::BadResponse,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_reachable() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        assert!(
+            Ping::new()
+                .is_reachable(&client, addr, Duration::from_secs(5))
+                .await
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_within_budget() -> anyhow::Result<()> {
+        // the client has no address for this node, so either it gives up or the budget ends the
+        // check
+        let client = test_utils::local_endpoint().await?;
+        let addr = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public());
+
+        let budget = Duration::from_secs(2);
+        let start = Instant::now();
+        let health = Ping::new().check(&client, addr, budget).await;
+        assert!(start.elapsed() < budget + Duration::from_millis(500));
+        assert!(!health.is_reachable());
+        assert!(
+            matches!(
+                health,
+                Health::Unreachable {
+                    reason: UnreachableReason::Timeout | UnreachableReason::NotDiscovered,
+                    ..
+                }
+            ),
+            "{health:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_alpn() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(b"not/ping/0", Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let health = Ping::new()
+            .check(&client, addr, Duration::from_secs(5))
+            .await;
+        assert!(
+            matches!(
+                health,
+                Health::Unreachable {
+                    reason: UnreachableReason::WrongAlpn,
+                    ..
+                }
+            ),
+            "{health:?}"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadExactError, ReadToEndError, RecvStream, SendStream},
+    protocol::AcceptError,
+};
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    codec::{self, CodecError, Subscription},
+    fallback_version, now_us, read_to_end_into, reject,
+    stream::{read_frame, Frame, FrameError},
+    Negotiated, Ping, PingError, PongResponse, ERR_INVALID_REQUEST,
+};
+
+/// Oldest protocol version with heartbeat subscriptions.
+const HEARTBEAT_VERSION: u8 = 7;
+
+/// A heartbeat pushed by the server, see [`HeartbeatStream::next`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// number of the heartbeat within the subscription, counting from 1
+    pub seq: u64,
+    /// server clock when sending, in microseconds since the unix epoch
+    pub sent_at_us: u64,
+    /// our clock when it arrived, in microseconds since the unix epoch
+    pub received_at_us: u64,
+    /// heartbeats the server skipped right before this one, because it fell behind
+    pub missed: u64,
+    /// whether heartbeats were skipped, or this one arrived more than twice the interval
+    /// after the previous one
+    pub gap: bool,
+}
+
+impl Heartbeat {
+    /// Time from the server sending the heartbeat to it arriving, if our clock is ahead.
+    ///
+    /// Includes the offset between the clocks of both nodes, so only changes of it mean
+    /// something unless the clocks are in sync.
+    pub fn one_way_delay(&self) -> Option<Duration> {
+        self.received_at_us
+            .checked_sub(self.sent_at_us)
+            .map(Duration::from_micros)
+    }
+}
+
+/// Heartbeats a server pushes at a fixed interval, see [`Ping::subscribe_heartbeats`].
+#[derive(Debug)]
+pub struct HeartbeatStream {
+    recv: RecvStream,
+    subscription: Subscription,
+    /// when the previous heartbeat arrived, or the subscription was acknowledged
+    last_arrival: Instant,
+    last_seq: u64,
+    buf: BytesMut,
+}
+
+impl Ping {
+    /// Ask the server at the other end of `conn` to push a heartbeat every `interval` for
+    /// `duration`, see [`HeartbeatStream`].
+    ///
+    /// Unlike pinging, this sends nothing per sample: the client sends `BEAT` and a
+    /// framed [`Subscription`] and finishes its side, and the server acknowledges with
+    /// `BEAT` and the subscription it granted, then pushes a framed [`PongResponse`] for
+    /// each heartbeat and finishes once the subscription ends. Servers shorten the
+    /// interval and the duration to their limits, see [`Ping::with_heartbeat_limits`].
+    ///
+    /// Fails with [`PingError::HeartbeatsUnsupported`] if the server is too old for
+    /// heartbeats. Does not close the connection.
+    pub async fn subscribe_heartbeats(
+        &self,
+        conn: &Connection,
+        interval: Duration,
+        duration: Duration,
+    ) -> Result<HeartbeatStream, PingError> {
+        let mut version = self.max_version;
+        let subscribe = async {
+            loop {
+                if version < HEARTBEAT_VERSION {
+                    return Err(PingError::HeartbeatsUnsupported { version });
+                }
+                match subscribe(conn, version, interval, duration).await? {
+                    Negotiated::Done(stream) => return Ok(stream),
+                    Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, subscribe)
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+    }
+
+    /// Set the shortest interval between heartbeats and the longest subscription this
+    /// node grants when serving, see [`Ping::subscribe_heartbeats`].
+    ///
+    /// Defaults to [`DEFAULT_MIN_HEARTBEAT_INTERVAL`] and
+    /// [`DEFAULT_MAX_HEARTBEAT_DURATION`], so no client keeps a timer going forever.
+    ///
+    /// # Panics
+    ///
+    /// If `min_interval` is zero.
+    ///
+    /// [`DEFAULT_MIN_HEARTBEAT_INTERVAL`]: crate::DEFAULT_MIN_HEARTBEAT_INTERVAL
+    /// [`DEFAULT_MAX_HEARTBEAT_DURATION`]: crate::DEFAULT_MAX_HEARTBEAT_DURATION
+    pub fn with_heartbeat_limits(mut self, min_interval: Duration, max_duration: Duration) -> Self {
+        assert!(
+            !min_interval.is_zero(),
+            "the heartbeat interval must not be zero"
+        );
+        self.min_heartbeat_interval = min_interval;
+        self.max_heartbeat_duration = max_duration;
+        self
+    }
+}
+
+impl HeartbeatStream {
+    /// the time between heartbeats the server granted
+    pub fn interval(&self) -> Duration {
+        self.subscription.interval
+    }
+
+    /// how long the server granted heartbeats for
+    pub fn duration(&self) -> Duration {
+        self.subscription.duration
+    }
+
+    /// Wait for the next heartbeat, `None` once the server ended the subscription.
+    ///
+    /// Waits as long as it takes, so wrap it in a timeout to notice a server that went
+    /// silent before the connection times out.
+    pub async fn next(&mut self) -> Result<Option<Heartbeat>, PingError> {
+        match read_frame(&mut self.recv, &mut self.buf, PongResponse::HEADER_LEN).await {
+            Ok(Frame::Body) => {}
+            Ok(Frame::End) => return Ok(None),
+            Err(err) => return Err(frame_error(err)),
+        }
+        let arrival = Instant::now();
+        let received_at_us = now_us();
+        let pong =
+            PongResponse::decode_body(&self.buf).map_err(|source| PingError::Decode { source })?;
+        if pong.nonce != self.subscription.nonce {
+            return Err(PingError::NonceMismatch {
+                expected: self.subscription.nonce,
+                got: pong.nonce,
+            });
+        }
+        if pong.seq <= self.last_seq {
+            return Err(PingError::InvalidResponse {
+                response: self.buf.to_vec(),
+            });
+        }
+        let missed = pong.seq - self.last_seq - 1;
+        let silence = arrival.duration_since(self.last_arrival);
+        self.last_seq = pong.seq;
+        self.last_arrival = arrival;
+        Ok(Some(Heartbeat {
+            seq: pong.seq,
+            sent_at_us: pong.sent_at_us,
+            received_at_us,
+            missed,
+            gap: missed > 0 || silence > 2 * self.subscription.interval,
+        }))
+    }
+
+    /// Stop the subscription before the server ends it.
+    pub fn stop(mut self) {
+        self.recv.stop(0u32.into()).ok();
+    }
+}
+
+/// Sends a `BEAT` in `version` and reads the server's acknowledgement.
+async fn subscribe(
+    conn: &Connection,
+    version: u8,
+    interval: Duration,
+    duration: Duration,
+) -> Result<Negotiated<HeartbeatStream>, PingError> {
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let nonce = rand::random();
+    let mut buf = BytesMut::new();
+    buf.put_u8(version);
+    buf.put_slice(b"BEAT");
+    Subscription {
+        interval,
+        duration,
+        nonce,
+    }
+    .encode(&mut buf);
+    send.write_all(&buf)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let mut header = [0u8; 5];
+    match recv.read_exact(&mut header).await {
+        Ok(()) if header[0] == version && &header[1..] == b"BEAT" => {}
+        Ok(()) => {
+            return Err(PingError::InvalidResponse {
+                response: header.to_vec(),
+            })
+        }
+        Err(ReadExactError::FinishedEarly(1)) if header[0] != version => {
+            return Ok(Negotiated::Fallback(header[0]))
+        }
+        Err(ReadExactError::FinishedEarly(n)) => {
+            return Err(PingError::InvalidResponse {
+                response: header[..n].to_vec(),
+            })
+        }
+        Err(ReadExactError::ReadError(source)) => {
+            return Err(PingError::Read {
+                source: ReadToEndError::Read(source),
+            })
+        }
+    }
+    match read_frame(&mut recv, &mut buf, Subscription::HEADER_LEN).await {
+        Ok(Frame::Body) => {}
+        Ok(Frame::End) => {
+            return Err(PingError::Decode {
+                source: CodecError::Truncated {
+                    expected: codec::LEN_PREFIX,
+                    got: 0,
+                },
+            })
+        }
+        Err(err) => return Err(frame_error(err)),
+    }
+    let subscription =
+        Subscription::decode_body(&buf).map_err(|source| PingError::Decode { source })?;
+    if subscription.nonce != nonce {
+        return Err(PingError::NonceMismatch {
+            expected: nonce,
+            got: subscription.nonce,
+        });
+    }
+    Ok(Negotiated::Done(HeartbeatStream {
+        recv,
+        subscription,
+        last_arrival: Instant::now(),
+        last_seq: 0,
+        buf,
+    }))
+}
+
+fn frame_error(err: FrameError) -> PingError {
+    match err {
+        FrameError::Codec(source) => PingError::Decode { source },
+        FrameError::Read(source) => PingError::Read {
+            source: ReadToEndError::Read(source),
+        },
+    }
+}
+
+/// Acknowledges a `BEAT` in `version` with the subscription granted within the limits of
+/// `ping`, and spawns the heartbeats onto `heartbeats`.
+///
+/// The heartbeats of a connection stop once the subscription ends, the client stops its
+/// stream, or the connection closes, which drops `heartbeats`.
+pub(crate) async fn handle_subscribe(
+    ping: &Ping,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    if version < HEARTBEAT_VERSION {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    }
+    let limit = codec::LEN_PREFIX + Subscription::HEADER_LEN;
+    match read_to_end_into(&mut recv, buf, limit).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    let Ok(request) = Subscription::decode(buf) else {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    };
+    let granted = Subscription {
+        interval: request.interval.max(ping.min_heartbeat_interval),
+        duration: request.duration.min(ping.max_heartbeat_duration),
+        nonce: request.nonce,
+    };
+    buf.clear();
+    buf.put_u8(version);
+    buf.put_slice(b"BEAT");
+    granted.encode(buf);
+    send.write_all(buf).await.map_err(AcceptError::from_err)?;
+    // the stream has a task of its own, which keeps beating until the subscription ends
+    beat(send, granted).await;
+    Ok(())
+}
+
+/// Pushes a heartbeat every interval of `subscription` until it ends, numbered by the
+/// interval they are due in, so heartbeats skipped when falling behind show as gaps.
+async fn beat(mut send: SendStream, subscription: Subscription) {
+    let Subscription {
+        interval,
+        duration,
+        nonce,
+    } = subscription;
+    let start = tokio::time::Instant::now();
+    let Some(first) = start.checked_add(interval).filter(|_| interval <= duration) else {
+        // not a single heartbeat is due
+        send.finish().ok();
+        return;
+    };
+    let mut ticks = tokio::time::interval_at(first, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut out = BytesMut::new();
+    loop {
+        let due = ticks.tick().await.duration_since(start);
+        if due > duration {
+            break;
+        }
+        let now = now_us();
+        out.clear();
+        PongResponse {
+            seq: (due.as_nanos() / interval.as_nanos()) as u64,
+            sent_at_us: now,
+            received_at_us: now,
+            processing_us: 0,
+            flags: 0,
+            nonce,
+            server_info: None,
+            observed_addr: None,
+            payload: &[],
+        }
+        .encode(&mut out);
+        if send.write_all(&out).await.is_err() {
+            // the client stopped listening, or is gone
+            return;
+        }
+    }
+    send.finish().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1, DEFAULT_MIN_HEARTBEAT_INTERVAL};
+
+    #[tokio::test]
+    async fn test_heartbeats() -> anyhow::Result<()> {
+        let server = Ping::new().with_heartbeat_limits(Duration::from_millis(10), Duration::MAX);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        let interval = Duration::from_millis(20);
+        let mut beats = ping
+            .subscribe_heartbeats(&conn, interval, 5 * interval)
+            .await?;
+        assert_eq!(beats.interval(), interval);
+        let mut seqs = Vec::new();
+        while let Some(beat) = beats.next().await? {
+            assert_eq!(beat.missed, 0);
+            // both clocks are the same here
+            let delay = beat.one_way_delay().expect("sent before it arrived");
+            assert!(delay < ping.timeout, "{delay:?}");
+            seqs.push(beat.seq);
+        }
+        assert_eq!(seqs, [1, 2, 3, 4, 5]);
+
+        // the connection is good for pings in between heartbeats
+        let beats = ping
+            .subscribe_heartbeats(&conn, interval, Duration::from_secs(60))
+            .await?;
+        ping.ping_on_conn(&conn).await?;
+        beats.stop();
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_limits() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        let beats = Ping::new()
+            .subscribe_heartbeats(&conn, Duration::ZERO, Duration::MAX)
+            .await?;
+        assert_eq!(beats.interval(), DEFAULT_MIN_HEARTBEAT_INTERVAL);
+        assert_eq!(beats.duration(), crate::DEFAULT_MAX_HEARTBEAT_DURATION);
+        beats.stop();
+
+        let old = Ping::new().with_max_version(HEARTBEAT_VERSION - 1);
+        let err = old
+            .subscribe_heartbeats(&conn, Duration::from_secs(1), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, PingError::HeartbeatsUnsupported { version } if version == HEARTBEAT_VERSION - 1),
+            "{err:?}"
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+}
@@ -1,27 +1,478 @@
 use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
 use iroh::{
-    endpoint::Connection,
-    protocol::{AcceptError, ProtocolHandler},
-    Endpoint, NodeAddr,
+    endpoint::{
+        BindError, ClosedStream, ConnectError, Connection, ConnectionError, ConnectionStats,
+        ConnectionType, ReadError, ReadExactError, ReadToEndError, RecvStream, SendDatagramError,
+        SendStream, WriteError,
+    },
+    protocol::{AcceptError, ProtocolHandler, RouterBuilder},
+    Endpoint, NodeAddr, NodeId, RelayUrl,
 };
-use iroh_metrics::{Counter, MetricsGroup};
-use tokio::time::sleep;
+use iroh_base::ticket::NodeTicket;
+use iroh_metrics::{Counter, Gauge, MetricsGroup, MetricsSource, Registry};
+use snafu::Snafu;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{field::Empty, Span};
+
+mod accept_loop;
+mod bind;
+mod burst;
+mod caps;
+mod close;
+mod codec;
+mod datagram;
+mod dial;
+mod direct;
+mod family;
+mod health;
+mod heartbeat;
+mod many;
+mod monitor;
+mod mtu;
+mod multi;
+#[cfg(feature = "otel")]
+mod otel;
+mod passive;
+mod path;
+mod pool;
+mod rate;
+mod record;
+mod retry;
+mod reverse;
+mod session;
+mod stats;
+#[cfg(feature = "stats")]
+mod stats_protocol;
+mod stream;
+mod sweep;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod throughput;
+mod transport;
+mod uni;
+
+pub use accept_loop::AcceptLoopHandle;
+pub use bind::{bind_endpoint, no_discovery_builder};
+pub use burst::{BurstReport, FloodStats};
+pub use caps::{
+    Capabilities, CAP_DATAGRAMS, CAP_DIAL_BACK, CAP_ERROR_RESPONSES, CAP_HEARTBEATS,
+    CAP_OBSERVED_ADDR, CAP_REVERSE_PING, CAP_SERVER_INFO, CAP_STREAMS, CAP_THROUGHPUT, CAP_UNI,
+};
+pub use close::CloseCode;
+pub use codec::{
+    CapsFrame, CodecError, DialRequest, DialResult, ErrorResponse, Nonce, ObservedAddr,
+    PingRequest, PongResponse, RejectReason, ServerInfo, Subscription, FLAG_OBSERVED_ADDR,
+    FLAG_REVERSE_PING, FLAG_SERVER_INFO, NONCE_LEN,
+};
+pub use datagram::DatagramReport;
+pub use dial::DialBackReport;
+pub use family::IpFamily;
+pub use health::{Health, UnreachableReason};
+pub use heartbeat::{Heartbeat, HeartbeatStream};
+pub use many::{PathChange, PingManyOpts, PingManyReport};
+pub use monitor::{Alert, MonitorConfig, MonitorEvent, PingMonitor};
+pub use path::{PathPreference, PathType};
+pub use pool::PingPool;
+pub use record::{PingRecorder, PingRecording, RecordedPing};
+pub use retry::RetryPolicy;
+pub use session::{PingEvent, PingResponse, PingSession};
+pub use stats::{PingStats, RollingPingStats, SmoothedRtt};
+#[cfg(feature = "stats")]
+pub use stats_protocol::{StatsProtocol, ALPN_STATS};
+pub use stream::PingStream;
+pub use sweep::{SweepOpts, SweepReport};
+pub use throughput::{ThroughputReport, ThroughputResult, Transfer};
+pub use transport::TransportStats;
+
+/// ALPN of the original ping protocol, which has no room to grow.
+///
+/// On a connection for this ALPN, the client opens a bidirectional stream per ping and
+/// sends the four bytes `PING`, which the server answers with the four bytes `PONG`,
+/// without any version byte or framing.
+///
+/// Servers keep answering it next to [`ALPN_V1`], see [`Ping::register`]. Clients try
+/// [`ALPN_V1`] first and fall back to this ALPN if the server refuses it in the handshake,
+/// which marks their pings as [`PingResult::legacy`]. Only plain pings work that way,
+/// everything else needs a server speaking [`ALPN_V1`].
+pub const ALPN: &[u8] = b"iroh/ping/0";
 
 /// Each protocol is identified by its ALPN string.
 ///
 /// The ALPN, or application-layer protocol negotiation, is exchanged in the connection handshake,
 /// and the connection is aborted unless both nodes pass the same bytestring.
-pub const ALPN: &[u8] = b"iroh/ping/0";
+///
+/// This is the ALPN of the versioned wire format, see [`PROTOCOL_VERSION`], so newer
+/// clients and servers keep talking to older ones within it. Servers that predate it only
+/// speak [`ALPN`].
+pub const ALPN_V1: &[u8] = b"iroh/ping/1";
+
+/// ALPN for connections that only carry datagram pings, see [`Ping::ping_datagram`].
+///
+/// Servers answer datagram pings on [`ALPN_V1`] connections as well, this ALPN just makes
+/// the intent explicit.
+pub const ALPN_DATAGRAM: &[u8] = b"iroh/ping-datagram/0";
+
+/// Newest version of the wire format spoken over [`ALPN_V1`].
+///
+/// Every request starts with a single byte holding the newest version the client speaks,
+/// and every response with the version the server answers in. Negotiation follows these
+/// rules:
+///
+/// - The server serves every version from 1 up to its own newest version.
+/// - If the client's version is one the server serves, the server handles the request and
+///   answers in that same version.
+/// - Otherwise the server does not look at the rest of the request, and answers with only
+///   its newest version byte. The client then repeats the request on a new stream in that
+///   version, provided it is lower than the one it asked for. A client that is talking to
+///   a newer server never sees a fallback, since servers serve all older versions.
+/// - Version 0 doesn't exist, a server closes the connection on it with
+///   [`CloseCode::UnsupportedVersion`].
+///
+/// Clients remember the negotiated version for the remaining requests on a connection,
+/// so the fallback costs at most one extra round trip per connection.
+///
+/// Version 1 requests are a four byte tag naming the message type followed by its body,
+/// e.g. `PING` followed by the payload to echo.
+/// Pings may also be sent as a single QUIC datagram each way, in the same format.
+///
+/// Version 2 frames the bodies of `PING` and `PONG` as [`PingRequest`] and
+/// [`PongResponse`], adding a sequence number and timestamps. All other messages, and
+/// datagram pings, are the same as in version 1.
+///
+/// Version 3 adds `STRM`, which the server acknowledges with its version byte and `STRM`.
+/// After that the client sends any number of framed [`PingRequest`]s on the stream, each
+/// answered by a framed [`PongResponse`], until it finishes the stream, upon which the
+/// server finishes its side too. See [`PingStream`].
+///
+/// Version 4 adds pings on unidirectional streams: `PING` and a framed [`PingRequest`] on
+/// a stream the client opens, answered by `PONG` and a framed [`PongResponse`] on a stream
+/// the server opens, or only its newest version byte to fall back. See [`Ping::ping_uni`].
+///
+/// Version 5 lets the server refuse a framed `PING` by answering with `EROR` and a framed
+/// [`ErrorResponse`] instead of `PONG`, on a stream of the same kind, e.g. when the client
+/// is rate limited (see [`Ping::with_server_rate_limit`]). Unlike a stream reset, this tells
+/// the client why, and when to try again.
+///
+/// Version 6 adds `WANT` and a framed [`CapsFrame`] listing the features a client wants,
+/// answered by `CAPS` and a framed [`CapsFrame`] with those the server supports, plus its
+/// limits. See [`Ping::negotiate`].
+///
+/// Version 7 adds `BEAT` and a framed [`Subscription`], which the server acknowledges with
+/// `BEAT` and the subscription it granted, then pushes a framed [`PongResponse`] as a
+/// heartbeat every interval on the same stream. See [`Ping::subscribe_heartbeats`].
+///
+/// Version 8 adds `DIAL` and a framed [`DialRequest`], upon which the server pings the
+/// client on a new connection it dials itself, then answers with `DIAL` and a framed
+/// [`DialResult`], or `EROR` if it doesn't dial back. See [`Ping::dial_back`].
+pub const PROTOCOL_VERSION: u8 = 8;
+
+/// The version a client speaks on a connection for [`ALPN`], which has no version byte.
+const LEGACY_VERSION: u8 = 0;
+
+/// Oldest protocol version with `EROR` responses.
+const ERROR_VERSION: u8 = 5;
+
+/// How long a single ping may take, including connection establishment, before it
+/// is considered failed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default upper bound for the payload a server accepts in a single ping.
+///
+/// A few KiB are plenty to measure how payload size affects the round trip, while a
+/// server answering many clients at once only buffers that much per ping.
+pub const DEFAULT_MAX_PAYLOAD: usize = 4 * 1024;
+
+/// Default upper bound for the data a server moves in a single throughput transfer.
+pub const DEFAULT_MAX_TRANSFER: u64 = 16 * 1024 * 1024;
+
+/// Default shortest interval between heartbeats a server grants, see
+/// [`Ping::with_heartbeat_limits`].
+pub const DEFAULT_MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default longest heartbeat subscription a server grants, see
+/// [`Ping::with_heartbeat_limits`].
+pub const DEFAULT_MAX_HEARTBEAT_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Default number of streams a server answers at once on a single connection, see
+/// [`Ping::with_max_concurrent_streams`].
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: usize = 64;
+
+/// Stream error code a server uses to reject a ping whose payload exceeds its limit.
+const ERR_PAYLOAD_TOO_LARGE: u32 = 1;
+
+/// Stream error code a server uses to reject a throughput transfer exceeding its limit.
+const ERR_TRANSFER_TOO_LARGE: u32 = 2;
+
+/// Stream error code a server uses to reject a request it can't make sense of.
+const ERR_INVALID_REQUEST: u32 = 3;
+
+/// Stream error code a server uses to reject a ping over its rate limit, in versions
+/// without `EROR` responses.
+const ERR_RATE_LIMITED: u32 = 4;
+
+/// Sequence number of the next framed ping, unique within the process.
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Microseconds since the unix epoch, for the timestamps of framed pings.
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Errors that can occur while sending a ping.
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum PingError {
+    /// Binding the local endpoint failed, see [`bind_endpoint`].
+    #[snafu(display("failed to bind to {addr}"))]
+    Bind {
+        addr: SocketAddr,
+        source: Box<BindError>,
+    },
+    /// The requested local port was taken, so the endpoint got another one, see
+    /// [`bind_endpoint`].
+    #[snafu(display("port of {addr} is in use, would have bound to {bound}"))]
+    BindPortTaken { addr: SocketAddr, bound: SocketAddr },
+    /// Establishing the connection to the remote node failed, though not for lack of a way
+    /// to reach it, e.g. because it doesn't serve the ALPN.
+    #[snafu(display("failed to connect"))]
+    Connect { source: ConnectError },
+    /// No address of the node could be discovered, or connecting to it failed.
+    ///
+    /// The reason is one of [`UnreachableReason::NotDiscovered`],
+    /// [`UnreachableReason::Timeout`] or [`UnreachableReason::ConnectFailed`].
+    #[snafu(display("node {} is unreachable: {}", node.fmt_short(), health::describe(*reason)))]
+    NodeUnreachable {
+        node: NodeId,
+        reason: UnreachableReason,
+        source: ConnectError,
+    },
+    /// The connection failed while opening a stream.
+    #[snafu(display("connection lost"))]
+    Connection { source: ConnectionError },
+    /// Writing the ping to the stream failed.
+    #[snafu(display("failed to send ping"))]
+    Write { source: WriteError },
+    /// Finishing the send side of the stream failed.
+    #[snafu(display("failed to finish stream"))]
+    Finish { source: ClosedStream },
+    /// Reading the response failed.
+    #[snafu(display("failed to read response"))]
+    Read { source: ReadToEndError },
+    /// Sending a datagram ping failed.
+    #[snafu(display("failed to send datagram"))]
+    SendDatagram { source: SendDatagramError },
+    /// The ping did not complete in time.
+    #[snafu(display("ping timed out after {timeout:?}"))]
+    Timeout { timeout: Duration },
+    /// The remote answered with something other than `PONG` and the echoed payload.
+    #[snafu(display("invalid response: {response:?}"))]
+    InvalidResponse { response: Vec<u8> },
+    /// The echoed payload differs from the one sent, by its CRC-32 checksum.
+    #[snafu(display("echoed payload has checksum {got:#010x}, expected {expected:#010x}"))]
+    IntegrityMismatch { expected: u32, got: u32 },
+    /// The remote answered with a `PONG` frame that could not be decoded.
+    #[snafu(display("invalid response frame"))]
+    Decode { source: CodecError },
+    /// The remote answered without the nonce of the request, so the answer isn't for it.
+    #[snafu(display("response nonce {got:02x?} does not match {expected:02x?}"))]
+    NonceMismatch { expected: Nonce, got: Nonce },
+    /// The remote rejected the ping because its payload exceeds the server's limit.
+    ///
+    /// The limit is known if the server sent it along, which servers do from protocol
+    /// version 5 on, but not when they reset the stream.
+    #[snafu(display("payload of {size} bytes rejected as too large{}", limit.map(|limit| format!(", the limit is {limit} bytes")).unwrap_or_default()))]
+    PayloadTooLarge { size: usize, limit: Option<usize> },
+    /// The remote refused a throughput transfer because it exceeds the server's limit.
+    #[snafu(display("transfer of {bytes} bytes rejected as too large"))]
+    TransferTooLarge { bytes: u64 },
+    /// There was no node to ping.
+    #[snafu(display("no nodes to ping"))]
+    NoTargets,
+    /// None of the given direct addresses could be reached, see [`Ping::ping_direct`].
+    #[snafu(display("none of the addresses {addrs:?} could be reached"))]
+    Unreachable { addrs: Vec<SocketAddr> },
+    /// The requested kind of path to the node is not available, see [`Ping::ping_via`].
+    #[snafu(display("no {path:?} path to the node"))]
+    PathUnavailable { path: PathPreference },
+    /// Every node pinged in a race failed, see [`Ping::ping_race`].
+    #[snafu(display("all {} pings failed", errors.len()))]
+    AllFailed { errors: Vec<(NodeAddr, PingError)> },
+    /// The server speaks a protocol version without ping streams, see
+    /// [`Ping::open_stream`].
+    #[snafu(display("protocol version {version} has no ping streams"))]
+    StreamUnsupported { version: u8 },
+    /// The server speaks a protocol version without heartbeats, see
+    /// [`Ping::subscribe_heartbeats`].
+    #[snafu(display("protocol version {version} has no heartbeats"))]
+    HeartbeatsUnsupported { version: u8 },
+    /// The server speaks a protocol version without dial-backs, see [`Ping::dial_back`].
+    #[snafu(display("protocol version {version} has no dial-backs"))]
+    DialBackUnsupported { version: u8 },
+    /// The server finished the ping stream, see [`PingStream::ping`].
+    #[snafu(display("ping stream finished by the server"))]
+    StreamFinished,
+    /// The server speaks a protocol version without pings on unidirectional streams, see
+    /// [`Ping::ping_uni`].
+    #[snafu(display("protocol version {version} has no pings on unidirectional streams"))]
+    UniUnsupported { version: u8 },
+    /// There is no path to the node in the required IP family, see [`Ping::with_ip_family`].
+    #[snafu(display("no path to the node in {family:?}"))]
+    IpFamilyUnavailable { family: IpFamily },
+    /// Datagrams are disabled on either side of the connection, too small for a ping, or
+    /// negotiated away, see [`Ping::ping_datagram_burst`] and [`PingSession::ping_datagram`].
+    #[snafu(display("datagrams are not supported on this connection"))]
+    DatagramsUnsupported,
+    /// The ping was cancelled before it completed, see [`Ping::ping_cancellable`].
+    #[snafu(display("ping cancelled"))]
+    Cancelled,
+    /// The server closed the connection because something went wrong, see [`CloseCode`].
+    #[snafu(display("server rejected: {code} ({reason})"))]
+    Rejected { code: CloseCode, reason: String },
+    /// The server refused the ping with an [`ErrorResponse`], leaving the connection open.
+    #[snafu(display(
+        "server refused the ping: {reason}{}",
+        retry_after.map_or(String::new(), |retry_after| format!(", retry after {retry_after:?}"))
+    ))]
+    RequestRejected {
+        reason: RejectReason,
+        retry_after: Option<Duration>,
+    },
+    /// The server answered a stats request with something other than a
+    /// [`MetricsSnapshot`], see [`Ping::fetch_stats`].
+    #[cfg(feature = "stats")]
+    #[snafu(display("invalid stats"))]
+    InvalidStats { source: serde_json::Error },
+}
+
+/// Timing of a single successful ping, and the path it took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingResult {
+    /// time it took to establish the connection, including the QUIC handshake
+    pub connect_time: Duration,
+    /// time from opening the stream until the full response arrived
+    pub ping_time: Duration,
+    /// the direct addresses the node was dialed at, from its [`NodeAddr`]
+    pub candidate_addrs: Vec<SocketAddr>,
+    /// the relay the node was dialed through, from its [`NodeAddr`]
+    pub candidate_relay: Option<RelayUrl>,
+    /// our socket the ping was sent from, if it went over a direct path
+    pub local_addr: Option<SocketAddr>,
+    /// the node's address the ping was sent to, if it went over a direct path
+    pub remote_addr: Option<SocketAddr>,
+    /// whether the ping went through the relay, possibly next to a direct path
+    pub relayed: bool,
+    /// the kind of path the connection used right after the ping
+    pub path_type: PathType,
+    /// time the ping was held back by the rate limit, see [`Ping::with_max_pings_per_second`]
+    pub rate_limit_wait: Duration,
+    /// transport counters of the connection right after the ping
+    pub transport: TransportStats,
+    /// The random nonce the server echoed, to correlate the ping with the server side.
+    ///
+    /// `None` if the server only speaks protocol version 1, which has no nonce.
+    pub nonce: Option<Nonce>,
+    /// Time the server took from reading the ping to starting its answer, as it reported.
+    ///
+    /// `None` if the server only speaks protocol version 1, which doesn't report it.
+    pub server_processing: Option<Duration>,
+    /// What the server reported about itself, if asked with [`Ping::with_server_info`] and
+    /// it speaks protocol version 2 or later.
+    pub server_info: Option<ServerInfo>,
+    /// How the server sees us, if asked with [`Ping::with_observed_addr`] and it shares
+    /// that, see [`Ping::with_share_observed_addr`].
+    pub observed_addr: Option<ObservedAddr>,
+    /// Round trip time of the server pinging us back over the same connection, as it
+    /// measured it, if asked with [`Ping::with_reverse_ping`] and the server supports it.
+    ///
+    /// Comparing it with [`PingResult::ping_time`] shows whether the path is symmetric.
+    pub reverse_rtt: Option<Duration>,
+    /// Whether the server only speaks the original protocol, so the ping fell back to
+    /// [`ALPN`].
+    ///
+    /// Such a server reports nothing about itself, so all the fields it would fill in are
+    /// `None`.
+    pub legacy: bool,
+}
+
+impl PingResult {
+    /// round trip time of the whole ping, including connection establishment
+    pub fn total_rtt(&self) -> Duration {
+        self.connect_time + self.ping_time
+    }
+
+    /// [`PingResult::ping_time`] without the time the server took to answer, i.e. the
+    /// time spent in the network and the endpoints
+    pub fn network_time(&self) -> Duration {
+        self.ping_time
+            .saturating_sub(self.server_processing.unwrap_or_default())
+    }
+}
+
+/// How a server answers incoming streams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PingServerMode {
+    /// speak the ping protocol, see [`PROTOCOL_VERSION`]
+    #[default]
+    Standard,
+    /// Echo back whatever a stream carries, up to the payload limit (see
+    /// [`Ping::with_max_payload`]), without looking at it.
+    ///
+    /// This turns the server into a plain QUIC echo service, e.g. for testing custom
+    /// payloads. Clients of the ping protocol won't get valid answers from it.
+    Echo,
+}
 
 /// Ping is a struct that holds both the client ping method, and the endpoint
 /// protocol implementation
 #[derive(Debug, Clone)]
 pub struct Ping {
     metrics: Arc<Metrics>,
+    timeout: Duration,
+    max_payload: usize,
+    max_transfer: u64,
+    max_version: u8,
+    server_mode: PingServerMode,
+    alpn: Vec<u8>,
+    log_connections: bool,
+    close_code: u32,
+    close_reason: Vec<u8>,
+    rate_limit: Option<Arc<rate::TokenBucket>>,
+    server_rate_limit: Option<Arc<rate::TokenBucket>>,
+    datagram_seq: Arc<AtomicU32>,
+    server_info: bool,
+    observed_addr: bool,
+    share_observed_addr: bool,
+    reverse_ping: bool,
+    datagrams: bool,
+    min_heartbeat_interval: Duration,
+    max_heartbeat_duration: Duration,
+    max_concurrent_streams: usize,
+    /// rate limit of dial-backs, `None` if this node doesn't dial back at all
+    dial_back: Option<Arc<rate::TokenBucket>>,
+    /// exports a span for every ping, see [`Ping::with_otel_tracer`]
+    #[cfg(feature = "otel")]
+    otel_tracer: Option<Arc<opentelemetry::global::BoxedTracer>>,
+    /// the endpoint serving pings, to look up the paths of clients, see [`Ping::register`]
+    endpoint: Option<Endpoint>,
+    ip_family: IpFamily,
+    /// when this instance was created, to report the uptime of servers
+    started: Instant,
 }
 
 impl Default for Ping {
@@ -35,140 +486,2967 @@ impl Ping {
     pub fn new() -> Self {
         Self {
             metrics: Arc::new(Metrics::default()),
+            timeout: DEFAULT_TIMEOUT,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_transfer: DEFAULT_MAX_TRANSFER,
+            max_version: PROTOCOL_VERSION,
+            server_mode: PingServerMode::Standard,
+            alpn: ALPN_V1.to_vec(),
+            log_connections: true,
+            close_code: CloseCode::Ok.code(),
+            close_reason: b"bye!".to_vec(),
+            rate_limit: None,
+            server_rate_limit: None,
+            datagram_seq: Arc::new(AtomicU32::new(0)),
+            server_info: false,
+            observed_addr: false,
+            share_observed_addr: true,
+            reverse_ping: false,
+            datagrams: true,
+            min_heartbeat_interval: DEFAULT_MIN_HEARTBEAT_INTERVAL,
+            max_heartbeat_duration: DEFAULT_MAX_HEARTBEAT_DURATION,
+            max_concurrent_streams: DEFAULT_MAX_CONCURRENT_STREAMS,
+            dial_back: None,
+            #[cfg(feature = "otel")]
+            otel_tracer: None,
+            endpoint: None,
+            ip_family: IpFamily::Any,
+            started: Instant::now(),
         }
     }
 
+    /// set how long a single ping may take before failing with [`PingError::Timeout`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// set the largest payload this node accepts when answering pings
+    pub fn with_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// set the largest amount of data this node moves in a single throughput transfer
+    pub fn with_max_transfer(mut self, max_transfer: u64) -> Self {
+        self.max_transfer = max_transfer;
+        self
+    }
+
+    /// set how this node answers incoming streams
+    pub fn with_server_mode(mut self, server_mode: PingServerMode) -> Self {
+        self.server_mode = server_mode;
+        self
+    }
+
+    /// Speak the protocol under another ALPN than [`ALPN_V1`], both as client and server.
+    ///
+    /// This runs separate ping services on one endpoint, or hides one from clients that
+    /// don't know its ALPN: connecting with a different ALPN fails in the handshake. Only
+    /// [`ALPN_V1`] falls back to [`ALPN`] for servers that predate it.
+    ///
+    /// # Panics
+    ///
+    /// If `alpn` is empty.
+    pub fn with_alpn(mut self, alpn: impl Into<Vec<u8>>) -> Self {
+        let alpn = alpn.into();
+        assert!(!alpn.is_empty(), "the ALPN must not be empty");
+        self.alpn = alpn;
+        self
+    }
+
+    /// the ALPN pings are sent and served under, see [`Ping::with_alpn`]
+    pub fn alpn(&self) -> &[u8] {
+        &self.alpn
+    }
+
+    /// Set whether to log every accepted connection, and every one the client closed with
+    /// a code other than [`CloseCode::Ok`], at the info level, which is the default.
+    ///
+    /// Otherwise they are only logged at the debug level.
+    pub fn with_log_connections(mut self, log_connections: bool) -> Self {
+        self.log_connections = log_connections;
+        self
+    }
+
+    /// Set the application error code to close connections with once done pinging,
+    /// [`CloseCode::Ok`] by default.
+    ///
+    /// Servers of this crate log any other code along with the reason, see
+    /// [`Ping::with_log_connections`]. Only applies to the connections this node opened.
+    pub fn with_close_code(mut self, close_code: u32) -> Self {
+        self.close_code = close_code;
+        self
+    }
+
+    /// set the reason to close connections with once done pinging, `bye!` by default
+    pub fn with_close_reason(mut self, close_reason: impl Into<Vec<u8>>) -> Self {
+        self.close_reason = close_reason.into();
+        self
+    }
+
+    /// Close `conn`, which is done pinging, with the code and reason set for that, see
+    /// [`Ping::with_close_code`] and [`Ping::with_close_reason`].
+    pub fn close_conn(&self, conn: &Connection) {
+        conn.close(self.close_code.into(), &self.close_reason);
+    }
+
+    /// ask servers to report their version, uptime and pings served, see
+    /// [`PingResult::server_info`]
+    pub fn with_server_info(mut self, server_info: bool) -> Self {
+        self.server_info = server_info;
+        self
+    }
+
+    /// ask servers to report the address they see us at, see [`PingResult::observed_addr`]
+    pub fn with_observed_addr(mut self, observed_addr: bool) -> Self {
+        self.observed_addr = observed_addr;
+        self
+    }
+
+    /// Set whether to tell clients the address they are seen at when they ask, which is the
+    /// default.
+    ///
+    /// Only servers set up with [`Ping::register`] know the address, others report none.
+    pub fn with_share_observed_addr(mut self, share_observed_addr: bool) -> Self {
+        self.share_observed_addr = share_observed_addr;
+        self
+    }
+
+    /// Ask servers to ping us back over the same connection after answering, see
+    /// [`PingResult::reverse_rtt`].
+    ///
+    /// Applies to the pings with a [`PingResult`], and takes another round trip after each.
+    pub fn with_reverse_ping(mut self, reverse_ping: bool) -> Self {
+        self.reverse_ping = reverse_ping;
+        self
+    }
+
+    /// Set whether this node answers datagram pings, on by default.
+    ///
+    /// Servers without them negotiate datagrams away, see [`Ping::negotiate`].
+    pub fn with_datagrams(mut self, datagrams: bool) -> Self {
+        self.datagrams = datagrams;
+        self
+    }
+
+    /// Set how many streams this node answers at once on a single connection as a server,
+    /// [`DEFAULT_MAX_CONCURRENT_STREAMS`] by default.
+    ///
+    /// Each stream is answered in a task of its own, so pings sent at once, e.g. by
+    /// [`Ping::ping_burst`], are answered at once too. Streams beyond the limit wait until
+    /// an earlier one is done. Ping streams and heartbeat subscriptions count for as long as
+    /// they are open.
+    ///
+    /// # Panics
+    ///
+    /// If `max_concurrent_streams` is zero.
+    pub fn with_max_concurrent_streams(mut self, max_concurrent_streams: usize) -> Self {
+        assert!(
+            max_concurrent_streams > 0,
+            "at least one stream must be answered at a time"
+        );
+        self.max_concurrent_streams = max_concurrent_streams;
+        self
+    }
+
+    /// Set which IP family to dial nodes over.
+    ///
+    /// With [`IpFamily::Ipv4Only`] or [`IpFamily::Ipv6Only`], pings fail with
+    /// [`PingError::IpFamilyUnavailable`] unless they get a direct path in that family,
+    /// instead of falling back to another one or the relay. Applies to all pings that
+    /// dial the node themselves, rather than using a given connection.
+    pub fn with_ip_family(mut self, ip_family: IpFamily) -> Self {
+        self.ip_family = ip_family;
+        self
+    }
+
+    /// Pretend to speak a different newest protocol version, to test negotiation.
+    #[cfg(test)]
+    pub(crate) fn with_max_version(mut self, max_version: u8) -> Self {
+        self.max_version = max_version;
+        self
+    }
+
+    /// Serve pings on a router under [`ALPN_V1`] (or the one set with [`Ping::with_alpn`])
+    /// and [`ALPN_DATAGRAM`], next to whatever other protocols it serves.
+    ///
+    /// Under [`ALPN_V1`], the router also answers clients that only speak the original
+    /// protocol under [`ALPN`].
+    ///
+    /// The router shares its endpoint between all protocols, so the same endpoint can
+    /// also send pings, as long as it isn't closed by [`Ping::ping`].
+    pub fn register(&self, builder: RouterBuilder) -> RouterBuilder {
+        let handler = Self {
+            endpoint: Some(builder.endpoint().clone()),
+            ..self.clone()
+        };
+        let builder = if self.alpn == ALPN_V1 {
+            builder.accept(ALPN, handler.clone())
+        } else {
+            builder
+        };
+        builder
+            .accept(&self.alpn, handler.clone())
+            .accept(ALPN_DATAGRAM, handler)
+    }
+
     /// handle to ping metrics
     pub fn metrics(&self) -> &Arc<Metrics> {
         &self.metrics
     }
 
     /// send a ping on the provided endpoint to a given node address
-    pub async fn ping(&self, endpoint: &Endpoint, addr: NodeAddr) -> anyhow::Result<Duration> {
-        let start = Instant::now();
-        // Open a connection to the accepting node
-        let conn = endpoint.connect(addr, ALPN).await?;
+    ///
+    /// The result tells connection establishment apart from the ping exchange itself.
+    ///
+    /// Besides the connection, this also closes `endpoint` once done, so it can't be used
+    /// for anything else afterwards. Use [`Ping::ping_once`] instead, which leaves the
+    /// endpoint open. In the next major version this method will stop closing the endpoint.
+    #[deprecated = "closes the whole endpoint, use `Ping::ping_once` instead"]
+    #[tracing::instrument(skip_all, fields(peer_addr = %addr.node_id.fmt_short()))]
+    pub async fn ping(&self, endpoint: &Endpoint, addr: NodeAddr) -> Result<PingResult, PingError> {
+        self.ping_closing(endpoint, addr).await
+    }
 
-        println!("client connected to addr");
+    /// Pings, then closes both the connection and the endpoint.
+    async fn ping_closing(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<PingResult, PingError> {
+        let (res, conn) = self.ping_conn(endpoint, addr).await?;
+        self.close_conn(&conn);
 
-        // Open a bidirectional QUIC stream
-        let (mut send, mut recv) = conn.open_bi().await?;
+        // The connection close only queues a close message to be sent (see how it's not
+        // async!). We need to actually call this to make sure this message is sent out.
+        //
+        // If we don't call this, but continue using the endpoint, we then the queued
+        // close call will eventually be picked up and sent.
+        // But always try to wait for endpoint.close().await to go through before dropping
+        // the endpoint to ensure any queued messages are sent through and connections are
+        // closed gracefully.
+        endpoint.close().await;
 
-        println!("client opened bidir stream");
+        Ok(res)
+    }
 
-        // Send some data to be pinged
-        send.write_all(b"PING").await?;
+    /// Like [`Ping::ping_once`], but gives up as soon as `cancel` is cancelled.
+    ///
+    /// On cancellation, the connection is closed with the reason `cancelled` if it was
+    /// already established, and the ping fails with [`PingError::Cancelled`]. Dropping the
+    /// future abandons the ping as well, but doesn't close the connection properly, so to
+    /// tear down cleanly cancel the token and let the future complete instead of dropping
+    /// it.
+    pub async fn ping_cancellable(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        cancel: &CancellationToken,
+    ) -> Result<Duration, PingError> {
+        if cancel
+            .run_until_cancelled(self.wait_for_turn())
+            .await
+            .is_none()
+        {
+            return Err(PingError::Cancelled);
+        }
+        let start = Instant::now();
+        let timed_out = || PingError::Timeout {
+            timeout: self.timeout,
+        };
+        let connect = tokio::time::timeout(self.timeout, self.connect(endpoint, addr));
+        let conn = tokio::select! {
+            conn = connect => match conn {
+                Ok(Ok(conn)) => conn,
+                Ok(Err(err)) => return Err(self.failed(err)),
+                Err(_) => return Err(self.failed(timed_out())),
+            },
+            _ = cancel.cancelled() => return Err(PingError::Cancelled),
+        };
 
-        println!("client wrote ping");
+        let remaining = self.timeout.saturating_sub(start.elapsed());
+        let version = self.version_on(&conn);
+        let exchange = tokio::time::timeout(remaining, exchange(&conn, &[], version));
+        let res = tokio::select! {
+            res = exchange => res.unwrap_or_else(|_| Err(timed_out())),
+            _ = cancel.cancelled() => {
+                conn.close(0u32.into(), b"cancelled");
+                return Err(PingError::Cancelled);
+            }
+        };
+        self.close_conn(&conn);
+        res.map_err(|err| self.failed(err))?;
+        self.metrics.pings_sent.inc();
+        Ok(start.elapsed())
+    }
 
-        // Signal the end of data for this particular stream
-        // send.finish()?;
+    /// Send a single ping from an endpoint of its own, and return its round trip time.
+    ///
+    /// Binds a new endpoint with the default discovery, pings, and closes the endpoint
+    /// again, so nothing needs to be set up beforehand. That is convenient for scripts and
+    /// tests, but expensive: every call pays for binding an endpoint and for the whole
+    /// handshake. Anything pinging more than once should share an endpoint and use
+    /// [`Ping::ping_once`] instead.
+    pub async fn ping_oneshot(&self, addr: NodeAddr) -> anyhow::Result<Duration> {
+        let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+        let res = self.ping_once(&endpoint, addr).await;
+        endpoint.close().await;
+        Ok(res?)
+    }
 
-        // read the response, which must be PONG as bytes
-        let response = recv.read_to_end(4).await?;
-        assert_eq!(&response, b"PONG");
+    /// send a ping on the provided endpoint to the node a ticket points to
+    ///
+    /// Like [`Ping::ping_once`], this leaves the endpoint open.
+    pub async fn ping_ticket(
+        &self,
+        endpoint: &Endpoint,
+        ticket: &NodeTicket,
+    ) -> anyhow::Result<Duration> {
+        let rtt = self.ping_once(endpoint, ticket.node_addr().clone()).await?;
+        Ok(rtt)
+    }
 
-        println!("client received pong");
+    /// send a ping on the provided endpoint to the node a serialized ticket points to
+    ///
+    /// Like [`Ping::ping_once`], this leaves the endpoint open.
+    pub async fn ping_ticket_str(
+        &self,
+        endpoint: &Endpoint,
+        ticket: &str,
+    ) -> anyhow::Result<Duration> {
+        let ticket = NodeTicket::from_str(ticket).context("invalid node ticket")?;
+        self.ping_ticket(endpoint, &ticket).await
+    }
 
+    /// Send a ping with retries, following the given [`RetryPolicy`].
+    ///
+    /// Only transient failures ([`PingError::Connect`], [`PingError::NodeUnreachable`] and
+    /// [`PingError::Timeout`]) are retried. Returns the round trip time of the successful ping together with the
+    /// number of attempts it took.
+    ///
+    /// Unlike [`Ping::ping`], this does not close the endpoint.
+    pub async fn ping_with_retries(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        policy: RetryPolicy,
+    ) -> Result<(Duration, u32), PingError> {
+        let mut attempts = 0;
         loop {
-            sleep(Duration::from_secs(10)).await;
+            attempts += 1;
+            match self.ping_once(endpoint, addr.clone()).await {
+                Ok(rtt) => return Ok((rtt, attempts)),
+                Err(err) if retry::is_transient(&err) => {
+                    let Some(delay) = policy.delay(attempts) else {
+                        self.metrics.ping_max_retries_exhausted.inc();
+                        return Err(err);
+                    };
+                    self.metrics.ping_retries_total.inc();
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
         }
+    }
 
-        // // Explicitly close the whole connection.
-        // conn.close(0u32.into(), b"bye!");
+    /// Send a ping, retrying transient failures with exponential backoff.
+    ///
+    /// Makes at most `max_attempts` attempts, but always at least one, waiting `backoff`
+    /// before the first retry and doubling the wait after each further failure. Like
+    /// [`Ping::ping_with_retries`], only connect failures and timeouts are retried.
+    /// Returns the last error once the attempts are exhausted. Does not close the endpoint.
+    pub async fn ping_retry(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        max_attempts: usize,
+        backoff: Duration,
+    ) -> anyhow::Result<Duration> {
+        let policy = RetryPolicy::Exponential {
+            base: backoff,
+            max_delay: Duration::MAX,
+            max_retries: max_attempts
+                .saturating_sub(1)
+                .try_into()
+                .unwrap_or(u32::MAX),
+            jitter: false,
+        };
+        let (rtt, _attempts) = self.ping_with_retries(endpoint, addr, policy).await?;
+        Ok(rtt)
+    }
 
-        // // The above call only queues a close message to be sent (see how it's not async!).
-        // // We need to actually call this to make sure this message is sent out.
-        // endpoint.close().await;
+    /// Send `count` pings one after the other, and summarize their round trip times.
+    ///
+    /// All pings go over a single connection, see [`Ping::ping_n_on_conn`], so the round
+    /// trip times don't include the handshake. The stats keep every round trip time, for
+    /// exact percentiles. Fails on the first ping that fails. Unlike [`Ping::ping`], this
+    /// does not close the endpoint.
+    pub async fn ping_n(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        count: usize,
+    ) -> Result<PingStats, PingError> {
+        self.ping_n_cancellable(endpoint, addr, count, &CancellationToken::new())
+            .await
+    }
 
-        // // at this point we've successfully pinged, mark the metric
-        // self.metrics.pings_sent.inc();
+    /// Like [`Ping::ping_n`], but stops early once `cancel` is cancelled.
+    ///
+    /// On cancellation the ping in flight is abandoned, closing its connection, and the
+    /// stats of the pings completed so far are returned.
+    pub async fn ping_n_cancellable(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        count: usize,
+        cancel: &CancellationToken,
+    ) -> Result<PingStats, PingError> {
+        let connect = tokio::time::timeout(self.timeout, self.connect(endpoint, addr));
+        let Some(conn) = cancel.run_until_cancelled(connect).await else {
+            return Ok(PingStats::with_samples());
+        };
+        let conn = match conn {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(err)) => return Err(self.failed(err)),
+            Err(_) => {
+                return Err(self.failed(PingError::Timeout {
+                    timeout: self.timeout,
+                }))
+            }
+        };
+        let res = self.ping_n_on_conn_until(&conn, count, cancel).await;
+        if let Err(err) = &res {
+            close::close_on_violation(&conn, err);
+        }
+        self.close_conn(&conn);
+        res
+    }
 
-        // // If we don't call this, but continue using the endpoint, we then the queued
-        // // close call will eventually be picked up and sent.
-        // // But always try to wait for endpoint.close().await to go through before dropping
-        // // the endpoint to ensure any queued messages are sent through and connections are
-        // // closed gracefully.
-        // Ok(Duration::from_millis(
-        //     Instant::now().duration_since(start).as_millis() as u64,
-        // ))
+    /// Send a single ping over an existing connection, on a new stream.
+    ///
+    /// The connection must have been established for [`ALPN_V1`], or [`ALPN`] for a server
+    /// that predates it. This measures only the exchange itself, without the connection
+    /// handshake. Does not close the connection.
+    pub async fn ping_on_conn(&self, conn: &Connection) -> Result<Duration, PingError> {
+        self.ping_on_conn_with_buf(conn, &mut BytesMut::new()).await
     }
-}
 
-impl ProtocolHandler for Ping {
-    /// The `accept` method is called for each incoming connection for our ALPN.
+    /// Like [`Ping::ping_on_conn`], but builds the request and reads the response in `buf`.
     ///
-    /// The returned future runs on a newly spawned tokio task, so it can run as long as
-    /// the connection lasts.
-    async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
-        let metrics = self.metrics.clone();
+    /// Passing the same buffer to every ping on a connection saves allocating for each of
+    /// them, which adds up when pinging often. Whatever `buf` holds is overwritten.
+    pub async fn ping_on_conn_with_buf(
+        &self,
+        conn: &Connection,
+        buf: &mut BytesMut,
+    ) -> Result<Duration, PingError> {
+        let (rtt, _version) = self
+            .ping_on_conn_version(conn, self.version_on(conn), buf)
+            .await?;
+        Ok(rtt)
+    }
 
-        // We can get the remote's node id from the connection.
-        let node_id = connection.remote_node_id()?;
-        println!("server accepted connection from {node_id}");
+    /// Send a single ping carrying `payload` over an existing connection, on a new stream.
+    ///
+    /// Like [`Ping::ping_on_conn`], but the server echoes `payload`, so larger pings show
+    /// how the round trip time grows with the size. Fails with
+    /// [`PingError::PayloadTooLarge`] if the payload exceeds the server's
+    /// [`Ping::with_max_payload`], and with [`PingError::IntegrityMismatch`] if the echo
+    /// doesn't match what was sent. Does not close the connection, unless the server broke
+    /// the protocol, like a mismatched echo does.
+    pub async fn ping_payload(
+        &self,
+        conn: &Connection,
+        payload: &[u8],
+    ) -> Result<Duration, PingError> {
+        self.wait_for_turn().await;
+        let start = Instant::now();
+        let mut buf = BytesMut::new();
+        tokio::time::timeout(
+            self.timeout,
+            exchange_with(conn, payload, self.version_on(conn), &mut buf),
+        )
+        .await
+        .unwrap_or(Err(PingError::Timeout {
+            timeout: self.timeout,
+        }))
+        .inspect_err(|err| close::close_on_violation(conn, err))
+        .map_err(|err| self.failed(err))?;
+        self.metrics.pings_sent.inc();
+        Ok(start.elapsed())
+    }
+
+    /// Send `count` pings one after the other over an existing connection, and summarize
+    /// their round trip times.
+    ///
+    /// Like [`Ping::ping_on_conn`], but negotiates the protocol version only once. Fails on
+    /// the first ping that fails. Does not close the connection.
+    pub async fn ping_n_on_conn(
+        &self,
+        conn: &Connection,
+        count: usize,
+    ) -> Result<PingStats, PingError> {
+        self.ping_n_on_conn_until(conn, count, &CancellationToken::new())
+            .await
+    }
+
+    /// Sends up to `count` pings over `conn`, stopping early once `cancel` is cancelled.
+    async fn ping_n_on_conn_until(
+        &self,
+        conn: &Connection,
+        count: usize,
+        cancel: &CancellationToken,
+    ) -> Result<PingStats, PingError> {
+        let mut stats = PingStats::with_samples();
+        let mut version = self.version_on(conn);
+        let mut buf = BytesMut::new();
+        for _ in 0..count {
+            let ping = self.ping_on_conn_version(conn, version, &mut buf);
+            let Some(res) = cancel.run_until_cancelled(ping).await else {
+                break;
+            };
+            let (rtt, negotiated) = res?;
+            version = negotiated;
+            stats.record_rtt(rtt);
+        }
+        Ok(stats)
+    }
 
-        // Our protocol is a simple request-response protocol, so we expect the
-        // connecting peer to open a single bi-directional stream.
-        let (mut send, mut recv) = connection.accept_bi().await?;
-        println!("server accepted bidir stream");
+    /// Sends a single ping over `conn` in the given protocol version, returning its round
+    /// trip time and the version it was answered in. `buf` is reused as in [`exchange_with`].
+    async fn ping_on_conn_version(
+        &self,
+        conn: &Connection,
+        version: u8,
+        buf: &mut BytesMut,
+    ) -> Result<(Duration, u8), PingError> {
+        self.wait_for_turn().await;
+        let start = Instant::now();
+        let version = tokio::time::timeout(self.timeout, exchange_with(conn, &[], version, buf))
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+            .map_err(|err| self.failed(err))?;
+        self.metrics.pings_sent.inc();
+        Ok((start.elapsed(), version))
+    }
+
+    /// The protocol version to start out with on `conn`, [`LEGACY_VERSION`] if it was
+    /// established for [`ALPN`].
+    fn version_on(&self, conn: &Connection) -> u8 {
+        if is_legacy(conn) {
+            LEGACY_VERSION
+        } else {
+            self.max_version
+        }
+    }
 
-        let req = recv.read_to_end(4).await.map_err(AcceptError::from_err)?;
-        assert_eq!(&req, b"PING");
+    /// Counts a failed ping in the metrics, passing the error through.
+    ///
+    /// An error from the server closing the connection with a [`CloseCode`] becomes a
+    /// [`PingError::Rejected`].
+    fn failed(&self, err: PingError) -> PingError {
+        let err = close::translate(err);
+        self.metrics.pings_failed.inc();
+        if matches!(err, PingError::Timeout { .. }) {
+            self.metrics.pings_timed_out.inc();
+        }
+        err
+    }
 
-        println!("the server read ping");
+    /// Connect to a node for pinging it later, without sending a ping yet.
+    ///
+    /// The QUIC handshake makes the first ping on a new connection much slower than the
+    /// ones after. Connecting ahead of time lets the caller do other work meanwhile, and
+    /// then take a first measurement without the handshake using [`Ping::ping_on_conn`].
+    /// Fails if the connection can't be established within the timeout (see
+    /// [`Ping::with_timeout`]).
+    ///
+    /// The connection counts towards the endpoint's limit of concurrent connections until
+    /// the caller closes it. The endpoint is not closed.
+    pub async fn pre_connect(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<Connection, PingError> {
+        tokio::time::timeout(self.timeout, self.connect(endpoint, addr))
+            .await
+            .map_err(|_| PingError::Timeout {
+                timeout: self.timeout,
+            })?
+    }
 
-        // send back "PONG" bytes
-        send.write_all(b"PONG")
+    /// Like [`Ping::connect`], but bounded by the timeout, and counted in the metrics as a
+    /// failed ping if it fails.
+    async fn connect_timeout(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<Connection, PingError> {
+        tokio::time::timeout(self.timeout, self.connect(endpoint, addr))
             .await
-            .map_err(AcceptError::from_err)?;
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+            .map_err(|err| self.failed(err))
+    }
+
+    /// Dials the node at the parts of `addr` in the configured IP family, and waits for a
+    /// path in it if the family is required, see [`Ping::with_ip_family`].
+    async fn connect(&self, endpoint: &Endpoint, addr: NodeAddr) -> Result<Connection, PingError> {
+        let node_id = addr.node_id;
+        let addr = self.ip_family.narrow(addr)?;
+        let conn = match endpoint.connect(addr.clone(), &self.alpn).await {
+            // a server from before `ALPN_V1`, which only speaks the original protocol
+            Err(err) if self.alpn == ALPN_V1 && health::alpn_refused(&err) => {
+                tracing::debug!("falling back to the legacy ALPN");
+                endpoint.connect(addr, ALPN).await
+            }
+            res => res,
+        }
+        .map_err(|source| health::connect_failed(node_id, source))?;
+        self.ip_family.wait_for_path(endpoint, node_id).await?;
+        Ok(conn)
+    }
 
-        println!("server wrote pong to client");
+    /// Send a ping and hand back the connection it was sent on, instead of closing it.
+    ///
+    /// This saves dialing (and hole punching) again when the ping is only a warm-up for
+    /// further requests. The connection was negotiated for [`ALPN_V1`] (or [`ALPN`] against
+    /// a server that only speaks the original protocol), so it is only useful
+    /// for talking this protocol, e.g. through further pings on new streams; other
+    /// protocols need their own connection. The returned stats describe the connection
+    /// right after the ping.
+    ///
+    /// Neither the connection nor the endpoint is closed, that is up to the caller.
+    pub async fn ping_keep(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<(PingResult, Connection, ConnectionStats), PingError> {
+        let (res, conn) = self.ping_conn(endpoint, addr).await?;
+        let stats = conn.stats();
+        Ok((res, conn, stats))
+    }
 
-        // // By calling `finish` on the send stream we signal that we will not send anything
-        // // further, which makes the receive stream on the other end terminate.
-        // send.finish()?;
+    /// Send a single ping on a new connection, and return its round trip time.
+    ///
+    /// Only the connection is closed afterwards, the endpoint stays open for further use.
+    pub async fn ping_once(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<Duration, PingError> {
+        let (res, conn) = self.ping_conn(endpoint, addr).await?;
 
-        // // Wait until the remote closes the connection, which it does once it
-        // // received the response.
-        // connection.closed().await;
+        // Explicitly close the whole connection.
+        self.close_conn(&conn);
 
-        // increment count of pings we've received
-        metrics.pings_recv.inc();
+        Ok(res.total_rtt())
+    }
 
-        loop {
-            sleep(Duration::from_secs(10)).await;
+    /// Connects and exchanges a single PING/PONG, returning the still open connection.
+    async fn ping_conn(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<(PingResult, Connection), PingError> {
+        self.ping_conn_via(endpoint, addr, PathPreference::Any)
+            .await
+    }
+
+    /// Like [`Ping::ping_conn`], but waits for a direct path before pinging if `path`
+    /// asks for one.
+    ///
+    /// Every single ping goes through here, so this is where their span starts, with the
+    /// times recorded once the ping was answered.
+    #[tracing::instrument(
+        name = "ping_conn",
+        skip_all,
+        fields(peer_addr = %addr.node_id.fmt_short(), connect_us = Empty, rtt_us = Empty),
+    )]
+    async fn ping_conn_via(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        path: PathPreference,
+    ) -> Result<(PingResult, Connection), PingError> {
+        #[cfg(feature = "otel")]
+        let otel = otel::PingSpan::start(self, addr.node_id);
+        let res = self.exchange_ping(endpoint, addr, path).await;
+        #[cfg(feature = "otel")]
+        otel.end(res.as_ref().map(|(res, _conn)| res));
+        res
+    }
+
+    /// The ping of [`Ping::ping_conn_via`], from connecting to reading the path taken.
+    async fn exchange_ping(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        path: PathPreference,
+    ) -> Result<(PingResult, Connection), PingError> {
+        let node_id = addr.node_id;
+        let candidate_addrs = addr.direct_addresses.iter().copied().collect();
+        let candidate_relay = addr.relay_url.clone();
+        let rate_limit_wait = self.wait_for_turn().await;
+        let start = Instant::now();
+        let (connected, conn, version, pong) = tokio::time::timeout(self.timeout, async {
+            // Open a connection to the accepting node
+            tracing::debug!("connecting");
+            let conn = self.connect(endpoint, addr).await?;
+            if path == PathPreference::DirectOnly {
+                path::wait_for_direct(endpoint, node_id).await?;
+            }
+            let connected = Instant::now();
+            let mut flags = 0;
+            if self.server_info {
+                flags |= FLAG_SERVER_INFO;
+            }
+            if self.observed_addr {
+                flags |= FLAG_OBSERVED_ADDR;
+            }
+            if self.reverse_ping {
+                flags |= FLAG_REVERSE_PING;
+            }
+            let (version, pong) = exchange_pong(
+                &conn,
+                &[],
+                self.version_on(&conn),
+                flags,
+                &mut BytesMut::new(),
+            )
+            .await
+            .inspect_err(|err| close::close_on_violation(&conn, err))?;
+            Ok::<_, PingError>((connected, conn, version, pong))
+        })
+        .await
+        .unwrap_or(Err(PingError::Timeout {
+            timeout: self.timeout,
+        }))
+        .map_err(|err| self.failed(err))?;
+
+        let ping_time = connected.elapsed();
+        let span = Span::current();
+        span.record("connect_us", (connected - start).as_micros() as u64);
+        span.record("rtt_us", ping_time.as_micros() as u64);
+        let reverse_rtt = match &pong {
+            Some(pong) if pong.reverse => Some(
+                self.answer_reverse(&conn, version)
+                    .await
+                    .inspect_err(|err| close::close_on_violation(&conn, err))
+                    .map_err(|err| self.failed(err))?,
+            ),
+            _ => None,
+        };
+
+        // Look up the path while the connection is still open.
+        let path = many::current_path(endpoint, node_id);
+        let remote_addr = match path {
+            Some(ConnectionType::Direct(addr) | ConnectionType::Mixed(addr, _)) => Some(addr),
+            _ => None,
+        };
+        let local_addr = remote_addr.and_then(|remote| {
+            endpoint
+                .bound_sockets()
+                .into_iter()
+                .find(|local| local.is_ipv4() == remote.is_ipv4())
+        });
+        let res = PingResult {
+            connect_time: connected - start,
+            ping_time,
+            candidate_addrs,
+            candidate_relay,
+            local_addr,
+            remote_addr,
+            relayed: matches!(
+                path,
+                Some(ConnectionType::Relay(_) | ConnectionType::Mixed(..))
+            ),
+            path_type: path.into(),
+            transport: TransportStats::from_conn(&conn),
+            rate_limit_wait,
+            nonce: pong.as_ref().map(|pong| pong.nonce),
+            server_processing: pong.as_ref().map(|pong| pong.server_processing),
+            server_info: pong.as_ref().and_then(|pong| pong.server_info.clone()),
+            observed_addr: pong.and_then(|pong| pong.observed_addr),
+            reverse_rtt,
+            legacy: version == LEGACY_VERSION,
+        };
+
+        // at this point we've successfully pinged, mark the metrics
+        self.metrics.pings_sent.inc();
+        self.metrics
+            .connect_time_us
+            .inc_by(res.connect_time.as_micros() as u64);
+
+        Ok((res, conn))
+    }
+}
+
+/// Whether `conn` was established for [`ALPN`], and speaks the original protocol.
+fn is_legacy(conn: &Connection) -> bool {
+    conn.alpn().as_deref() == Some(ALPN)
+}
+
+/// Outcome of a request sent in a particular protocol version.
+enum Negotiated<T> {
+    /// the server answered in the requested version
+    Done(T),
+    /// the server asked to repeat the request in the given, older version
+    Fallback(u8),
+}
+
+/// Checks that a server's fallback version is one we can actually fall back to.
+fn fallback_version(requested: u8, offered: u8) -> Result<u8, PingError> {
+    if offered == 0 || offered >= requested {
+        return Err(PingError::InvalidResponse {
+            response: vec![offered],
+        });
+    }
+    Ok(offered)
+}
+
+/// Performs a single PING/PONG exchange, negotiating the protocol version if needed.
+///
+/// Returns the protocol version the exchange happened in, which should be passed in as
+/// `version` for the next exchange on the same connection.
+async fn exchange(conn: &Connection, payload: &[u8], version: u8) -> Result<u8, PingError> {
+    exchange_with(conn, payload, version, &mut BytesMut::new()).await
+}
+
+/// Like [`exchange`], but builds the request and reads the response in `buf`.
+///
+/// Passing the same buffer to consecutive exchanges saves allocating for every one.
+async fn exchange_with(
+    conn: &Connection,
+    payload: &[u8],
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<u8, PingError> {
+    let (version, _pong) = exchange_pong(conn, payload, version, 0, buf).await?;
+    Ok(version)
+}
+
+/// What the server put in a framed `PONG`, next to the echoed payload.
+struct PongInfo {
+    nonce: Nonce,
+    server_processing: Duration,
+    server_info: Option<ServerInfo>,
+    observed_addr: Option<ObservedAddr>,
+    /// whether the server is going to ping us back
+    reverse: bool,
+}
+
+/// Like [`exchange_with`], but also returns what the server put in its `PONG`, if the
+/// negotiated version is framed. `flags` are those of the [`PingRequest`].
+async fn exchange_pong(
+    conn: &Connection,
+    payload: &[u8],
+    mut version: u8,
+    flags: u64,
+    buf: &mut BytesMut,
+) -> Result<(u8, Option<PongInfo>), PingError> {
+    loop {
+        match exchange_version(conn, payload, version, flags, buf).await? {
+            Negotiated::Done(pong) => return Ok((version, pong)),
+            Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
         }
+    }
+}
 
-        Ok(())
+/// Performs a single exchange of the original protocol, see [`ALPN`], which has no room
+/// for a payload.
+async fn legacy_exchange(conn: &Connection, payload: &[u8]) -> Result<(), PingError> {
+    if !payload.is_empty() {
+        return Err(PingError::PayloadTooLarge {
+            size: payload.len(),
+            limit: Some(0),
+        });
     }
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    send.write_all(b"PING")
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+    let response = recv
+        .read_to_end(4)
+        .await
+        .map_err(|source| PingError::Read { source })?;
+    if response != b"PONG" {
+        return Err(PingError::InvalidResponse { response });
+    }
+    Ok(())
 }
 
-/// Enum of metrics for the module
-#[derive(Debug, Default, MetricsGroup)]
-#[metrics(name = "ping")]
-pub struct Metrics {
-    /// count of valid ping messages sent
-    pub pings_sent: Counter,
-    /// count of valid ping messages received
-    pub pings_recv: Counter,
+/// Performs a single PING/PONG exchange on a fresh bidirectional stream.
+///
+/// The request is `PING` followed by `payload`, and the remote must answer with `PONG`
+/// followed by the same payload. From version 2 on, both are framed, see [`PingRequest`],
+/// and what the server put in its `PONG` is returned. From version 5 on, the server may
+/// answer with `EROR` instead, see [`ErrorResponse`].
+async fn exchange_version(
+    conn: &Connection,
+    payload: &[u8],
+    version: u8,
+    flags: u64,
+    buf: &mut BytesMut,
+) -> Result<Negotiated<Option<PongInfo>>, PingError> {
+    if version == LEGACY_VERSION {
+        return legacy_exchange(conn, payload)
+            .await
+            .map(|()| Negotiated::Done(None));
+    }
+    let too_large = |limit: Option<u64>| PingError::PayloadTooLarge {
+        size: payload.len(),
+        limit: limit.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX)),
+    };
+
+    // Open a bidirectional QUIC stream
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    tracing::debug!(version, "stream_opened");
+
+    // Send some data to be pinged
+    buf.clear();
+    buf.put_u8(version);
+    buf.put_slice(b"PING");
+    let framed = if version >= 2 {
+        let request = PingRequest {
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            sent_at_us: now_us(),
+            flags,
+            nonce: rand::random(),
+            payload,
+        };
+        request.encode(buf);
+        Some((request.seq, request.nonce))
+    } else {
+        buf.put_slice(payload);
+        None
+    };
+    match send.write_all(&buf[..]).await {
+        Ok(()) => {}
+        Err(WriteError::Stopped(code)) if code == ERR_PAYLOAD_TOO_LARGE.into() => {
+            return Err(too_large(None))
+        }
+        Err(source) => return Err(PingError::Write { source }),
+    }
+
+    // Signal the end of data for this particular stream
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+    tracing::debug!(len = buf.len(), "payload_sent");
+
+    // read the response, which must be PONG followed by our payload, or EROR
+    let mut limit = buf.len() + PongResponse::HEADER_LEN - PingRequest::HEADER_LEN;
+    if flags & FLAG_SERVER_INFO != 0 {
+        limit += ServerInfo::MAX_LEN;
+    }
+    if flags & FLAG_OBSERVED_ADDR != 0 {
+        limit += ObservedAddr::MAX_LEN;
+    }
+    match read_to_end_into(&mut recv, buf, limit).await {
+        Ok(()) => {}
+        Err(ReadToEndError::Read(ReadError::Reset(code)))
+            if code == ERR_PAYLOAD_TOO_LARGE.into() =>
+        {
+            return Err(too_large(None))
+        }
+        Err(source) => return Err(PingError::Read { source }),
+    }
+    tracing::debug!(len = buf.len(), "response_received");
+    let invalid = || PingError::InvalidResponse {
+        response: buf.to_vec(),
+    };
+    match buf.split_first() {
+        Some((&v, [])) if v != version => Ok(Negotiated::Fallback(v)),
+        Some((&v, rest))
+            if v == version && version >= ERROR_VERSION && rest.starts_with(b"EROR") =>
+        {
+            let (seq, nonce) = framed.expect("versions with EROR are framed");
+            let error =
+                ErrorResponse::decode(&rest[4..]).map_err(|source| PingError::Decode { source })?;
+            if error.nonce != nonce {
+                return Err(PingError::NonceMismatch {
+                    expected: nonce,
+                    got: error.nonce,
+                });
+            }
+            if error.seq != seq {
+                return Err(invalid());
+            }
+            match error.reason {
+                RejectReason::PayloadTooLarge => Err(too_large(error.limit)),
+                reason => Err(PingError::RequestRejected {
+                    reason,
+                    retry_after: error.retry_after,
+                }),
+            }
+        }
+        Some((&v, rest)) if v == version => match (rest.strip_prefix(b"PONG"), framed) {
+            (Some(frame), Some((seq, nonce))) => {
+                let max_len = limit;
+                let pong = PongResponse::decode(frame, max_len)
+                    .map_err(|source| PingError::Decode { source })?;
+                if pong.nonce != nonce {
+                    return Err(PingError::NonceMismatch {
+                        expected: nonce,
+                        got: pong.nonce,
+                    });
+                }
+                if pong.seq != seq {
+                    return Err(invalid());
+                }
+                verify_echo(payload, pong.payload)?;
+                Ok(Negotiated::Done(Some(PongInfo {
+                    nonce,
+                    server_processing: Duration::from_micros(pong.processing_us),
+                    server_info: pong.server_info,
+                    observed_addr: pong.observed_addr,
+                    reverse: pong.flags & FLAG_REVERSE_PING != 0,
+                })))
+            }
+            (Some(echoed), None) => {
+                verify_echo(payload, echoed)?;
+                Ok(Negotiated::Done(None))
+            }
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use iroh::{protocol::Router, Endpoint, Watcher};
+/// CRC-32 as used by Ethernet and zip, for [`verify_echo`].
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 
-    use super::*;
+/// Checks that the payload a server echoed is the one we sent, by their checksums.
+///
+/// QUIC already protects streams against corruption, so a mismatch most likely points at
+/// the server, and the checksums in the error show how the echo diverged without dumping
+/// large payloads.
+fn verify_echo(sent: &[u8], echoed: &[u8]) -> Result<(), PingError> {
+    let expected = CRC32.checksum(sent);
+    let got = CRC32.checksum(echoed);
+    if expected != got {
+        return Err(PingError::IntegrityMismatch { expected, got });
+    }
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn test_ping() -> anyhow::Result<()> {
-        let ep = Endpoint::builder().discovery_n0().bind().await?;
-        let router = Router::builder(ep).accept(ALPN, Ping::new()).spawn();
-        let addr = router.endpoint().node_addr().initialized().await?;
+/// Reads the rest of `recv` into `buf`, replacing its contents but keeping its allocation.
+///
+/// Like [`RecvStream::read_to_end`], fails with [`ReadToEndError::TooLong`] once the
+/// stream carries more than `limit` bytes.
+async fn read_to_end_into(
+    recv: &mut RecvStream,
+    buf: &mut BytesMut,
+    limit: usize,
+) -> Result<(), ReadToEndError> {
+    buf.clear();
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        if buf.len() + chunk.bytes.len() > limit {
+            return Err(ReadToEndError::TooLong);
+        }
+        buf.extend_from_slice(&chunk.bytes);
+    }
+    Ok(())
+}
 
-        let client = Endpoint::builder().discovery_n0().bind().await?;
-        let ping_client = Ping::new();
-        let res = ping_client.ping(&client, addr.clone()).await?;
+impl ProtocolHandler for Ping {
+    /// The `accept` method is called for each incoming connection for our ALPN.
+    ///
+    /// The returned future runs on a newly spawned tokio task, so it can run as long as
+    /// the connection lasts.
+    #[tracing::instrument(skip_all, fields(remote = Empty))]
+    async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+        let metrics = self.metrics.clone();
+        let _in_flight = InFlightGuard::new(&metrics.pings_in_flight);
+
+        // We can get the remote's node id from the connection.
+        let node_id = connection.remote_node_id()?;
+        Span::current().record("remote", tracing::field::display(node_id.fmt_short()));
+        if self.log_connections {
+            tracing::info!(%node_id, "accepted");
+        } else {
+            tracing::debug!("accepted");
+        }
+
+        // Datagram pings may arrive at any time, so answer them alongside the streams,
+        // unless turned off.
+        let datagrams = async {
+            if self.datagrams {
+                mtu::echo_datagrams(self, &connection).await;
+            }
+            // Once the connection is gone, the streams decide how the connection ended.
+            std::future::pending().await
+        };
+        let streams = async {
+            // Our protocol is a simple request-response protocol: the connecting peer opens
+            // a stream per request, bi-directional for most. Each stream is answered in a
+            // task of its own, so a slow request doesn't hold up the others, until the
+            // remote closes the connection, which it does once it received its responses.
+            // Up to `max_concurrent_streams` are answered at once, further streams wait.
+            let mut tasks = JoinSet::new();
+            loop {
+                let accepted = tokio::select! {
+                    Some(res) = tasks.join_next() => {
+                        match res.map_err(AcceptError::from_err)? {
+                            Ok(true) => continue,
+                            Ok(false) => break,
+                            // A failed request only takes its own stream down, the others
+                            // on the connection carry on. Should the connection be gone,
+                            // accepting the next stream tells how it ended.
+                            Err(err) => {
+                                tracing::debug!(%err, "stream_failed");
+                                continue;
+                            }
+                        }
+                    }
+                    res = connection.accept_bi(), if tasks.len() < self.max_concurrent_streams => {
+                        res.map(Ok)
+                    }
+                    res = connection.accept_uni(), if tasks.len() < self.max_concurrent_streams => {
+                        res.map(Err)
+                    }
+                };
+                let ping = self.clone();
+                let conn = connection.clone();
+                match accepted {
+                    Ok(Ok((send, recv))) => {
+                        tasks
+                            .spawn(async move { ping.answer_bi(&conn, node_id, send, recv).await });
+                    }
+                    Ok(Err(mut recv))
+                        if self.server_mode == PingServerMode::Echo || is_legacy(&connection) =>
+                    {
+                        recv.stop(ERR_INVALID_REQUEST.into()).ok();
+                    }
+                    Ok(Err(recv)) => {
+                        tasks.spawn(async move {
+                            let (mut buf, mut out) = (BytesMut::new(), BytesMut::new());
+                            uni::handle_uni(&ping, &conn, node_id, recv, &mut buf, &mut out)
+                                .await?;
+                            Ok(true)
+                        });
+                    }
+                    Err(ConnectionError::ApplicationClosed(close)) => {
+                        let code = CloseCode::from_code(close.error_code);
+                        if self.log_connections && code != Some(CloseCode::Ok) {
+                            let code = code.map_or(close.error_code.to_string(), |c| c.to_string());
+                            let reason = String::from_utf8_lossy(&close.reason);
+                            tracing::info!(%code, %reason, "closed");
+                        } else {
+                            tracing::debug!(code = %close.error_code, "closed");
+                        }
+                        break;
+                    }
+                    // we closed it ourselves, in answer to a request
+                    Err(ConnectionError::LocallyClosed) => break,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // requests still running, e.g. heartbeats, end with the connection
+            Ok(())
+        };
+        let res = tokio::select! {
+            res = streams => res,
+            never = datagrams => never,
+        };
+        if res.is_err() {
+            // a no-op if the connection is already gone
+            CloseCode::Internal.close(&connection, b"internal error");
+        }
+        res
+    }
+}
+
+impl Ping {
+    /// Answers the request on a bidirectional stream of `connection` from `node_id`, in a
+    /// task of its own.
+    ///
+    /// Returns whether to keep serving the connection, which is not the case once the
+    /// request was bad enough to close it.
+    async fn answer_bi(
+        &self,
+        connection: &Connection,
+        node_id: NodeId,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<bool, AcceptError> {
+        let metrics = &self.metrics;
+        // the payload of the request, and the framed response
+        let mut buf = BytesMut::new();
+        let mut out = BytesMut::new();
+        if self.server_mode == PingServerMode::Echo {
+            if self.admit().is_err() {
+                reject(&mut send, &mut recv, ERR_RATE_LIMITED);
+                return Ok(true);
+            }
+            echo(send, recv, self.max_payload, &mut buf, metrics).await?;
+            return Ok(true);
+        }
+        if is_legacy(connection) {
+            return legacy_pong(self, send, recv).await.map(|()| true);
+        }
+
+        // Every request starts with the client's protocol version, see
+        // `PROTOCOL_VERSION` for how we negotiate.
+        let mut version = [0u8];
+        if !read_header(connection, &mut recv, &mut version).await? {
+            return Ok(false);
+        }
+        let [version] = version;
+        if version == 0 {
+            CloseCode::UnsupportedVersion.close(connection, b"version 0");
+            return Ok(false);
+        }
+        if version > self.max_version {
+            send.write_all(&[self.max_version])
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish()?;
+            return Ok(true);
+        }
+
+        // Followed by a four byte tag naming the message type.
+        let mut tag = [0u8; 4];
+        if !read_header(connection, &mut recv, &mut tag).await? {
+            return Ok(false);
+        }
+        let request = String::from_utf8_lossy(&tag);
+        tracing::debug!(version, %request, "stream_opened");
+        match &tag {
+            b"PING" if version >= 2 => {
+                if pong(self, connection, send, recv, version, &mut buf, &mut out).await? {
+                    let reverse = reverse::ping_client(connection, version, &mut buf);
+                    // a client that doesn't answer only holds up its own connection
+                    tokio::time::timeout(self.timeout, reverse)
+                        .await
+                        .unwrap_or(Ok(()))?;
+                }
+                return Ok(true);
+            }
+            b"PING" => {}
+            b"STRM" => {
+                let (buf, out) = (&mut buf, &mut out);
+                stream::handle_stream(self, node_id, send, recv, version, buf, out).await?;
+                return Ok(true);
+            }
+            b"BEAT" => {
+                heartbeat::handle_subscribe(self, send, recv, version, &mut buf).await?;
+                return Ok(true);
+            }
+            b"DIAL" => {
+                dial::handle_dial(self, node_id, send, recv, version, &mut buf).await?;
+                return Ok(true);
+            }
+            b"WANT" => {
+                caps::handle_caps(self, send, recv, version, &mut buf).await?;
+                return Ok(true);
+            }
+            b"UPLD" => {
+                throughput::handle_upload(send, recv, version, self.max_transfer).await?;
+                return Ok(true);
+            }
+            b"DNLD" => {
+                throughput::handle_download(send, recv, version, self.max_transfer).await?;
+                return Ok(true);
+            }
+            _ => {
+                reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+                return Ok(true);
+            }
+        }
+
+        match read_to_end_into(&mut recv, &mut buf, self.max_payload).await {
+            Ok(()) => {}
+            Err(ReadToEndError::TooLong) => {
+                // Refuse to buffer any more of the payload, but keep the connection
+                // around for further pings.
+                recv.stop(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+                send.reset(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+                return Ok(true);
+            }
+            Err(err) => {
+                abandon(&mut send, &mut recv, &err);
+                return Ok(true);
+            }
+        }
+        if self.admit().is_err() {
+            reject(&mut send, &mut recv, ERR_RATE_LIMITED);
+            return Ok(true);
+        }
+
+        // send back "PONG" bytes in the negotiated version, followed by the payload we
+        // received
+        out.clear();
+        out.put_u8(version);
+        out.put_slice(b"PONG");
+        out.put_slice(&buf);
+        if let Err(err) = send.write_all(&out).await {
+            abandon(&mut send, &mut recv, &err);
+            return Ok(true);
+        }
+
+        // By calling `finish` on the send stream we signal that we will not send anything
+        // further, which makes the receive stream on the other end terminate.
+        send.finish()?;
+        tracing::debug!("response_sent");
+
+        // increment count of pings we've received
+        metrics.pings_recv.inc();
+        Ok(true)
+    }
+}
+
+/// Answers a ping of the original protocol, see [`ALPN`]: a bare `PING` gets a bare `PONG`.
+async fn legacy_pong(
+    ping: &Ping,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<(), AcceptError> {
+    if ping.admit().is_err() {
+        reject(&mut send, &mut recv, ERR_RATE_LIMITED);
+        return Ok(());
+    }
+    let request = match recv.read_to_end(4).await {
+        Ok(request) => request,
+        Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    };
+    if request != b"PING" {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    }
+    send.write_all(b"PONG")
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.finish()?;
+    tracing::debug!("legacy response_sent");
+    ping.metrics.pings_recv.inc();
+    Ok(())
+}
+
+/// Reads the version byte or tag at the start of a request into `header`.
+///
+/// A stream that finishes before the header is complete breaks the protocol, so the
+/// connection is closed with [`CloseCode::BadRequest`] and `false` returned.
+async fn read_header(
+    connection: &Connection,
+    recv: &mut RecvStream,
+    header: &mut [u8],
+) -> Result<bool, AcceptError> {
+    match recv.read_exact(header).await {
+        Ok(()) => Ok(true),
+        Err(ReadExactError::FinishedEarly(_)) => {
+            CloseCode::BadRequest.close(connection, b"truncated request");
+            Ok(false)
+        }
+        Err(ReadExactError::ReadError(err)) => Err(AcceptError::from_err(err)),
+    }
+}
+
+/// Gives up on a request whose stream failed, e.g. because the client reset it.
+///
+/// Only the stream is reset, the connection keeps serving its other streams.
+fn abandon(send: &mut SendStream, recv: &mut RecvStream, err: &dyn std::error::Error) {
+    tracing::debug!(%err, "stream_failed");
+    reject(send, recv, ERR_INVALID_REQUEST);
+}
+
+/// Refuses a request with the given stream error code, in both directions.
+fn reject(send: &mut SendStream, recv: &mut RecvStream, code: u32) {
+    recv.stop(code.into()).ok();
+    send.reset(code.into()).ok();
+}
+
+/// Answers a framed `PING` in version 2 or later, after its version byte and tag were read.
+///
+/// The frame is rejected from its length prefix alone if the payload exceeds
+/// `max_payload`, before buffering any of it. From version 5 on, that and the rate limit
+/// are answered with an `EROR` response, older versions get the stream reset. `out` holds
+/// the response.
+///
+/// Returns whether the client asked to be pinged back, and the response promised it, see
+/// [`FLAG_REVERSE_PING`].
+async fn pong(
+    ping: &Ping,
+    connection: &Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    buf: &mut BytesMut,
+    out: &mut BytesMut,
+) -> Result<bool, AcceptError> {
+    let mut prefix = [0u8; codec::LEN_PREFIX];
+    match recv.read_exact(&mut prefix).await {
+        Ok(()) => {}
+        // like a truncated header, see `read_header`
+        Err(ReadExactError::FinishedEarly(_)) => {
+            CloseCode::BadRequest.close(connection, b"truncated request");
+            return Ok(false);
+        }
+        Err(ReadExactError::ReadError(err)) => {
+            abandon(&mut send, &mut recv, &err);
+            return Ok(false);
+        }
+    }
+    let len = match codec::frame_len(prefix, PingRequest::HEADER_LEN + ping.max_payload) {
+        Ok(len) => len,
+        Err(_) if version >= ERROR_VERSION => {
+            // the fields in front of the payload are all we need to answer
+            let mut header = [0u8; PingRequest::HEADER_LEN];
+            let request = match recv.read_exact(&mut header).await {
+                Ok(()) => PingRequest::decode_body(&header).expect("the header is complete"),
+                Err(ReadExactError::FinishedEarly(_)) => {
+                    reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+                    return Ok(false);
+                }
+                Err(ReadExactError::ReadError(err)) => {
+                    abandon(&mut send, &mut recv, &err);
+                    return Ok(false);
+                }
+            };
+            recv.stop(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+            let reason = RejectReason::PayloadTooLarge;
+            let limit = Some(ping.max_payload as u64);
+            return refuse(send, version, &request, reason, None, limit, out)
+                .await
+                .map(|()| false);
+        }
+        Err(_) => {
+            reject(&mut send, &mut recv, ERR_PAYLOAD_TOO_LARGE);
+            return Ok(false);
+        }
+    };
+    match read_to_end_into(&mut recv, buf, len).await {
+        Ok(()) if buf.len() == len => {}
+        Ok(()) | Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(false);
+        }
+        Err(err) => {
+            abandon(&mut send, &mut recv, &err);
+            return Ok(false);
+        }
+    }
+    let received = Instant::now();
+    let received_at_us = now_us();
+    let Ok(request) = PingRequest::decode_body(buf) else {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(false);
+    };
+    if let Err(retry_after) = ping.admit() {
+        if version < ERROR_VERSION {
+            reject(&mut send, &mut recv, ERR_RATE_LIMITED);
+            return Ok(false);
+        }
+        let reason = RejectReason::RateLimited;
+        return refuse(
+            send,
+            version,
+            &request,
+            reason,
+            Some(retry_after),
+            None,
+            out,
+        )
+        .await
+        .map(|()| false);
+    }
+
+    out.clear();
+    out.put_u8(version);
+    out.put_slice(b"PONG");
+    let node_id = connection.remote_node_id()?;
+    ping.encode_pong(&request, node_id, received, received_at_us, out);
+    if let Err(err) = send.write_all(out).await {
+        abandon(&mut send, &mut recv, &err);
+        return Ok(false);
+    }
+    send.finish()?;
+    tracing::debug!(seq = request.seq, "response_sent");
+    ping.metrics.pings_recv.inc();
+    Ok(request.flags & FLAG_REVERSE_PING != 0)
+}
+
+/// Answers a framed `PING` in version 5 or later with an `EROR` response, leaving the
+/// connection usable for further requests. `out` holds the response.
+async fn refuse(
+    mut send: SendStream,
+    version: u8,
+    request: &PingRequest<'_>,
+    reason: RejectReason,
+    retry_after: Option<Duration>,
+    limit: Option<u64>,
+    out: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    out.clear();
+    out.put_u8(version);
+    out.put_slice(b"EROR");
+    ErrorResponse {
+        seq: request.seq,
+        reason,
+        retry_after,
+        limit,
+        nonce: request.nonce,
+    }
+    .encode(out);
+    send.write_all(out).await.map_err(AcceptError::from_err)?;
+    send.finish()?;
+    Ok(())
+}
+
+impl Ping {
+    /// Appends the response frame to `request` from `node_id`, which arrived at
+    /// `received`, or `received_at_us` by the clock.
+    fn encode_pong(
+        &self,
+        request: &PingRequest<'_>,
+        node_id: NodeId,
+        received: Instant,
+        received_at_us: u64,
+        out: &mut BytesMut,
+    ) {
+        // only answer the flags we know, and want to
+        let mut known = FLAG_SERVER_INFO | FLAG_REVERSE_PING;
+        if self.share_observed_addr {
+            known |= FLAG_OBSERVED_ADDR;
+        }
+        let flags = request.flags & known;
+        let observed_addr = (flags & FLAG_OBSERVED_ADDR != 0).then(|| self.observed(node_id));
+        let server_info = (flags & FLAG_SERVER_INFO != 0).then(|| ServerInfo {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: self.max_version,
+            uptime: self.started.elapsed(),
+            pings_served: self.metrics.pings_recv.get(),
+        });
+        PongResponse {
+            seq: request.seq,
+            sent_at_us: request.sent_at_us,
+            received_at_us,
+            processing_us: received.elapsed().as_micros() as u64,
+            flags,
+            nonce: request.nonce,
+            server_info,
+            observed_addr,
+            payload: request.payload,
+        }
+        .encode(out);
+    }
+
+    /// How the client `node_id` looks from our endpoint, by the path to it.
+    fn observed(&self, node_id: NodeId) -> ObservedAddr {
+        let path = self
+            .endpoint
+            .as_ref()
+            .and_then(|endpoint| many::current_path(endpoint, node_id));
+        match path {
+            Some(ConnectionType::Direct(addr)) => ObservedAddr {
+                addr: Some(addr),
+                via_relay: false,
+            },
+            Some(ConnectionType::Mixed(addr, _)) => ObservedAddr {
+                addr: Some(addr),
+                via_relay: true,
+            },
+            Some(ConnectionType::Relay(_)) => ObservedAddr {
+                addr: None,
+                via_relay: true,
+            },
+            Some(ConnectionType::None) | None => ObservedAddr::default(),
+        }
+    }
+}
+
+/// Sends back everything received on a stream, for [`PingServerMode::Echo`].
+async fn echo(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    max_payload: usize,
+    buf: &mut BytesMut,
+    metrics: &Metrics,
+) -> Result<(), AcceptError> {
+    match read_to_end_into(&mut recv, buf, max_payload).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            recv.stop(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+            send.reset(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    send.write_all(&buf[..])
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.finish()?;
+    metrics.pings_recv.inc();
+    Ok(())
+}
+
+/// Enum of metrics for the module
+#[derive(Debug, Default, MetricsGroup)]
+#[metrics(name = "ping")]
+pub struct Metrics {
+    /// count of valid ping messages sent
+    pub pings_sent: Counter,
+    /// count of valid ping messages received
+    pub pings_recv: Counter,
+    /// count of pings that failed for any reason, including timeouts
+    pub pings_failed: Counter,
+    /// count of pings that failed because they timed out
+    pub pings_timed_out: Counter,
+    /// count of retry attempts made after a transient ping failure
+    pub ping_retries_total: Counter,
+    /// count of pings that still failed after using up all retries
+    pub ping_max_retries_exhausted: Counter,
+    /// total time spent establishing connections for successful pings, in microseconds
+    pub connect_time_us: Counter,
+    /// total time pings were held back by the rate limit, in microseconds
+    pub rate_limit_wait_us: Counter,
+    /// count of pings in a [`PingPool`] that reused an idle connection
+    pub pool_hits: Counter,
+    /// count of pings in a [`PingPool`] that had to dial a new connection
+    pub pool_misses: Counter,
+    /// number of incoming connections currently being served
+    pub pings_in_flight: Gauge,
+    /// number of pings currently in flight in [`Ping::ping_all`] and [`Ping::ping_fastest`]
+    pub client_pings_in_flight: Gauge,
+}
+
+impl Metrics {
+    /// Encode the metrics in the Prometheus (OpenMetrics) text exposition format.
+    ///
+    /// Metric names carry the `ping_` group prefix, and counters the conventional
+    /// `_total` suffix, e.g. `ping_pings_sent_total`.
+    pub fn encode_prometheus(self: &Arc<Self>) -> String {
+        let mut registry = Registry::default();
+        registry.register(self.clone());
+        registry
+            .encode_openmetrics_to_string()
+            .expect("writing to a string cannot fail")
+    }
+
+    /// Read the current value of every metric.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            pings_sent: self.pings_sent.get(),
+            pings_recv: self.pings_recv.get(),
+            pings_failed: self.pings_failed.get(),
+            pings_timed_out: self.pings_timed_out.get(),
+            ping_retries_total: self.ping_retries_total.get(),
+            ping_max_retries_exhausted: self.ping_max_retries_exhausted.get(),
+            connect_time_us: self.connect_time_us.get(),
+            rate_limit_wait_us: self.rate_limit_wait_us.get(),
+            pool_hits: self.pool_hits.get(),
+            pool_misses: self.pool_misses.get(),
+            pings_in_flight: self.pings_in_flight.get(),
+            client_pings_in_flight: self.client_pings_in_flight.get(),
+        }
+    }
+}
+
+/// Counts something as in flight for as long as the guard lives.
+pub(crate) struct InFlightGuard<'a>(&'a Gauge);
+
+impl<'a> InFlightGuard<'a> {
+    pub(crate) fn new(gauge: &'a Gauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Point-in-time copy of [`Metrics`], see [`Metrics::snapshot`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetricsSnapshot {
+    /// count of valid ping messages sent
+    pub pings_sent: u64,
+    /// count of valid ping messages received
+    pub pings_recv: u64,
+    /// count of pings that failed for any reason, including timeouts
+    pub pings_failed: u64,
+    /// count of pings that failed because they timed out
+    pub pings_timed_out: u64,
+    /// count of retry attempts made after a transient ping failure
+    pub ping_retries_total: u64,
+    /// count of pings that still failed after using up all retries
+    pub ping_max_retries_exhausted: u64,
+    /// total time spent establishing connections for successful pings, in microseconds
+    pub connect_time_us: u64,
+    /// total time pings were held back by the rate limit, in microseconds
+    pub rate_limit_wait_us: u64,
+    /// count of pings in a [`PingPool`] that reused an idle connection
+    pub pool_hits: u64,
+    /// count of pings in a [`PingPool`] that had to dial a new connection
+    pub pool_misses: u64,
+    /// number of incoming connections currently being served
+    pub pings_in_flight: i64,
+    /// number of pings currently in flight in [`Ping::ping_all`] and [`Ping::ping_fastest`]
+    pub client_pings_in_flight: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use iroh::protocol::Router;
+
+    use super::*;
+
+    #[tokio::test]
+    #[allow(deprecated)]
+    async fn test_ping() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping_client = Ping::new();
+        let res = ping_client.ping(&client, addr.clone()).await?;
         println!("ping response: {res:?}");
+        // the round trip time keeps sub-millisecond precision
+        let rtt = res.total_rtt();
+        assert_ne!(rtt.subsec_nanos() % 1_000_000, 0, "{rtt:?}");
+        assert!(res.connect_time > Duration::ZERO && res.ping_time > Duration::ZERO);
+        assert_eq!(
+            res.candidate_addrs,
+            addr.direct_addresses.iter().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(res.candidate_relay, addr.relay_url);
+        assert!(res.relayed || res.remote_addr.is_some(), "{res:?}");
+        assert_eq!(res.local_addr.is_some(), res.remote_addr.is_some());
+        assert_eq!(
+            ping_client.metrics().connect_time_us.get(),
+            res.connect_time.as_micros() as u64
+        );
+        assert!(client.is_closed());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_once() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping_client = Ping::new();
+        for _ in 0..2 {
+            let rtt = ping_client.ping_once(&client, addr.clone()).await?;
+            assert!(rtt > Duration::ZERO);
+        }
+        // only the connections were closed
+        assert!(!client.is_closed());
+        assert_eq!(ping_client.metrics().pings_sent.get(), 2);
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_oneshot() -> anyhow::Result<()> {
+        let (_router, addr, _client) = test_utils::local_pair().await?;
+        let rtt = Ping::new().ping_oneshot(addr).await?;
+        assert!(rtt > Duration::ZERO && rtt < DEFAULT_TIMEOUT, "{rtt:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_ticket() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+        let ticket = NodeTicket::new(addr);
+
+        let client = test_utils::local_endpoint().await?;
+        Ping::new().ping_ticket(&client, &ticket).await?;
+
+        // the endpoint stays open for further pings
+        Ping::new()
+            .ping_ticket_str(&client, &ticket.to_string())
+            .await?;
+        assert!(!client.is_closed());
+
+        let err = Ping::new()
+            .ping_ticket_str(&client, "nodenotaticket")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid node ticket");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_prometheus() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        ping_client.ping_once(&client, addr).await?;
+
+        let encoded = ping_client.metrics().encode_prometheus();
+        assert!(encoded.contains("# TYPE ping_pings_sent counter"));
+        assert!(encoded.contains("ping_pings_sent_total 1\n"));
+        assert!(encoded.contains("ping_pings_recv_total 0\n"));
+        assert!(encoded.contains("# TYPE ping_ping_retries_total counter"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_n() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        let stats = ping_client.ping_n(&client, addr, 3).await?;
+        assert_eq!(stats.received(), 3);
+        assert!(stats.min() <= stats.avg() && stats.avg() <= stats.max());
+        assert_eq!(ping_client.metrics().pings_sent.get(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_keep() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        let (_rtt, conn, stats) = ping_client.ping_keep(&client, addr).await?;
+        assert!(stats.udp_tx.datagrams > 0);
+
+        // the server still answers on a new stream of the same connection
+        assert_eq!(
+            exchange(&conn, b"again", PROTOCOL_VERSION).await?,
+            PROTOCOL_VERSION
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pings_in_flight() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, server.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let (_rtt, conn, _stats) = Ping::new().ping_keep(&client, addr).await?;
+        assert_eq!(server.metrics().pings_in_flight.get(), 1);
+
+        conn.close(0u32.into(), b"bye!");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.metrics().pings_in_flight.get() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_n_cancellable() -> anyhow::Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Observed { closed: tx }).await?;
+        let client = test_utils::local_endpoint().await?;
+
+        // pings half a second apart, so the cancellation lands well before the third
+        let ping = Ping::new().with_max_pings_per_second(2);
+        let cancel = CancellationToken::new();
+        let canceller = tokio::spawn({
+            let (ping, cancel) = (ping.clone(), cancel.clone());
+            async move {
+                while ping.metrics().pings_sent.get() < 2 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                cancel.cancel();
+                Instant::now()
+            }
+        });
+        let stats = ping.ping_n_cancellable(&client, addr, 10, &cancel).await?;
+        let cancelled = canceller.await?.elapsed();
+        assert!(cancelled < Duration::from_millis(250), "{cancelled:?}");
+        assert_eq!(stats.sent(), 2);
+        assert_eq!(stats.received(), 2);
+
+        let closed = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await?
+            .expect("the server saw the close");
+        assert_eq!(close_of(&closed), (Some(CloseCode::Ok), &b"bye!"[..]));
+
+        Ok(())
+    }
+
+    /// Answers every stream with garbage, and reports why the connection closed.
+    #[derive(Debug, Clone)]
+    struct Garbage {
+        closed: tokio::sync::mpsc::UnboundedSender<ConnectionError>,
+    }
+
+    impl ProtocolHandler for Garbage {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let (mut send, _recv) = connection.accept_bi().await?;
+            send.write_all(b"nonsense")
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish()?;
+            self.closed.send(connection.closed().await).ok();
+            Ok(())
+        }
+    }
+
+    /// The code and reason a connection was closed with by the peer.
+    fn close_of(err: &ConnectionError) -> (Option<CloseCode>, &[u8]) {
+        match err {
+            ConnectionError::ApplicationClosed(close) => {
+                (CloseCode::from_code(close.error_code), &close.reason)
+            }
+            _ => (None, b""),
+        }
+    }
+
+    /// Answers pings like a regular server, and reports why the connection closed.
+    #[derive(Debug, Clone)]
+    struct Observed {
+        closed: tokio::sync::mpsc::UnboundedSender<ConnectionError>,
+    }
+
+    impl ProtocolHandler for Observed {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            Ping::new().accept(connection.clone()).await?;
+            self.closed.send(connection.closed().await).ok();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_streams() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        // an open ping stream doesn't hold up other pings on the connection
+        let mut stream = ping.open_stream(&conn).await?;
+        stream.ping().await?;
+        ping.ping_on_conn(&conn).await?;
+        stream.ping().await?;
+        stream.finish().await?;
+        conn.close(0u32.into(), b"bye!");
+
+        // unless it takes up all the streams the server answers at once
+        let server = Ping::new().with_max_concurrent_streams(1);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let mut stream = ping.open_stream(&conn).await?;
+        stream.ping().await?;
+        let mut waiting = tokio::spawn({
+            let (ping, conn) = (ping.clone(), conn.clone());
+            async move { ping.ping_on_conn(&conn).await }
+        });
+        let held_up = tokio::time::timeout(Duration::from_millis(300), &mut waiting).await;
+        assert!(
+            held_up.is_err(),
+            "answered alongside the stream: {held_up:?}"
+        );
+        // and is answered once the stream is done
+        stream.finish().await?;
+        waiting.await??;
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_code() -> anyhow::Result<()> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Observed { closed: tx }).await?;
+        let client = test_utils::local_pair().await?.2;
+
+        Ping::new().ping_once(&client, addr.clone()).await?;
+        let closed = rx.recv().await.expect("the server saw the close");
+        assert_eq!(close_of(&closed), (Some(CloseCode::Ok), &b"bye!"[..]));
+
+        let ping = Ping::new()
+            .with_close_code(42)
+            .with_close_reason("interop run 7");
+        ping.ping_once(&client, addr).await?;
+        let closed = rx.recv().await.expect("the server saw the close");
+        let ConnectionError::ApplicationClosed(close) = closed else {
+            panic!("expected an application close, got {closed:?}");
+        };
+        assert_eq!(close.error_code, 42u32.into());
+        assert_eq!(&close.reason[..], b"interop run 7");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_codes() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new();
+
+        // a request that ends within its header
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(&[PROTOCOL_VERSION, b'P']).await?;
+        send.finish()?;
+        let closed = conn.closed().await;
+        assert_eq!(
+            close_of(&closed),
+            (Some(CloseCode::BadRequest), &b"truncated request"[..])
+        );
+        let err = ping.ping_on_conn(&conn).await.unwrap_err();
+        assert!(
+            matches!(&err, PingError::Rejected { code: CloseCode::BadRequest, reason } if reason == "truncated request"),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "server rejected: bad request (truncated request)"
+        );
+
+        // a version that doesn't exist
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(b"\0PING").await?;
+        send.finish()?;
+        conn.closed().await;
+        let err = ping.ping_on_conn(&conn).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::Rejected {
+                    code: CloseCode::UnsupportedVersion,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        // a ping that ends within its length prefix
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(&[PROTOCOL_VERSION, b'P', b'I', b'N', b'G', 0])
+            .await?;
+        send.finish()?;
+        let closed = conn.closed().await;
+        assert_eq!(
+            close_of(&closed),
+            (Some(CloseCode::BadRequest), &b"truncated request"[..])
+        );
+        let err = ping.ping_on_conn(&conn).await.unwrap_err();
+        assert!(
+            matches!(&err, PingError::Rejected { code: CloseCode::BadRequest, reason } if reason == "truncated request"),
+            "{err:?}"
+        );
+
+        // a stream the client reset only fails that stream
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(&[PROTOCOL_VERSION]).await?;
+        send.reset(0u32.into())?;
+        ping.ping_on_conn(&conn).await?;
+        assert!(conn.close_reason().is_none());
+
+        // and the client closing on a server that answers garbage
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Garbage { closed: tx }).await?;
+        let err = ping.ping_once(&client, addr.clone()).await.unwrap_err();
+        assert!(matches!(err, PingError::InvalidResponse { .. }), "{err:?}");
+        let closed = rx.recv().await.expect("the server saw the close");
+        assert_eq!(
+            close_of(&closed),
+            (Some(CloseCode::BadRequest), &b"invalid response"[..])
+        );
+
+        // pings with a payload, on a connection of the caller, too
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let err = ping.ping_payload(&conn, b"payload").await.unwrap_err();
+        assert!(matches!(err, PingError::InvalidResponse { .. }), "{err:?}");
+        let closed = rx.recv().await.expect("the server saw the close");
+        assert_eq!(
+            close_of(&closed),
+            (Some(CloseCode::BadRequest), &b"invalid response"[..])
+        );
+        client.close().await;
+
+        Ok(())
+    }
+
+    /// Accepts pings but never answers them, and reports when a ping arrived and why the
+    /// connection closed.
+    #[derive(Debug, Clone)]
+    struct Silent {
+        pinged: Arc<tokio::sync::Notify>,
+        closed: tokio::sync::mpsc::UnboundedSender<ConnectionError>,
+    }
+
+    impl ProtocolHandler for Silent {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let _streams = connection.accept_bi().await?;
+            self.pinged.notify_one();
+            self.closed.send(connection.closed().await).ok();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_cancellable() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let cancel = CancellationToken::new();
+        let rtt = Ping::new().ping_cancellable(&client, addr, &cancel).await?;
+        assert!(rtt > Duration::ZERO);
+
+        let pinged = Arc::new(tokio::sync::Notify::new());
+        let (closed, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let silent = Silent {
+            pinged: pinged.clone(),
+            closed,
+        };
+        let (_router, addr) = test_utils::local_router(ALPN_V1, silent).await?;
+        let ping = Ping::new();
+        let pinging = ping.ping_cancellable(&client, addr.clone(), &cancel);
+        let cancelling = async {
+            pinged.notified().await;
+            cancel.cancel();
+        };
+        let (res, ()) = tokio::join!(pinging, cancelling);
+        assert!(matches!(res, Err(PingError::Cancelled)), "{res:?}");
+        // cancelling is no failure
+        assert_eq!(ping.metrics().pings_failed.get(), 0);
+
+        // the server learns why the connection closed
+        match rx.recv().await.expect("connection closed") {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(&close.reason[..], b"cancelled")
+            }
+            reason => panic!("unexpected close: {reason:?}"),
+        }
+
+        // a token cancelled beforehand cancels right away
+        let err = ping
+            .ping_cancellable(&client, addr, &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::Cancelled), "{err:?}");
+
+        Ok(())
+    }
+
+    /// A protocol that echoes a single stream, to serve next to pings.
+    #[derive(Debug, Clone)]
+    struct Echo;
+
+    const ECHO_ALPN: &[u8] = b"iroh/echo/0";
+
+    impl ProtocolHandler for Echo {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            let data = recv
+                .read_to_end(1024)
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.write_all(&data).await.map_err(AcceptError::from_err)?;
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        let server = Ping::new();
+        let router = server
+            .register(Router::builder(ep))
+            .accept(ECHO_ALPN, Echo)
+            .spawn();
+        let addr = test_utils::local_addr(router.endpoint());
+
+        let client = test_utils::local_endpoint().await?;
+        Ping::new().ping_n(&client, addr.clone(), 1).await?;
+        assert_eq!(server.metrics().pings_recv.get(), 1);
+
+        let conn = client.connect(addr, ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"hello").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(1024).await?, b"hello");
+        conn.close(0u32.into(), b"bye!");
+
+        // the router's endpoint can send pings of its own
+        let client_addr = test_utils::local_addr(&client);
+        let _client_router = Router::builder(client.clone())
+            .accept(ALPN_V1, Ping::new())
+            .spawn();
+        Ping::new()
+            .ping_n(router.endpoint(), client_addr, 1)
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo_mode() -> anyhow::Result<()> {
+        let server = Ping::new()
+            .with_server_mode(PingServerMode::Echo)
+            .with_max_payload(16);
+        let (_router, addr) = test_utils::local_router(ALPN_V1, server.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        for payload in [&b"anything"[..], b"", b"PING"] {
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(payload).await?;
+            send.finish()?;
+            assert_eq!(recv.read_to_end(16).await?, payload);
+        }
+        assert_eq!(server.metrics().pings_recv.get(), 3);
+
+        // too large payloads are refused like pings
+        let (mut send, mut recv) = conn.open_bi().await?;
+        // the server may stop the stream before we are done writing
+        send.write_all(&[0; 32]).await.ok();
+        send.finish().ok();
+        assert!(recv.read_to_end(16).await.is_err());
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_on_conn() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr) = test_utils::local_router(ALPN_V1, server.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping_client = Ping::new();
+        ping_client.ping_on_conn(&conn).await?;
+        let stats = ping_client.ping_n_on_conn(&conn, 3).await?;
+        assert_eq!(stats.received(), 3);
+        assert_eq!(ping_client.metrics().pings_sent.get(), 4);
+        assert_eq!(server.metrics().pings_recv.get(), 4);
+        // all over the one connection
+        assert_eq!(server.metrics().pings_in_flight.get(), 1);
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_alpn() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        let server = Ping::new().with_alpn(b"custom/ping/0");
+        let router = server.register(Router::builder(ep)).spawn();
+        let addr = test_utils::local_addr(router.endpoint());
+
+        let client = test_utils::local_endpoint().await?;
+        Ping::new()
+            .with_alpn(b"custom/ping/0")
+            .ping_n(&client, addr.clone(), 1)
+            .await?;
+        assert_eq!(server.metrics().pings_recv.get(), 1);
+
+        // the default ALPN is not served
+        let err = Ping::new().ping_n(&client, addr, 1).await.unwrap_err();
+        assert!(matches!(err, PingError::Connect { .. }), "{err:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pre_connect() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr, client) = test_utils::local_pair_with(server.clone()).await?;
+
+        let ping_client = Ping::new();
+        let conn = ping_client.pre_connect(&client, addr).await?;
+        assert_eq!(conn.alpn().as_deref(), Some(ALPN_V1));
+        assert_eq!(ping_client.metrics().pings_sent.get(), 0);
+        // without a handshake to wait for, every ping is quick
+        for _ in 0..3 {
+            let rtt = ping_client.ping_on_conn(&conn).await?;
+            assert!(rtt < Duration::from_millis(100), "{rtt:?}");
+        }
+        assert_eq!(server.metrics().pings_recv.get(), 3);
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+
+    /// A server of the original protocol under [`ALPN`]: a bare `PING` gets a bare `PONG`.
+    #[derive(Debug, Clone)]
+    struct LegacyServer;
+
+    impl ProtocolHandler for LegacyServer {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                let request = recv.read_to_end(4).await.map_err(AcceptError::from_err)?;
+                assert_eq!(request, b"PING");
+                send.write_all(b"PONG")
+                    .await
+                    .map_err(AcceptError::from_err)?;
+                send.finish()?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v1_client_v1_server() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+
+        let (res, conn) = Ping::new().ping_conn(&client, addr).await?;
+        assert!(!res.legacy);
+        assert_eq!(conn.alpn().as_deref(), Some(ALPN_V1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v1_client_v0_server() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN, LegacyServer).await?;
+        let client = test_utils::local_endpoint().await?;
+
+        let ping = Ping::new();
+        let (res, conn) = ping.ping_conn(&client, addr).await?;
+        assert!(res.legacy);
+        assert!(res.nonce.is_none() && res.server_info.is_none());
+        assert_eq!(conn.alpn().as_deref(), Some(ALPN));
+        // further pings on the connection stick to the original protocol
+        ping.ping_on_conn(&conn).await?;
+        let err = ping.ping_payload(&conn, b"hi").await.unwrap_err();
+        assert!(matches!(err, PingError::PayloadTooLarge { .. }), "{err:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_v0_client_v1_server() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (_router, addr, client) = test_utils::local_pair_with(server.clone()).await?;
+
+        let conn = client.connect(addr, ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"PING").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4).await?, b"PONG");
+        assert_eq!(server.metrics().pings_recv.get(), 1);
+
+        // anything else is refused, without closing the connection
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"HUH?").await?;
+        send.finish()?;
+        let err = recv.read_to_end(4).await.unwrap_err();
+        assert!(
+            matches!(err, ReadToEndError::Read(ReadError::Reset(code)) if code == ERR_INVALID_REQUEST.into()),
+            "{err:?}"
+        );
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(b"PING").await?;
+        send.finish()?;
+        assert_eq!(recv.read_to_end(4).await?, b"PONG");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_negotiation() -> anyhow::Result<()> {
+        // a server that only speaks version 1
+        let (_router, addr) =
+            test_utils::local_router(ALPN_V1, Ping::new().with_max_version(1)).await?;
+
+        // a client that would like to speak version 2 falls back to 1
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        assert_eq!(exchange(&conn, b"hi", 2).await?, 1);
+        assert_eq!(exchange(&conn, b"hi", 1).await?, 1);
+
+        // and pings work as well
+        let ping_client = Ping::new().with_max_version(2);
+        ping_client.ping_n(&client, addr, 2).await?;
+
+        Ok(())
+    }
+
+    /// A broken server that answers pings with the wrong nonce.
+    #[derive(Debug, Clone)]
+    struct WrongNonce;
+
+    impl ProtocolHandler for WrongNonce {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            let request = recv
+                .read_to_end(1024)
+                .await
+                .map_err(AcceptError::from_err)?;
+            let (version, frame) = request.split_at(5);
+            let request = PingRequest::decode(frame, 1024).map_err(AcceptError::from_err)?;
+            let mut response = BytesMut::from(&version[..1]);
+            response.put_slice(b"PONG");
+            PongResponse {
+                seq: request.seq,
+                sent_at_us: request.sent_at_us,
+                received_at_us: now_us(),
+                processing_us: 0,
+                flags: 0,
+                nonce: request.nonce.map(|b| !b),
+                server_info: None,
+                observed_addr: None,
+                payload: request.payload,
+            }
+            .encode(&mut response);
+            send.write_all(&response)
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed_pong() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+        let client = test_utils::local_endpoint().await?;
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr).await?;
+        assert!(res.nonce.is_some());
+        // the server answers right away on loopback
+        let processing = res.server_processing.expect("reported in version 2");
+        assert!(processing < Duration::from_millis(50), "{processing:?}");
+        assert!(res.ping_time >= processing, "{res:?}");
+        assert_eq!(res.network_time(), res.ping_time - processing);
+        conn.close(0u32.into(), b"bye!");
+
+        // a server speaking version 1 echoes no nonce
+        let (_router, addr) =
+            test_utils::local_router(ALPN_V1, Ping::new().with_max_version(1)).await?;
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr).await?;
+        assert_eq!(res.nonce, None);
+        assert_eq!(res.server_processing, None);
+        assert_eq!(res.network_time(), res.ping_time);
+        conn.close(0u32.into(), b"bye!");
+
+        let (_router, addr) = test_utils::local_router(ALPN_V1, WrongNonce).await?;
+        let err = Ping::new().ping_once(&client, addr).await.unwrap_err();
+        match err {
+            PingError::NonceMismatch { expected, got } => {
+                assert_eq!(got, expected.map(|b| !b))
+            }
+            err => panic!("unexpected error: {err:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_payload() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        let server = Ping::new().with_max_payload(1024);
+        let router = Router::builder(ep).accept(ALPN_V1, server.clone()).spawn();
+        let addr = test_utils::local_addr(router.endpoint());
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        for version in 1..=PROTOCOL_VERSION {
+            exchange(&conn, &[1; 1024], version).await?;
+            let err = exchange(&conn, &[1; 1025], version).await.unwrap_err();
+            // servers tell the limit once they answer with errors
+            let limit = (version >= ERROR_VERSION).then_some(1024);
+            assert!(
+                matches!(err, PingError::PayloadTooLarge { size: 1025, limit: l } if l == limit),
+                "{err:?}"
+            );
+        }
+        let err = exchange(&conn, &[1; 1025], PROTOCOL_VERSION)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "payload of 1025 bytes rejected as too large, the limit is 1024 bytes"
+        );
+
+        // a framed request is refused from its length prefix, before its payload arrives
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&[ERROR_VERSION - 1]).await?;
+        send.write_all(b"PING").await?;
+        send.write_all(&u32::MAX.to_be_bytes()).await?;
+        let err = recv.read_to_end(64).await.unwrap_err();
+        assert!(
+            matches!(err, ReadToEndError::Read(ReadError::Reset(code)) if code == ERR_PAYLOAD_TOO_LARGE.into()),
+            "{err:?}"
+        );
+        // from the fields in front of the payload, once there are error responses
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&[ERROR_VERSION]).await?;
+        send.write_all(b"PING").await?;
+        send.write_all(&u32::MAX.to_be_bytes()).await?;
+        send.write_all(&[7; PingRequest::HEADER_LEN]).await?;
+        let response = recv.read_to_end(64).await?;
+        let frame = response
+            .strip_prefix(&[ERROR_VERSION])
+            .and_then(|rest| rest.strip_prefix(b"EROR"))
+            .expect("error response");
+        let error = ErrorResponse::decode(frame)?;
+        assert_eq!(error.reason, RejectReason::PayloadTooLarge);
+        assert_eq!(error.limit, Some(1024));
+        assert_eq!(error.nonce, [7; NONCE_LEN]);
+
+        // and the connection is still good for pings within the limit
+        exchange(&conn, b"hi", PROTOCOL_VERSION).await?;
+        assert_eq!(
+            server.metrics().pings_recv.get(),
+            PROTOCOL_VERSION as u64 + 1
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_info() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr.clone()).await?;
+        assert_eq!(res.server_info, None);
+        conn.close(0u32.into(), b"bye!");
+
+        let ping = Ping::new().with_server_info(true);
+        let mut infos = Vec::new();
+        for _ in 0..2 {
+            let (res, conn, _stats) = ping.ping_keep(&client, addr.clone()).await?;
+            infos.push(res.server_info.expect("asked for"));
+            conn.close(0u32.into(), b"bye!");
+        }
+        assert_eq!(infos[0].crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(infos[0].protocol_version, PROTOCOL_VERSION);
+        assert_eq!(infos[0].pings_served, 1);
+        assert_eq!(infos[1].pings_served, 2);
+        assert!(infos[0].uptime <= infos[1].uptime, "{infos:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_observed_addr() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr.clone()).await?;
+        assert_eq!(res.observed_addr, None);
+        conn.close(0u32.into(), b"bye!");
+
+        // on localhost, the server sees us at the port we are bound to
+        let ping = Ping::new().with_observed_addr(true);
+        let (res, conn, _stats) = ping.ping_keep(&client, addr).await?;
+        let observed = res.observed_addr.expect("asked for");
+        assert!(!observed.via_relay, "{observed:?}");
+        let observed = observed.addr.expect("a direct path");
+        assert!(observed.ip().is_loopback(), "{observed:?}");
+        assert!(
+            client
+                .bound_sockets()
+                .iter()
+                .any(|bound| bound.port() == observed.port()),
+            "{observed:?}"
+        );
+        conn.close(0u32.into(), b"bye!");
+
+        // unless the server keeps it to itself
+        let server = Ping::new().with_share_observed_addr(false);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let (res, conn, _stats) = ping.ping_keep(&client, addr).await?;
+        assert_eq!(res.observed_addr, None);
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let requests: [&[u8]; 2] = [
+            // an unknown tag
+            b"\x02NOPE",
+            // a frame too short for its fields
+            b"\x02PING\x00\x00\x00\x02ab",
+        ];
+        for request in requests {
+            let (mut send, mut recv) = conn.open_bi().await?;
+            send.write_all(request).await?;
+            send.finish()?;
+            let err = recv.read_to_end(64).await.unwrap_err();
+            assert!(
+                matches!(err, ReadToEndError::Read(ReadError::Reset(code)) if code == ERR_INVALID_REQUEST.into()),
+                "{err:?}"
+            );
+        }
+
+        // the connection survives bad requests
+        assert_eq!(
+            exchange(&conn, b"hi", PROTOCOL_VERSION).await?,
+            PROTOCOL_VERSION
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_version_negotiation_newer_server() -> anyhow::Result<()> {
+        // a server speaking version 2 still serves version 1 clients directly
+        let (_router, addr) =
+            test_utils::local_router(ALPN_V1, Ping::new().with_max_version(2)).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        assert_eq!(exchange(&conn, b"hi", 1).await?, 1);
+        assert_eq!(exchange(&conn, b"hi", 2).await?, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_with_retries() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        let policy = RetryPolicy::Fixed {
+            delay: Duration::from_millis(10),
+            max_retries: 3,
+        };
+        let (_rtt, attempts) = ping_client.ping_with_retries(&client, addr, policy).await?;
+        assert_eq!(attempts, 1);
+        assert_eq!(ping_client.metrics().ping_retries_total.get(), 0);
+        assert_eq!(ping_client.metrics().ping_max_retries_exhausted.get(), 0);
+
+        Ok(())
+    }
+
+    /// A ping responder that leaves the first few connections unanswered.
+    #[derive(Debug, Clone)]
+    struct Flaky {
+        ping: Ping,
+        ignore: Arc<AtomicUsize>,
+    }
+
+    impl ProtocolHandler for Flaky {
+        async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+            let ignore = self
+                .ignore
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+            if ignore.is_ok() {
+                // wait for the client to give up
+                connection.closed().await;
+                return Ok(());
+            }
+            self.ping.accept(connection).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_retry() -> anyhow::Result<()> {
+        let flaky = Flaky {
+            ping: Ping::new(),
+            ignore: Arc::new(AtomicUsize::new(0)),
+        };
+        let (_router, addr) = test_utils::local_router(ALPN_V1, flaky.clone()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        // connect once up front, so the first attempt doesn't time out before reaching the
+        // server
+        Ping::new().ping_n(&client, addr.clone(), 1).await?;
+
+        let ping_client = Ping::new().with_timeout(Duration::from_millis(500));
+        flaky.ignore.store(2, Ordering::Relaxed);
+        ping_client
+            .ping_retry(&client, addr.clone(), 3, Duration::from_millis(10))
+            .await?;
+        assert_eq!(ping_client.metrics().ping_retries_total.get(), 2);
+        assert_eq!(ping_client.metrics().pings_failed.get(), 2);
+        assert_eq!(ping_client.metrics().pings_timed_out.get(), 2);
+
+        // not enough attempts to get through
+        flaky.ignore.store(2, Ordering::Relaxed);
+        let err = ping_client
+            .ping_retry(&client, addr, 2, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(PingError::Timeout { .. })),
+            "{err:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_undiscoverable() -> anyhow::Result<()> {
+        // a node id without addresses, which a client without discovery can't find any for
+        let node = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let client = test_utils::local_endpoint().await?;
+        let err = Ping::new()
+            .ping_once(&client, NodeAddr::new(node))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                PingError::NodeUnreachable {
+                    node: n,
+                    reason: UnreachableReason::NotDiscovered,
+                    ..
+                } if *n == node
+            ),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("could not discover"), "{err}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_with_retries_exhausted() -> anyhow::Result<()> {
+        // A node nobody is listening as, with no addressing information: every attempt
+        // fails to connect.
+        let addr = NodeAddr::new(iroh::SecretKey::generate(rand::rngs::OsRng).public());
+
+        let client = test_utils::local_endpoint().await?;
+        let ping_client = Ping::new();
+        let policy = RetryPolicy::Fixed {
+            delay: Duration::from_millis(10),
+            max_retries: 2,
+        };
+        let err = ping_client
+            .ping_with_retries(&client, addr, policy)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::NodeUnreachable {
+                    reason: UnreachableReason::NotDiscovered,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(ping_client.metrics().ping_retries_total.get(), 2);
+        assert_eq!(ping_client.metrics().ping_max_retries_exhausted.get(), 1);
+        assert_eq!(ping_client.metrics().pings_failed.get(), 3);
+        assert_eq!(ping_client.metrics().pings_timed_out.get(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_echo() {
+        let sent = b"some payload";
+        verify_echo(sent, sent).unwrap();
+
+        let mut corrupted = sent.to_vec();
+        corrupted[3] ^= 0x01;
+        let err = verify_echo(sent, &corrupted).unwrap_err();
+        assert!(
+            matches!(err, PingError::IntegrityMismatch { expected, got }
+                if expected == CRC32.checksum(sent) && got != expected),
+            "{err:?}"
+        );
+        let err = verify_echo(sent, &sent[..4]).unwrap_err();
+        assert!(
+            matches!(err, PingError::IntegrityMismatch { .. }),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ping_payload() -> anyhow::Result<()> {
+        let server = Ping::new().with_max_payload(1024 * 1024);
+        let (router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let payload: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let ping = Ping::new();
+        ping.ping_payload(&conn, &payload).await?;
+        assert_eq!(ping.metrics().pings_sent.get(), 1);
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_metrics_snapshot_serde() -> anyhow::Result<()> {
+        let metrics = Metrics::default();
+        metrics.pings_sent.inc_by(3);
+        metrics.pings_recv.inc();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot,
+            MetricsSnapshot {
+                pings_sent: 3,
+                pings_recv: 1,
+                pings_failed: 0,
+                pings_timed_out: 0,
+                ping_retries_total: 0,
+                ping_max_retries_exhausted: 0,
+                connect_time_us: 0,
+                rate_limit_wait_us: 0,
+                pool_hits: 0,
+                pool_misses: 0,
+                pings_in_flight: 0,
+                client_pings_in_flight: 0,
+            }
+        );
+
+        let json = serde_json::to_string(&snapshot)?;
+        let decoded: MetricsSnapshot = serde_json::from_str(&json)?;
+        assert_eq!(decoded, snapshot);
+
+        Ok(())
+    }
+
+    /// Counts the bytes allocated by the current thread, so tests running in parallel don't
+    /// interfere.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCATED.with(|allocated| allocated.set(allocated.get() + layout.size()));
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    #[tokio::test]
+    async fn test_default_payload_limit() -> anyhow::Result<()> {
+        let (router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        // anything but the four bytes of the first protocol version
+        ping.ping_payload(&conn, b"hello").await?;
+        ping.ping_payload(&conn, &[0xa5; DEFAULT_MAX_PAYLOAD])
+            .await?;
+        let err = ping
+            .ping_payload(&conn, &[0xa5; DEFAULT_MAX_PAYLOAD + 1])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::PayloadTooLarge { size, limit: Some(4096) }
+                    if size == DEFAULT_MAX_PAYLOAD + 1
+            ),
+            "{err:?}"
+        );
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_exchange_reuses_buffer() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        let mut buf = BytesMut::new();
+        for size in [0, 1, 100, DEFAULT_MAX_PAYLOAD] {
+            let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            exchange_with(&conn, &payload, PROTOCOL_VERSION, &mut buf).await?;
+        }
+        let err = exchange_with(&conn, &vec![0; DEFAULT_MAX_PAYLOAD + 1], 1, &mut buf)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::PayloadTooLarge { .. }), "{err:?}");
+
+        // The test runtime runs the server on this thread as well, so this counts both
+        // sides, next to whatever the connection itself allocates.
+        let payload = vec![0xa5; DEFAULT_MAX_PAYLOAD];
+        let allocated = || ALLOCATED.with(|allocated| allocated.get());
+        let before = allocated();
+        for _ in 0..10 {
+            exchange_with(&conn, &payload, PROTOCOL_VERSION, &mut BytesMut::new()).await?;
+        }
+        let fresh = allocated() - before;
+        let before = allocated();
+        for _ in 0..10 {
+            exchange_with(&conn, &payload, PROTOCOL_VERSION, &mut buf).await?;
+        }
+        let reused = allocated() - before;
+        // the client allocates at least one buffer of the payload size per fresh ping
+        assert!(
+            reused + 10 * DEFAULT_MAX_PAYLOAD <= fresh,
+            "reused: {reused}, fresh: {fresh}"
+        );
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+
+    // On a multi threaded runtime, the endpoints and the server run on the worker threads,
+    // so the test thread only counts what the pings allocate themselves.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ping_on_conn_with_buf() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        let mut buf = BytesMut::new();
+        ping.ping_on_conn_with_buf(&conn, &mut buf).await?;
+
+        let allocations = || ALLOCATIONS.with(|allocations| allocations.get());
+        let before = allocations();
+        for _ in 0..100 {
+            ping.ping_on_conn(&conn).await?;
+        }
+        let fresh = allocations() - before;
+        let before = allocations();
+        for _ in 0..100 {
+            ping.ping_on_conn_with_buf(&conn, &mut buf).await?;
+        }
+        let reused = allocations() - before;
+        // a fresh buffer has to be allocated for every ping
+        assert!(reused + 100 <= fresh, "reused: {reused}, fresh: {fresh}");
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
 
         Ok(())
     }
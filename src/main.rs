@@ -1,10 +1,27 @@
-use std::str::FromStr;
+use std::{
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
 use iroh::Watcher;
-use iroh::{protocol::Router, Endpoint, NodeAddr};
+use iroh::{endpoint::ConnectionType, protocol::Router, Endpoint, NodeAddr, NodeId};
 use iroh_base::ticket::NodeTicket;
-use iroh_ping::{Ping, ALPN as PingALPN};
+use iroh_ping::{
+    bind_endpoint, no_discovery_builder, IpFamily, Ping, PingError, PingResponse, PingSession,
+    PingStats, PongResponse,
+};
+use owo_colors::{AnsiColors, OwoColorize, Style};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::Level;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
 
 /// Return whether our process is a client.
 ///
@@ -33,47 +50,1910 @@ fn is_client() -> Result<bool> {
     }
 }
 
-/// Gets the first ticket string from the command line arguments.
-fn ticket() -> Result<String> {
+/// Gets the ticket strings from the command line arguments, in order, one per `--ticket`.
+fn tickets(args: impl IntoIterator<Item = String>) -> Result<Vec<String>> {
+    let tickets: Vec<_> = args
+        .into_iter()
+        .filter_map(|arg| match arg.split_once("=") {
+            Some(("--ticket", t)) => Some(t.to_string()),
+            _ => None,
+        })
+        .collect();
+    if tickets.is_empty() {
+        return Err(Error::msg(
+            "No ticket provided. Clients must provide a ticket to find a server.",
+        ));
+    }
+    Ok(tickets)
+}
+
+/// How to ping several targets, from the `--parallel` and `--parallel-concurrency` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Schedule {
+    /// one target after the other, the default
+    Sequential,
+    /// all targets at once, at most `concurrency` of them at a time if limited
+    Parallel { concurrency: Option<usize> },
+}
+
+impl Schedule {
+    /// `--parallel-concurrency` implies `--parallel`.
+    fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut schedule = Self::Sequential;
+        for arg in args {
+            if arg == "--parallel" {
+                if schedule == Self::Sequential {
+                    schedule = Self::Parallel { concurrency: None };
+                }
+            } else if let Some(("--parallel-concurrency", n)) = arg.split_once("=") {
+                let concurrency = n
+                    .parse()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .with_context(|| format!("invalid --parallel-concurrency {n:?}"))?;
+                schedule = Self::Parallel {
+                    concurrency: Some(concurrency),
+                };
+            }
+        }
+        Ok(schedule)
+    }
+}
+
+/// Gets the number of pings to send from the `--count` command line argument.
+///
+/// Defaults to a single ping.
+fn count() -> Result<usize> {
     for arg in std::env::args() {
-        if let Some(("--ticket", t)) = arg.split_once("=") {
-            return Ok(t.to_string());
+        if let Some(("--count", n)) = arg.split_once("=") {
+            return Ok(n.parse()?);
         }
     }
 
-    Err(Error::msg(
-        "No ticket provided. Clients must provide a ticket to find a server.",
+    Ok(1)
+}
+
+/// Gets the local address to bind to from the `--bind` command line argument, if any.
+fn bind_addr() -> Result<Option<SocketAddr>> {
+    for arg in std::env::args() {
+        if let Some(("--bind", addr)) = arg.split_once("=") {
+            let addr = addr
+                .parse()
+                .with_context(|| format!("invalid --bind address {addr:?}"))?;
+            return Ok(Some(addr));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Gets the ALPN to speak instead of the default from the `--alpn` command line argument,
+/// if any.
+fn alpn(args: impl IntoIterator<Item = String>) -> Result<Option<String>> {
+    for arg in args {
+        if let Some(("--alpn", alpn)) = arg.split_once("=") {
+            if alpn.is_empty() {
+                return Err(Error::msg("The --alpn must not be empty."));
+            }
+            return Ok(Some(alpn.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Which IP family to ping over, from the `--ipv4`, `--ipv6`, `--prefer-ipv4` and
+/// `--prefer-ipv6` flags, which exclude each other.
+fn ip_family(args: impl IntoIterator<Item = String>) -> Result<IpFamily> {
+    let mut family = None;
+    for arg in args {
+        let flag = match arg.as_str() {
+            "--ipv4" => IpFamily::Ipv4Only,
+            "--ipv6" => IpFamily::Ipv6Only,
+            "--prefer-ipv4" => IpFamily::PreferIpv4,
+            "--prefer-ipv6" => IpFamily::PreferIpv6,
+            _ => continue,
+        };
+        if family.is_some_and(|family| family != flag) {
+            return Err(Error::msg(
+                "Only one of --ipv4, --ipv6, --prefer-ipv4 and --prefer-ipv6 may be given.",
+            ));
+        }
+        family = Some(flag);
+    }
+
+    Ok(family.unwrap_or_default())
+}
+
+/// Whether to go without discovery and relays, from the `--no-discovery` flag, so nodes
+/// are only reached at the direct addresses in their tickets.
+fn is_no_discovery() -> bool {
+    std::env::args().any(|arg| arg == "--no-discovery")
+}
+
+/// Keeps only the direct addresses of `addr`, for `--no-discovery`, failing if it has none.
+fn direct_only(addr: NodeAddr) -> Result<NodeAddr> {
+    if addr.direct_addresses.is_empty() {
+        return Err(Error::msg(format!(
+            "The ticket of {} has no direct addresses to reach it at without discovery.",
+            addr.node_id.fmt_short()
+        )));
+    }
+    Ok(NodeAddr::from_parts(
+        addr.node_id,
+        None,
+        addr.direct_addresses,
     ))
 }
 
+/// Create the endpoint, bound to the `--bind` address if one was given, which must be in
+/// the IP family pings are restricted to. With `no_discovery` it uses neither n0 discovery
+/// nor relays.
+async fn endpoint(family: IpFamily, no_discovery: bool) -> Result<Endpoint> {
+    let builder = if no_discovery {
+        no_discovery_builder()
+    } else {
+        Endpoint::builder().discovery_n0()
+    };
+    let endpoint = match bind_addr()? {
+        Some(addr) if !family.allows(addr) => {
+            return Err(Error::msg(format!(
+                "The --bind address {addr} is not in {family:?}."
+            )))
+        }
+        Some(addr) => bind_endpoint(builder, addr).await?,
+        None => builder.bind().await?,
+    };
+    Ok(endpoint)
+}
+
+/// How long to flood the server with pings, from the `--flood` flag, optionally with the
+/// number of seconds as in `--flood=SECS`.
+///
+/// Defaults to 10 seconds when just `--flood` is given.
+fn flood(args: impl IntoIterator<Item = String>) -> Result<Option<Duration>> {
+    for arg in args {
+        if arg == "--flood" {
+            return Ok(Some(Duration::from_secs(10)));
+        }
+        if let Some(("--flood", secs)) = arg.split_once("=") {
+            let secs: u64 = secs
+                .parse()
+                .with_context(|| format!("invalid --flood duration {secs:?}"))?;
+            return Ok(Some(Duration::from_secs(secs)));
+        }
+    }
+    Ok(None)
+}
+
+/// Number of pings `--flood` keeps in flight.
+const FLOOD_CONCURRENCY: usize = 32;
+
+/// Flood the server with pings for `duration`, updating a counter in place while it runs.
+///
+/// Returns the round trip times of the answered pings.
+async fn ping_flood(
+    ping: &Ping,
+    endpoint: &Endpoint,
+    addr: NodeAddr,
+    duration: Duration,
+) -> Result<PingStats> {
+    let flood = ping.ping_flood(endpoint, addr, duration, FLOOD_CONCURRENCY);
+    tokio::pin!(flood);
+    let start = std::time::Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    let mut out = std::io::stdout();
+    let stats = loop {
+        tokio::select! {
+            res = &mut flood => break res?,
+            _ = ticker.tick() => {
+                let metrics = ping.metrics();
+                let sent = metrics.pings_sent.get();
+                let pps = sent as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+                write!(
+                    out,
+                    "\r{sent} pings, {} failed, {pps:.0} pings/s",
+                    metrics.pings_failed.get()
+                )?;
+                out.flush()?;
+            }
+        }
+    };
+    println!(
+        "\r{} pings, {} failed, {:.0} pings/s",
+        stats.successful, stats.failed, stats.pings_per_second
+    );
+    println!(
+        "{} bytes in {:?}, {:.0} bytes/s",
+        stats.total_bytes, stats.elapsed, stats.bytes_per_second
+    );
+    println!(
+        "one ping at a time would reach {:.0} pings/s, so {:.1} of {FLOOD_CONCURRENCY} pings were in flight on average",
+        stats.sequential_max(),
+        stats.effective_concurrency()
+    );
+    Ok(stats.rtt_stats)
+}
+
+/// The file to write a row per ping to, from the `--csv-output=FILE` or
+/// `--csv-output FILE` command line argument, if any.
+fn csv_output(args: impl IntoIterator<Item = String>) -> Result<Option<PathBuf>> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let path = match arg.split_once("=") {
+            Some(("--csv-output", path)) => path.to_string(),
+            _ if arg == "--csv-output" => args
+                .next()
+                .context("--csv-output needs the file to write to")?,
+            _ => continue,
+        };
+        if path.is_empty() {
+            return Err(Error::msg("The --csv-output file must not be empty."));
+        }
+        return Ok(Some(path.into()));
+    }
+    Ok(None)
+}
+
+/// Columns of the `--csv-output` file.
+const CSV_HEADER: [&str; 10] = [
+    "timestamp_rfc3339",
+    "seq",
+    "peer_node_id",
+    "rtt_us",
+    "connect_time_us",
+    "ping_time_us",
+    "payload_size",
+    "connection_type",
+    "status",
+    "error",
+];
+
+/// Opens the `--csv-output` file for appending rows, creating it with the header row if
+/// it doesn't exist yet.
+fn open_csv(path: &Path) -> Result<csv::Writer<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open --csv-output file {}", path.display()))?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut out = csv::Writer::from_writer(file);
+    if is_new {
+        out.write_record(CSV_HEADER)?;
+        out.flush()?;
+    }
+    Ok(out)
+}
+
+/// Colors for the round trip times of the pings.
+///
+/// They are only used if stdout supports them, see [`RttColors::for_stdout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RttColors {
+    /// round trip times below this are green
+    low: Duration,
+    /// round trip times above this are red, those in between yellow
+    high: Duration,
+}
+
+impl RttColors {
+    /// The colors to use, from the `--color-thresholds` command line argument, or `None`
+    /// if colors are turned off with `--no-color`.
+    fn from_args(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let mut colors = Self {
+            low: Duration::from_millis(10),
+            high: Duration::from_millis(100),
+        };
+        let mut enabled = true;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let thresholds = match arg.split_once("=") {
+                Some(("--color-thresholds", thresholds)) => thresholds.to_string(),
+                _ if arg == "--color-thresholds" => args
+                    .next()
+                    .context("--color-thresholds needs low=MS,high=MS")?,
+                _ if arg == "--no-color" => {
+                    enabled = false;
+                    continue;
+                }
+                _ => continue,
+            };
+            for threshold in thresholds.split(',') {
+                let invalid = || format!("invalid --color-thresholds {thresholds:?}");
+                let (name, ms) = threshold.split_once('=').with_context(invalid)?;
+                let ms = Duration::from_millis(ms.parse().with_context(invalid)?);
+                match name {
+                    "low" => colors.low = ms,
+                    "high" => colors.high = ms,
+                    _ => return Err(Error::msg(invalid())),
+                }
+            }
+            if colors.low > colors.high {
+                return Err(Error::msg(
+                    "The low --color-thresholds must not be above the high one.",
+                ));
+            }
+        }
+        Ok(enabled.then_some(colors))
+    }
+
+    /// Like [`RttColors::from_args`], but also `None` if stdout doesn't support colors,
+    /// which honours `NO_COLOR` as well, see <https://no-color.org>.
+    fn for_stdout(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let supported = supports_color::on_cached(supports_color::Stream::Stdout).is_some();
+        Ok(Self::from_args(args)?.filter(|_| supported))
+    }
+
+    /// Wraps `text` in the color for `rtt`.
+    fn rtt(&self, rtt: Duration, text: &str) -> String {
+        let color = if rtt < self.low {
+            AnsiColors::Green
+        } else if rtt <= self.high {
+            AnsiColors::Yellow
+        } else {
+            AnsiColors::Red
+        };
+        text.color(color).to_string()
+    }
+
+    /// Wraps `text` in the color for failed and lost pings.
+    fn lost(&self, text: &str) -> String {
+        text.style(Style::new().red().bold()).to_string()
+    }
+}
+
+/// How to format the timestamps in front of the lines, for `--timestamp`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TimestampFormat {
+    /// seconds since the unix epoch, with microseconds, like `ping -D`
+    #[default]
+    Unix,
+    /// an RFC 3339 timestamp in UTC, with microseconds
+    Rfc3339,
+    /// seconds since the first ping, with microseconds
+    Relative,
+    /// milliseconds since the first ping
+    Millis,
+}
+
+impl TimestampFormat {
+    /// The format to prefix the lines with, from the `--timestamp` flag and the
+    /// `--timestamp-format=FORMAT` or `--timestamp-format FORMAT` argument, which implies
+    /// it, or `None` without either.
+    fn from_args(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let mut format = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.split_once("=") {
+                Some(("--timestamp-format", name)) => name.to_string(),
+                _ if arg == "--timestamp-format" => args
+                    .next()
+                    .context("--timestamp-format needs unix, rfc3339, relative or millis")?,
+                _ if arg == "--timestamp" => {
+                    format = format.or(Some(Self::Unix));
+                    continue;
+                }
+                _ => continue,
+            };
+            format = Some(match name.as_str() {
+                "unix" => Self::Unix,
+                "rfc3339" => Self::Rfc3339,
+                "relative" => Self::Relative,
+                "millis" => Self::Millis,
+                _ => {
+                    return Err(Error::msg(format!(
+                        "invalid --timestamp-format {name:?}, expected unix, rfc3339, relative \
+                        or millis"
+                    )))
+                }
+            });
+        }
+        Ok(format)
+    }
+}
+
+/// Timestamps for the lines of a run, in the brackets `ping -D` uses.
+#[derive(Debug)]
+struct Timestamps {
+    format: TimestampFormat,
+    /// when the first line was stamped, which relative timestamps count from
+    first: Option<SystemTime>,
+}
+
+impl Timestamps {
+    fn new(format: TimestampFormat) -> Self {
+        Self {
+            format,
+            first: None,
+        }
+    }
+
+    /// The prefix for a line at `now`, including the space before the line.
+    fn prefix(&mut self, now: SystemTime) -> String {
+        let first = *self.first.get_or_insert(now);
+        let since_first = now.duration_since(first).unwrap_or_default();
+        let stamp = match self.format {
+            TimestampFormat::Unix => {
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                format!(
+                    "{}.{:06}",
+                    since_epoch.as_secs(),
+                    since_epoch.subsec_micros()
+                )
+            }
+            TimestampFormat::Rfc3339 => humantime::format_rfc3339_micros(now).to_string(),
+            TimestampFormat::Relative => format!("{:.6}", since_first.as_secs_f64()),
+            TimestampFormat::Millis => since_first.as_millis().to_string(),
+        };
+        format!("[{stamp}] ")
+    }
+}
+
+/// Whether to keep pinging until interrupted, from the `--continuous` flag.
+fn is_continuous() -> bool {
+    std::env::args().any(|arg| arg == "--continuous")
+}
+
+/// Whether to keep pinging while redrawing a status block in place, from the `--watch`
+/// flag.
+fn is_watch() -> bool {
+    std::env::args().any(|arg| arg == "--watch")
+}
+
+/// How much to print, from the `--quiet`, `--verbose` (or `-v`) and `-vv` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    /// only the summary, and on the server nothing per connection
+    Quiet,
+    /// a line per ping
+    Normal,
+    /// a line per ping with the path kind, the timing breakdown and the smoothed round trip
+    /// time
+    Verbose,
+    /// like [`Verbosity::Verbose`], plus the full node id, the path and the relay
+    VeryVerbose,
+}
+
+impl Verbosity {
+    fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut quiet = false;
+        let mut verbose = Self::Normal;
+        for arg in args {
+            match arg.as_str() {
+                "--quiet" => quiet = true,
+                "--verbose" | "-v" => verbose = verbose.max(Self::Verbose),
+                "-vv" => verbose = Self::VeryVerbose,
+                _ => {}
+            }
+        }
+        match (quiet, verbose) {
+            (true, Self::Normal) => Ok(Self::Quiet),
+            (true, _) => Err(Error::msg("--quiet and --verbose exclude each other.")),
+            (false, verbose) => Ok(verbose),
+        }
+    }
+}
+
+/// Size of the answer to a ping without payload: the version byte, the `PONG` tag, the
+/// length prefix and the frame header.
+const PONG_LEN: usize = 1 + 4 + 4 + PongResponse::HEADER_LEN;
+
+/// Whether a ping went directly or through the relay, or both.
+fn path_kind(path: Option<&ConnectionType>) -> &'static str {
+    match path {
+        Some(ConnectionType::Direct(_)) => "direct",
+        Some(ConnectionType::Relay(_)) => "relay",
+        Some(ConnectionType::Mixed(..)) => "mixed",
+        Some(ConnectionType::None) | None => "no path",
+    }
+}
+
+/// Print the outcome of a single ping in continuous mode.
+fn print_ping(
+    out: &mut impl Write,
+    verbosity: Verbosity,
+    res: &Result<PingResponse, PingError>,
+    session: &PingSession,
+    path: Option<ConnectionType>,
+) -> std::io::Result<()> {
+    match (verbosity, res) {
+        (Verbosity::Quiet, _) => Ok(()),
+        (Verbosity::Normal, Ok(res)) => writeln!(out, "{res}"),
+        (Verbosity::Verbose | Verbosity::VeryVerbose, Ok(res)) => {
+            let node_id = res.node_id.to_string();
+            let kind = path_kind(path.as_ref());
+            let smoothed = session.smoothed_rtt();
+            write!(
+                out,
+                "{PONG_LEN} bytes from {} ({kind}): seq={} connect={}µs ping={:.1}µs rtt={:.1}ms \
+                srtt={:?} rttvar={:?}",
+                &node_id[..16],
+                res.seq,
+                res.connect_time.as_micros(),
+                res.ping_time.as_secs_f64() * 1e6,
+                res.rtt.as_secs_f64() * 1e3,
+                smoothed.srtt().unwrap_or_default(),
+                smoothed.rttvar()
+            )?;
+            if verbosity == Verbosity::VeryVerbose {
+                write!(out, " node={node_id}")?;
+                if let Some(path) = &path {
+                    write!(out, " path={path}")?;
+                }
+                if let Some(ConnectionType::Relay(relay) | ConnectionType::Mixed(_, relay)) = &path
+                {
+                    write!(out, " relay={relay}")?;
+                }
+            }
+            writeln!(out)
+        }
+        (_, Err(err)) => writeln!(out, "seq={} failed: {}", session.last_seq(), err),
+    }
+}
+
+/// Shows the pings of a continuous run as they come in.
+trait PingDisplay {
+    fn show(
+        &mut self,
+        res: &Result<PingResponse, PingError>,
+        session: &PingSession,
+        path: Option<ConnectionType>,
+    ) -> std::io::Result<()>;
+}
+
+/// Appends a line per ping, see [`print_ping`], colored by its round trip time if there
+/// are colors, and after a timestamp and a label if there are those.
+struct PingLines<W> {
+    out: W,
+    verbosity: Verbosity,
+    colors: Option<RttColors>,
+    timestamps: Option<Timestamps>,
+    /// tells the lines of targets pinged in parallel apart
+    label: Option<String>,
+}
+
+impl<W: Write> PingDisplay for PingLines<W> {
+    fn show(
+        &mut self,
+        res: &Result<PingResponse, PingError>,
+        session: &PingSession,
+        path: Option<ConnectionType>,
+    ) -> std::io::Result<()> {
+        if self.colors.is_none() && self.timestamps.is_none() && self.label.is_none() {
+            return print_ping(&mut self.out, self.verbosity, res, session, path);
+        }
+        // stamp the line before formatting it, so it tells when the ping completed
+        let now = SystemTime::now();
+        let mut line = Vec::new();
+        print_ping(&mut line, self.verbosity, res, session, path)?;
+        let line = String::from_utf8_lossy(&line);
+        let Some(line) = line.strip_suffix('\n') else {
+            return Ok(());
+        };
+        let line = match (&self.colors, res) {
+            (Some(colors), Ok(res)) => colors.rtt(res.rtt, line),
+            (Some(colors), Err(_)) => colors.lost(line),
+            (None, _) => line.to_string(),
+        };
+        let mut prefix = match &mut self.timestamps {
+            Some(timestamps) => timestamps.prefix(now),
+            None => String::new(),
+        };
+        if let Some(label) = &self.label {
+            prefix.push_str(&format!("{label}: "));
+        }
+        writeln!(self.out, "{prefix}{line}")
+    }
+}
+
+impl<D: PingDisplay + ?Sized> PingDisplay for Box<D> {
+    fn show(
+        &mut self,
+        res: &Result<PingResponse, PingError>,
+        session: &PingSession,
+        path: Option<ConnectionType>,
+    ) -> std::io::Result<()> {
+        (**self).show(res, session, path)
+    }
+}
+
+/// Shows the pings on `display`, and appends a row for each to a CSV file, for
+/// `--csv-output`.
+struct CsvRows<D, W: Write> {
+    display: D,
+    out: csv::Writer<W>,
+    /// the node being pinged, for the rows of failed pings
+    node_id: NodeId,
+}
+
+impl<D: PingDisplay, W: Write> PingDisplay for CsvRows<D, W> {
+    fn show(
+        &mut self,
+        res: &Result<PingResponse, PingError>,
+        session: &PingSession,
+        path: Option<ConnectionType>,
+    ) -> std::io::Result<()> {
+        let timestamp = humantime::format_rfc3339_micros(SystemTime::now()).to_string();
+        let kind = path_kind(path.as_ref());
+        // the pings of the CLI carry no payload
+        match res {
+            Ok(res) => self.out.write_record([
+                timestamp,
+                res.seq.to_string(),
+                res.node_id.to_string(),
+                res.rtt.as_micros().to_string(),
+                res.connect_time.as_micros().to_string(),
+                res.ping_time.as_micros().to_string(),
+                "0".to_string(),
+                kind.to_string(),
+                "ok".to_string(),
+                String::new(),
+            ])?,
+            Err(err) => self.out.write_record([
+                timestamp,
+                session.last_seq().to_string(),
+                self.node_id.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                "0".to_string(),
+                kind.to_string(),
+                "failed".to_string(),
+                err.to_string(),
+            ])?,
+        }
+        // flush every row, so an interrupted run leaves no partial rows
+        self.out.flush()?;
+        self.display.show(res, session, path)
+    }
+}
+
+/// Redraws a compact status block after every ping, for `--watch` on a terminal.
+struct StatusBlock<W> {
+    out: W,
+}
+
+impl<W: Write> PingDisplay for StatusBlock<W> {
+    fn show(
+        &mut self,
+        res: &Result<PingResponse, PingError>,
+        session: &PingSession,
+        path: Option<ConnectionType>,
+    ) -> std::io::Result<()> {
+        // move the cursor home and clear the screen, then draw the block
+        write!(
+            self.out,
+            "\x1b[H\x1b[2J{}",
+            status_block(res, session, path)
+        )?;
+        self.out.flush()
+    }
+}
+
+/// The status block drawn by [`StatusBlock`].
+fn status_block(
+    res: &Result<PingResponse, PingError>,
+    session: &PingSession,
+    path: Option<ConnectionType>,
+) -> String {
+    let stats = session.snapshot();
+    let current = match res {
+        Ok(res) => format!("{res}"),
+        Err(err) => format!("seq={} failed: {err}", session.last_seq()),
+    };
+    let path = path.map(|path| path.to_string()).unwrap_or_default();
+    format!(
+        "{current}\n\
+        rtt min/avg/max = {:?}/{:?}/{:?}\n\
+        {} sent, {} received, {:.1}% loss\n\
+        path: {path}\n",
+        stats.min(),
+        stats.avg(),
+        stats.max(),
+        stats.sent(),
+        stats.received(),
+        stats.loss_pct(),
+    )
+}
+
+/// Ping every `interval` and show each ping on `display`, until `stop` completes or
+/// `limit` pings were sent.
+async fn ping_continuously(
+    pinger: &Ping,
+    endpoint: &Endpoint,
+    addr: NodeAddr,
+    interval: Duration,
+    limit: Option<usize>,
+    display: &mut impl PingDisplay,
+    stop: impl std::future::Future<Output = ()>,
+) -> Result<PingSession> {
+    let mut session = PingSession::new();
+    tokio::pin!(stop);
+    loop {
+        let res = tokio::select! {
+            _ = &mut stop => break,
+            res = session.ping(pinger, endpoint, addr.clone()) => res,
+        };
+        let path = Ping::latest_rtt(endpoint, addr.node_id).map(|(_, path)| path);
+        display.show(&res, &session, path)?;
+        if limit.is_some_and(|limit| session.sent() >= limit as u64) {
+            break;
+        }
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+    Ok(session)
+}
+
+/// Print the summary of a ping run, in the spirit of `ping`, with any loss highlighted if
+/// there are colors.
+fn print_summary(stats: &PingStats, colors: Option<&RttColors>) {
+    println!("--- ping statistics ---");
+    let loss = format!(
+        "{} pings transmitted, {} received, {:.1}% loss",
+        stats.sent(),
+        stats.received(),
+        stats.loss_pct()
+    );
+    match colors {
+        Some(colors) if stats.lost() > 0 => println!("{}", colors.lost(&loss)),
+        _ => println!("{loss}"),
+    }
+    println!(
+        "rtt min/avg/max/mdev = {:?}/{:?}/{:?}/{:?}, jitter = {:?}",
+        stats.min(),
+        stats.avg(),
+        stats.max(),
+        stats.mdev(),
+        stats.jitter()
+    );
+}
+
+/// How to ping several targets and show their pings, see [`ping_targets`].
+#[derive(Debug, Clone, Copy)]
+struct TargetOpts {
+    interval: Duration,
+    limit: Option<usize>,
+    verbosity: Verbosity,
+    colors: Option<RttColors>,
+    timestamps: Option<TimestampFormat>,
+}
+
+impl TargetOpts {
+    fn display(&self, label: Option<String>) -> PingLines<std::io::Stdout> {
+        PingLines {
+            out: std::io::stdout(),
+            verbosity: self.verbosity,
+            colors: self.colors,
+            timestamps: self.timestamps.map(Timestamps::new),
+            label,
+        }
+    }
+}
+
+/// Ping each of `addrs` like [`ping_continuously`] does, one after the other with their
+/// lines and summary grouped, or all at once with their lines interleaved and labelled
+/// by node, until `stop` is cancelled. Returns the statistics of every target, in order.
+async fn ping_targets(
+    pinger: &Ping,
+    endpoint: &Endpoint,
+    addrs: Vec<NodeAddr>,
+    schedule: Schedule,
+    opts: TargetOpts,
+    stop: CancellationToken,
+) -> Result<Vec<(NodeId, PingStats)>> {
+    let concurrency = match schedule {
+        Schedule::Sequential => {
+            let mut results = Vec::with_capacity(addrs.len());
+            for addr in addrs {
+                let node_id = addr.node_id;
+                println!("PING {}", node_id.fmt_short());
+                let mut display = opts.display(None);
+                let session = ping_continuously(
+                    pinger,
+                    endpoint,
+                    addr,
+                    opts.interval,
+                    opts.limit,
+                    &mut display,
+                    stop.clone().cancelled_owned(),
+                )
+                .await?;
+                print_summary(&session.snapshot(), opts.colors.as_ref());
+                results.push((node_id, session.snapshot()));
+                if stop.is_cancelled() {
+                    break;
+                }
+            }
+            return Ok(results);
+        }
+        Schedule::Parallel { concurrency } => concurrency.unwrap_or(addrs.len()),
+    };
+
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+    for (i, addr) in addrs.into_iter().enumerate() {
+        let (pinger, endpoint) = (pinger.clone(), endpoint.clone());
+        let (permits, stop) = (permits.clone(), stop.clone());
+        tasks.spawn(async move {
+            let node_id = addr.node_id;
+            let Ok(_permit) = permits.acquire_owned().await else {
+                unreachable!("the semaphore is never closed");
+            };
+            let mut display = opts.display(Some(node_id.fmt_short()));
+            let session = ping_continuously(
+                &pinger,
+                &endpoint,
+                addr,
+                opts.interval,
+                opts.limit,
+                &mut display,
+                stop.cancelled_owned(),
+            )
+            .await?;
+            anyhow::Ok((i, node_id, session.snapshot()))
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        results.push(res??);
+    }
+    results.sort_by_key(|(i, ..)| *i);
+    for (_, node_id, stats) in &results {
+        println!("--- {} ---", node_id.fmt_short());
+        print_summary(stats, opts.colors.as_ref());
+    }
+    Ok(results
+        .into_iter()
+        .map(|(_, node_id, stats)| (node_id, stats))
+        .collect())
+}
+
+/// A table comparing the targets of a run, marking the one with the lowest average round
+/// trip time among those that answered at all.
+fn comparison_table(results: &[(NodeId, PingStats)]) -> String {
+    let fastest = results
+        .iter()
+        .filter(|(_, stats)| stats.received() > 0)
+        .min_by_key(|(_, stats)| stats.avg())
+        .map(|(node_id, _)| *node_id);
+    let mut table = format!(
+        "--- comparison ---\n{:<10}  {:>5}  {:>5}  {:>6}  {:>12}\n",
+        "target", "sent", "recv", "loss", "avg"
+    );
+    for (node_id, stats) in results {
+        let avg = if stats.received() > 0 {
+            format!("{:.3}ms", stats.avg().as_secs_f64() * 1e3)
+        } else {
+            "-".to_string()
+        };
+        let marker = if fastest == Some(*node_id) {
+            "  fastest"
+        } else {
+            ""
+        };
+        table.push_str(&format!(
+            "{:<10}  {:>5}  {:>5}  {:>5.1}%  {avg:>12}{marker}\n",
+            node_id.fmt_short(),
+            stats.sent(),
+            stats.received(),
+            stats.loss_pct(),
+        ));
+    }
+    table
+}
+
+/// Exit status of the CLI, so scripts can tell how the pings went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    /// every ping was answered, or every target answered
+    Success,
+    /// no ping was answered, or no target answered, or the run failed
+    AllFailed,
+    /// some pings were answered and some not, or some targets
+    SomeFailed,
+    /// the command line arguments don't make sense
+    InvalidConfig,
+}
+
+impl Exit {
+    /// From the pings of a run against a single target.
+    fn from_stats(stats: &PingStats) -> Self {
+        match (stats.received(), stats.lost()) {
+            (_, 0) => Self::Success,
+            (0, _) => Self::AllFailed,
+            _ => Self::SomeFailed,
+        }
+    }
+
+    /// From the fraction of the targets of a run that answered any ping at all.
+    fn from_targets(results: &[(NodeId, PingStats)]) -> Self {
+        let answered = results
+            .iter()
+            .filter(|(_, stats)| stats.received() > 0)
+            .count();
+        match answered {
+            n if n == results.len() => Self::Success,
+            0 => Self::AllFailed,
+            _ => Self::SomeFailed,
+        }
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(match exit {
+            Exit::Success => 0,
+            Exit::AllFailed => 1,
+            Exit::SomeFailed => 2,
+            Exit::InvalidConfig => 3,
+        })
+    }
+}
+
+/// Marks an error as caused by the command line arguments, see [`Exit::InvalidConfig`].
+#[derive(Debug)]
+struct InvalidConfig;
+
+impl std::fmt::Display for InvalidConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("invalid arguments")
+    }
+}
+
+/// Mark `err` as caused by the command line arguments.
+fn invalid(err: Error) -> Error {
+    err.context(InvalidConfig)
+}
+
+/// Send `count` pings one after the other over a single connection, like
+/// [`Ping::ping_n`], but keep going after failed pings and count them as lost,
+/// reconnecting after those that took the connection down. Each ping is shown on
+/// `display`, as in continuous mode.
+async fn ping_counted(
+    pinger: &Ping,
+    endpoint: &Endpoint,
+    addr: NodeAddr,
+    count: usize,
+    display: &mut impl PingDisplay,
+) -> Result<PingStats> {
+    let node_id = addr.node_id;
+    let mut session = PingSession::new();
+    let mut conn = None;
+    for _ in 0..count {
+        // the first ping on a connection also tells how long the handshake took
+        let res = if let Some(conn) = &conn {
+            pinger
+                .ping_on_conn(conn)
+                .await
+                .map(|rtt| (Duration::ZERO, rtt))
+        } else {
+            match pinger.ping_keep(endpoint, addr.clone()).await {
+                Ok((res, new, _)) => {
+                    conn = Some(new);
+                    Ok((res.connect_time, res.ping_time))
+                }
+                Err(err) => Err(err),
+            }
+        };
+        // like `ping_n`, the handshake of a connection is left out of the round trip times
+        let res = match res {
+            Ok((connect_time, rtt)) => {
+                session.record_success(rtt);
+                Ok(PingResponse {
+                    node_id,
+                    seq: session.last_seq(),
+                    rtt,
+                    connect_time,
+                    ping_time: rtt,
+                })
+            }
+            Err(err) => {
+                session.record_failure();
+                if conn
+                    .as_ref()
+                    .is_some_and(|conn| conn.close_reason().is_some())
+                {
+                    conn = None;
+                }
+                Err(err)
+            }
+        };
+        let path = Ping::latest_rtt(endpoint, node_id).map(|(_, path)| path);
+        display.show(&res, &session, path)?;
+    }
+    if let Some(conn) = conn {
+        pinger.close_conn(&conn);
+    }
+    Ok(session.snapshot())
+}
+
+/// Prints what this crate logs to stderr, such as the connections a server accepts (see
+/// [`Ping::with_log_connections`]), leaving out the chatter of iroh itself.
+fn init_logging() {
+    let filter = Targets::new().with_target("iroh_ping", Level::INFO);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_writer(std::io::stderr),
+        )
+        .with(filter)
+        .init();
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    if is_client()? {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(exit) => exit.into(),
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            if err.downcast_ref::<InvalidConfig>().is_some() {
+                Exit::InvalidConfig.into()
+            } else {
+                Exit::AllFailed.into()
+            }
+        }
+    }
+}
+
+/// Runs the client or the server as the command line arguments say.
+async fn run() -> Result<Exit> {
+    let verbosity = Verbosity::from_args(std::env::args()).map_err(invalid)?;
+    let alpn = alpn(std::env::args()).map_err(invalid)?;
+    let family = ip_family(std::env::args()).map_err(invalid)?;
+    let colors = RttColors::for_stdout(std::env::args()).map_err(invalid)?;
+    let timestamps = TimestampFormat::from_args(std::env::args()).map_err(invalid)?;
+    let no_discovery = is_no_discovery();
+    let mut ping = Ping::new().with_ip_family(family);
+    if let Some(alpn) = &alpn {
+        ping = ping.with_alpn(alpn.as_bytes());
+    }
+    if is_client().map_err(invalid)? {
+        let mut addrs = tickets(std::env::args())
+            .and_then(|tickets| {
+                tickets
+                    .iter()
+                    .map(|ticket| Ok(NodeAddr::from(NodeTicket::from_str(ticket)?)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .and_then(|addrs| {
+                if !no_discovery {
+                    return Ok(addrs);
+                }
+                addrs.into_iter().map(direct_only).collect()
+            })
+            .map_err(invalid)?;
+        let count = count().map_err(invalid)?;
+        let flood = flood(std::env::args()).map_err(invalid)?;
+        let csv_output = csv_output(std::env::args()).map_err(invalid)?;
         // create a send side & send a ping
-        let send_ep = Endpoint::builder().discovery_n0().bind().await?;
-        let send_pinger = Ping::new();
-        let rtt = send_pinger
-            .ping(&send_ep, NodeAddr::from(NodeTicket::from_str(&ticket()?)?))
+        let send_ep = endpoint(family, no_discovery).await?;
+        let send_pinger = ping;
+        if addrs.len() > 1 {
+            let schedule = Schedule::from_args(std::env::args()).map_err(invalid)?;
+            if flood.is_some() || is_watch() || csv_output.is_some() {
+                return Err(invalid(Error::msg(
+                    "--flood, --watch and --csv-output only support a single --ticket.",
+                )));
+            }
+            if is_continuous() && schedule == Schedule::Sequential {
+                return Err(invalid(Error::msg(
+                    "--continuous with several --ticket needs --parallel.",
+                )));
+            }
+            let (interval, limit) = if is_continuous() {
+                (Duration::from_secs(1), None)
+            } else {
+                (Duration::ZERO, Some(count))
+            };
+            let opts = TargetOpts {
+                interval,
+                limit,
+                verbosity,
+                colors,
+                timestamps,
+            };
+            let stop = CancellationToken::new();
+            let ctrl_c = stop.clone();
+            tokio::spawn(async move {
+                tokio::signal::ctrl_c().await.ok();
+                ctrl_c.cancel();
+            });
+            let results = ping_targets(&send_pinger, &send_ep, addrs, schedule, opts, stop).await?;
+            send_ep.close().await;
+            print!("{}", comparison_table(&results));
+            return Ok(Exit::from_targets(&results));
+        }
+        let addr = addrs.remove(0);
+        if flood.is_some() && csv_output.is_some() {
+            return Err(invalid(Error::msg("--flood doesn't support --csv-output.")));
+        }
+        // create the file right away, so a run without any pings still leaves the header
+        let csv = csv_output.map(|path| open_csv(&path)).transpose()?;
+        let stdout = std::io::stdout();
+        let mut display: Box<dyn PingDisplay> = if is_watch() && stdout.is_terminal() {
+            Box::new(StatusBlock { out: stdout })
+        } else {
+            // --watch without a terminal to redraw on appends lines like the other modes
+            Box::new(PingLines {
+                out: stdout,
+                verbosity,
+                colors,
+                timestamps: timestamps.map(Timestamps::new),
+                label: None,
+            })
+        };
+        if let Some(out) = csv {
+            display = Box::new(CsvRows {
+                display,
+                out,
+                node_id: addr.node_id,
+            });
+        }
+        let stats = if let Some(duration) = flood {
+            ping_flood(&send_pinger, &send_ep, addr, duration).await?
+        } else if is_continuous() || is_watch() {
+            // ping once a second until interrupted
+            let ctrl_c = async {
+                tokio::signal::ctrl_c().await.ok();
+            };
+            let session = ping_continuously(
+                &send_pinger,
+                &send_ep,
+                addr,
+                Duration::from_secs(1),
+                None,
+                &mut display,
+                ctrl_c,
+            )
             .await?;
-        println!("ping took: {:?} to complete", rtt);
+            session.snapshot()
+        } else {
+            let node_id = addr.node_id;
+            let stats = ping_counted(&send_pinger, &send_ep, addr, count, &mut display).await?;
+            if verbosity >= Verbosity::Verbose {
+                if let Some((latency, path)) = Ping::latest_rtt(&send_ep, node_id) {
+                    println!("path: {path}, latency estimate: {latency:?}");
+                }
+            }
+            stats
+        };
+        send_ep.close().await;
+        print_summary(&stats, colors.as_ref());
+        Ok(Exit::from_stats(&stats))
     } else {
+        init_logging();
         // create the receive side
-        let recv_ep = Endpoint::builder().discovery_n0().bind().await?;
-        let recv_router = Router::builder(recv_ep)
-            .accept(PingALPN, Ping::new())
+        let recv_ep = endpoint(family, no_discovery).await?;
+        let recv_router = ping
+            .with_log_connections(verbosity != Verbosity::Quiet)
+            .register(Router::builder(recv_ep))
             .spawn();
         let addr = recv_router.endpoint().node_addr().initialized().await?;
 
+        let mut flags = alpn
+            .map(|alpn| format!(" --alpn={alpn}"))
+            .unwrap_or_default();
+        if no_discovery {
+            flags.push_str(" --no-discovery");
+        }
         println!(
             "Connect to this server with:\n\
-            cargo run client --ticket={}\n\
+            cargo run client --ticket={}{flags}\n\
             \n\
             ctrl-c to quit.",
             NodeTicket::new(addr)
         );
 
-        loop {}
+        tokio::signal::ctrl_c().await?;
+        recv_router.shutdown().await?;
+        Ok(Exit::Success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+    use iroh_ping::test_utils;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_verbosity_from_args() {
+        let parse = |a: &[&str]| Verbosity::from_args(args(a));
+        assert_eq!(parse(&["client"]).unwrap(), Verbosity::Normal);
+        assert_eq!(parse(&["client", "--quiet"]).unwrap(), Verbosity::Quiet);
+        assert_eq!(parse(&["server", "--quiet"]).unwrap(), Verbosity::Quiet);
+        assert_eq!(parse(&["client", "--verbose"]).unwrap(), Verbosity::Verbose);
+        assert_eq!(parse(&["client", "-v"]).unwrap(), Verbosity::Verbose);
+        assert_eq!(parse(&["client", "-vv"]).unwrap(), Verbosity::VeryVerbose);
+        assert_eq!(
+            parse(&["client", "-vv", "--verbose"]).unwrap(),
+            Verbosity::VeryVerbose
+        );
+        assert!(parse(&["client", "--quiet", "-vv"]).is_err());
+        assert!(parse(&["client", "--quiet", "--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_tickets() {
+        assert!(tickets(args(&["client"])).is_err());
+        assert_eq!(
+            tickets(args(&["client", "--ticket=a", "--count=2", "--ticket=b"])).unwrap(),
+            ["a", "b"]
+        );
     }
 
-    Ok(())
+    #[test]
+    fn test_schedule() {
+        let parse = |a: &[&str]| Schedule::from_args(args(a));
+        assert_eq!(parse(&["client"]).unwrap(), Schedule::Sequential);
+        assert_eq!(
+            parse(&["client", "--parallel"]).unwrap(),
+            Schedule::Parallel { concurrency: None }
+        );
+        let limited = Schedule::Parallel {
+            concurrency: Some(2),
+        };
+        assert_eq!(
+            parse(&["client", "--parallel-concurrency=2", "--parallel"]).unwrap(),
+            limited
+        );
+        assert_eq!(
+            parse(&["client", "--parallel-concurrency=2"]).unwrap(),
+            limited
+        );
+        assert!(parse(&["client", "--parallel-concurrency=0"]).is_err());
+        assert!(parse(&["client", "--parallel-concurrency=x"]).is_err());
+    }
+
+    #[test]
+    fn test_comparison_table() {
+        let [a, b, c] = [0; 3].map(|_| SecretKey::generate(rand::rngs::OsRng).public());
+        let stats = |rtts: &[u64], lost: usize| {
+            let mut stats = PingStats::default();
+            for rtt in rtts {
+                stats.record_rtt(Duration::from_millis(*rtt));
+            }
+            for _ in 0..lost {
+                stats.record_loss();
+            }
+            stats
+        };
+        let table = comparison_table(&[
+            (a, stats(&[20, 40], 0)),
+            (b, stats(&[10, 12], 2)),
+            (c, stats(&[], 2)),
+        ]);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 5, "{table}");
+        assert!(lines[2].starts_with(&a.fmt_short()), "{table}");
+        assert!(lines[2].ends_with("30.000ms"), "{table}");
+        assert!(lines[3].contains("50.0%"), "{table}");
+        assert!(lines[3].ends_with("11.000ms  fastest"), "{table}");
+        // no answers make no average, and can't be the fastest
+        assert!(lines[4].ends_with("  -"), "{table}");
+    }
+
+    #[test]
+    fn test_exit() {
+        let stats = |received: usize, lost: usize| {
+            let mut stats = PingStats::default();
+            for _ in 0..received {
+                stats.record_rtt(Duration::from_millis(1));
+            }
+            for _ in 0..lost {
+                stats.record_loss();
+            }
+            stats
+        };
+        assert_eq!(Exit::from_stats(&stats(3, 0)), Exit::Success);
+        assert_eq!(Exit::from_stats(&stats(0, 3)), Exit::AllFailed);
+        assert_eq!(Exit::from_stats(&stats(2, 1)), Exit::SomeFailed);
+
+        let node = || SecretKey::generate(rand::rngs::OsRng).public();
+        let targets = |answered: &[bool]| {
+            answered
+                .iter()
+                .map(|&answered| (node(), stats(answered as usize, 1)))
+                .collect::<Vec<_>>()
+        };
+        // a target that answered some pings responded
+        assert_eq!(Exit::from_targets(&targets(&[true, true])), Exit::Success);
+        assert_eq!(
+            Exit::from_targets(&targets(&[false, false])),
+            Exit::AllFailed
+        );
+        assert_eq!(
+            Exit::from_targets(&targets(&[true, false])),
+            Exit::SomeFailed
+        );
+
+        let err = invalid(Error::msg("bad flag"));
+        assert!(err.downcast_ref::<InvalidConfig>().is_some());
+        assert!(Error::msg("unreachable")
+            .downcast_ref::<InvalidConfig>()
+            .is_none());
+    }
+
+    #[test]
+    fn test_direct_only() {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let relay: iroh::RelayUrl = "https://relay.example.com".parse().unwrap();
+        let direct: SocketAddr = "192.168.1.10:4433".parse().unwrap();
+
+        let addr = NodeAddr::from_parts(node_id, Some(relay.clone()), [direct]);
+        let addr = direct_only(addr).unwrap();
+        assert_eq!(addr.relay_url, None);
+        assert_eq!(
+            addr.direct_addresses.into_iter().collect::<Vec<_>>(),
+            [direct]
+        );
+
+        // a relay alone is no use without relays
+        assert!(direct_only(NodeAddr::from_parts(node_id, Some(relay), [])).is_err());
+    }
+
+    #[test]
+    fn test_alpn() {
+        assert_eq!(alpn(args(&["server"])).unwrap(), None);
+        assert_eq!(
+            alpn(args(&["server", "--alpn=my/ping/0"]))
+                .unwrap()
+                .as_deref(),
+            Some("my/ping/0")
+        );
+        assert!(alpn(args(&["client", "--alpn="])).is_err());
+    }
+
+    #[test]
+    fn test_csv_output() {
+        let parse = |list: &[&str]| csv_output(args(list));
+        assert_eq!(parse(&["client"]).unwrap(), None);
+        assert_eq!(
+            parse(&["client", "--csv-output=out.csv"]).unwrap(),
+            Some(PathBuf::from("out.csv"))
+        );
+        assert_eq!(
+            parse(&["client", "--csv-output", "out.csv"]).unwrap(),
+            Some(PathBuf::from("out.csv"))
+        );
+        assert!(parse(&["client", "--csv-output"]).is_err());
+        assert!(parse(&["client", "--csv-output="]).is_err());
+    }
+
+    #[test]
+    fn test_csv_rows() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("iroh-ping-{}.csv", rand::random::<u64>()));
+        open_csv(&path)?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            format!("{}\n", CSV_HEADER.join(","))
+        );
+
+        // appending to an existing file keeps its rows and doesn't repeat the header
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let mut rows = CsvRows {
+            display: Recorded::default(),
+            out: open_csv(&path)?,
+            node_id,
+        };
+        let mut session = PingSession::new();
+        session.record_success(Duration::from_micros(1_500));
+        let res = Ok(PingResponse {
+            node_id,
+            seq: 1,
+            rtt: Duration::from_micros(1_500),
+            connect_time: Duration::from_micros(1_000),
+            ping_time: Duration::from_micros(500),
+        });
+        let path_type = Some(ConnectionType::Direct("127.0.0.1:1234".parse().unwrap()));
+        rows.show(&res, &session, path_type)?;
+        session.record_failure();
+        // an error with a separator in it still takes a single field
+        let err = PingError::PayloadTooLarge {
+            size: 5,
+            limit: Some(4),
+        };
+        let message = err.to_string();
+        assert!(message.contains(','), "{message}");
+        rows.show(&Err(err), &session, None)?;
+        assert_eq!(rows.display.sent, [1, 2]);
+        drop(rows);
+
+        let mut reader = csv::Reader::from_path(&path)?;
+        assert_eq!(reader.headers()?, &csv::StringRecord::from(&CSV_HEADER[..]));
+        let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(records.len(), 2, "{records:?}");
+        let ok: Vec<_> = records[0].iter().collect();
+        assert_eq!(
+            ok[1..],
+            [
+                "1",
+                &node_id.to_string(),
+                "1500",
+                "1000",
+                "500",
+                "0",
+                "direct",
+                "ok",
+                ""
+            ]
+        );
+        assert!(humantime::parse_rfc3339(ok[0]).is_ok(), "{ok:?}");
+        let failed: Vec<_> = records[1].iter().collect();
+        assert_eq!(
+            failed[1..9],
+            [
+                "2",
+                &node_id.to_string(),
+                "",
+                "",
+                "",
+                "0",
+                "no path",
+                "failed"
+            ]
+        );
+        assert_eq!(failed[9], message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rtt_colors() {
+        let parse = |list: &[&str]| RttColors::from_args(args(list));
+        let default = RttColors {
+            low: Duration::from_millis(10),
+            high: Duration::from_millis(100),
+        };
+        assert_eq!(parse(&["client"]).unwrap(), Some(default));
+        assert_eq!(parse(&["client", "--no-color"]).unwrap(), None);
+
+        let colors = parse(&["client", "--color-thresholds", "low=5,high=50"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(colors.low, Duration::from_millis(5));
+        assert_eq!(colors.high, Duration::from_millis(50));
+        let colors = parse(&["client", "--color-thresholds=high=500"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(colors.high, Duration::from_millis(500));
+        assert!(parse(&["client", "--color-thresholds", "low=fast"]).is_err());
+        assert!(parse(&["client", "--color-thresholds", "mid=5"]).is_err());
+        assert!(parse(&["client", "--color-thresholds", "low=50,high=5"]).is_err());
+        assert!(parse(&["client", "--color-thresholds"]).is_err());
+
+        assert_eq!(
+            default.rtt(Duration::from_millis(5), "x"),
+            "\x1b[32mx\x1b[39m"
+        );
+        assert_eq!(
+            default.rtt(Duration::from_millis(50), "x"),
+            "\x1b[33mx\x1b[39m"
+        );
+        assert_eq!(
+            default.rtt(Duration::from_millis(500), "x"),
+            "\x1b[31mx\x1b[39m"
+        );
+        assert_eq!(default.lost("x"), "\x1b[31;1mx\x1b[0m");
+
+        let mut lines = PingLines {
+            out: Vec::new(),
+            verbosity: Verbosity::Normal,
+            colors: Some(default),
+            timestamps: None,
+            label: None,
+        };
+        let mut session = PingSession::new();
+        session.record_failure();
+        lines
+            .show(&Err(PingError::NoTargets), &session, None)
+            .unwrap();
+        let out = String::from_utf8(lines.out).unwrap();
+        assert!(out.starts_with("\x1b[31;1mseq=1 failed"), "{out:?}");
+        assert!(out.ends_with("\x1b[0m\n"), "{out:?}");
+    }
+
+    #[test]
+    fn test_timestamp_format() {
+        let parse = |list: &[&str]| TimestampFormat::from_args(args(list));
+        assert_eq!(parse(&["client"]).unwrap(), None);
+        assert_eq!(
+            parse(&["client", "--timestamp"]).unwrap(),
+            Some(TimestampFormat::Unix)
+        );
+        assert_eq!(
+            parse(&["client", "--timestamp", "--timestamp-format=rfc3339"]).unwrap(),
+            Some(TimestampFormat::Rfc3339)
+        );
+        // the format alone turns the timestamps on
+        assert_eq!(
+            parse(&["client", "--timestamp-format", "millis"]).unwrap(),
+            Some(TimestampFormat::Millis)
+        );
+        assert_eq!(
+            parse(&["client", "--timestamp-format=relative", "--timestamp"]).unwrap(),
+            Some(TimestampFormat::Relative)
+        );
+        assert!(parse(&["client", "--timestamp-format=iso"]).is_err());
+        assert!(parse(&["client", "--timestamp-format"]).is_err());
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let first = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let later = first + Duration::from_millis(1_500);
+        let stamps = |format| {
+            let mut timestamps = Timestamps::new(format);
+            (timestamps.prefix(first), timestamps.prefix(later))
+        };
+        assert_eq!(
+            stamps(TimestampFormat::Unix),
+            (
+                "[1700000000.123456] ".to_string(),
+                "[1700000001.623456] ".to_string()
+            )
+        );
+        assert_eq!(
+            stamps(TimestampFormat::Rfc3339),
+            (
+                "[2023-11-14T22:13:20.123456Z] ".to_string(),
+                "[2023-11-14T22:13:21.623456Z] ".to_string()
+            )
+        );
+        assert_eq!(
+            stamps(TimestampFormat::Relative),
+            ("[0.000000] ".to_string(), "[1.500000] ".to_string())
+        );
+        assert_eq!(
+            stamps(TimestampFormat::Millis),
+            ("[0] ".to_string(), "[1500] ".to_string())
+        );
+
+        // in front of the lines, and outside their colors
+        let mut lines = PingLines {
+            out: Vec::new(),
+            verbosity: Verbosity::Normal,
+            colors: Some(RttColors {
+                low: Duration::from_millis(10),
+                high: Duration::from_millis(100),
+            }),
+            timestamps: Some(Timestamps::new(TimestampFormat::Relative)),
+            label: None,
+        };
+        let mut session = PingSession::new();
+        session.record_failure();
+        lines
+            .show(&Err(PingError::NoTargets), &session, None)
+            .unwrap();
+        let out = String::from_utf8(lines.out).unwrap();
+        assert!(
+            out.starts_with("[0.000000] \x1b[31;1mseq=1 failed"),
+            "{out:?}"
+        );
+
+        // with the label of a target after the timestamp
+        let mut lines = PingLines {
+            out: Vec::new(),
+            verbosity: Verbosity::Normal,
+            colors: None,
+            timestamps: Some(Timestamps::new(TimestampFormat::Relative)),
+            label: Some("5a1c0e7f3b".to_string()),
+        };
+        lines
+            .show(&Err(PingError::NoTargets), &session, None)
+            .unwrap();
+        let out = String::from_utf8(lines.out).unwrap();
+        assert!(
+            out.starts_with("[0.000000] 5a1c0e7f3b: seq=1 failed"),
+            "{out:?}"
+        );
+    }
+
+    #[test]
+    fn test_flood() {
+        let parse = |list: &[&str]| flood(args(list));
+        assert_eq!(parse(&["client"]).unwrap(), None);
+        assert_eq!(
+            parse(&["client", "--flood"]).unwrap(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            parse(&["client", "--flood=3"]).unwrap(),
+            Some(Duration::from_secs(3))
+        );
+        assert!(parse(&["client", "--flood=soon"]).is_err());
+    }
+
+    #[test]
+    fn test_ip_family() {
+        let parse = |a: &[&str]| ip_family(args(a));
+        assert_eq!(parse(&["client"]).unwrap(), IpFamily::Any);
+        assert_eq!(parse(&["client", "--ipv4"]).unwrap(), IpFamily::Ipv4Only);
+        assert_eq!(parse(&["client", "--ipv6"]).unwrap(), IpFamily::Ipv6Only);
+        assert_eq!(
+            parse(&["client", "--prefer-ipv6", "--prefer-ipv6"]).unwrap(),
+            IpFamily::PreferIpv6
+        );
+        assert!(parse(&["client", "--ipv4", "--prefer-ipv6"]).is_err());
+    }
+
+    /// Keeps what a watch run would have drawn.
+    #[derive(Default)]
+    struct Recorded {
+        blocks: Vec<String>,
+        sent: Vec<u64>,
+    }
+
+    impl PingDisplay for Recorded {
+        fn show(
+            &mut self,
+            res: &Result<PingResponse, PingError>,
+            session: &PingSession,
+            path: Option<ConnectionType>,
+        ) -> std::io::Result<()> {
+            self.blocks.push(status_block(res, session, path));
+            self.sent.push(session.sent());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_targets() -> anyhow::Result<()> {
+        let mut routers = Vec::new();
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let ep = test_utils::local_endpoint().await?;
+            let router = Ping::new().register(Router::builder(ep)).spawn();
+            addrs.push(test_utils::local_addr(router.endpoint()));
+            routers.push(router);
+        }
+        let node_ids: Vec<_> = addrs.iter().map(|addr| addr.node_id).collect();
+
+        let client = test_utils::local_endpoint().await?;
+        let opts = TargetOpts {
+            interval: Duration::ZERO,
+            limit: Some(2),
+            verbosity: Verbosity::Quiet,
+            colors: None,
+            timestamps: None,
+        };
+        for schedule in [
+            Schedule::Sequential,
+            Schedule::Parallel {
+                concurrency: Some(1),
+            },
+            Schedule::Parallel { concurrency: None },
+        ] {
+            let results = ping_targets(
+                &Ping::new(),
+                &client,
+                addrs.clone(),
+                schedule,
+                opts,
+                CancellationToken::new(),
+            )
+            .await?;
+            // in the order of the targets, however they were pinged
+            let got: Vec<_> = results.iter().map(|(node_id, _)| *node_id).collect();
+            assert_eq!(got, node_ids, "{schedule:?}");
+            for (_, stats) in &results {
+                assert_eq!(stats.received(), 2, "{schedule:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_counted() -> anyhow::Result<()> {
+        let display = |verbosity, colors| PingLines {
+            out: Vec::new(),
+            verbosity,
+            colors,
+            timestamps: None,
+            label: None,
+        };
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let mut shown = display(Verbosity::Verbose, None);
+        let stats = ping_counted(&Ping::new(), &client, addr.clone(), 3, &mut shown).await?;
+        assert_eq!(stats.received(), 3);
+        assert_eq!(Exit::from_stats(&stats), Exit::Success);
+        // a verbose line per ping
+        let out = String::from_utf8(shown.out)?;
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3, "{out}");
+        for (seq, line) in (1..).zip(&lines) {
+            assert!(line.contains(&format!("seq={seq} connect=")), "{line}");
+        }
+        // only the first ping waited for the handshake
+        let connect_us = |line: &str| -> u64 {
+            let (_, rest) = line.split_once("connect=").expect("a connect time");
+            let (us, _) = rest.split_once("µs").expect("in microseconds");
+            us.parse().expect("a number")
+        };
+        assert!(connect_us(lines[0]) > 0, "{}", lines[0]);
+        assert_eq!(connect_us(lines[1]), 0, "{}", lines[1]);
+        assert_eq!(connect_us(lines[2]), 0, "{}", lines[2]);
+
+        // colored by their round trip times, all well below the low threshold
+        let colors = RttColors {
+            low: Duration::from_secs(10),
+            high: Duration::from_secs(20),
+        };
+        let mut shown = display(Verbosity::Normal, Some(colors));
+        ping_counted(&Ping::new(), &client, addr.clone(), 2, &mut shown).await?;
+        let out = String::from_utf8(shown.out)?;
+        for line in out.lines() {
+            assert!(line.starts_with("\x1b[32mPONG from "), "{line:?}");
+        }
+
+        // after a timestamp each
+        let mut shown = PingLines {
+            timestamps: Some(Timestamps::new(TimestampFormat::Millis)),
+            ..display(Verbosity::Normal, None)
+        };
+        ping_counted(&Ping::new(), &client, addr, 2, &mut shown).await?;
+        let out = String::from_utf8(shown.out)?;
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2, "{out}");
+        for line in lines {
+            let (stamp, line) = line.split_once("] ").expect("a timestamp");
+            assert!(
+                stamp.strip_prefix('[').unwrap().parse::<u64>().is_ok(),
+                "{stamp}"
+            );
+            assert!(line.starts_with("PONG from "), "{line}");
+        }
+
+        // every ping to a node nobody can find fails, but is counted
+        let bogus = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public());
+        let ping = Ping::new().with_timeout(Duration::from_millis(200));
+        let mut shown = display(Verbosity::Quiet, None);
+        let stats = ping_counted(&ping, &client, bogus.clone(), 2, &mut shown).await?;
+        assert_eq!((stats.sent(), stats.lost()), (2, 2));
+        assert!(shown.out.is_empty());
+        assert_eq!(Exit::from_stats(&stats), Exit::AllFailed);
+        // in bold red
+        let mut shown = display(Verbosity::Normal, Some(colors));
+        ping_counted(&ping, &client, bogus, 1, &mut shown).await?;
+        let out = String::from_utf8(shown.out)?;
+        assert!(out.starts_with("\x1b[31;1mseq=1 failed"), "{out:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_counted_csv() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let path = std::env::temp_dir().join(format!("iroh-ping-{}.csv", rand::random::<u64>()));
+        let mut rows = CsvRows {
+            display: PingLines {
+                out: Vec::new(),
+                verbosity: Verbosity::Normal,
+                colors: None,
+                timestamps: None,
+                label: None,
+            },
+            out: open_csv(&path)?,
+            node_id: addr.node_id,
+        };
+        ping_counted(&Ping::new(), &client, addr, 3, &mut rows).await?;
+        // the lines are shown as without the rows
+        let out = String::from_utf8(rows.display.out)?;
+        assert_eq!(out.lines().count(), 3, "{out}");
+        drop(rows.out);
+
+        let mut reader = csv::Reader::from_path(&path)?;
+        let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(records.len(), 3, "{records:?}");
+        // a single connection, so only the first ping waited for the handshake
+        let connect_us: Vec<u64> = records
+            .iter()
+            .map(|record| record[4].parse())
+            .collect::<Result<_, _>>()?;
+        assert!(connect_us[0] > 0, "{connect_us:?}");
+        assert_eq!(connect_us[1..], [0, 0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_continuously() -> anyhow::Result<()> {
+        let (router, addr, client) = test_utils::local_pair().await?;
+        let mut display = Recorded::default();
+        let session = ping_continuously(
+            &Ping::new(),
+            &client,
+            addr,
+            Duration::from_millis(10),
+            Some(3),
+            &mut display,
+            std::future::pending(),
+        )
+        .await?;
+
+        assert_eq!(display.sent, [1, 2, 3]);
+        let stats = session.snapshot();
+        assert_eq!(stats.received(), 3);
+        assert!(stats.min() <= stats.avg() && stats.avg() <= stats.max());
+        let last = display.blocks.last().unwrap();
+        assert!(last.contains("seq=3"), "{last}");
+        assert!(last.contains("3 sent, 3 received, 0.0% loss"), "{last}");
+
+        // a stopped run ends before the next ping
+        let session = ping_continuously(
+            &Ping::new(),
+            &client,
+            test_utils::local_addr(router.endpoint()),
+            Duration::from_millis(10),
+            None,
+            &mut display,
+            std::future::ready(()),
+        )
+        .await?;
+        assert_eq!(session.sent(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_ping() {
+        let mut session = PingSession::new();
+        session.record_success(Duration::from_millis(5));
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let res = Ok(PingResponse {
+            node_id,
+            seq: 1,
+            rtt: Duration::from_millis(5),
+            connect_time: Duration::from_micros(4_500),
+            ping_time: Duration::from_micros(500),
+        });
+        let path = Some(ConnectionType::Direct("127.0.0.1:1234".parse().unwrap()));
+        let print = |verbosity, res: &Result<PingResponse, PingError>| {
+            let mut out = Vec::new();
+            print_ping(&mut out, verbosity, res, &session, path.clone()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(print(Verbosity::Quiet, &res), "");
+        let normal = print(Verbosity::Normal, &res);
+        assert!(normal.contains("seq=1"), "{normal}");
+        assert!(!normal.contains("srtt"), "{normal}");
+        let verbose = print(Verbosity::Verbose, &res);
+        assert!(verbose.contains(" (direct): seq=1 "), "{verbose}");
+        assert!(
+            verbose.contains("connect=4500µs ping=500.0µs rtt=5.0ms"),
+            "{verbose}"
+        );
+        assert!(verbose.contains("srtt=5ms"), "{verbose}");
+        assert!(!verbose.contains("127.0.0.1:1234"), "{verbose}");
+        let very_verbose = print(Verbosity::VeryVerbose, &res);
+        assert!(
+            very_verbose.starts_with(verbose.trim_end()),
+            "{very_verbose}"
+        );
+        assert!(very_verbose.contains("127.0.0.1:1234"), "{very_verbose}");
+        assert!(
+            very_verbose.contains(&node_id.to_string()),
+            "{very_verbose}"
+        );
+
+        // failed pings only show in the summary when quiet
+        let err = Err(PingError::NoTargets);
+        assert_eq!(print(Verbosity::Quiet, &err), "");
+        assert!(print(Verbosity::Normal, &err).contains("failed"));
+    }
 }
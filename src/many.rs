@@ -0,0 +1,470 @@
+use std::{
+    future::Future,
+    time::{Duration, SystemTime},
+};
+
+use iroh::{endpoint::ConnectionType, Endpoint, NodeAddr, NodeId, Watcher};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Ping, PingError, PingRecorder, PingStats, RecordedPing};
+
+/// How long in-flight pings may still complete after the deadline of a run passed.
+const DEADLINE_GRACE: Duration = Duration::from_millis(500);
+
+/// Options for [`Ping::ping_many`].
+#[derive(Debug, Clone)]
+pub struct PingManyOpts {
+    /// number of pings to send
+    pub count: usize,
+    /// time between the start of consecutive pings
+    pub interval: Duration,
+    /// Total time budget for the run, like `ping -w`.
+    ///
+    /// Once it elapses no further pings are sent, and a ping still in flight gets a short
+    /// grace period to complete.
+    pub deadline: Option<Duration>,
+    /// Token to stop the run from elsewhere.
+    ///
+    /// Once cancelled, the run returns right away, abandoning the ping in flight. Together
+    /// with a `count` of `usize::MAX` this makes for a continuous ping.
+    pub cancel: Option<CancellationToken>,
+    /// Where to record every ping as it completes.
+    ///
+    /// Recording errors don't affect the run, call [`PingRecorder::finish`] afterwards to
+    /// check for them.
+    pub recorder: Option<PingRecorder>,
+}
+
+impl Default for PingManyOpts {
+    fn default() -> Self {
+        Self {
+            count: 4,
+            interval: Duration::from_secs(1),
+            deadline: None,
+            cancel: None,
+            recorder: None,
+        }
+    }
+}
+
+/// A change of the network path to the node, noticed during [`Ping::ping_many`] or
+/// [`PingSession::run`](crate::PingSession::run).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathChange {
+    /// time since the start of the run at which the change was noticed
+    pub at: Duration,
+    /// the path before the change
+    pub from: ConnectionType,
+    /// the path after the change
+    pub to: ConnectionType,
+}
+
+/// Outcome of [`Ping::ping_many`].
+#[derive(Debug)]
+pub struct PingManyReport {
+    /// the result of every ping sent, in order
+    pub results: Vec<Result<Duration, PingError>>,
+    /// the path to the node right after each ping in `results`, if known
+    pub paths: Vec<Option<ConnectionType>>,
+    /// every change of the path between consecutive pings, in order
+    pub path_changes: Vec<PathChange>,
+    /// statistics over the pings sent
+    pub stats: PingStats,
+    /// number of pings not sent, or abandoned, because the run was cut short
+    pub not_attempted: usize,
+    /// whether the deadline cut the run short
+    pub deadline_reached: bool,
+    /// whether cancellation cut the run short
+    pub cancelled: bool,
+}
+
+impl Ping {
+    /// Send a series of pings at a fixed interval.
+    ///
+    /// Failed pings are recorded rather than ending the run. The path to the node is
+    /// noted after every ping, so that jumps in latency can be told apart from network
+    /// changes, e.g. switching from a relay to a direct connection. Does not close the
+    /// endpoint.
+    pub async fn ping_many(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        opts: PingManyOpts,
+    ) -> PingManyReport {
+        let node_id = addr.node_id;
+        run_many(
+            opts,
+            || self.ping_once(endpoint, addr.clone()),
+            || current_path(endpoint, node_id),
+        )
+        .await
+    }
+}
+
+/// The path the endpoint currently uses to reach a node, if it has one.
+pub(crate) fn current_path(endpoint: &Endpoint, node_id: NodeId) -> Option<ConnectionType> {
+    let conn_type = endpoint.conn_type(node_id)?.get().ok()?;
+    (!matches!(conn_type, ConnectionType::None)).then_some(conn_type)
+}
+
+/// Follows the path to a node from one ping to the next, to notice when it changes.
+#[derive(Debug, Default)]
+pub(crate) struct PathTracker {
+    last: Option<ConnectionType>,
+}
+
+impl PathTracker {
+    /// Notes the path after a ping, `at` the given time into the run, returning the change
+    /// if it differs from the path known before. An unknown path is no change.
+    pub(crate) fn observe(
+        &mut self,
+        path: Option<&ConnectionType>,
+        at: Duration,
+    ) -> Option<PathChange> {
+        let to = path?;
+        match self.last.replace(to.clone()) {
+            Some(from) if !same_path(&from, to) => Some(PathChange {
+                at,
+                from,
+                to: to.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Whether two connection types describe the same path.
+///
+/// A direct address that is still being confirmed next to the relay (mixed) is the same
+/// path as the confirmed direct address.
+fn same_path(a: &ConnectionType, b: &ConnectionType) -> bool {
+    use ConnectionType::*;
+    match (a, b) {
+        (Direct(a) | Mixed(a, _), Direct(b) | Mixed(b, _)) => a == b,
+        (a, b) => a == b,
+    }
+}
+
+/// Runs a series of pings, taking each sample with `ping`, and looking up the path after
+/// each one with `path`.
+async fn run_many<F, Fut, P>(opts: PingManyOpts, mut ping: F, mut path: P) -> PingManyReport
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Duration, PingError>>,
+    P: FnMut() -> Option<ConnectionType>,
+{
+    let start = Instant::now();
+    let deadline = opts.deadline.map(|deadline| start + deadline);
+    let never = CancellationToken::new();
+    let cancel = opts.cancel.as_ref().unwrap_or(&never);
+    let mut report = PingManyReport {
+        // the count may well be unbounded
+        results: Vec::with_capacity(opts.count.min(1024)),
+        paths: Vec::with_capacity(opts.count.min(1024)),
+        path_changes: Vec::new(),
+        stats: PingStats::with_samples(),
+        not_attempted: 0,
+        deadline_reached: false,
+        cancelled: false,
+    };
+    let mut paths = PathTracker::default();
+    // when the next ping is due, kept as a running sum as the count may be unbounded
+    let mut next = start;
+
+    for seq in 0..opts.count {
+        if let Some(deadline) = deadline.filter(|deadline| next >= *deadline) {
+            // Don't linger until the next ping would have been due.
+            if cancel
+                .run_until_cancelled(tokio::time::sleep_until(deadline))
+                .await
+                .is_none()
+            {
+                report.cancelled = true;
+            } else {
+                report.deadline_reached = true;
+            }
+            report.not_attempted = opts.count - seq;
+            break;
+        }
+
+        let attempt = async {
+            tokio::time::sleep_until(next).await;
+            let ping = ping();
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline + DEADLINE_GRACE, ping)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(PingError::Timeout {
+                            timeout: deadline + DEADLINE_GRACE - next,
+                        })
+                    }),
+                None => ping.await,
+            }
+        };
+        let Some(res) = cancel.run_until_cancelled(attempt).await else {
+            report.not_attempted = opts.count - seq;
+            report.cancelled = true;
+            break;
+        };
+        report.stats.record(&res);
+        report.results.push(res);
+
+        let path = path();
+        if let Some(change) = paths.observe(path.as_ref(), start.elapsed()) {
+            report.path_changes.push(change);
+        }
+        if let Some(recorder) = &opts.recorder {
+            let rtt = report.results.last().expect("just pushed");
+            recorder.record(&RecordedPing {
+                at: SystemTime::now(),
+                seq: seq as u64,
+                rtt: rtt.as_ref().copied().map_err(|err| err.to_string()),
+                path: path.clone().into(),
+            });
+        }
+        report.paths.push(path);
+
+        let Some(due) = next.checked_add(opts.interval) else {
+            // no further ping would ever be due
+            report.not_attempted = opts.count - seq - 1;
+            break;
+        };
+        next = due;
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_ping_many() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let opts = PingManyOpts {
+            count: 3,
+            interval: Duration::from_millis(10),
+            deadline: None,
+            cancel: None,
+            recorder: None,
+        };
+        let report = Ping::new().ping_many(&client, addr, opts).await;
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.stats.received(), 3);
+        assert_eq!(report.not_attempted, 0);
+        assert!(!report.deadline_reached);
+        assert!(!report.cancelled);
+        assert_eq!(report.paths.len(), 3);
+        assert!(report.paths[0].is_some());
+        assert!(report.path_changes.is_empty(), "{:?}", report.path_changes);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_many_jitter() {
+        let mut rtts = [10, 20, 15, 15].map(Duration::from_millis).into_iter();
+        let opts = PingManyOpts {
+            count: 4,
+            interval: Duration::ZERO,
+            deadline: None,
+            cancel: None,
+            recorder: None,
+        };
+        let report = run_many(
+            opts,
+            || {
+                let rtt = rtts.next().unwrap();
+                async move { Ok(rtt) }
+            },
+            || None,
+        )
+        .await;
+
+        // (10 + 5 + 0) / 3
+        assert_eq!(report.stats.jitter(), Duration::from_millis(5));
+        // J += (|D| - J) / 16 for D = 10, 5, 0
+        let expected = Duration::from_nanos(842_285);
+        let jitter = report.stats.jitter_rfc3550();
+        assert!(
+            jitter.abs_diff(expected) < Duration::from_micros(1),
+            "{jitter:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_many_recorder() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("iroh-ping-{}-run-many.txt", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut results = [Ok(5), Err(()), Ok(7)].into_iter();
+        let opts = PingManyOpts {
+            count: 3,
+            interval: Duration::ZERO,
+            recorder: Some(PingRecorder::create(&path)?),
+            ..Default::default()
+        };
+        let recorder = opts.recorder.clone().unwrap();
+        run_many(
+            opts,
+            || {
+                let res = results.next().unwrap();
+                async move {
+                    res.map(Duration::from_millis)
+                        .map_err(|()| PingError::NoTargets)
+                }
+            },
+            || None,
+        )
+        .await;
+        recorder.finish()?;
+
+        let recording = crate::PingRecording::load(&path)?;
+        std::fs::remove_file(&path)?;
+        let rtts: Vec<_> = recording
+            .pings
+            .iter()
+            .map(|ping| ping.rtt.clone())
+            .collect();
+        assert_eq!(
+            rtts,
+            [
+                Ok(Duration::from_millis(5)),
+                Err("no nodes to ping".to_string()),
+                Ok(Duration::from_millis(7)),
+            ]
+        );
+        assert_eq!(recording.summary().received(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_many_deadline() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        // connect once up front, so the first ping isn't much slower than the others
+        Ping::new().ping_n(&client, addr.clone(), 1).await?;
+
+        let opts = PingManyOpts {
+            count: 100,
+            interval: Duration::from_millis(100),
+            deadline: Some(Duration::from_millis(350)),
+            cancel: None,
+            recorder: None,
+        };
+        let start = Instant::now();
+        let report = Ping::new().ping_many(&client, addr, opts).await;
+        let elapsed = start.elapsed();
+
+        assert!(
+            (3..=4).contains(&report.results.len()),
+            "{}",
+            report.results.len()
+        );
+        assert!(report.deadline_reached);
+        assert_eq!(report.not_attempted, 100 - report.results.len());
+        assert_eq!(report.stats.sent(), report.results.len() as u64);
+        assert!(elapsed >= Duration::from_millis(350), "{elapsed:?}");
+        assert!(
+            elapsed < Duration::from_millis(350) + DEADLINE_GRACE,
+            "{elapsed:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_many_path_changes() {
+        let relay: iroh::RelayUrl = "https://relay.example".parse().unwrap();
+        let udp = "192.0.2.1:1234".parse().unwrap();
+        let other_udp = "192.0.2.2:1234".parse().unwrap();
+        let mut paths = [
+            Some(ConnectionType::Relay(relay.clone())),
+            Some(ConnectionType::Mixed(udp, relay.clone())),
+            Some(ConnectionType::Direct(udp)),
+            None,
+            Some(ConnectionType::Direct(other_udp)),
+        ]
+        .into_iter();
+        let opts = PingManyOpts {
+            count: 5,
+            interval: Duration::ZERO,
+            deadline: None,
+            cancel: None,
+            recorder: None,
+        };
+        let report = run_many(
+            opts,
+            || async { Ok(Duration::from_millis(1)) },
+            || paths.next().unwrap(),
+        )
+        .await;
+
+        assert_eq!(report.paths.len(), 5);
+        assert_eq!(report.paths[3], None);
+        // confirming the direct address is no change, and unknown paths are skipped
+        let changes: Vec<_> = report
+            .path_changes
+            .into_iter()
+            .map(|change| (change.from, change.to))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    ConnectionType::Relay(relay.clone()),
+                    ConnectionType::Mixed(udp, relay)
+                ),
+                (
+                    ConnectionType::Direct(udp),
+                    ConnectionType::Direct(other_udp)
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_many_cancel() {
+        let cancel = CancellationToken::new();
+        let opts = PingManyOpts {
+            count: usize::MAX,
+            interval: Duration::from_millis(10),
+            deadline: None,
+            cancel: Some(cancel.clone()),
+            recorder: None,
+        };
+        // the first two pings are answered, the third one hangs
+        let mut pings = 0;
+        let run = run_many(
+            opts,
+            || {
+                pings += 1;
+                let hang = pings > 2;
+                async move {
+                    if hang {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok(Duration::from_millis(1))
+                }
+            },
+            || None,
+        );
+        let start = Instant::now();
+        let (report, ()) = tokio::join!(run, async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(report.cancelled);
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.not_attempted, usize::MAX - 2);
+    }
+}
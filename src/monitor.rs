@@ -0,0 +1,332 @@
+use std::time::Duration;
+
+use iroh::{Endpoint, NodeAddr};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{Ping, RollingPingStats};
+
+/// Configuration of a [`PingMonitor`].
+#[derive(Debug, Clone)]
+pub struct MonitorConfig {
+    /// time between the start of consecutive pings
+    pub interval: Duration,
+    /// alert when the mean round trip time over the window exceeds this
+    pub max_rtt: Option<Duration>,
+    /// alert when this many pings in a row fail
+    pub max_consecutive_losses: Option<u32>,
+    /// number of recent pings the latency threshold is evaluated over
+    pub window: usize,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_rtt: None,
+            max_consecutive_losses: Some(3),
+            window: 10,
+        }
+    }
+}
+
+/// Why a [`PingMonitor`] considers the connection degraded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alert {
+    /// the mean round trip time over the window exceeded [`MonitorConfig::max_rtt`]
+    HighLatency {
+        /// mean round trip time over the window
+        avg_rtt: Duration,
+    },
+    /// [`MonitorConfig::max_consecutive_losses`] pings in a row failed
+    ConsecutiveLosses {
+        /// number of pings that failed in a row
+        count: u32,
+    },
+}
+
+/// Event emitted by a [`PingMonitor`] when a threshold is crossed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorEvent {
+    /// the connection crossed a threshold
+    Degraded(Alert),
+    /// the connection is back within all thresholds
+    Recovered,
+}
+
+/// Pings a node at a fixed interval in the background, reporting when latency or loss
+/// cross the configured thresholds, and again when they recover.
+///
+/// Events are delivered on the channel returned by [`PingMonitor::new`]. The background
+/// task stops on [`PingMonitor::stop`], when the monitor is dropped, or when the event
+/// receiver is dropped.
+#[derive(Debug)]
+pub struct PingMonitor {
+    ping: Ping,
+    endpoint: Endpoint,
+    addr: NodeAddr,
+    config: MonitorConfig,
+    events: mpsc::Sender<MonitorEvent>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl PingMonitor {
+    /// Create a monitor for the node at `addr`, along with the receiver for its events.
+    ///
+    /// The monitor does not ping until [`PingMonitor::start`] is called.
+    pub fn new(
+        ping: Ping,
+        endpoint: Endpoint,
+        addr: NodeAddr,
+        config: MonitorConfig,
+    ) -> (Self, mpsc::Receiver<MonitorEvent>) {
+        let (events, events_rx) = mpsc::channel(16);
+        let monitor = Self {
+            ping,
+            endpoint,
+            addr,
+            config,
+            events,
+            task: None,
+        };
+        (monitor, events_rx)
+    }
+
+    /// start pinging in the background, if not already running
+    pub fn start(&mut self) {
+        if self.is_running() {
+            return;
+        }
+        let mut state = MonitorState::new(self.config.clone());
+        let ping = self.ping.clone();
+        let endpoint = self.endpoint.clone();
+        let addr = self.addr.clone();
+        let events = self.events.clone();
+        let mut interval = tokio::time::interval(self.config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        self.task = Some(tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let res = ping.ping_once(&endpoint, addr.clone()).await;
+                if let Some(event) = state.update(res.ok()) {
+                    if events.send(event).await.is_err() {
+                        // nobody is listening anymore
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+
+    /// stop pinging
+    pub fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// whether the background task is running
+    pub fn is_running(&self) -> bool {
+        self.task.as_ref().is_some_and(|task| !task.is_finished())
+    }
+}
+
+impl Drop for PingMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Threshold tracking, separate from the pinging so it can be tested on its own.
+#[derive(Debug)]
+struct MonitorState {
+    config: MonitorConfig,
+    window: RollingPingStats,
+    consecutive_losses: u32,
+    degraded: bool,
+}
+
+impl MonitorState {
+    fn new(config: MonitorConfig) -> Self {
+        Self {
+            window: RollingPingStats::new(config.window),
+            config,
+            consecutive_losses: 0,
+            degraded: false,
+        }
+    }
+
+    /// Records the outcome of a ping, returning an event if a threshold was crossed.
+    fn update(&mut self, rtt: Option<Duration>) -> Option<MonitorEvent> {
+        self.window.push(rtt);
+        match rtt {
+            Some(_) => self.consecutive_losses = 0,
+            None => self.consecutive_losses += 1,
+        }
+
+        let alert = self.alert();
+        match (alert, self.degraded) {
+            (Some(alert), false) => {
+                self.degraded = true;
+                Some(MonitorEvent::Degraded(alert))
+            }
+            (None, true) => {
+                self.degraded = false;
+                Some(MonitorEvent::Recovered)
+            }
+            _ => None,
+        }
+    }
+
+    fn alert(&self) -> Option<Alert> {
+        if let Some(max) = self.config.max_consecutive_losses {
+            if self.consecutive_losses >= max {
+                return Some(Alert::ConsecutiveLosses {
+                    count: self.consecutive_losses,
+                });
+            }
+        }
+        if let Some(max_rtt) = self.config.max_rtt {
+            let stats = self.window.stats();
+            if stats.received() > 0 && stats.avg() > max_rtt {
+                return Some(Alert::HighLatency {
+                    avg_rtt: stats.avg(),
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use iroh::{
+        endpoint::Connection,
+        protocol::{AcceptError, ProtocolHandler},
+        SecretKey,
+    };
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    /// A ping responder that waits before answering.
+    #[derive(Debug, Clone)]
+    struct Delayed {
+        ping: Ping,
+        delay_ms: Arc<AtomicU64>,
+    }
+
+    impl ProtocolHandler for Delayed {
+        async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+            let delay = Duration::from_millis(self.delay_ms.load(Ordering::Relaxed));
+            tokio::time::sleep(delay).await;
+            self.ping.accept(connection).await
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let mut state = MonitorState::new(MonitorConfig {
+            interval: ms(10),
+            max_rtt: Some(ms(100)),
+            max_consecutive_losses: Some(2),
+            window: 2,
+        });
+
+        assert_eq!(state.update(Some(ms(50))), None);
+        assert_eq!(
+            state.update(Some(ms(250))),
+            Some(MonitorEvent::Degraded(Alert::HighLatency {
+                avg_rtt: ms(150)
+            }))
+        );
+        // still degraded, no new event
+        assert_eq!(state.update(Some(ms(200))), None);
+        assert_eq!(state.update(Some(ms(10))), None);
+        assert_eq!(state.update(Some(ms(10))), Some(MonitorEvent::Recovered));
+
+        assert_eq!(state.update(None), None);
+        assert_eq!(
+            state.update(None),
+            Some(MonitorEvent::Degraded(Alert::ConsecutiveLosses {
+                count: 2
+            }))
+        );
+        assert_eq!(state.update(Some(ms(10))), Some(MonitorEvent::Recovered));
+    }
+
+    #[tokio::test]
+    async fn test_monitor_latency_alert_and_recovery() -> anyhow::Result<()> {
+        let delay_ms = Arc::new(AtomicU64::new(300));
+        let handler = Delayed {
+            ping: Ping::new(),
+            delay_ms: delay_ms.clone(),
+        };
+        let (_router, addr) = test_utils::local_router(ALPN_V1, handler).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let config = MonitorConfig {
+            interval: ms(50),
+            max_rtt: Some(ms(250)),
+            max_consecutive_losses: None,
+            window: 1,
+        };
+        let (mut monitor, mut events) = PingMonitor::new(Ping::new(), client, addr, config);
+        monitor.start();
+
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await?;
+        assert!(
+            matches!(
+                event,
+                Some(MonitorEvent::Degraded(Alert::HighLatency { .. }))
+            ),
+            "{event:?}"
+        );
+
+        delay_ms.store(0, Ordering::Relaxed);
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await?;
+        assert_eq!(event, Some(MonitorEvent::Recovered));
+
+        monitor.stop();
+        assert!(!monitor.is_running());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_monitor_loss_alert_and_drop() -> anyhow::Result<()> {
+        let client = test_utils::local_endpoint().await?;
+        let addr = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public());
+        let config = MonitorConfig {
+            interval: ms(10),
+            max_rtt: None,
+            max_consecutive_losses: Some(3),
+            window: 10,
+        };
+        let (mut monitor, mut events) = PingMonitor::new(Ping::new(), client, addr, config);
+        monitor.start();
+
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await?;
+        assert_eq!(
+            event,
+            Some(MonitorEvent::Degraded(Alert::ConsecutiveLosses {
+                count: 3
+            }))
+        );
+
+        // dropping the monitor stops the task, which closes the channel
+        drop(monitor);
+        let event = tokio::time::timeout(Duration::from_secs(10), events.recv()).await?;
+        assert_eq!(event, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use iroh::{endpoint::Connection, Endpoint, NodeAddr};
+use tokio::time::Instant;
+
+use crate::{exchange, Ping};
+
+/// Upper bound for the payloads tried by [`Ping::probe_max_payload`].
+const MAX_PROBE_PAYLOAD: usize = 64 * 1024;
+
+/// Smallest probe payload, just enough for the probe's sequence number.
+const MIN_PROBE_PAYLOAD: usize = 4;
+
+/// Size of the version byte and message tag preceding the payload of a datagram ping.
+pub(crate) const HEADER_LEN: usize = 5;
+
+/// How long to wait for the answer to a single datagram ping.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often a probe size is tried before it is considered too large, since datagrams
+/// may get lost for other reasons.
+const PROBE_ATTEMPTS: u32 = 2;
+
+impl Ping {
+    /// Find the largest payload a single datagram ping can carry to a node and back.
+    ///
+    /// Binary searches the payload size with unreliable QUIC datagrams, each of which has
+    /// to fit into a single packet, so the result reflects the effective path MTU, e.g.
+    /// whether the path goes through a relay. The search is capped by what the connection
+    /// currently allows to send. Does not close the endpoint.
+    pub async fn probe_max_payload(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> anyhow::Result<usize> {
+        let conn = endpoint.connect(addr, &self.alpn).await?;
+        let res = probe(&conn, self.max_version).await;
+        self.close_conn(&conn);
+        res
+    }
+}
+
+/// Binary searches the largest payload that round trips on `conn`.
+async fn probe(conn: &Connection, version: u8) -> anyhow::Result<usize> {
+    // Settle on a protocol version with a regular ping first.
+    let version = exchange(conn, &[], version).await?;
+    let max_datagram = conn
+        .max_datagram_size()
+        .context("datagrams are not supported on this connection")?;
+    let mut hi = max_datagram
+        .saturating_sub(HEADER_LEN)
+        .min(MAX_PROBE_PAYLOAD);
+    if hi < MIN_PROBE_PAYLOAD {
+        bail!("datagrams of {max_datagram} bytes are too small to probe");
+    }
+
+    let mut seq = 0u32;
+    let mut lo = MIN_PROBE_PAYLOAD;
+    if !try_payload(conn, version, &mut seq, lo).await? {
+        bail!("no datagram ping got through");
+    }
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if try_payload(conn, version, &mut seq, mid).await? {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Ok(lo)
+}
+
+/// Whether a datagram ping with a payload of `size` bytes gets answered.
+async fn try_payload(
+    conn: &Connection,
+    version: u8,
+    seq: &mut u32,
+    size: usize,
+) -> anyhow::Result<bool> {
+    for _ in 0..PROBE_ATTEMPTS {
+        *seq += 1;
+        let mut request = vec![0u8; HEADER_LEN + size];
+        request[0] = version;
+        request[1..HEADER_LEN].copy_from_slice(b"PING");
+        request[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&seq.to_be_bytes());
+        conn.send_datagram(request.clone().into())?;
+
+        // Answers to earlier, timed out probes may still trickle in, skip those.
+        let deadline = Instant::now() + PROBE_TIMEOUT;
+        loop {
+            let Ok(response) = tokio::time::timeout_at(deadline, conn.read_datagram()).await else {
+                break;
+            };
+            let response = response?;
+            if response.len() == request.len()
+                && response[0] == version
+                && &response[1..HEADER_LEN] == b"PONG"
+                && response[HEADER_LEN..] == request[HEADER_LEN..]
+            {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Answers datagram pings on `conn` until it closes.
+///
+/// A datagram ping is the same as a stream ping, but in a single datagram each way. There
+/// is no room for negotiation, so datagrams in versions we don't serve are answered with
+/// only our newest version byte.
+pub(crate) async fn echo_datagrams(ping: &Ping, conn: &Connection) {
+    let max_version = ping.max_version;
+    while let Ok(datagram) = conn.read_datagram().await {
+        let response = match datagram.split_first() {
+            Some((&version, rest)) if version != 0 && version <= max_version => {
+                let Some(payload) = rest.strip_prefix(b"PING") else {
+                    continue;
+                };
+                // datagrams have no way to refuse, pings over the rate limit are lost
+                if ping.admit().is_err() {
+                    continue;
+                }
+                let mut response = Vec::with_capacity(datagram.len());
+                response.push(version);
+                response.extend_from_slice(b"PONG");
+                response.extend_from_slice(payload);
+                response
+            }
+            Some(_) => vec![max_version],
+            None => continue,
+        };
+        // The answer may not fit into a datagram towards the client, in which case the
+        // ping is lost just as if the path had dropped it.
+        conn.send_datagram(response.into()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_probe_max_payload() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let size = Ping::new().probe_max_payload(&client, addr).await?;
+        // QUIC packets are at least 1200 bytes, minus some room for headers
+        assert!((1000..=MAX_PROBE_PAYLOAD).contains(&size), "{size}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use iroh::{Endpoint, NodeAddr};
+use tokio::task::JoinSet;
+
+use crate::{InFlightGuard, Ping, PingError};
+
+impl Ping {
+    /// Ping several nodes concurrently.
+    ///
+    /// Returns once every ping completed or failed, with the results in the same order as
+    /// `addrs`. Each ping is given at most `timeout` to complete.
+    pub async fn ping_all(
+        &self,
+        endpoint: &Endpoint,
+        addrs: Vec<NodeAddr>,
+        timeout: Duration,
+    ) -> Vec<(NodeAddr, Result<Duration, PingError>)> {
+        let mut tasks = self.spawn_pings(endpoint, &addrs, timeout);
+        let mut results: Vec<Option<Result<Duration, PingError>>> =
+            addrs.iter().map(|_| None).collect();
+        while let Some(res) = tasks.join_next().await {
+            // the tasks are never aborted, so this only fails if a ping panicked
+            let (i, res) = res.expect("ping task panicked");
+            results[i] = Some(res);
+        }
+
+        addrs
+            .into_iter()
+            .zip(results)
+            .map(|(addr, res)| (addr, res.expect("every ping completed")))
+            .collect()
+    }
+
+    /// Ping several nodes concurrently, returning the first one to respond.
+    ///
+    /// The remaining pings are cancelled as soon as one succeeds. If every ping fails, the
+    /// last error is returned.
+    pub async fn ping_fastest(
+        &self,
+        endpoint: &Endpoint,
+        addrs: Vec<NodeAddr>,
+        timeout: Duration,
+    ) -> Result<(NodeAddr, Duration), PingError> {
+        let mut tasks = self.spawn_pings(endpoint, &addrs, timeout);
+        let mut last_err = PingError::NoTargets;
+        while let Some(res) = tasks.join_next().await {
+            match res.expect("ping task panicked") {
+                (i, Ok(rtt)) => {
+                    // cancel the pings still in flight
+                    tasks.abort_all();
+                    return Ok((addrs[i].clone(), rtt));
+                }
+                (_, Err(err)) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Ping several nodes concurrently, returning the first one to respond.
+    ///
+    /// Like [`Ping::ping_fastest`], but each ping gets the configured timeout (see
+    /// [`Ping::with_timeout`]), and the pings still in flight have been torn down by the
+    /// time this returns. If every ping fails, all errors are returned together in
+    /// [`PingError::AllFailed`].
+    pub async fn ping_race(
+        &self,
+        endpoint: &Endpoint,
+        addrs: Vec<NodeAddr>,
+    ) -> Result<(NodeAddr, Duration), PingError> {
+        if addrs.is_empty() {
+            return Err(PingError::NoTargets);
+        }
+        let mut tasks = self.spawn_pings(endpoint, &addrs, self.timeout);
+        let mut errors = Vec::new();
+        while let Some(res) = tasks.join_next().await {
+            match res.expect("ping task panicked") {
+                (i, Ok(rtt)) => {
+                    // cancel the pings still in flight, and wait for them to be dropped so
+                    // their connections are closed
+                    tasks.abort_all();
+                    while tasks.join_next().await.is_some() {}
+                    return Ok((addrs[i].clone(), rtt));
+                }
+                (i, Err(err)) => errors.push((addrs[i].clone(), err)),
+            }
+        }
+        Err(PingError::AllFailed { errors })
+    }
+
+    /// Spawns one ping per address, each tagged with its index in `addrs`.
+    fn spawn_pings(
+        &self,
+        endpoint: &Endpoint,
+        addrs: &[NodeAddr],
+        timeout: Duration,
+    ) -> JoinSet<(usize, Result<Duration, PingError>)> {
+        let mut tasks = JoinSet::new();
+        for (i, addr) in addrs.iter().enumerate() {
+            let ping = self.clone();
+            let endpoint = endpoint.clone();
+            let addr = addr.clone();
+            tasks.spawn(async move {
+                let _in_flight = InFlightGuard::new(&ping.metrics.client_pings_in_flight);
+                let res = tokio::time::timeout(timeout, ping.ping_once(&endpoint, addr))
+                    .await
+                    .unwrap_or(Err(PingError::Timeout { timeout }));
+                (i, res)
+            });
+        }
+        tasks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::{
+        endpoint::Connection,
+        protocol::{AcceptError, ProtocolHandler},
+        SecretKey,
+    };
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    fn bogus_addr() -> NodeAddr {
+        NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public())
+    }
+
+    /// A server that accepts connections, but never answers a ping.
+    #[derive(Debug, Clone)]
+    struct Mute;
+
+    impl ProtocolHandler for Mute {
+        async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+            connection.closed().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_all() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let bogus = bogus_addr();
+        let results = Ping::new()
+            .ping_all(
+                &client,
+                vec![bogus.clone(), addr.clone()],
+                Duration::from_secs(5),
+            )
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, bogus);
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].0, addr);
+        assert!(results[1].1.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_race() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new().with_timeout(Duration::from_secs(5));
+        let (winner, _rtt) = ping
+            .ping_race(&client, vec![bogus_addr(), addr.clone(), bogus_addr()])
+            .await?;
+        assert_eq!(winner, addr);
+        // the losing pings are gone already
+        assert_eq!(ping.metrics().client_pings_in_flight.get(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_race_all_failed() -> anyhow::Result<()> {
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new().with_timeout(Duration::from_secs(1));
+        let bogus = [bogus_addr(), bogus_addr()];
+        let err = ping.ping_race(&client, bogus.to_vec()).await.unwrap_err();
+        let PingError::AllFailed { errors } = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|(addr, _)| bogus.contains(addr)));
+
+        let err = ping.ping_race(&client, vec![]).await.unwrap_err();
+        assert!(matches!(err, PingError::NoTargets), "{err:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_pings_in_flight() -> anyhow::Result<()> {
+        let (_first, first) = test_utils::local_router(ALPN_V1, Mute).await?;
+        let (_second, second) = test_utils::local_router(ALPN_V1, Mute).await?;
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new();
+        let (results, in_flight) = tokio::join!(
+            ping.ping_all(&client, vec![first, second], Duration::from_secs(1)),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                ping.metrics().client_pings_in_flight.get()
+            }
+        );
+        assert_eq!(results.len(), 2);
+        assert_eq!(in_flight, 2);
+        assert_eq!(ping.metrics().client_pings_in_flight.get(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_fastest() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let (winner, _rtt) = Ping::new()
+            .ping_fastest(
+                &client,
+                vec![bogus_addr(), addr.clone(), bogus_addr()],
+                Duration::from_secs(5),
+            )
+            .await?;
+        assert_eq!(winner, addr);
+
+        let err = Ping::new()
+            .ping_fastest(&client, vec![], Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::NoTargets), "{err:?}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use iroh::NodeId;
+use opentelemetry::{
+    global::{self, BoxedSpan, BoxedTracer},
+    trace::{Span, Status, Tracer},
+    KeyValue,
+};
+
+use crate::{Ping, PingError, PingResult};
+
+impl Ping {
+    /// Record a span named `iroh.ping` with `tracer` for every ping, to export it to any
+    /// OpenTelemetry backend.
+    ///
+    /// Spans carry the node pinged as `peer.id`, its IP as `net.peer.ip` if the ping went
+    /// over a direct path, and the round trip time as `rtt_us`. Failed pings get an error
+    /// status. Independently of the tracer, answered pings are counted by `iroh.ping.sent`
+    /// and their round trip times go into the `iroh.ping.rtt` histogram, in seconds, both
+    /// on the global meter.
+    pub fn with_otel_tracer<T>(mut self, tracer: T) -> Self
+    where
+        T: Tracer + Send + Sync + 'static,
+        T::Span: Send + Sync + 'static,
+    {
+        self.otel_tracer = Some(Arc::new(BoxedTracer::new(Box::new(tracer))));
+        self
+    }
+}
+
+/// The OpenTelemetry span of a single ping, see [`Ping::with_otel_tracer`].
+pub(crate) struct PingSpan(Option<BoxedSpan>);
+
+impl PingSpan {
+    /// Starts the span of a ping to `node_id`, if `ping` has a tracer.
+    pub(crate) fn start(ping: &Ping, node_id: NodeId) -> Self {
+        Self(ping.otel_tracer.as_ref().map(|tracer| {
+            let mut span = tracer.start("iroh.ping");
+            span.set_attribute(KeyValue::new("peer.id", node_id.to_string()));
+            span
+        }))
+    }
+
+    /// Ends the span with the outcome of the ping, and records it on the global meter.
+    pub(crate) fn end(self, res: Result<&PingResult, &PingError>) {
+        if let Ok(res) = res {
+            let meter = global::meter("iroh-ping");
+            meter
+                .u64_counter("iroh.ping.sent")
+                .with_description("pings answered")
+                .build()
+                .add(1, &[]);
+            meter
+                .f64_histogram("iroh.ping.rtt")
+                .with_description("round trip times of pings")
+                .with_unit("s")
+                .build()
+                .record(res.ping_time.as_secs_f64(), &[]);
+        }
+        let Some(mut span) = self.0 else {
+            return;
+        };
+        match res {
+            Ok(res) => {
+                if let Some(addr) = res.remote_addr {
+                    span.set_attribute(KeyValue::new("net.peer.ip", addr.ip().to_string()));
+                }
+                let rtt_us = res.ping_time.as_micros() as i64;
+                span.set_attribute(KeyValue::new("rtt_us", rtt_us));
+            }
+            Err(err) => span.set_status(Status::error(err.to_string())),
+        }
+        span.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{trace::TracerProvider, Value};
+    use opentelemetry_sdk::{
+        metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider},
+        trace::{InMemorySpanExporter, SdkTracerProvider},
+    };
+
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_otel() -> anyhow::Result<()> {
+        let spans = InMemorySpanExporter::default();
+        let tracer = SdkTracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build()
+            .tracer("test");
+        let metrics = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metrics.clone()).build())
+            .build();
+        global::set_meter_provider(meter_provider.clone());
+
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let node_id = addr.node_id;
+        let ping = Ping::new().with_otel_tracer(tracer);
+        ping.ping_once(&client, addr).await?;
+
+        let spans = spans.get_finished_spans()?;
+        let [span] = spans.as_slice() else {
+            panic!("one span per ping: {spans:?}");
+        };
+        assert_eq!(span.name, "iroh.ping");
+        assert_eq!(span.status, Status::Unset);
+        let attribute = |key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attribute("peer.id"), Some(Value::from(node_id.to_string())));
+        assert_eq!(attribute("net.peer.ip"), Some(Value::from("127.0.0.1")));
+        assert!(matches!(attribute("rtt_us"), Some(Value::I64(rtt_us)) if rtt_us > 0));
+
+        meter_provider.force_flush()?;
+        let metrics = metrics.get_finished_metrics()?;
+        let names: Vec<_> = metrics
+            .iter()
+            .flat_map(|metrics| metrics.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .map(|metric| metric.name().to_string())
+            .collect();
+        assert!(
+            names.iter().any(|name| name == "iroh.ping.sent"),
+            "{names:?}"
+        );
+        assert!(
+            names.iter().any(|name| name == "iroh.ping.rtt"),
+            "{names:?}"
+        );
+
+        Ok(())
+    }
+}
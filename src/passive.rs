@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use iroh::{endpoint::ConnectionType, Endpoint, NodeId};
+
+use crate::Ping;
+
+impl Ping {
+    /// The endpoint's current latency estimate for a node, together with the type of path
+    /// it applies to.
+    ///
+    /// This reads what the endpoint already knows from the traffic it exchanges with the
+    /// node, without sending anything, so it is cheap enough to call between active pings.
+    /// Returns `None` if there is no path to the node, or no estimate for it yet.
+    pub fn latest_rtt(endpoint: &Endpoint, node_id: NodeId) -> Option<(Duration, ConnectionType)> {
+        let info = endpoint.remote_info(node_id)?;
+        if matches!(info.conn_type, ConnectionType::None) {
+            return None;
+        }
+        Some((info.latency?, info.conn_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_latest_rtt() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        Ping::new().ping_n(&client, addr.clone(), 1).await?;
+
+        let (rtt, _conn_type) =
+            Ping::latest_rtt(&client, addr.node_id).expect("a latency estimate");
+        assert!(rtt < Duration::from_secs(10));
+
+        let bogus = SecretKey::generate(rand::rngs::OsRng).public();
+        assert!(Ping::latest_rtt(&client, bogus).is_none());
+
+        Ok(())
+    }
+}
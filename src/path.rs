@@ -0,0 +1,179 @@
+use iroh::{endpoint::ConnectionType, Endpoint, NodeAddr, NodeId, Watcher};
+
+use crate::{Ping, PingError, PingResult};
+
+/// Which kind of path [`Ping::ping_via`] should ping over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathPreference {
+    /// whatever path the endpoint picks
+    #[default]
+    Any,
+    /// Dial through the node's relay only.
+    ///
+    /// The endpoint may still upgrade the connection to a direct path while pinging, the
+    /// result tells which path was actually used.
+    RelayOnly,
+    /// Only ping once a direct path to the node is confirmed.
+    DirectOnly,
+}
+
+/// The kind of path a ping took, see [`PingResult::path_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PathType {
+    /// straight over UDP
+    Direct,
+    /// through the relay
+    Relay,
+    /// through the relay while a direct path is being tried
+    Mixed,
+    /// the endpoint had no path information for the node (anymore)
+    Unknown,
+}
+
+impl From<Option<ConnectionType>> for PathType {
+    fn from(path: Option<ConnectionType>) -> Self {
+        match path {
+            Some(ConnectionType::Direct(_)) => Self::Direct,
+            Some(ConnectionType::Relay(_)) => Self::Relay,
+            Some(ConnectionType::Mixed(..)) => Self::Mixed,
+            Some(ConnectionType::None) | None => Self::Unknown,
+        }
+    }
+}
+
+impl Ping {
+    /// Send a ping over a particular kind of path.
+    ///
+    /// Dials the node only at the parts of `addr` matching `path`. Fails with
+    /// [`PingError::PathUnavailable`] if `addr` has no such parts, or if a direct path is
+    /// required but can't be confirmed within the timeout (see [`Ping::with_timeout`]). Note
+    /// that the endpoint may know further addresses of the node from earlier contact. The
+    /// result reports the path the ping took. Does not close the endpoint.
+    pub async fn ping_via(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        path: PathPreference,
+    ) -> Result<PingResult, PingError> {
+        let unavailable = || PingError::PathUnavailable { path };
+        let addr = match path {
+            PathPreference::Any => addr,
+            PathPreference::RelayOnly => {
+                let relay_url = addr.relay_url.ok_or_else(unavailable)?;
+                NodeAddr::from_parts(addr.node_id, Some(relay_url), [])
+            }
+            PathPreference::DirectOnly => {
+                if addr.direct_addresses.is_empty() {
+                    return Err(unavailable());
+                }
+                NodeAddr::from_parts(addr.node_id, None, addr.direct_addresses)
+            }
+        };
+        match self.ping_conn_via(endpoint, addr, path).await {
+            Ok((res, conn)) => {
+                self.close_conn(&conn);
+                Ok(res)
+            }
+            Err(PingError::Timeout { .. }) if path == PathPreference::DirectOnly => {
+                Err(unavailable())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Waits until the endpoint confirmed a direct path to the node.
+pub(crate) async fn wait_for_direct(endpoint: &Endpoint, node_id: NodeId) -> Result<(), PingError> {
+    let unavailable = || PingError::PathUnavailable {
+        path: PathPreference::DirectOnly,
+    };
+    let mut conn_type = endpoint.conn_type(node_id).ok_or_else(unavailable)?;
+    let mut current = conn_type.get().map_err(|_| unavailable())?;
+    while !matches!(current, ConnectionType::Direct(_)) {
+        current = conn_type.updated().await.map_err(|_| unavailable())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, net::SocketAddr, time::Duration};
+
+    use iroh::{protocol::Router, RelayMode};
+
+    use super::*;
+    use crate::ALPN_V1;
+
+    #[tokio::test]
+    async fn test_ping_via_direct() -> anyhow::Result<()> {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = ep.node_id();
+        let addrs: Vec<_> = ep
+            .bound_sockets()
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()))
+            .collect();
+        let _router = Router::builder(ep).accept(ALPN_V1, Ping::new()).spawn();
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let ping = Ping::new().with_timeout(Duration::from_secs(5));
+        let addr = NodeAddr::from_parts(node_id, None, addrs.clone());
+        let res = ping
+            .ping_via(&client, addr.clone(), PathPreference::DirectOnly)
+            .await?;
+        assert!(!res.relayed);
+        assert_eq!(res.path_type, PathType::Direct);
+        assert!(addrs.contains(&res.remote_addr.expect("a direct path")));
+
+        // there is no relay to go through
+        let err = ping
+            .ping_via(&client, addr, PathPreference::RelayOnly)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::PathUnavailable {
+                    path: PathPreference::RelayOnly
+                }
+            ),
+            "{err:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_path_type() -> anyhow::Result<()> {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let node_id = ep.node_id();
+        let addrs = ep
+            .bound_sockets()
+            .into_iter()
+            .filter(SocketAddr::is_ipv4)
+            .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()));
+        let addr = NodeAddr::from_parts(node_id, None, addrs);
+        let _router = Router::builder(ep).accept(ALPN_V1, Ping::new()).spawn();
+
+        let client = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr).await?;
+        assert_ne!(res.path_type, PathType::Unknown);
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+}
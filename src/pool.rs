@@ -0,0 +1,276 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use iroh::{endpoint::Connection, Endpoint, NodeAddr, NodeId};
+
+use crate::{CloseCode, Ping, PingError};
+
+/// Keeps connections around between pings, so only the first ping to a node pays for the
+/// handshake.
+///
+/// Cloning the pool shares its connections. Hits and misses are counted in
+/// [`Metrics::pool_hits`](crate::Metrics::pool_hits) and
+/// [`Metrics::pool_misses`](crate::Metrics::pool_misses).
+///
+/// Close the pool with [`PingPool::close_all`] when done. If the last clone is dropped
+/// with connections still in it, they are closed with [`CloseCode::Ok`] and a warning is
+/// logged for each, since that is usually a missed shutdown.
+#[derive(Debug, Clone)]
+pub struct PingPool {
+    ping: Ping,
+    max_idle_per_peer: usize,
+    idle_timeout: Duration,
+    idle: Arc<Idle>,
+}
+
+/// The idle connections shared by a pool and its clones.
+#[derive(Debug, Default)]
+struct Idle {
+    conns: DashMap<NodeId, Vec<IdleConn>>,
+}
+
+impl Drop for Idle {
+    fn drop(&mut self) {
+        for (node_id, conns) in std::mem::take(&mut self.conns) {
+            for IdleConn { conn, .. } in conns {
+                if conn.close_reason().is_none() {
+                    tracing::warn!(peer = %node_id, "pool dropped with an open connection");
+                    CloseCode::Ok.close(&conn, b"dropped");
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IdleConn {
+    conn: Connection,
+    since: Instant,
+}
+
+impl PingPool {
+    /// Create a pool keeping up to `max_idle_per_peer` connections per node, each for at
+    /// most `idle_timeout` between pings.
+    pub fn new(max_idle_per_peer: usize, idle_timeout: Duration) -> Self {
+        Self {
+            ping: Ping::new(),
+            max_idle_per_peer,
+            idle_timeout,
+            idle: Default::default(),
+        }
+    }
+
+    /// send the pings with the given [`Ping`], and count them in its metrics
+    pub fn with_ping(mut self, ping: Ping) -> Self {
+        self.ping = ping;
+        self
+    }
+
+    /// the [`Ping`] sending the pings
+    pub fn ping_client(&self) -> &Ping {
+        &self.ping
+    }
+
+    /// Ping a node, over an idle connection to it if there is one.
+    ///
+    /// Returns the round trip time of the ping exchange, without the handshake of a new
+    /// connection. On success the connection goes back to the pool, if there is room for
+    /// it. A pooled connection that turns out to be gone, e.g. because the node closed it
+    /// meanwhile, is dropped from the pool and the node dialed again, once. Any other
+    /// connection that fails a ping is closed, and the error returned. Does not close the
+    /// endpoint.
+    pub async fn ping(&self, endpoint: &Endpoint, addr: NodeAddr) -> Result<Duration, PingError> {
+        let node_id = addr.node_id;
+        if let Some(conn) = self.checkout(node_id) {
+            self.ping.metrics.pool_hits.inc();
+            match self.ping.ping_on_conn(&conn).await {
+                Ok(rtt) => {
+                    self.checkin(node_id, conn);
+                    return Ok(rtt);
+                }
+                Err(err) if !is_stale(&conn) => {
+                    self.ping.close_conn(&conn);
+                    return Err(err);
+                }
+                Err(err) => {
+                    tracing::debug!(peer = %node_id, %err, "evicting stale connection");
+                }
+            }
+        }
+        self.ping.metrics.pool_misses.inc();
+        let (res, conn, _stats) = self.ping.ping_keep(endpoint, addr).await?;
+        self.checkin(node_id, conn);
+        Ok(res.ping_time)
+    }
+
+    /// Close all pooled connections.
+    pub async fn close_all(&self) {
+        let mut idle = Vec::new();
+        self.idle.conns.retain(|_, conns| {
+            idle.append(conns);
+            false
+        });
+        for IdleConn { conn, .. } in &idle {
+            self.ping.close_conn(conn);
+        }
+        for IdleConn { conn, .. } in idle {
+            conn.closed().await;
+        }
+    }
+
+    /// Number of idle connections to a node.
+    pub fn idle_count(&self, node_id: NodeId) -> usize {
+        self.idle.conns.get(&node_id).map_or(0, |conns| conns.len())
+    }
+
+    /// Takes the most recently used healthy connection to a node out of the pool, closing
+    /// the ones that idled for too long.
+    fn checkout(&self, node_id: NodeId) -> Option<Connection> {
+        let mut found = None;
+        {
+            let mut conns = self.idle.conns.get_mut(&node_id)?;
+            while let Some(IdleConn { conn, since }) = conns.pop() {
+                if conn.close_reason().is_some() {
+                    continue;
+                }
+                if since.elapsed() > self.idle_timeout {
+                    // older ones idled even longer
+                    for IdleConn { conn: older, .. } in conns.drain(..) {
+                        self.ping.close_conn(&older);
+                    }
+                    self.ping.close_conn(&conn);
+                    break;
+                }
+                found = Some(conn);
+                break;
+            }
+        }
+        self.idle
+            .conns
+            .remove_if(&node_id, |_, conns| conns.is_empty());
+        found
+    }
+
+    /// Puts a connection back into the pool, or closes it if there is no room.
+    fn checkin(&self, node_id: NodeId, conn: Connection) {
+        {
+            let mut conns = self.idle.conns.entry(node_id).or_default();
+            if conns.len() < self.max_idle_per_peer {
+                conns.push(IdleConn {
+                    conn,
+                    since: Instant::now(),
+                });
+                return;
+            }
+        }
+        self.ping.close_conn(&conn);
+        self.idle
+            .conns
+            .remove_if(&node_id, |_, conns| conns.is_empty());
+    }
+}
+
+/// Whether a pooled connection failed a ping because the connection itself is gone,
+/// rather than just the ping.
+fn is_stale(conn: &Connection) -> bool {
+    conn.close_reason().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, Logs};
+
+    #[tokio::test]
+    async fn test_ping_pool() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let pool = PingPool::new(2, Duration::from_secs(60));
+        for _ in 0..3 {
+            pool.ping(&client, addr.clone()).await?;
+        }
+        let metrics = pool.ping_client().metrics();
+        assert_eq!(metrics.pool_misses.get(), 1);
+        assert_eq!(metrics.pool_hits.get(), 2);
+        assert_eq!(pool.idle_count(addr.node_id), 1);
+
+        pool.close_all().await;
+        assert_eq!(pool.idle_count(addr.node_id), 0);
+        pool.ping(&client, addr.clone()).await?;
+        assert_eq!(metrics.pool_misses.get(), 2);
+
+        // connections that idled too long are not reused
+        let pool = PingPool::new(2, Duration::ZERO);
+        pool.ping(&client, addr.clone()).await?;
+        pool.ping(&client, addr.clone()).await?;
+        assert_eq!(pool.ping_client().metrics().pool_misses.get(), 2);
+
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pool_stale() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let pool = PingPool::new(2, Duration::from_secs(60));
+        pool.ping(&client, addr.clone()).await?;
+
+        // have the server close the pooled connection, by asking for version 0
+        let conn = pool.checkout(addr.node_id).expect("pooled");
+        let (mut send, _recv) = conn.open_bi().await?;
+        send.write_all(&[0]).await?;
+        send.finish()?;
+        pool.checkin(addr.node_id, conn.clone());
+        conn.closed().await;
+        assert!(pool.ping_client().ping_on_conn(&conn).await.is_err());
+        assert!(is_stale(&conn));
+
+        // the stale connection is dropped, and the node dialed again
+        pool.ping(&client, addr.clone()).await?;
+        let metrics = pool.ping_client().metrics();
+        assert_eq!(metrics.pool_misses.get(), 2);
+        assert_eq!(pool.idle_count(addr.node_id), 1);
+
+        pool.close_all().await;
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_pool_dropped() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let (logs, _guard) = Logs::capture();
+
+        let pool = PingPool::new(2, Duration::from_secs(60));
+        pool.ping(&client, addr.clone()).await?;
+        pool.close_all().await;
+        drop(pool);
+        assert!(
+            !logs.contents().contains("pool dropped"),
+            "{}",
+            logs.contents()
+        );
+
+        let pool = PingPool::new(2, Duration::from_secs(60));
+        pool.ping(&client, addr.clone()).await?;
+        let conn = pool.checkout(addr.node_id).expect("pooled");
+        pool.checkin(addr.node_id, conn.clone());
+        // only the last clone closes the connections
+        drop(pool.clone());
+        assert!(conn.close_reason().is_none());
+        drop(pool);
+        let logs = logs.contents();
+        assert!(
+            logs.contains("pool dropped with an open connection"),
+            "{logs}"
+        );
+        assert!(logs.contains(&addr.node_id.to_string()), "{logs}");
+        assert!(conn.close_reason().is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::Ping;
+
+/// Token bucket limiting how often pings are sent.
+///
+/// Tokens refill continuously at `rate` per second, up to `capacity`. Taking a token from
+/// an empty bucket reserves the next one to refill, so concurrent callers queue up in the
+/// order they asked.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// may be negative when tokens are reserved ahead of time
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub(crate) fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token at `now`, returning how long to wait until it is available.
+    fn reserve_at(&self, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("poisoned");
+        let refill = now.saturating_duration_since(state.refilled).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.capacity) - 1.0;
+        state.refilled = now.max(state.refilled);
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+
+    /// Takes a token at `now` if there is one, or returns how long until there is, without
+    /// reserving it.
+    fn try_take_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("poisoned");
+        let refill = now.saturating_duration_since(state.refilled).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.capacity);
+        state.refilled = now.max(state.refilled);
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+        }
+    }
+
+    /// Takes a token now if there is one, or returns how long until there is.
+    pub(crate) fn try_take(&self) -> Result<(), Duration> {
+        self.try_take_at(Instant::now())
+    }
+
+    /// Waits for a token, returning how long that took.
+    pub(crate) async fn acquire(&self) -> Duration {
+        let wait = self.reserve_at(Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        wait
+    }
+}
+
+impl Ping {
+    /// limit how many pings this instance sends per second, across all its clones
+    ///
+    /// Pings beyond the limit are delayed rather than dropped, without the delay counting
+    /// towards their round trip times. Bursts are smoothed out to evenly spaced pings.
+    ///
+    /// # Panics
+    ///
+    /// If `max_pings_per_second` is zero.
+    pub fn with_max_pings_per_second(mut self, max_pings_per_second: u32) -> Self {
+        assert!(max_pings_per_second > 0, "the rate limit must be positive");
+        let bucket = TokenBucket::new(max_pings_per_second.into(), 1.0);
+        self.rate_limit = Some(Arc::new(bucket));
+        self
+    }
+
+    /// Limit how many pings this instance answers per second as a server, across all
+    /// connections and clones.
+    ///
+    /// Pings beyond the limit are refused right away, whichever way they arrive. Clients
+    /// speaking protocol version 5 or later get a [`PingError::RequestRejected`] telling
+    /// them when to retry, with the connection left open, older ones get the stream reset.
+    /// A ping stream is reset once one of its pings is over the limit, older clients get
+    /// no answer to a ping on a unidirectional stream, and datagram pings over the limit
+    /// are dropped.
+    ///
+    /// # Panics
+    ///
+    /// If `max_pings_per_second` is zero.
+    ///
+    /// [`PingError::RequestRejected`]: crate::PingError::RequestRejected
+    pub fn with_server_rate_limit(mut self, max_pings_per_second: u32) -> Self {
+        assert!(max_pings_per_second > 0, "the rate limit must be positive");
+        let rate = f64::from(max_pings_per_second);
+        self.server_rate_limit = Some(Arc::new(TokenBucket::new(rate, rate)));
+        self
+    }
+
+    /// Whether the server rate limit allows answering another ping, or how long until it
+    /// does.
+    pub(crate) fn admit(&self) -> Result<(), Duration> {
+        match &self.server_rate_limit {
+            Some(bucket) => bucket.try_take(),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until the rate limit allows another ping, returning how long that took.
+    pub(crate) async fn wait_for_turn(&self) -> Duration {
+        let Some(bucket) = &self.rate_limit else {
+            return Duration::ZERO;
+        };
+        let wait = bucket.acquire().await;
+        self.metrics
+            .rate_limit_wait_us
+            .inc_by(wait.as_micros() as u64);
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::{test_utils, PingError, RejectReason, ALPN_V1, ERROR_VERSION};
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(5.0, 2.0);
+        let start = bucket.state.lock().unwrap().refilled;
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // the full bucket allows a burst of two
+        assert_eq!(bucket.reserve_at(at(0)), Duration::ZERO);
+        assert_eq!(bucket.reserve_at(at(0)), Duration::ZERO);
+        // then tokens come every 200ms, queued callers wait for their own one
+        assert_eq!(bucket.reserve_at(at(0)), Duration::from_millis(200));
+        assert_eq!(bucket.reserve_at(at(0)), Duration::from_millis(400));
+        assert_eq!(bucket.reserve_at(at(500)), Duration::from_millis(100));
+        // waiting long refills the bucket, but not beyond its capacity
+        assert_eq!(bucket.reserve_at(at(10_000)), Duration::ZERO);
+        assert_eq!(bucket.reserve_at(at(10_000)), Duration::ZERO);
+        assert_eq!(bucket.reserve_at(at(10_000)), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_token_bucket_try_take() {
+        let bucket = TokenBucket::new(5.0, 2.0);
+        let start = bucket.state.lock().unwrap().refilled;
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(bucket.try_take_at(at(0)), Ok(()));
+        assert_eq!(bucket.try_take_at(at(0)), Ok(()));
+        // refused callers don't queue up, they all wait for the same token
+        assert_eq!(bucket.try_take_at(at(0)), Err(Duration::from_millis(200)));
+        assert_eq!(bucket.try_take_at(at(0)), Err(Duration::from_millis(200)));
+        assert_eq!(bucket.try_take_at(at(100)), Err(Duration::from_millis(100)));
+        assert_eq!(bucket.try_take_at(at(200)), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_server_rate_limit() -> anyhow::Result<()> {
+        let server = Ping::new().with_server_rate_limit(5);
+        let (router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        // the server answers a burst of up to a second's worth of pings
+        for _ in 0..5 {
+            ping.ping_on_conn(&conn).await?;
+        }
+        let err = ping.ping_on_conn(&conn).await.unwrap_err();
+        let PingError::RequestRejected {
+            reason: RejectReason::RateLimited,
+            retry_after: Some(retry_after),
+        } = err
+        else {
+            panic!("{err:?}");
+        };
+        assert!(retry_after <= Duration::from_millis(200), "{retry_after:?}");
+
+        // older clients just get the stream reset
+        let old = Ping::new().with_max_version(ERROR_VERSION - 1);
+        let err = old.ping_on_conn(&conn).await.unwrap_err();
+        assert!(matches!(err, PingError::Read { .. }), "{err:?}");
+
+        // the connection stays usable for when the limit allows another ping
+        tokio::time::sleep(retry_after).await;
+        ping.ping_on_conn(&conn).await?;
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_rate_limit_streams() -> anyhow::Result<()> {
+        let server = Ping::new().with_server_rate_limit(2);
+        let (router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+
+        // pings on a unidirectional stream are limited too
+        ping.ping_uni(&conn).await?;
+        ping.ping_uni(&conn).await?;
+        let err = ping.ping_uni(&conn).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::RequestRejected {
+                    reason: RejectReason::RateLimited,
+                    retry_after: Some(_),
+                }
+            ),
+            "{err:?}"
+        );
+
+        // and so are the pings on a ping stream, which ends the stream
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut stream = ping.open_stream(&conn).await?;
+        stream.ping().await?;
+        stream.ping().await?;
+        let err = stream.ping().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                PingError::RequestRejected {
+                    reason: RejectReason::RateLimited,
+                    retry_after: None,
+                }
+            ),
+            "{err:?}"
+        );
+        drop(stream);
+
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_cancellable() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new().with_max_pings_per_second(5);
+        let cancel = CancellationToken::new();
+        let start = Instant::now();
+        for _ in 0..3 {
+            ping.ping_cancellable(&client, addr.clone(), &cancel)
+                .await?;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+
+        // cancelling also ends the wait for the rate limit
+        cancel.cancel();
+        let err = ping
+            .ping_cancellable(&client, addr, &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::Cancelled), "{err:?}");
+
+        client.close().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new().with_max_pings_per_second(5);
+        let start = Instant::now();
+        let stats = ping.ping_n_on_conn(&conn, 10).await?;
+        let elapsed = start.elapsed();
+        assert_eq!(stats.received(), 10);
+        // the first ping goes out right away, every further one 200ms later
+        assert!(elapsed >= Duration::from_millis(1800), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+        // the waiting is recorded apart from the round trip times
+        let waited = ping.metrics().rate_limit_wait_us.get();
+        assert!(waited >= 1_000_000, "{waited}");
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{PathType, PingStats};
+
+/// How many pings may be buffered before a [`PingRecorder`] flushes them to its file.
+const FLUSH_EVERY: usize = 16;
+
+/// How long pings may sit in the buffer of a [`PingRecorder`] before it flushes them.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A single ping as written by a [`PingRecorder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedPing {
+    /// when the ping was recorded, with microsecond precision
+    pub at: SystemTime,
+    /// sequence number of the ping within its run
+    pub seq: u64,
+    /// round trip time of the ping, with microsecond precision, or why it failed
+    pub rtt: Result<Duration, String>,
+    /// the path to the node right after the ping
+    pub path: PathType,
+}
+
+impl RecordedPing {
+    /// Encodes the ping as a single line, without the line break.
+    ///
+    /// Fields are separated by tabs: the time in microseconds since the unix epoch, the
+    /// sequence number, the path, and then either `ok` and the round trip time in
+    /// microseconds, or `err` and the error message.
+    fn to_line(&self) -> String {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let path = match self.path {
+            PathType::Direct => "direct",
+            PathType::Relay => "relay",
+            PathType::Mixed => "mixed",
+            PathType::Unknown => "unknown",
+        };
+        match &self.rtt {
+            Ok(rtt) => format!("{at}\t{}\t{path}\tok\t{}", self.seq, rtt.as_micros()),
+            Err(err) => {
+                // keep the message on its line, and in its field
+                let err = err.replace(['\t', '\n', '\r'], " ");
+                format!("{at}\t{}\t{path}\terr\t{err}", self.seq)
+            }
+        }
+    }
+
+    /// Decodes a line written by [`RecordedPing::to_line`].
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, '\t');
+        let at = UNIX_EPOCH + Duration::from_micros(fields.next()?.parse().ok()?);
+        let seq = fields.next()?.parse().ok()?;
+        let path = match fields.next()? {
+            "direct" => PathType::Direct,
+            "relay" => PathType::Relay,
+            "mixed" => PathType::Mixed,
+            "unknown" => PathType::Unknown,
+            _ => return None,
+        };
+        let rtt = match (fields.next()?, fields.next()?) {
+            ("ok", rtt) => Ok(Duration::from_micros(rtt.parse().ok()?)),
+            ("err", err) => Err(err.to_string()),
+            _ => return None,
+        };
+        Some(Self { at, seq, rtt, path })
+    }
+}
+
+/// Appends pings to a file, one line each, to analyze them later with [`PingRecording`].
+///
+/// Clones write to the same file. Writes are buffered and flushed every few pings, or
+/// once a second, so a crash loses little. Attach a recorder to a run through
+/// [`PingManyOpts::recorder`](crate::PingManyOpts::recorder), or record pings yourself.
+#[derive(Debug, Clone)]
+pub struct PingRecorder {
+    inner: Arc<Mutex<RecorderState>>,
+}
+
+#[derive(Debug)]
+struct RecorderState {
+    file: BufWriter<File>,
+    unflushed: usize,
+    last_flush: Instant,
+    /// the first write error, kept for [`PingRecorder::finish`]
+    error: Option<io::Error>,
+}
+
+impl PingRecorder {
+    /// Open a file for recording, appending to it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RecorderState {
+                file: BufWriter::new(file),
+                unflushed: 0,
+                last_flush: Instant::now(),
+                error: None,
+            })),
+        })
+    }
+
+    /// Record a ping.
+    ///
+    /// Failing to write doesn't stop anything, the error is reported by
+    /// [`PingRecorder::finish`] instead.
+    pub fn record(&self, ping: &RecordedPing) {
+        let mut state = self.inner.lock().expect("poisoned");
+        if state.error.is_some() {
+            return;
+        }
+        let mut res = writeln!(state.file, "{}", ping.to_line());
+        state.unflushed += 1;
+        if res.is_ok()
+            && (state.unflushed >= FLUSH_EVERY || state.last_flush.elapsed() >= FLUSH_INTERVAL)
+        {
+            res = state.flush();
+        }
+        if let Err(err) = res {
+            state.error = Some(err);
+        }
+    }
+
+    /// Flush what was recorded so far, and report the first error while recording, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.inner.lock().expect("poisoned");
+        match state.error.take() {
+            Some(err) => Err(err),
+            None => state.flush(),
+        }
+    }
+}
+
+impl RecorderState {
+    fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        self.file.flush()
+    }
+}
+
+/// Pings loaded back from a file written by a [`PingRecorder`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PingRecording {
+    /// the recorded pings, in the order they were written
+    pub pings: Vec<RecordedPing>,
+    /// Line numbers, starting from 1, of the lines that could not be read.
+    ///
+    /// A recording cut short, e.g. by a crash, may end in a partial line.
+    pub skipped_lines: Vec<usize>,
+}
+
+impl PingRecording {
+    /// Read a recording, skipping lines that can't be decoded.
+    ///
+    /// A warning is logged for every skipped line, see also
+    /// [`PingRecording::skipped_lines`]. Fails only if the file can't be read.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        let mut recording = Self::default();
+        for (i, line) in file.split(b'\n').enumerate() {
+            let line = line?;
+            let ping = std::str::from_utf8(&line)
+                .ok()
+                .and_then(RecordedPing::from_line);
+            match ping {
+                Some(ping) => recording.pings.push(ping),
+                None => {
+                    tracing::warn!(line = i + 1, path = %path.display(), "skipping malformed line");
+                    recording.skipped_lines.push(i + 1);
+                }
+            }
+        }
+        Ok(recording)
+    }
+
+    /// Statistics over the recorded pings.
+    pub fn summary(&self) -> PingStats {
+        let mut stats = PingStats::with_samples();
+        for ping in &self.pings {
+            match ping.rtt {
+                Ok(rtt) => stats.record_rtt(rtt),
+                Err(_) => stats.record_loss(),
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::test_utils::Logs;
+
+    /// A file in the temp dir, removed again when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("iroh-ping-{}-{name}.txt", std::process::id()));
+            std::fs::remove_file(&path).ok();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn pings(n: u64) -> Vec<RecordedPing> {
+        let paths = [
+            PathType::Direct,
+            PathType::Relay,
+            PathType::Mixed,
+            PathType::Unknown,
+        ];
+        (0..n)
+            .map(|seq| RecordedPing {
+                at: UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_000 + seq * 1_000_000),
+                seq,
+                rtt: if seq % 5 == 4 {
+                    Err(format!("ping timed out\tafter {seq}s\n"))
+                } else {
+                    Ok(Duration::from_micros(10_000 + seq * 123))
+                },
+                path: paths[seq as usize % paths.len()],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_record_and_load() -> anyhow::Result<()> {
+        let file = TempFile::new("record");
+        let recorder = PingRecorder::create(&file.0)?;
+        let pings = pings(20);
+        for ping in &pings {
+            recorder.record(ping);
+        }
+        recorder.finish()?;
+
+        let recording = PingRecording::load(&file.0)?;
+        assert!(recording.skipped_lines.is_empty());
+        assert_eq!(recording.pings.len(), 20);
+        for (loaded, recorded) in recording.pings.iter().zip(&pings) {
+            assert_eq!(loaded.at, recorded.at);
+            assert_eq!(loaded.seq, recorded.seq);
+            assert_eq!(loaded.path, recorded.path);
+            match (&loaded.rtt, &recorded.rtt) {
+                (Ok(loaded), Ok(recorded)) => assert_eq!(loaded, recorded),
+                (Err(loaded), Err(_)) => assert!(loaded.starts_with("ping timed out after")),
+                _ => panic!("{loaded:?} != {recorded:?}"),
+            }
+        }
+
+        let summary = recording.summary();
+        assert_eq!(summary.sent(), 20);
+        assert_eq!(summary.received(), 16);
+        assert_eq!(summary.min(), Duration::from_micros(10_000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_skips_partial_lines() -> anyhow::Result<()> {
+        let file = TempFile::new("partial");
+        let recorder = PingRecorder::create(&file.0)?;
+        for ping in &pings(3) {
+            recorder.record(ping);
+        }
+        recorder.finish()?;
+        let mut f = OpenOptions::new().append(true).open(&file.0)?;
+        f.write_all(b"1700000003000000\t3\tdir")?;
+
+        let (logs, _guard) = Logs::capture();
+        let recording = PingRecording::load(&file.0)?;
+        assert_eq!(recording.pings, pings(3));
+        assert_eq!(recording.skipped_lines, [4]);
+        let logs = logs.contents();
+        assert!(logs.contains("skipping malformed line line=4"), "{logs}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::PingError;
+
+/// How [`Ping::ping_with_retries`](crate::Ping::ping_with_retries) should retry after a
+/// transient failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Wait the same `delay` between each attempt.
+    Fixed {
+        /// delay between attempts
+        delay: Duration,
+        /// maximum number of retries after the initial attempt
+        max_retries: u32,
+    },
+    /// Double the delay after each attempt, starting at `base` and capped at `max_delay`.
+    Exponential {
+        /// delay before the first retry
+        base: Duration,
+        /// upper bound for the delay between attempts
+        max_delay: Duration,
+        /// maximum number of retries after the initial attempt
+        max_retries: u32,
+        /// pick a random delay between zero and the computed delay ("full jitter"), to
+        /// avoid many clients retrying in lockstep
+        jitter: bool,
+    },
+    /// Never retry.
+    None,
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait after the given number of failed `attempts`, or `None`
+    /// if no further attempts should be made.
+    pub fn delay(&self, attempts: u32) -> Option<Duration> {
+        match *self {
+            RetryPolicy::Fixed { delay, max_retries } => (attempts <= max_retries).then_some(delay),
+            RetryPolicy::Exponential {
+                base,
+                max_delay,
+                max_retries,
+                jitter,
+            } => {
+                if attempts > max_retries {
+                    return None;
+                }
+                let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+                let delay = base.saturating_mul(factor).min(max_delay);
+                if jitter {
+                    Some(delay.mul_f64(rand::thread_rng().gen_range(0.0..=1.0)))
+                } else {
+                    Some(delay)
+                }
+            }
+            RetryPolicy::None => None,
+        }
+    }
+}
+
+/// Whether the error is worth retrying.
+///
+/// An invalid response indicates a protocol bug, which retrying won't fix.
+pub(crate) fn is_transient(err: &PingError) -> bool {
+    matches!(
+        err,
+        PingError::Connect { .. } | PingError::NodeUnreachable { .. } | PingError::Timeout { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_delay() {
+        let policy = RetryPolicy::Fixed {
+            delay: Duration::from_millis(100),
+            max_retries: 2,
+        };
+        assert_eq!(policy.delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(2), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(3), None);
+    }
+
+    #[test]
+    fn test_exponential_delay() {
+        let policy = RetryPolicy::Exponential {
+            base: Duration::from_millis(100),
+            max_delay: Duration::from_millis(350),
+            max_retries: 4,
+            jitter: false,
+        };
+        assert_eq!(policy.delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.delay(3), Some(Duration::from_millis(350)));
+        assert_eq!(policy.delay(4), Some(Duration::from_millis(350)));
+        assert_eq!(policy.delay(5), None);
+    }
+
+    #[test]
+    fn test_exponential_jitter_bounded() {
+        let policy = RetryPolicy::Exponential {
+            base: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            max_retries: 10,
+            jitter: true,
+        };
+        for attempt in 1..=10 {
+            let delay = policy.delay(attempt).unwrap();
+            assert!(delay <= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn test_none() {
+        assert_eq!(RetryPolicy::None.delay(1), None);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadExactError, ReadToEndError, RecvStream, WriteError},
+    protocol::AcceptError,
+};
+
+use crate::{
+    codec, now_us, read_to_end_into, reject, Ping, PingError, PingRequest, PongResponse,
+    ERR_INVALID_REQUEST, NEXT_SEQ,
+};
+
+/// Pings the client back on a bidirectional stream the server opens, after answering a
+/// `PING` that asked for it with [`FLAG_REVERSE_PING`].
+///
+/// The server sends its version byte, `RPNG` and a framed [`PingRequest`] without
+/// payload, and the client answers with a framed [`PongResponse`] and finishes its side.
+/// Then the server sends the round trip time it measured, in microseconds as a big endian
+/// `u64`, and finishes too. A client that answers wrong gets the stream reset.
+///
+/// [`FLAG_REVERSE_PING`]: crate::FLAG_REVERSE_PING
+pub(crate) async fn ping_client(
+    conn: &Connection,
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    let request = PingRequest {
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        sent_at_us: now_us(),
+        flags: 0,
+        nonce: rand::random(),
+        payload: &[],
+    };
+    buf.clear();
+    buf.put_u8(version);
+    buf.put_slice(b"RPNG");
+    request.encode(buf);
+    let start = Instant::now();
+    match send.write_all(buf).await {
+        Ok(()) => {}
+        // the client is gone, nothing left to measure
+        Err(WriteError::ConnectionLost(_)) => return Ok(()),
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+
+    let limit = codec::LEN_PREFIX + PongResponse::HEADER_LEN;
+    match read_to_end_into(&mut recv, buf, limit).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    let rtt = start.elapsed();
+    match PongResponse::decode(buf, PongResponse::HEADER_LEN) {
+        Ok(pong) if pong.seq == request.seq && pong.nonce == request.nonce => {}
+        _ => {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        }
+    }
+    let rtt_us = rtt.as_micros() as u64;
+    send.write_all(&rtt_us.to_be_bytes())
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.finish()?;
+    Ok(())
+}
+
+impl Ping {
+    /// Answers the reverse ping a server promised in its `PONG` to a ping in `version`,
+    /// returning the round trip time the server measured, see [`ping_client`].
+    pub(crate) async fn answer_reverse(
+        &self,
+        conn: &Connection,
+        version: u8,
+    ) -> Result<Duration, PingError> {
+        tokio::time::timeout(self.timeout, answer(conn, version))
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+    }
+}
+
+async fn answer(conn: &Connection, version: u8) -> Result<Duration, PingError> {
+    let (mut send, mut recv) = conn
+        .accept_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let mut header = [0u8; 1 + 4 + codec::LEN_PREFIX];
+    read_exact(&mut recv, &mut header).await?;
+    let invalid = |response: &[u8]| PingError::InvalidResponse {
+        response: response.to_vec(),
+    };
+    let (tag, prefix) = header.split_at(5);
+    if tag[0] != version || &tag[1..] != b"RPNG" {
+        return Err(invalid(&header));
+    }
+    let prefix = prefix.try_into().expect("split at the prefix");
+    let len = codec::frame_len(prefix, PingRequest::HEADER_LEN)
+        .map_err(|source| PingError::Decode { source })?;
+    let mut body = vec![0u8; len];
+    read_exact(&mut recv, &mut body).await?;
+    let received = Instant::now();
+    let received_at_us = now_us();
+    let request = PingRequest::decode_body(&body).map_err(|source| PingError::Decode { source })?;
+
+    let mut out = BytesMut::new();
+    PongResponse {
+        seq: request.seq,
+        sent_at_us: request.sent_at_us,
+        received_at_us,
+        processing_us: received.elapsed().as_micros() as u64,
+        flags: 0,
+        nonce: request.nonce,
+        server_info: None,
+        observed_addr: None,
+        payload: &[],
+    }
+    .encode(&mut out);
+    send.write_all(&out)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let rtt = recv
+        .read_to_end(8)
+        .await
+        .map_err(|source| PingError::Read { source })?;
+    let rtt_us = <[u8; 8]>::try_from(rtt.as_slice()).map_err(|_| invalid(&rtt))?;
+    Ok(Duration::from_micros(u64::from_be_bytes(rtt_us)))
+}
+
+/// Like [`RecvStream::read_exact`], with the errors of a ping.
+async fn read_exact(recv: &mut RecvStream, buf: &mut [u8]) -> Result<(), PingError> {
+    match recv.read_exact(buf).await {
+        Ok(()) => Ok(()),
+        Err(ReadExactError::FinishedEarly(got)) => Err(PingError::InvalidResponse {
+            response: buf[..got].to_vec(),
+        }),
+        Err(ReadExactError::ReadError(source)) => Err(PingError::Read {
+            source: ReadToEndError::Read(source),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_reverse_ping() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+
+        let (res, conn, _stats) = Ping::new().ping_keep(&client, addr.clone()).await?;
+        assert_eq!(res.reverse_rtt, None);
+        conn.close(0u32.into(), b"bye!");
+
+        let ping = Ping::new().with_reverse_ping(true);
+        let (res, conn, _stats) = ping.ping_keep(&client, addr).await?;
+        assert!(res.ping_time > Duration::ZERO);
+        let reverse_rtt = res.reverse_rtt.expect("asked for");
+        assert!(reverse_rtt > Duration::ZERO);
+        assert!(reverse_rtt < ping.timeout, "{reverse_rtt:?}");
+
+        // the connection is good for more pings afterwards
+        ping.ping_on_conn(&conn).await?;
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reverse_ping_invalid_answer() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let mut buf = BytesMut::new();
+        buf.put_u8(crate::PROTOCOL_VERSION);
+        buf.put_slice(b"PING");
+        PingRequest {
+            seq: 1,
+            sent_at_us: now_us(),
+            flags: crate::FLAG_REVERSE_PING,
+            nonce: [1; crate::NONCE_LEN],
+            payload: &[],
+        }
+        .encode(&mut buf);
+        send.write_all(&buf).await?;
+        send.finish()?;
+        recv.read_to_end(1024).await?;
+
+        // answering with garbage gets the reverse stream reset
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let mut header = [0u8; 5];
+        recv.read_exact(&mut header).await?;
+        assert_eq!(&header[1..], b"RPNG");
+        send.write_all(b"nonsense").await?;
+        send.finish()?;
+        let err = recv.read_to_end(1024).await.unwrap_err();
+        assert!(
+            matches!(err, ReadToEndError::Read(iroh::endpoint::ReadError::Reset(code)) if code == ERR_INVALID_REQUEST.into()),
+            "{err:?}"
+        );
+
+        conn.close(0u32.into(), b"bye!");
+
+        Ok(())
+    }
+}
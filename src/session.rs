@@ -0,0 +1,431 @@
+use std::{fmt, time::Duration};
+
+use iroh::{
+    endpoint::{Connection, ConnectionType},
+    Endpoint, NodeAddr, NodeId,
+};
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::{
+    many::{current_path, PathTracker},
+    Capabilities, PathChange, Ping, PingError, PingStats, PingStream, SmoothedRtt,
+};
+
+/// A ping answered during a [`PingSession`].
+///
+/// Displays like a line of `ping` output, e.g. `PONG from 5a1c0e7f3b: seq=1 time=0.532 ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingResponse {
+    /// the node that answered
+    pub node_id: NodeId,
+    /// sequence number of the ping, see [`PingSession::last_seq`]
+    pub seq: u32,
+    /// round trip time of the ping
+    pub rtt: Duration,
+    /// part of the round trip time spent establishing the connection, zero for pings on
+    /// an existing one
+    pub connect_time: Duration,
+    /// part of the round trip time spent on the ping itself, once connected
+    pub ping_time: Duration,
+}
+
+impl PingResponse {
+    /// format as a line of `ping` output, same as the [`Display`](fmt::Display) impl
+    pub fn format_line(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for PingResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PONG from {}: seq={} time={:.3} ms",
+            self.node_id.fmt_short(),
+            self.seq,
+            self.rtt.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Outcome of a ping sent by [`PingSession::run`].
+#[derive(Debug)]
+pub enum PingEvent {
+    /// the ping was answered
+    Response(PingResponse),
+    /// the ping failed
+    Failure {
+        /// sequence number of the ping, see [`PingSession::last_seq`]
+        seq: u32,
+        /// why the ping failed
+        error: PingError,
+    },
+    /// the path to the node changed since the previous ping, noticed right after the ping
+    /// whose outcome came before
+    PathChanged(PathChange),
+}
+
+/// State of a long-running series of pings.
+///
+/// A session numbers the pings it records and accumulates their results, so the loss and
+/// latency so far can be read at any point, e.g. to print a summary when the user
+/// interrupts a continuous ping.
+#[derive(Debug, Default, Clone)]
+pub struct PingSession {
+    stats: PingStats,
+    smoothed: SmoothedRtt,
+    seq: u32,
+    capabilities: Option<Capabilities>,
+}
+
+impl PingSession {
+    /// start a new session
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send a ping and record its outcome.
+    ///
+    /// Does not close the endpoint.
+    pub async fn ping(
+        &mut self,
+        ping: &Ping,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<PingResponse, PingError> {
+        let node_id = addr.node_id;
+        let res = ping.ping_conn(endpoint, addr).await.map(|(res, conn)| {
+            ping.close_conn(&conn);
+            (res.connect_time, res.ping_time)
+        });
+        self.record(node_id, res)
+    }
+
+    /// Ping with `interval` between pings, recording each ping and sending its outcome on `events` as
+    /// soon as it is known.
+    ///
+    /// Stops after `count` pings, or keeps going if it is `None`. Also stops once the
+    /// receiver of `events` is dropped, without waiting for the next ping. The outcomes
+    /// are sent in the order of the pings, each followed by a [`PingEvent::PathChanged`]
+    /// if the path to the node changed since the ping before. Does not close the endpoint.
+    pub async fn run(
+        &mut self,
+        ping: &Ping,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        interval: Duration,
+        count: Option<usize>,
+        events: &mpsc::Sender<PingEvent>,
+    ) {
+        let node_id = addr.node_id;
+        let path = || current_path(endpoint, node_id);
+        self.run_with_path(ping, endpoint, addr, interval, count, events, path)
+            .await
+    }
+
+    /// Like [`PingSession::run`], looking up the path after each ping with `path`.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_with_path(
+        &mut self,
+        ping: &Ping,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        interval: Duration,
+        count: Option<usize>,
+        events: &mpsc::Sender<PingEvent>,
+        mut path: impl FnMut() -> Option<ConnectionType>,
+    ) {
+        let start = Instant::now();
+        let mut paths = PathTracker::default();
+        for n in 0..count.unwrap_or(usize::MAX) {
+            if n > 0 {
+                tokio::select! {
+                    _ = events.closed() => return,
+                    _ = tokio::time::sleep(interval) => {}
+                }
+            }
+            let res = tokio::select! {
+                _ = events.closed() => return,
+                res = self.ping(ping, endpoint, addr.clone()) => res,
+            };
+            let event = match res {
+                Ok(res) => PingEvent::Response(res),
+                Err(error) => PingEvent::Failure {
+                    seq: self.seq,
+                    error,
+                },
+            };
+            if events.send(event).await.is_err() {
+                // nobody is listening anymore
+                return;
+            }
+            if let Some(change) = paths.observe(path().as_ref(), start.elapsed()) {
+                if events.send(PingEvent::PathChanged(change)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Send a ping on a [`PingStream`] and record its outcome.
+    ///
+    /// Unlike [`PingSession::ping`], this doesn't open a new connection or even a new
+    /// stream for every ping.
+    pub async fn ping_streamed(
+        &mut self,
+        stream: &mut PingStream,
+    ) -> Result<PingResponse, PingError> {
+        let res = stream.ping().await.map(|rtt| (Duration::ZERO, rtt));
+        self.record(stream.node_id(), res)
+    }
+
+    /// Agree with the server at the other end of `conn` on what it supports, see
+    /// [`Ping::negotiate`], and adapt the pings of this session to it.
+    pub async fn negotiate(
+        &mut self,
+        ping: &Ping,
+        conn: &Connection,
+    ) -> Result<Capabilities, PingError> {
+        let caps = ping.negotiate(conn).await?;
+        self.capabilities = Some(caps);
+        Ok(caps)
+    }
+
+    /// the capabilities agreed on with [`PingSession::negotiate`], `None` before
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.capabilities.as_ref()
+    }
+
+    /// Send a ping as a QUIC datagram on `conn` and record its outcome, see
+    /// [`Ping::ping_datagram`].
+    ///
+    /// Fails with [`PingError::DatagramsUnsupported`] without sending or recording anything
+    /// if the server negotiated datagrams away, or the connection has them disabled.
+    pub async fn ping_datagram(
+        &mut self,
+        ping: &Ping,
+        conn: &Connection,
+    ) -> Result<PingResponse, PingError> {
+        let negotiated_away = self.capabilities.is_some_and(|caps| !caps.datagrams());
+        if negotiated_away || conn.max_datagram_size().is_none() {
+            return Err(PingError::DatagramsUnsupported);
+        }
+        let node_id = conn
+            .remote_node_id()
+            .expect("the handshake authenticated the remote node");
+        let res = ping
+            .ping_datagram(conn)
+            .await
+            .map(|rtt| (Duration::ZERO, rtt));
+        self.record(node_id, res)
+    }
+
+    /// Records a ping that took `connect_time` to connect and `ping_time` to be answered,
+    /// or failed.
+    fn record(
+        &mut self,
+        node_id: NodeId,
+        res: Result<(Duration, Duration), PingError>,
+    ) -> Result<PingResponse, PingError> {
+        match res {
+            Ok((connect_time, ping_time)) => {
+                let rtt = connect_time + ping_time;
+                self.record_success(rtt);
+                Ok(PingResponse {
+                    node_id,
+                    seq: self.seq,
+                    rtt,
+                    connect_time,
+                    ping_time,
+                })
+            }
+            Err(err) => {
+                self.record_failure();
+                Err(err)
+            }
+        }
+    }
+
+    /// record a ping that completed with the given round trip time
+    pub fn record_success(&mut self, rtt: Duration) {
+        self.seq += 1;
+        self.stats.record_rtt(rtt);
+        self.smoothed.update(rtt);
+    }
+
+    /// record a ping that failed
+    pub fn record_failure(&mut self) {
+        self.seq += 1;
+        self.stats.record_loss();
+    }
+
+    /// percentage of pings that failed so far
+    pub fn loss_pct(&self) -> f64 {
+        self.stats.loss_pct()
+    }
+
+    /// number of pings recorded so far
+    pub fn sent(&self) -> u64 {
+        self.stats.sent()
+    }
+
+    /// number of successful pings recorded so far
+    pub fn recv(&self) -> u64 {
+        self.stats.received()
+    }
+
+    /// statistics over all pings recorded so far
+    pub fn snapshot(&self) -> PingStats {
+        self.stats.clone()
+    }
+
+    /// smoothed round trip time over the pings so far, see [`SmoothedRtt`]
+    pub fn smoothed_rtt(&self) -> &SmoothedRtt {
+        &self.smoothed
+    }
+
+    /// Sequence number of the last ping recorded.
+    ///
+    /// Pings are numbered from 1, so this is 0 until the first ping is recorded.
+    pub fn last_seq(&self) -> u32 {
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[test]
+    fn test_record() {
+        let mut session = PingSession::new();
+        assert_eq!(session.last_seq(), 0);
+        assert_eq!(session.loss_pct(), 0.0);
+
+        session.record_success(Duration::from_millis(10));
+        session.record_failure();
+        session.record_success(Duration::from_millis(20));
+        session.record_failure();
+
+        assert_eq!(session.last_seq(), 4);
+        assert_eq!(session.sent(), 4);
+        assert_eq!(session.recv(), 2);
+        assert_eq!(session.loss_pct(), 50.0);
+        assert_eq!(session.snapshot().avg(), Duration::from_millis(15));
+        // 7/8 * 10 + 1/8 * 20
+        assert_eq!(
+            session.smoothed_rtt().srtt(),
+            Some(Duration::from_micros(11_250))
+        );
+    }
+
+    #[test]
+    fn test_format_line() {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        let res = PingResponse {
+            node_id,
+            seq: 3,
+            rtt: Duration::from_micros(532),
+            connect_time: Duration::ZERO,
+            ping_time: Duration::from_micros(532),
+        };
+        let line = res.format_line();
+        assert_eq!(
+            line,
+            format!("PONG from {}: seq=3 time=0.532 ms", node_id.fmt_short())
+        );
+        assert_eq!(res.to_string(), line);
+    }
+
+    #[tokio::test]
+    async fn test_run() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new();
+        let mut session = PingSession::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        session
+            .run(&ping, &client, addr.clone(), Duration::ZERO, Some(3), &tx)
+            .await;
+        for seq in 1..=3 {
+            match rx.recv().await {
+                Some(PingEvent::Response(res)) => {
+                    assert_eq!(res.seq, seq);
+                    assert_eq!(res.node_id, addr.node_id);
+                }
+                other => panic!("expected a response, got {other:?}"),
+            }
+        }
+        assert_eq!(session.recv(), 3);
+
+        // dropping the receiver stops an endless run
+        drop(rx);
+        session
+            .run(&ping, &client, addr, Duration::ZERO, None, &tx)
+            .await;
+        assert_eq!(session.sent(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_run_path_changed() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let ping = Ping::new();
+        let mut session = PingSession::new();
+        let (tx, mut rx) = mpsc::channel(8);
+        let (a, b) = ("127.0.0.1:1".parse()?, "127.0.0.1:2".parse()?);
+        let mut paths = vec![
+            None,
+            Some(ConnectionType::Direct(b)),
+            Some(ConnectionType::Direct(a)),
+        ];
+        session
+            .run_with_path(&ping, &client, addr, Duration::ZERO, Some(3), &tx, || {
+                paths.pop().expect("a path per ping")
+            })
+            .await;
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        match &events[..] {
+            [PingEvent::Response(first), PingEvent::Response(second), PingEvent::PathChanged(change), PingEvent::Response(third)] =>
+            {
+                assert_eq!((first.seq, second.seq, third.seq), (1, 2, 3));
+                assert_eq!(change.from, ConnectionType::Direct(a));
+                assert_eq!(change.to, ConnectionType::Direct(b));
+            }
+            other => panic!("unexpected events {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_session_ping() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new();
+        let mut session = PingSession::new();
+        session.ping(&ping, &client, addr.clone()).await?;
+        let res = session.ping(&ping, &client, addr.clone()).await?;
+        assert_eq!(res.seq, 2);
+        assert_eq!(res.node_id, addr.node_id);
+        assert_eq!(res.rtt, res.connect_time + res.ping_time);
+        assert!(res.connect_time > Duration::ZERO);
+        let bogus = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public());
+        assert!(session.ping(&ping, &client, bogus).await.is_err());
+
+        assert_eq!(session.last_seq(), 3);
+        assert_eq!(session.recv(), 2);
+        assert_eq!(session.snapshot().lost(), 1);
+
+        Ok(())
+    }
+}
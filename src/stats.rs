@@ -0,0 +1,619 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use crate::PingError;
+
+/// Number of buckets per power of two in the percentile histogram, bounding the relative
+/// error of percentile estimates to `1 / SUB_BUCKETS`.
+const SUB_BUCKETS: u64 = 64;
+
+/// Summary statistics over a series of pings.
+///
+/// Results are fed in incrementally with [`PingStats::record`], and the statistics are
+/// kept in constant space: the mean and deviation are tracked with Welford's algorithm,
+/// and percentiles are estimated from a log-linear histogram with 1/64 relative
+/// precision, so a stats instance can follow an hours-long run. Stats gathered separately,
+/// e.g. per worker, can be combined with [`PingStats::merge`].
+///
+/// When exact percentiles matter more than memory, e.g. for checking an SLA over a
+/// bounded batch of pings, create the stats with [`PingStats::with_samples`] to also keep
+/// every round trip time.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingStats {
+    sent: u64,
+    received: u64,
+    min: Duration,
+    max: Duration,
+    /// running mean of the round trip times, in seconds
+    mean: f64,
+    /// running sum of squared differences from the mean, in seconds squared
+    m2: f64,
+    /// last round trip time recorded, to compute the difference to the next one
+    last: Option<Duration>,
+    /// sum of the absolute differences between consecutive round trip times
+    diff_sum: Duration,
+    /// number of consecutive round trip time pairs
+    diff_count: u64,
+    ipdv: Duration,
+    /// interarrival jitter estimate as defined by RFC 3550, in seconds
+    jitter_rfc3550: f64,
+    /// round trip time histogram, mapping bucket index to count
+    buckets: BTreeMap<u16, u64>,
+    /// every round trip time recorded, if kept for exact percentiles
+    samples: Option<Vec<Duration>>,
+}
+
+impl PingStats {
+    /// Compute the statistics of the given round trip times, in the order they were
+    /// measured.
+    ///
+    /// The round trip times are kept, see [`PingStats::with_samples`].
+    pub fn from_rtts(rtts: &[Duration]) -> Self {
+        let mut stats = Self::with_samples();
+        for &rtt in rtts {
+            stats.record_rtt(rtt);
+        }
+        stats
+    }
+
+    /// Create empty stats that keep every round trip time recorded, so percentiles are
+    /// exact rather than estimated.
+    pub fn with_samples() -> Self {
+        Self {
+            samples: Some(Vec::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of a ping.
+    pub fn record(&mut self, result: &Result<Duration, PingError>) {
+        match result {
+            Ok(rtt) => self.record_rtt(*rtt),
+            Err(_) => self.record_loss(),
+        }
+    }
+
+    /// Record a successful ping with the given round trip time.
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.sent += 1;
+        self.received += 1;
+
+        if self.received == 1 {
+            self.min = rtt;
+            self.max = rtt;
+        } else {
+            self.min = self.min.min(rtt);
+            self.max = self.max.max(rtt);
+        }
+
+        let secs = rtt.as_secs_f64();
+        let delta = secs - self.mean;
+        self.mean += delta / self.received as f64;
+        self.m2 += delta * (secs - self.mean);
+
+        if let Some(last) = self.last {
+            let diff = rtt.abs_diff(last);
+            self.diff_sum += diff;
+            self.diff_count += 1;
+            self.ipdv = self.ipdv.max(diff);
+            self.jitter_rfc3550 += (diff.as_secs_f64() - self.jitter_rfc3550) / 16.0;
+        }
+        self.last = Some(rtt);
+
+        *self.buckets.entry(bucket(rtt)).or_default() += 1;
+        if let Some(samples) = &mut self.samples {
+            samples.push(rtt);
+        }
+    }
+
+    /// Record a ping that got no response.
+    pub fn record_loss(&mut self) {
+        self.sent += 1;
+    }
+
+    /// Combine the statistics of another series of pings into these.
+    ///
+    /// `other` is treated as having been measured after `self`, which only matters for
+    /// the jitter metrics: the difference between the last sample of `self` and the first
+    /// of `other` is not taken into account. The merged stats only keep samples if both
+    /// sides did.
+    pub fn merge(&mut self, other: &PingStats) {
+        if other.received > 0 {
+            if self.received == 0 {
+                self.min = other.min;
+                self.max = other.max;
+            } else {
+                self.min = self.min.min(other.min);
+                self.max = self.max.max(other.max);
+            }
+
+            // Chan et al.'s parallel variant of Welford's algorithm
+            let n_a = self.received as f64;
+            let n_b = other.received as f64;
+            let n = n_a + n_b;
+            let delta = other.mean - self.mean;
+            self.mean += delta * n_b / n;
+            self.m2 += other.m2 + delta * delta * n_a * n_b / n;
+
+            self.last = other.last;
+        }
+        self.sent += other.sent;
+        self.received += other.received;
+
+        // The RFC 3550 estimate depends on the order of all samples, so the best we can
+        // do is weigh both estimates by their number of samples.
+        let pairs = self.diff_count + other.diff_count;
+        if pairs > 0 {
+            self.jitter_rfc3550 = (self.jitter_rfc3550 * self.diff_count as f64
+                + other.jitter_rfc3550 * other.diff_count as f64)
+                / pairs as f64;
+        }
+        self.diff_sum += other.diff_sum;
+        self.diff_count += other.diff_count;
+        self.ipdv = self.ipdv.max(other.ipdv);
+
+        for (&bucket, &count) in &other.buckets {
+            *self.buckets.entry(bucket).or_default() += count;
+        }
+        match (&mut self.samples, &other.samples) {
+            (Some(samples), Some(other)) => samples.extend_from_slice(other),
+            _ => self.samples = None,
+        }
+    }
+
+    /// number of pings recorded, successful or not
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// number of successful pings recorded
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// number of pings that got no response
+    pub fn lost(&self) -> u64 {
+        self.sent - self.received
+    }
+
+    /// percentage of pings that got no response, zero if nothing was sent
+    pub fn loss_pct(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost() as f64 * 100.0 / self.sent as f64
+    }
+
+    /// shortest round trip time
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// longest round trip time
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// mean round trip time
+    pub fn avg(&self) -> Duration {
+        Duration::from_secs_f64(self.mean)
+    }
+
+    /// mean deviation of the round trip times, computed as their standard deviation like
+    /// `ping` does
+    pub fn mdev(&self) -> Duration {
+        if self.received == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((self.m2 / self.received as f64).max(0.0).sqrt())
+    }
+
+    /// mean absolute difference between consecutive round trip times, zero with fewer
+    /// than two samples
+    pub fn jitter(&self) -> Duration {
+        if self.diff_count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.diff_sum.as_secs_f64() / self.diff_count as f64)
+    }
+
+    /// Interarrival jitter as defined by RFC 3550, an exponentially smoothed mean of the
+    /// differences between consecutive round trip times that favors recent samples.
+    ///
+    /// Zero with fewer than two samples. After a [`PingStats::merge`] this is only an
+    /// approximation.
+    pub fn jitter_rfc3550(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter_rfc3550)
+    }
+
+    /// largest absolute difference between consecutive round trip times (inter-packet
+    /// delay variation)
+    pub fn ipdv(&self) -> Duration {
+        self.ipdv
+    }
+
+    /// The `p`th percentile of the round trip times, `p` ranging from 0 to 100.
+    ///
+    /// If the samples were kept, this interpolates linearly between the closest ranks of
+    /// the sorted samples. Otherwise it is estimated from the histogram, within 1/64 of
+    /// the true value.
+    ///
+    /// Returns `None` if there are no samples.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.received == 0 {
+            return None;
+        }
+        let p = p.clamp(0.0, 100.0);
+        // samples may be missing from deserialized stats, the histogram always has them
+        if let Some(samples) = self.samples.as_ref().filter(|samples| !samples.is_empty()) {
+            let mut sorted = samples.clone();
+            sorted.sort_unstable();
+            let rank = p / 100.0 * (sorted.len() - 1) as f64;
+            let lower = sorted[rank.floor() as usize];
+            let upper = sorted[rank.ceil() as usize];
+            return Some(lower + (upper - lower).mul_f64(rank.fract()));
+        }
+
+        let rank = ((p / 100.0 * self.received as f64).ceil() as u64).max(1);
+        // the extremes are known exactly
+        if rank == 1 {
+            return Some(self.min);
+        }
+        if rank >= self.received {
+            return Some(self.max);
+        }
+        let mut seen = 0;
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_value(bucket).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// median round trip time
+    pub fn p50(&self) -> Duration {
+        self.percentile(50.0).unwrap_or_default()
+    }
+
+    /// 95th percentile round trip time
+    pub fn p95(&self) -> Duration {
+        self.percentile(95.0).unwrap_or_default()
+    }
+
+    /// 99th percentile round trip time
+    pub fn p99(&self) -> Duration {
+        self.percentile(99.0).unwrap_or_default()
+    }
+}
+
+/// Statistics over the last `window` pings only.
+///
+/// All-time statistics hide recent changes in a long-running monitor; this answers "how
+/// did the last N pings do" instead.
+#[derive(Debug, Clone)]
+pub struct RollingPingStats {
+    window: usize,
+    /// recent results, oldest first, with `None` marking a lost ping
+    results: VecDeque<Option<Duration>>,
+}
+
+impl RollingPingStats {
+    /// Create rolling statistics over the last `window` pings.
+    ///
+    /// A window of zero is treated as a window of one.
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            results: VecDeque::with_capacity(window),
+        }
+    }
+
+    /// Add the result of a ping, `None` meaning it was lost, evicting the oldest result if
+    /// the window is full.
+    pub fn push(&mut self, result: Option<Duration>) {
+        if self.results.len() == self.window {
+            self.results.pop_front();
+        }
+        self.results.push_back(result);
+    }
+
+    /// statistics over the pings currently in the window
+    pub fn stats(&self) -> PingStats {
+        let mut stats = PingStats::default();
+        for result in &self.results {
+            match result {
+                Some(rtt) => stats.record_rtt(*rtt),
+                None => stats.record_loss(),
+            }
+        }
+        stats
+    }
+}
+
+/// Exponentially weighted moving average of round trip times, as TCP computes its
+/// smoothed RTT (RFC 6298).
+///
+/// Raw round trip times are noisy; the smoothed value is better suited for a live display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmoothedRtt {
+    alpha: f64,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Default for SmoothedRtt {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_ALPHA)
+    }
+}
+
+impl SmoothedRtt {
+    /// Weight of a new sample, matching TCP's SRTT.
+    pub const DEFAULT_ALPHA: f64 = 0.125;
+
+    /// Weight of a new sample in the variance, as recommended by RFC 6298.
+    const BETA: f64 = 0.25;
+
+    /// Create a tracker giving each new sample the weight `alpha`, between 0 and 1.
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha: alpha.clamp(0.0, 1.0),
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    /// Add a round trip time, returning the updated smoothed value.
+    pub fn update(&mut self, rtt: Duration) -> Duration {
+        let srtt = match self.srtt {
+            // the first sample initializes the estimate, see RFC 6298 section 2.2
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar =
+                    self.rttvar.mul_f64(1.0 - Self::BETA) + srtt.abs_diff(rtt).mul_f64(Self::BETA);
+                srtt.mul_f64(1.0 - self.alpha) + rtt.mul_f64(self.alpha)
+            }
+        };
+        self.srtt = Some(srtt);
+        srtt
+    }
+
+    /// the smoothed round trip time, `None` before the first sample
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// the round trip time variation, computed as in RFC 6298
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+/// Maps a round trip time to its histogram bucket.
+///
+/// Below `SUB_BUCKETS` microseconds every microsecond has its own bucket. Above, each
+/// power of two is split into `SUB_BUCKETS` equally wide buckets.
+fn bucket(rtt: Duration) -> u16 {
+    let micros = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX);
+    if micros < SUB_BUCKETS {
+        return micros as u16;
+    }
+    // shift so that the value lands in SUB_BUCKETS..2 * SUB_BUCKETS
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKETS.trailing_zeros();
+    let sub = (micros >> shift) - SUB_BUCKETS;
+    (SUB_BUCKETS * (1 + shift as u64) + sub) as u16
+}
+
+/// The value in the middle of a histogram bucket.
+fn bucket_value(bucket: u16) -> Duration {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return Duration::from_micros(bucket);
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let lower = (SUB_BUCKETS + bucket % SUB_BUCKETS) << shift;
+    Duration::from_micros(lower + (1 << shift) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_from_rtts() {
+        let stats = PingStats::from_rtts(&[ms(10), ms(30), ms(20), ms(20)]);
+        assert_eq!(stats.received(), 4);
+        assert_eq!(stats.min(), ms(10));
+        assert_eq!(stats.max(), ms(30));
+        assert_eq!(stats.avg(), ms(20));
+        // sqrt((100 + 100 + 0 + 0) / 4)
+        assert_eq!(stats.mdev().as_micros(), 7071);
+        // (20 + 10 + 0) / 3
+        assert_eq!(stats.jitter(), ms(10));
+        assert_eq!(stats.ipdv(), ms(20));
+    }
+
+    #[test]
+    fn test_from_rtts_too_few_samples() {
+        assert_eq!(PingStats::from_rtts(&[]), PingStats::with_samples());
+        assert_eq!(PingStats::default().percentile(50.0), None);
+
+        let stats = PingStats::from_rtts(&[ms(5)]);
+        assert_eq!(stats.avg(), ms(5));
+        assert_eq!(stats.jitter(), Duration::ZERO);
+        assert_eq!(stats.ipdv(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut stats = PingStats::default();
+        stats.record(&Ok(ms(1)));
+        stats.record(&Err(PingError::NoTargets));
+        stats.record(&Ok(ms(3)));
+        stats.record_loss();
+
+        assert_eq!(stats.sent(), 4);
+        assert_eq!(stats.received(), 2);
+        assert_eq!(stats.lost(), 2);
+        assert_eq!(stats.loss_pct(), 50.0);
+        assert_eq!(stats.avg(), ms(2));
+    }
+
+    #[test]
+    fn test_bucket_round_trip() {
+        for micros in [
+            0,
+            1,
+            63,
+            64,
+            65,
+            127,
+            128,
+            1000,
+            12_345,
+            1_000_000,
+            u32::MAX as u64,
+        ] {
+            let rtt = Duration::from_micros(micros);
+            let estimate = bucket_value(bucket(rtt)).as_micros() as f64;
+            let error = (estimate - micros as f64).abs() / (micros as f64).max(1.0);
+            assert!(error <= 1.0 / SUB_BUCKETS as f64, "{micros}µs: {estimate}");
+        }
+        assert!(bucket(Duration::MAX) > bucket(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_percentiles_within_tolerance() {
+        // 1ms, 2ms, ..., 1000ms in a scrambled order
+        let mut rtts: Vec<_> = (1..=1000).map(ms).collect();
+        rtts.reverse();
+        rtts.swap(0, 500);
+        let mut stats = PingStats::default();
+        for rtt in rtts {
+            stats.record_rtt(rtt);
+        }
+
+        for (p, exact) in [(50.0, ms(500)), (95.0, ms(950)), (99.0, ms(990))] {
+            let estimate = stats.percentile(p).unwrap();
+            let error = estimate.abs_diff(exact).as_secs_f64() / exact.as_secs_f64();
+            assert!(error < 0.02, "p{p}: {estimate:?} vs {exact:?}");
+        }
+        assert_eq!(stats.percentile(0.0), Some(ms(1)));
+        assert_eq!(stats.percentile(100.0), Some(ms(1000)));
+    }
+
+    #[test]
+    fn test_exact_percentiles() {
+        let rtts: Vec<_> = (1..=100).rev().map(ms).collect();
+        let stats = PingStats::from_rtts(&rtts);
+        assert_eq!(stats.p50(), Duration::from_micros(50_500));
+        assert_eq!(stats.p95(), Duration::from_micros(95_050));
+        assert_eq!(stats.p99(), Duration::from_micros(99_010));
+        assert_eq!(stats.percentile(0.0), Some(ms(1)));
+        assert_eq!(stats.percentile(100.0), Some(ms(100)));
+
+        let stats = PingStats::from_rtts(&[ms(7)]);
+        assert_eq!(stats.p99(), ms(7));
+    }
+
+    #[test]
+    fn test_merge() {
+        let rtts: Vec<_> = (1..=100).map(|i| ms(i * 7 % 60)).collect();
+        let all = PingStats::from_rtts(&rtts);
+
+        let mut merged = PingStats::from_rtts(&rtts[..40]);
+        let mut rest = PingStats::from_rtts(&rtts[40..]);
+        rest.record_loss();
+        merged.merge(&rest);
+
+        assert_eq!(merged.sent(), all.sent() + 1);
+        assert_eq!(merged.received(), all.received());
+        assert_eq!(merged.min(), all.min());
+        assert_eq!(merged.max(), all.max());
+        assert_eq!(merged.avg().as_micros(), all.avg().as_micros());
+        assert_eq!(merged.mdev().as_micros(), all.mdev().as_micros());
+        assert_eq!(merged.ipdv(), all.ipdv());
+        assert_eq!(merged.p50(), all.p50());
+        assert_eq!(merged.p99(), all.p99());
+
+        let mut empty = PingStats::default();
+        empty.merge(&all);
+        assert_eq!(empty.min(), all.min());
+        assert_eq!(empty.avg().as_micros(), all.avg().as_micros());
+    }
+
+    #[test]
+    fn test_rolling() {
+        let mut rolling = RollingPingStats::new(3);
+        assert_eq!(rolling.stats().sent(), 0);
+
+        rolling.push(Some(ms(100)));
+        rolling.push(None);
+        rolling.push(Some(ms(10)));
+        let stats = rolling.stats();
+        assert_eq!(stats.sent(), 3);
+        assert_eq!(stats.lost(), 1);
+        assert_eq!(stats.max(), ms(100));
+
+        // the slow ping and the loss fall out of the window
+        rolling.push(Some(ms(20)));
+        rolling.push(Some(ms(30)));
+        let stats = rolling.stats();
+        assert_eq!(stats.sent(), 3);
+        assert_eq!(stats.lost(), 0);
+        assert_eq!(stats.max(), ms(30));
+        assert_eq!(stats.avg(), ms(20));
+    }
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let mut smoothed = SmoothedRtt::default();
+        assert_eq!(smoothed.srtt(), None);
+
+        assert_eq!(smoothed.update(ms(100)), ms(100));
+        assert_eq!(smoothed.rttvar(), ms(50));
+
+        // srtt = 7/8 * 100 + 1/8 * 200, rttvar = 3/4 * 50 + 1/4 * |100 - 200|
+        assert_eq!(smoothed.update(ms(200)), Duration::from_micros(112_500));
+        assert_eq!(smoothed.rttvar(), Duration::from_micros(62_500));
+        assert_eq!(smoothed.srtt(), Some(Duration::from_micros(112_500)));
+
+        let mut unsmoothed = SmoothedRtt::new(1.0);
+        unsmoothed.update(ms(100));
+        assert_eq!(unsmoothed.update(ms(200)), ms(200));
+    }
+
+    #[test]
+    fn test_percentile_without_samples() {
+        let mut stats = PingStats::with_samples();
+        stats.record_rtt(ms(10));
+        stats.record_rtt(ms(30));
+        // as deserialized from stats that lost their samples on the way
+        stats.samples = Some(Vec::new());
+        assert_eq!(stats.percentile(0.0), Some(ms(10)));
+        assert_eq!(stats.percentile(100.0), Some(ms(30)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() -> anyhow::Result<()> {
+        let stats = PingStats::from_rtts(&[ms(10), ms(30), ms(20)]);
+        let json = serde_json::to_string(&stats)?;
+        let decoded: PingStats = serde_json::from_str(&json)?;
+        assert_eq!(decoded, stats);
+        Ok(())
+    }
+}
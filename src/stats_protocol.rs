@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use iroh::{
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+    Endpoint, NodeAddr,
+};
+
+use crate::{health, Metrics, MetricsSnapshot, Ping, PingError};
+
+/// ALPN of the companion protocol serving a ping server's metrics, see [`StatsProtocol`].
+pub const ALPN_STATS: &[u8] = b"iroh/ping-stats/0";
+
+/// Largest stats response a client reads.
+const MAX_STATS_LEN: usize = 64 * 1024;
+
+/// Serves the [`MetricsSnapshot`] of a ping server under [`ALPN_STATS`], so operators can
+/// query it remotely, see [`Ping::fetch_stats`].
+///
+/// On every connection the server opens a unidirectional stream, writes the snapshot as
+/// JSON and finishes it, then waits for the client to close the connection. Anybody who
+/// can connect sees the metrics, so only register it where that is fine.
+#[derive(Debug, Clone)]
+pub struct StatsProtocol {
+    metrics: Arc<Metrics>,
+}
+
+impl StatsProtocol {
+    /// Serve the metrics of `ping`, and of every clone of it.
+    pub fn new(ping: &Ping) -> Self {
+        Self {
+            metrics: ping.metrics().clone(),
+        }
+    }
+}
+
+impl ProtocolHandler for StatsProtocol {
+    async fn accept(&self, connection: Connection) -> n0_snafu::Result<(), AcceptError> {
+        let json = serde_json::to_vec(&self.metrics.snapshot()).map_err(AcceptError::from_err)?;
+        let mut send = connection.open_uni().await?;
+        send.write_all(&json).await.map_err(AcceptError::from_err)?;
+        send.finish()?;
+        // the client closes once it read everything
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+impl Ping {
+    /// Fetch the metrics of the ping server at `addr`, which must serve a
+    /// [`StatsProtocol`].
+    ///
+    /// Fails with [`PingError::Connect`] if the node doesn't serve stats, and with
+    /// [`PingError::InvalidStats`] if it sends something other than a snapshot. Closes
+    /// the connection afterwards, but not the endpoint.
+    pub async fn fetch_stats(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+    ) -> Result<MetricsSnapshot, PingError> {
+        let node_id = addr.node_id;
+        let fetch = async {
+            let conn = endpoint
+                .connect(addr, ALPN_STATS)
+                .await
+                .map_err(|source| health::connect_failed(node_id, source))?;
+            let mut recv = conn
+                .accept_uni()
+                .await
+                .map_err(|source| PingError::Connection { source })?;
+            let json = recv
+                .read_to_end(MAX_STATS_LEN)
+                .await
+                .map_err(|source| PingError::Read { source })?;
+            self.close_conn(&conn);
+            serde_json::from_slice(&json).map_err(|source| PingError::InvalidStats { source })
+        };
+        tokio::time::timeout(self.timeout, fetch)
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::protocol::Router;
+
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_fetch_stats() -> anyhow::Result<()> {
+        let ep = test_utils::local_endpoint().await?;
+        let addr = test_utils::local_addr(&ep);
+        let server = Ping::new();
+        let _router = server
+            .register(Router::builder(ep))
+            .accept(ALPN_STATS, StatsProtocol::new(&server))
+            .spawn();
+
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new();
+        ping.ping_n(&client, addr.clone(), 3).await?;
+
+        let stats = ping.fetch_stats(&client, addr).await?;
+        assert_eq!(stats.pings_recv, 3);
+        assert_eq!(stats.pings_sent, 0);
+        assert_eq!(stats.pings_failed, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_stats_not_served() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let err = Ping::new().fetch_stats(&client, addr).await.unwrap_err();
+        assert!(matches!(err, PingError::Connect { .. }), "{err:?}");
+
+        Ok(())
+    }
+}
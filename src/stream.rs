@@ -0,0 +1,412 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadError, ReadExactError, ReadToEndError, RecvStream, SendStream},
+    protocol::AcceptError,
+    NodeId,
+};
+
+use crate::{
+    codec::{self, CodecError},
+    fallback_version, now_us, reject, Negotiated, Ping, PingError, PingRequest, PongResponse,
+    RejectReason, ERR_INVALID_REQUEST, ERR_PAYLOAD_TOO_LARGE, ERR_RATE_LIMITED, FLAG_REVERSE_PING,
+    NEXT_SEQ,
+};
+
+/// Oldest protocol version with ping streams.
+const STREAM_VERSION: u8 = 3;
+
+/// Many pings over a single long-lived stream, see [`Ping::open_stream`].
+///
+/// Each ping is a framed [`PingRequest`] on the stream, answered by a framed
+/// [`PongResponse`], so pinging saves opening a stream every time. Pinging regularly also
+/// keeps the connection alive.
+///
+/// End the stream with [`PingStream::finish`]. Dropping it unfinished closes its
+/// connection with the close code of the [`Ping`] and logs a warning, as the server can't
+/// tell an abandoned stream from a failed one.
+#[derive(Debug)]
+pub struct PingStream {
+    ping: Ping,
+    node_id: NodeId,
+    conn: Connection,
+    finished: bool,
+    send: SendStream,
+    recv: RecvStream,
+    buf: BytesMut,
+}
+
+impl Ping {
+    /// Open a stream on `conn` to send many pings over, see [`PingStream`].
+    ///
+    /// Takes a round trip to agree on the protocol version. Fails with
+    /// [`PingError::StreamUnsupported`] if the server is too old for ping streams.
+    ///
+    /// The server answers other requests on the connection alongside the stream, though
+    /// the open stream counts towards its limit of streams answered at once, see
+    /// [`Ping::with_max_concurrent_streams`].
+    pub async fn open_stream(&self, conn: &Connection) -> Result<PingStream, PingError> {
+        let node_id = conn
+            .remote_node_id()
+            .expect("the handshake authenticated the remote node");
+        let mut version = self.max_version;
+        let (send, recv) = loop {
+            if version < STREAM_VERSION {
+                return Err(PingError::StreamUnsupported { version });
+            }
+            match open(conn, version).await? {
+                Negotiated::Done(streams) => break streams,
+                Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+            }
+        };
+        Ok(PingStream {
+            ping: self.clone(),
+            node_id,
+            conn: conn.clone(),
+            finished: false,
+            send,
+            recv,
+            buf: BytesMut::new(),
+        })
+    }
+}
+
+impl PingStream {
+    /// the node at the other end of the stream
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Send a ping on the stream and return its round trip time.
+    ///
+    /// Fails with [`PingError::StreamFinished`] once the server finished the stream. A ping
+    /// that timed out may still be answered later, that answer is skipped by the next ping.
+    /// A ping over the server's rate limit fails with [`PingError::RequestRejected`], and
+    /// ends the stream.
+    pub async fn ping(&mut self) -> Result<Duration, PingError> {
+        self.ping.wait_for_turn().await;
+        let start = Instant::now();
+        let timeout = self.ping.timeout;
+        tokio::time::timeout(timeout, self.exchange())
+            .await
+            .unwrap_or(Err(PingError::Timeout { timeout }))
+            .map_err(|err| self.ping.failed(err))?;
+        self.ping.metrics.pings_sent.inc();
+        Ok(start.elapsed())
+    }
+
+    /// Finish the stream, and wait for the server to finish its side too.
+    pub async fn finish(mut self) -> Result<(), PingError> {
+        self.finished = true;
+        self.send
+            .finish()
+            .map_err(|source| PingError::Finish { source })?;
+        // anything still arriving answers pings that timed out
+        while self
+            .recv
+            .read_chunk(usize::MAX, true)
+            .await
+            .map_err(|source| PingError::Read {
+                source: ReadToEndError::Read(source),
+            })?
+            .is_some()
+        {}
+        Ok(())
+    }
+
+    async fn exchange(&mut self) -> Result<(), PingError> {
+        let request = PingRequest {
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            sent_at_us: now_us(),
+            flags: 0,
+            nonce: rand::random(),
+            payload: &[],
+        };
+        self.buf.clear();
+        request.encode(&mut self.buf);
+        self.send
+            .write_all(&self.buf)
+            .await
+            .map_err(|source| PingError::Write { source })?;
+
+        loop {
+            match read_frame(&mut self.recv, &mut self.buf, PongResponse::HEADER_LEN).await {
+                Ok(Frame::Body) => {}
+                Ok(Frame::End) => return Err(PingError::StreamFinished),
+                Err(FrameError::Codec(source)) => return Err(PingError::Decode { source }),
+                Err(FrameError::Read(ReadError::Reset(code)))
+                    if code == ERR_RATE_LIMITED.into() =>
+                {
+                    return Err(PingError::RequestRejected {
+                        reason: RejectReason::RateLimited,
+                        retry_after: None,
+                    })
+                }
+                Err(FrameError::Read(source)) => {
+                    return Err(PingError::Read {
+                        source: ReadToEndError::Read(source),
+                    })
+                }
+            }
+            let pong = PongResponse::decode_body(&self.buf)
+                .map_err(|source| PingError::Decode { source })?;
+            // a late answer to a ping that timed out
+            if pong.seq < request.seq {
+                continue;
+            }
+            if pong.nonce != request.nonce {
+                return Err(PingError::NonceMismatch {
+                    expected: request.nonce,
+                    got: pong.nonce,
+                });
+            }
+            if pong.seq != request.seq || !pong.payload.is_empty() {
+                return Err(PingError::InvalidResponse {
+                    response: self.buf.to_vec(),
+                });
+            }
+            return Ok(());
+        }
+    }
+}
+
+impl Drop for PingStream {
+    fn drop(&mut self) {
+        if !self.finished && self.conn.close_reason().is_none() {
+            tracing::warn!(peer = %self.node_id, "ping stream dropped unfinished, closing its connection");
+            self.ping.close_conn(&self.conn);
+        }
+    }
+}
+
+/// Opens a stream for pings, which the server acknowledges with its version and `STRM`.
+async fn open(
+    conn: &Connection,
+    version: u8,
+) -> Result<Negotiated<(SendStream, RecvStream)>, PingError> {
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let mut request = vec![version];
+    request.extend_from_slice(b"STRM");
+    send.write_all(&request)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+
+    let mut response = [0u8; 5];
+    match recv.read_exact(&mut response).await {
+        Ok(()) if response[0] == version && &response[1..] == b"STRM" => {
+            Ok(Negotiated::Done((send, recv)))
+        }
+        Ok(()) => Err(PingError::InvalidResponse {
+            response: response.to_vec(),
+        }),
+        Err(ReadExactError::FinishedEarly(1)) if response[0] != version => {
+            Ok(Negotiated::Fallback(response[0]))
+        }
+        Err(ReadExactError::FinishedEarly(n)) => Err(PingError::InvalidResponse {
+            response: response[..n].to_vec(),
+        }),
+        Err(ReadExactError::ReadError(source)) => Err(PingError::Read {
+            source: ReadToEndError::Read(source),
+        }),
+    }
+}
+
+/// What [`read_frame`] found on a stream.
+pub(crate) enum Frame {
+    /// a frame, whose body is now in the buffer
+    Body,
+    /// the stream finished cleanly between two frames
+    End,
+}
+
+/// Errors of [`read_frame`].
+pub(crate) enum FrameError {
+    /// the frame is too large, or the stream finished in the middle of it
+    Codec(CodecError),
+    Read(ReadError),
+}
+
+/// Reads the next frame from `recv` and puts its body into `buf`, replacing its contents.
+///
+/// Frames with a body larger than `max_len` are refused from their length prefix alone.
+pub(crate) async fn read_frame(
+    recv: &mut RecvStream,
+    buf: &mut BytesMut,
+    max_len: usize,
+) -> Result<Frame, FrameError> {
+    let mut prefix = [0u8; codec::LEN_PREFIX];
+    match recv.read_exact(&mut prefix).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(Frame::End),
+        Err(ReadExactError::FinishedEarly(got)) => {
+            return Err(FrameError::Codec(CodecError::Truncated {
+                expected: codec::LEN_PREFIX,
+                got,
+            }))
+        }
+        Err(ReadExactError::ReadError(err)) => return Err(FrameError::Read(err)),
+    }
+    let len = codec::frame_len(prefix, max_len).map_err(FrameError::Codec)?;
+    buf.clear();
+    buf.resize(len, 0);
+    match recv.read_exact(buf).await {
+        Ok(()) => Ok(Frame::Body),
+        Err(ReadExactError::FinishedEarly(got)) => Err(FrameError::Codec(CodecError::Truncated {
+            expected: codec::LEN_PREFIX + len,
+            got: codec::LEN_PREFIX + got,
+        })),
+        Err(ReadExactError::ReadError(err)) => Err(FrameError::Read(err)),
+    }
+}
+
+/// Answers the pings on a stream opened with `STRM`, after its version byte and tag were
+/// read, until the client finishes the stream.
+///
+/// Every request is answered as soon as it arrived, like a framed `PING` would be. A frame
+/// that is too large or can't be decoded ends the stream. `buf` holds the requests and
+/// `out` the responses.
+pub(crate) async fn handle_stream(
+    ping: &Ping,
+    node_id: NodeId,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    buf: &mut BytesMut,
+    out: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    if version < STREAM_VERSION {
+        reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+        return Ok(());
+    }
+    out.clear();
+    out.put_u8(version);
+    out.put_slice(b"STRM");
+    send.write_all(out).await.map_err(AcceptError::from_err)?;
+
+    loop {
+        let max_len = PingRequest::HEADER_LEN + ping.max_payload;
+        match read_frame(&mut recv, buf, max_len).await {
+            Ok(Frame::Body) => {}
+            Ok(Frame::End) => break,
+            Err(FrameError::Codec(CodecError::TooLarge { .. })) => {
+                reject(&mut send, &mut recv, ERR_PAYLOAD_TOO_LARGE);
+                return Ok(());
+            }
+            Err(FrameError::Codec(_)) => {
+                reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+                return Ok(());
+            }
+            Err(FrameError::Read(err)) => return Err(AcceptError::from_err(err)),
+        }
+        let received = Instant::now();
+        let received_at_us = now_us();
+        let Ok(request) = PingRequest::decode_body(buf) else {
+            reject(&mut send, &mut recv, ERR_INVALID_REQUEST);
+            return Ok(());
+        };
+        if ping.admit().is_err() {
+            // a ping stream has no room for an `EROR` response, so it ends here
+            reject(&mut send, &mut recv, ERR_RATE_LIMITED);
+            return Ok(());
+        }
+        // reverse pings only follow pings on their own bidirectional stream
+        let request = PingRequest {
+            flags: request.flags & !FLAG_REVERSE_PING,
+            ..request
+        };
+        out.clear();
+        ping.encode_pong(&request, node_id, received, received_at_us, out);
+        send.write_all(out).await.map_err(AcceptError::from_err)?;
+        ping.metrics.pings_recv.inc();
+    }
+
+    // the client finished its side, so finish ours
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{self, Logs},
+        PingSession, ALPN_V1,
+    };
+
+    #[tokio::test]
+    async fn test_ping_stream() -> anyhow::Result<()> {
+        let (router, addr, client) = test_utils::local_pair().await?;
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let ping = Ping::new();
+        let mut stream = ping.open_stream(&conn).await?;
+        assert_eq!(stream.node_id(), addr.node_id);
+
+        let mut session = PingSession::new();
+        for seq in 1..=100 {
+            let res = session.ping_streamed(&mut stream).await?;
+            assert_eq!(res.seq, seq);
+            assert!(res.rtt > Duration::ZERO);
+        }
+        assert_eq!(session.recv(), 100);
+        assert_eq!(ping.metrics().pings_sent.get(), 100);
+        stream.finish().await?;
+
+        // the connection serves other requests again once the stream is finished
+        ping.ping_on_conn(&conn).await?;
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_stream_dropped() -> anyhow::Result<()> {
+        let (_router, addr, client) = test_utils::local_pair().await?;
+        let (logs, _guard) = Logs::capture();
+        let conn = client.connect(addr.clone(), ALPN_V1).await?;
+        let ping = Ping::new();
+
+        let mut stream = ping.open_stream(&conn).await?;
+        stream.ping().await?;
+        stream.finish().await?;
+        assert!(conn.close_reason().is_none());
+        assert!(!logs.contents().contains("dropped unfinished"));
+
+        let mut stream = ping.open_stream(&conn).await?;
+        stream.ping().await?;
+        drop(stream);
+        let logs = logs.contents();
+        assert!(logs.contains("ping stream dropped unfinished"), "{logs}");
+        assert!(logs.contains(&addr.node_id.to_string()), "{logs}");
+        assert!(conn.close_reason().is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_stream_old_server() -> anyhow::Result<()> {
+        let server = Ping::new().with_max_version(STREAM_VERSION - 1);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        let err = Ping::new().open_stream(&conn).await.unwrap_err();
+        assert!(
+            matches!(err, PingError::StreamUnsupported { version } if version == STREAM_VERSION - 1),
+            "{err:?}"
+        );
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use iroh::{Endpoint, NodeAddr};
+
+use crate::{close, exchange_with, Ping, PingError};
+
+/// Options for [`Ping::sweep`].
+#[derive(Debug, Clone)]
+pub struct SweepOpts {
+    /// payload size of the first probe, in bytes
+    pub start: usize,
+    /// largest payload size to probe, in bytes
+    pub max: usize,
+}
+
+impl Default for SweepOpts {
+    fn default() -> Self {
+        Self {
+            start: 64,
+            max: 1024 * 1024,
+        }
+    }
+}
+
+/// Outcome of a payload size sweep.
+#[derive(Debug)]
+pub struct SweepReport {
+    /// the round trip time for each payload size probed, in the order they were probed
+    pub results: Vec<(usize, Result<Duration, PingError>)>,
+    /// the largest payload size that round-tripped successfully
+    pub max_ok: Option<usize>,
+}
+
+impl Ping {
+    /// Find the largest payload that round-trips to a node.
+    ///
+    /// Starting at `opts.start` bytes, pings with a doubling payload size over a single
+    /// connection until `opts.max` is reached or a probe fails. A probe the server rejects
+    /// as too large ends the sweep with a [`PingError::PayloadTooLarge`] entry, marking the
+    /// boundary.
+    ///
+    /// Connecting and every probe are bounded by the timeout (see [`Ping::with_timeout`]),
+    /// and failed probes are counted in the metrics like any failed ping.
+    pub async fn sweep(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        opts: SweepOpts,
+    ) -> Result<SweepReport, PingError> {
+        let conn = self.connect_timeout(endpoint, addr).await?;
+
+        let mut report = SweepReport {
+            results: Vec::new(),
+            max_ok: None,
+        };
+        let mut version = self.max_version;
+        let mut buf = BytesMut::new();
+        let mut size = opts.start;
+        while size <= opts.max {
+            let payload = vec![0xa5; size];
+            self.wait_for_turn().await;
+            let start = Instant::now();
+            let res = match tokio::time::timeout(
+                self.timeout,
+                exchange_with(&conn, &payload, version, &mut buf),
+            )
+            .await
+            {
+                Ok(res) => res.map(|negotiated| {
+                    version = negotiated;
+                    start.elapsed()
+                }),
+                Err(_) => Err(PingError::Timeout {
+                    timeout: self.timeout,
+                }),
+            }
+            .inspect_err(|err| close::close_on_violation(&conn, err))
+            .map_err(|err| self.failed(err));
+            let failed = res.is_err();
+            if !failed {
+                self.metrics.pings_sent.inc();
+                report.max_ok = Some(size);
+            }
+            report.results.push((size, res));
+            if failed {
+                break;
+            }
+            size = match size.checked_mul(2) {
+                // an empty payload would never grow
+                Some(0) => 1,
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        self.close_conn(&conn);
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_sweep_stops_at_server_limit() -> anyhow::Result<()> {
+        let (_router, addr) =
+            test_utils::local_router(ALPN_V1, Ping::new().with_max_payload(4 * 1024)).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let opts = SweepOpts {
+            start: 64,
+            max: 64 * 1024,
+        };
+        let ping = Ping::new();
+        let report = ping.sweep(&client, addr, opts).await?;
+        assert_eq!(ping.metrics().pings_sent.get(), 7);
+        assert_eq!(ping.metrics().pings_failed.get(), 1);
+
+        assert_eq!(report.max_ok, Some(4 * 1024));
+        let sizes: Vec<_> = report.results.iter().map(|(size, _)| *size).collect();
+        assert_eq!(sizes, [64, 128, 256, 512, 1024, 2048, 4096, 8192]);
+        let (_, last) = report.results.last().unwrap();
+        assert!(
+            matches!(
+                last,
+                Err(PingError::PayloadTooLarge {
+                    size: 8192,
+                    limit: Some(4096)
+                })
+            ),
+            "{last:?}"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sweep_times_out() -> anyhow::Result<()> {
+        let (_router, _addr, client) = test_utils::local_pair().await?;
+        // a socket that swallows the handshake
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let node_id = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+        let addr = NodeAddr::from_parts(node_id, None, [silent.local_addr()?]);
+
+        let ping = Ping::new().with_timeout(Duration::from_millis(200));
+        let err = ping
+            .sweep(&client, addr, SweepOpts::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PingError::Timeout { .. }), "{err:?}");
+        assert_eq!(ping.metrics().pings_failed.get(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+//! Helpers for testing code that pings, here and in crates depending on this one.
+//!
+//! Only built for this crate's tests, or with the `test-utils` feature.
+
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(test)]
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use iroh::{
+    protocol::{ProtocolHandler, Router},
+    Endpoint, NodeAddr, RelayMode,
+};
+
+use crate::Ping;
+
+/// Start a ping server and bind a client endpoint to ping it with, both on localhost.
+///
+/// Returns the server's router, its address, and the client endpoint. Neither endpoint
+/// uses a relay or discovery, so this works offline. Dropping the router stops the server.
+pub async fn local_pair() -> anyhow::Result<(Router, NodeAddr, Endpoint)> {
+    local_pair_with(Ping::new()).await
+}
+
+/// Like [`local_pair`], but the server answers with `server`, e.g. to set its limits or
+/// look at its metrics.
+pub async fn local_pair_with(server: Ping) -> anyhow::Result<(Router, NodeAddr, Endpoint)> {
+    let ep = local_endpoint().await?;
+    let addr = local_addr(&ep);
+    let router = server.register(Router::builder(ep)).spawn();
+    let client = local_endpoint().await?;
+    Ok((router, addr, client))
+}
+
+/// Start a server on localhost that answers `alpn` with `handler`, e.g. one misbehaving
+/// on purpose, and return its router and address.
+pub async fn local_router(
+    alpn: &[u8],
+    handler: impl ProtocolHandler,
+) -> anyhow::Result<(Router, NodeAddr)> {
+    let ep = local_endpoint().await?;
+    let addr = local_addr(&ep);
+    let router = Router::builder(ep).accept(alpn, handler).spawn();
+    Ok((router, addr))
+}
+
+/// The address of `ep` over localhost, to reach a server bound with [`local_endpoint`]
+/// without discovery.
+pub fn local_addr(ep: &Endpoint) -> NodeAddr {
+    let addrs = ep
+        .bound_sockets()
+        .into_iter()
+        .filter(SocketAddr::is_ipv4)
+        .map(|addr| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()));
+    NodeAddr::from_parts(ep.node_id(), None, addrs)
+}
+
+/// Bind an endpoint on localhost, without a relay or discovery, e.g. for a server with
+/// several protocols or for another client.
+pub async fn local_endpoint() -> anyhow::Result<Endpoint> {
+    let ep = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .bind()
+        .await?;
+    Ok(ep)
+}
+
+/// Log output captured by [`Logs::capture`], for tests checking what gets logged.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Logs(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Logs {
+    /// Capture the warnings and errors logged on this thread until the guard is dropped.
+    pub(crate) fn capture() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Self::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// everything logged so far
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().expect("poisoned")).into_owned()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().expect("poisoned").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
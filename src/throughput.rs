@@ -0,0 +1,364 @@
+use std::time::{Duration, Instant};
+
+use iroh::{
+    endpoint::{Connection, ReadError, ReadToEndError, RecvStream, SendStream, WriteError},
+    protocol::AcceptError,
+    Endpoint, NodeAddr,
+};
+
+use crate::{fallback_version, health, Negotiated, Ping, PingError, ERR_TRANSFER_TOO_LARGE};
+
+/// Size of the chunks data is written in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Data moved in one direction during a throughput measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// number of bytes transferred
+    pub bytes: u64,
+    /// time it took to transfer them
+    pub duration: Duration,
+}
+
+impl Transfer {
+    /// goodput in bytes per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.duration.as_secs_f64()
+    }
+
+    /// goodput in megabits per second
+    pub fn mbit_per_sec(&self) -> f64 {
+        self.bytes_per_sec() * 8.0 / 1_000_000.0
+    }
+}
+
+/// Result of [`Ping::throughput`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThroughputReport {
+    /// data sent from us to the remote node
+    pub upload: Transfer,
+    /// data sent from the remote node to us
+    pub download: Transfer,
+}
+
+/// Result of [`Ping::measure_throughput`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThroughputResult {
+    /// number of bytes sent
+    pub bytes_sent: usize,
+    /// time from opening the stream until the remote acknowledged receiving everything
+    pub duration: Duration,
+    /// achieved rate in megabytes (10^6 bytes) per second
+    pub mb_per_sec: f64,
+}
+
+impl Ping {
+    /// Measure the goodput to and from a node.
+    ///
+    /// Streams `bytes` of data to the node and waits for it to acknowledge their receipt,
+    /// then asks the node to send `bytes` back. The node refuses transfers above its limit
+    /// (see [`Ping::with_max_transfer`]) with [`PingError::TransferTooLarge`].
+    pub async fn throughput(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        bytes: u64,
+    ) -> Result<ThroughputReport, PingError> {
+        let node_id = addr.node_id;
+        let conn = endpoint
+            .connect(addr, &self.alpn)
+            .await
+            .map_err(|source| health::connect_failed(node_id, source))?;
+
+        let mut version = self.max_version;
+        let upload = loop {
+            match upload(&conn, bytes, version).await? {
+                Negotiated::Done(upload) => break upload,
+                Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+            }
+        };
+        let download = loop {
+            match download(&conn, bytes, version).await? {
+                Negotiated::Done(download) => break download,
+                Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+            }
+        };
+
+        self.close_conn(&conn);
+
+        Ok(ThroughputReport { upload, download })
+    }
+}
+
+impl Ping {
+    /// Measure how fast data can be sent to a node.
+    ///
+    /// Streams `bytes` of data to the node, which discards it and acknowledges the total.
+    /// Use [`Ping::throughput`] to measure both directions.
+    pub async fn measure_throughput(
+        &self,
+        endpoint: &Endpoint,
+        addr: NodeAddr,
+        bytes: usize,
+    ) -> anyhow::Result<ThroughputResult> {
+        let conn = endpoint.connect(addr, &self.alpn).await?;
+
+        let mut version = self.max_version;
+        let transfer = loop {
+            match upload(&conn, bytes as u64, version).await? {
+                Negotiated::Done(transfer) => break transfer,
+                Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+            }
+        };
+
+        self.close_conn(&conn);
+
+        Ok(ThroughputResult {
+            bytes_sent: bytes,
+            duration: transfer.duration,
+            mb_per_sec: transfer.bytes_per_sec() / 1_000_000.0,
+        })
+    }
+}
+
+/// Streams `bytes` of data to the server, which answers with how much it got.
+async fn upload(
+    conn: &Connection,
+    bytes: u64,
+    version: u8,
+) -> Result<Negotiated<Transfer>, PingError> {
+    let too_large = || PingError::TransferTooLarge { bytes };
+
+    let start = Instant::now();
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    send.write_all(&[version])
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.write_all(b"UPLD")
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    let chunk = vec![0u8; CHUNK_SIZE];
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let n = remaining.min(CHUNK_SIZE as u64) as usize;
+        match send.write_all(&chunk[..n]).await {
+            Ok(()) => {}
+            Err(WriteError::Stopped(code)) if code == ERR_TRANSFER_TOO_LARGE.into() => {
+                return Err(too_large());
+            }
+            Err(source) => return Err(PingError::Write { source }),
+        }
+        remaining -= n as u64;
+    }
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+    let response = match recv.read_to_end(13).await {
+        Ok(response) => response,
+        Err(ReadToEndError::Read(ReadError::Reset(code)))
+            if code == ERR_TRANSFER_TOO_LARGE.into() =>
+        {
+            return Err(too_large());
+        }
+        Err(source) => return Err(PingError::Read { source }),
+    };
+    let duration = start.elapsed();
+    match response.split_first() {
+        Some((&v, [])) if v != version => Ok(Negotiated::Fallback(v)),
+        Some((&v, rest))
+            if v == version && rest.strip_prefix(b"RCVD") == Some(&bytes.to_be_bytes()) =>
+        {
+            Ok(Negotiated::Done(Transfer { bytes, duration }))
+        }
+        _ => Err(PingError::InvalidResponse { response }),
+    }
+}
+
+/// Asks the server for `bytes` of data, counting them as they arrive.
+async fn download(
+    conn: &Connection,
+    bytes: u64,
+    version: u8,
+) -> Result<Negotiated<Transfer>, PingError> {
+    let too_large = || PingError::TransferTooLarge { bytes };
+
+    let start = Instant::now();
+    let (mut send, mut recv) = conn
+        .open_bi()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let mut request = vec![version];
+    request.extend_from_slice(b"DNLD");
+    request.extend_from_slice(&bytes.to_be_bytes());
+    send.write_all(&request)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let mut received = 0u64;
+    let mut answered_version = None;
+    loop {
+        match recv.read_chunk(CHUNK_SIZE, true).await {
+            Ok(Some(mut chunk)) => {
+                if answered_version.is_none() {
+                    answered_version = chunk.bytes.first().copied();
+                    chunk.bytes = chunk.bytes.slice(1..);
+                }
+                received += chunk.bytes.len() as u64;
+            }
+            Ok(None) => break,
+            Err(ReadError::Reset(code)) if code == ERR_TRANSFER_TOO_LARGE.into() => {
+                return Err(too_large());
+            }
+            Err(err) => {
+                return Err(PingError::Read {
+                    source: ReadToEndError::Read(err),
+                })
+            }
+        }
+    }
+    let duration = start.elapsed();
+    match answered_version {
+        Some(v) if v != version && received == 0 => Ok(Negotiated::Fallback(v)),
+        Some(v) if v == version && received == bytes => Ok(Negotiated::Done(Transfer {
+            bytes: received,
+            duration,
+        })),
+        _ => Err(PingError::InvalidResponse {
+            response: received.to_be_bytes().to_vec(),
+        }),
+    }
+}
+
+/// Drains an upload, answering with `RCVD` and the number of bytes received.
+pub(crate) async fn handle_upload(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    max_transfer: u64,
+) -> Result<(), AcceptError> {
+    let mut received = 0u64;
+    while let Some(chunk) = recv
+        .read_chunk(CHUNK_SIZE, true)
+        .await
+        .map_err(AcceptError::from_err)?
+    {
+        received += chunk.bytes.len() as u64;
+        if received > max_transfer {
+            recv.stop(ERR_TRANSFER_TOO_LARGE.into()).ok();
+            send.reset(ERR_TRANSFER_TOO_LARGE.into()).ok();
+            return Ok(());
+        }
+    }
+
+    send.write_all(&[version])
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.write_all(b"RCVD")
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.write_all(&received.to_be_bytes())
+        .await
+        .map_err(AcceptError::from_err)?;
+    send.finish()?;
+    Ok(())
+}
+
+/// Sends back as many bytes as requested, unless that exceeds `max_transfer`.
+pub(crate) async fn handle_download(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    version: u8,
+    max_transfer: u64,
+) -> Result<(), AcceptError> {
+    let mut len = [0u8; 8];
+    recv.read_exact(&mut len)
+        .await
+        .map_err(AcceptError::from_err)?;
+    let len = u64::from_be_bytes(len);
+    if len > max_transfer {
+        send.reset(ERR_TRANSFER_TOO_LARGE.into()).ok();
+        return Ok(());
+    }
+
+    send.write_all(&[version])
+        .await
+        .map_err(AcceptError::from_err)?;
+    let chunk = vec![0u8; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = remaining.min(CHUNK_SIZE as u64) as usize;
+        send.write_all(&chunk[..n])
+            .await
+            .map_err(AcceptError::from_err)?;
+        remaining -= n as u64;
+    }
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[tokio::test]
+    async fn test_throughput() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let report = Ping::new().throughput(&client, addr.clone(), MIB).await?;
+
+        assert_eq!(report.upload.bytes, MIB);
+        assert_eq!(report.download.bytes, MIB);
+        assert!(report.upload.mbit_per_sec() > 0.0);
+        assert!(report.download.mbit_per_sec() > 0.0);
+
+        // a client newer than the server falls back to the server's version
+        let report = Ping::new()
+            .with_max_version(crate::PROTOCOL_VERSION + 1)
+            .throughput(&client, addr, MIB)
+            .await?;
+        assert_eq!(report.download.bytes, MIB);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_measure_throughput() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let bytes = 4 * MIB as usize;
+        let result = Ping::new().measure_throughput(&client, addr, bytes).await?;
+
+        assert_eq!(result.bytes_sent, bytes);
+        assert!(result.duration > Duration::ZERO);
+        assert!(result.mb_per_sec > 0.0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_throughput_over_limit() -> anyhow::Result<()> {
+        let (_router, addr) =
+            test_utils::local_router(ALPN_V1, Ping::new().with_max_transfer(MIB / 2)).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let err = Ping::new()
+            .throughput(&client, addr, MIB)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, PingError::TransferTooLarge { bytes: MIB }),
+            "{err:?}"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use iroh::endpoint::{Connection, ConnectionStats};
+
+/// Transport level counters of a connection.
+///
+/// Every [`PingResult`](crate::PingResult) carries these for its connection. They explain
+/// what the round trip times alone don't, e.g. whether packets got lost and had to be sent
+/// again. All counters cover the connection so far, use [`TransportStats::delta`] for a
+/// part of it, like a run of pings.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransportStats {
+    /// number of packets sent
+    pub packets_sent: u64,
+    /// number of packets considered lost
+    pub packets_lost: u64,
+    /// bytes sent in UDP datagrams
+    pub bytes_sent: u64,
+    /// bytes received in UDP datagrams
+    pub bytes_received: u64,
+    /// bytes in the packets considered lost
+    pub bytes_lost: u64,
+    /// number of times the congestion controller backed off
+    pub congestion_events: u64,
+    /// the congestion window at the time of the snapshot, in bytes
+    pub cwnd: u64,
+}
+
+impl TransportStats {
+    /// Snapshot the current counters of a connection.
+    pub fn from_conn(conn: &Connection) -> Self {
+        Self::from(&conn.stats())
+    }
+
+    /// What happened on the connection since the `earlier` snapshot.
+    ///
+    /// The congestion window is not a counter, so it is taken from `self`.
+    pub fn delta(&self, earlier: &Self) -> Self {
+        Self {
+            packets_sent: self.packets_sent.saturating_sub(earlier.packets_sent),
+            packets_lost: self.packets_lost.saturating_sub(earlier.packets_lost),
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            bytes_lost: self.bytes_lost.saturating_sub(earlier.bytes_lost),
+            congestion_events: self
+                .congestion_events
+                .saturating_sub(earlier.congestion_events),
+            cwnd: self.cwnd,
+        }
+    }
+}
+
+impl From<&ConnectionStats> for TransportStats {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            packets_sent: stats.path.sent_packets,
+            packets_lost: stats.path.lost_packets,
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+            bytes_lost: stats.path.lost_bytes,
+            congestion_events: stats.path.congestion_events,
+            cwnd: stats.path.cwnd,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, Ping, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_transport_stats() -> anyhow::Result<()> {
+        let (_router, addr) = test_utils::local_router(ALPN_V1, Ping::new()).await?;
+
+        let client = test_utils::local_endpoint().await?;
+        let ping = Ping::new();
+        let (res, conn, _stats) = ping.ping_keep(&client, addr).await?;
+        let transport = res.transport;
+        assert!(transport.packets_sent > 0, "{transport:?}");
+        assert!(transport.bytes_sent > 0, "{transport:?}");
+        assert!(transport.bytes_received > 0, "{transport:?}");
+        assert!(transport.cwnd > 0, "{transport:?}");
+
+        // only count what a run of pings adds
+        let start = TransportStats::from_conn(&conn);
+        ping.ping_n_on_conn(&conn, 5).await?;
+        let run = TransportStats::from_conn(&conn).delta(&start);
+        assert!(run.packets_sent > 0, "{run:?}");
+        assert!(run.bytes_received > 0, "{run:?}");
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
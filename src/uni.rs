@@ -0,0 +1,281 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, BytesMut};
+use iroh::{
+    endpoint::{Connection, ReadToEndError, RecvStream, WriteError},
+    protocol::AcceptError,
+    NodeId,
+};
+
+use crate::{
+    codec, fallback_version, now_us, read_to_end_into, CloseCode, ErrorResponse, Negotiated, Ping,
+    PingError, PingRequest, PongResponse, RejectReason, ERROR_VERSION, ERR_INVALID_REQUEST,
+    ERR_PAYLOAD_TOO_LARGE, FLAG_REVERSE_PING, NEXT_SEQ,
+};
+
+/// Oldest protocol version with pings on unidirectional streams.
+const UNI_VERSION: u8 = 4;
+
+impl Ping {
+    /// Send a single ping on a unidirectional stream, and wait for the answer on one the
+    /// server opens.
+    ///
+    /// Measures the same as [`Ping::ping_on_conn`], but exercises `open_uni` and
+    /// `accept_uni` instead of bidirectional streams. Prefer it to test a path for
+    /// applications that only use unidirectional streams, or when the peer limits the
+    /// number of bidirectional streams. Otherwise the bidirectional pings are simpler,
+    /// and they can be many at a time: answers on unidirectional streams are not tied to
+    /// their request, so only one of these pings may be in flight on a connection.
+    ///
+    /// Fails with [`PingError::UniUnsupported`] if the server is too old to answer them,
+    /// and with [`PingError::RequestRejected`] if it refuses the ping, e.g. over its rate
+    /// limit.
+    /// Servers that predate version negotiation for them don't answer at all, which shows
+    /// as a [`PingError::Timeout`]. Does not close the connection.
+    pub async fn ping_uni(&self, conn: &Connection) -> Result<Duration, PingError> {
+        self.wait_for_turn().await;
+        let start = Instant::now();
+        tokio::time::timeout(self.timeout, uni_exchange(conn, self.max_version))
+            .await
+            .unwrap_or(Err(PingError::Timeout {
+                timeout: self.timeout,
+            }))
+            .map_err(|err| self.failed(err))?;
+        self.metrics.pings_sent.inc();
+        Ok(start.elapsed())
+    }
+}
+
+/// Sends a ping on a unidirectional stream, negotiating the protocol version if needed.
+async fn uni_exchange(conn: &Connection, mut version: u8) -> Result<(), PingError> {
+    let mut buf = BytesMut::new();
+    loop {
+        if version < UNI_VERSION {
+            return Err(PingError::UniUnsupported { version });
+        }
+        match uni_exchange_version(conn, version, &mut buf).await? {
+            Negotiated::Done(()) => return Ok(()),
+            Negotiated::Fallback(offered) => version = fallback_version(version, offered)?,
+        }
+    }
+}
+
+/// Sends `PING` and a framed [`PingRequest`] on a fresh unidirectional stream, and reads
+/// `PONG` and the framed [`PongResponse`] from the next one the server opens.
+async fn uni_exchange_version(
+    conn: &Connection,
+    version: u8,
+    buf: &mut BytesMut,
+) -> Result<Negotiated<()>, PingError> {
+    let mut send = conn
+        .open_uni()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let request = PingRequest {
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        sent_at_us: now_us(),
+        flags: 0,
+        nonce: rand::random(),
+        payload: &[],
+    };
+    buf.clear();
+    buf.put_u8(version);
+    buf.put_slice(b"PING");
+    request.encode(buf);
+    send.write_all(buf)
+        .await
+        .map_err(|source| PingError::Write { source })?;
+    send.finish()
+        .map_err(|source| PingError::Finish { source })?;
+
+    let mut recv = conn
+        .accept_uni()
+        .await
+        .map_err(|source| PingError::Connection { source })?;
+    let limit = 1 + 4 + codec::LEN_PREFIX + PongResponse::HEADER_LEN.max(ErrorResponse::HEADER_LEN);
+    read_to_end_into(&mut recv, buf, limit)
+        .await
+        .map_err(|source| PingError::Read { source })?;
+    let invalid = || PingError::InvalidResponse {
+        response: buf.to_vec(),
+    };
+    match buf.split_first() {
+        Some((&v, [])) if v != version => Ok(Negotiated::Fallback(v)),
+        Some((&v, rest))
+            if v == version && version >= ERROR_VERSION && rest.starts_with(b"EROR") =>
+        {
+            let error =
+                ErrorResponse::decode(&rest[4..]).map_err(|source| PingError::Decode { source })?;
+            if error.nonce != request.nonce {
+                return Err(PingError::NonceMismatch {
+                    expected: request.nonce,
+                    got: error.nonce,
+                });
+            }
+            if error.seq != request.seq {
+                return Err(invalid());
+            }
+            Err(PingError::RequestRejected {
+                reason: error.reason,
+                retry_after: error.retry_after,
+            })
+        }
+        Some((&v, rest)) if v == version => {
+            let frame = rest.strip_prefix(b"PONG").ok_or_else(invalid)?;
+            let pong = PongResponse::decode(frame, PongResponse::HEADER_LEN)
+                .map_err(|source| PingError::Decode { source })?;
+            if pong.nonce != request.nonce {
+                return Err(PingError::NonceMismatch {
+                    expected: request.nonce,
+                    got: pong.nonce,
+                });
+            }
+            if pong.seq != request.seq || !pong.payload.is_empty() {
+                return Err(invalid());
+            }
+            Ok(Negotiated::Done(()))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Answers a ping on a unidirectional stream with one of our own.
+///
+/// Requests in a version we don't serve get our newest version byte back, those in a
+/// version without unidirectional pings, or that can't be decoded, are refused. `buf`
+/// holds the request and `out` the response.
+pub(crate) async fn handle_uni(
+    ping: &Ping,
+    conn: &Connection,
+    node_id: NodeId,
+    mut recv: RecvStream,
+    buf: &mut BytesMut,
+    out: &mut BytesMut,
+) -> Result<(), AcceptError> {
+    let mut header = [0u8; 5];
+    recv.read_exact(&mut header)
+        .await
+        .map_err(AcceptError::from_err)?;
+    let [version, tag @ ..] = header;
+    out.clear();
+    if version == 0 {
+        CloseCode::UnsupportedVersion.close(conn, b"version 0");
+        return Ok(());
+    }
+    if version > ping.max_version {
+        recv.stop(0u32.into()).ok();
+        out.put_u8(ping.max_version);
+        return respond(conn, out).await;
+    }
+    if version < UNI_VERSION || &tag != b"PING" {
+        recv.stop(ERR_INVALID_REQUEST.into()).ok();
+        return Ok(());
+    }
+
+    let max_len = PingRequest::HEADER_LEN + ping.max_payload;
+    match read_to_end_into(&mut recv, buf, codec::LEN_PREFIX + max_len).await {
+        Ok(()) => {}
+        Err(ReadToEndError::TooLong) => {
+            recv.stop(ERR_PAYLOAD_TOO_LARGE.into()).ok();
+            return Ok(());
+        }
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    let received = Instant::now();
+    let received_at_us = now_us();
+    let Ok(request) = PingRequest::decode(buf, max_len) else {
+        return Ok(());
+    };
+    if let Err(retry_after) = ping.admit() {
+        // older clients have no way to learn about it, their ping is lost
+        if version >= ERROR_VERSION {
+            out.put_u8(version);
+            out.put_slice(b"EROR");
+            ErrorResponse {
+                seq: request.seq,
+                reason: RejectReason::RateLimited,
+                retry_after: Some(retry_after),
+                limit: None,
+                nonce: request.nonce,
+            }
+            .encode(out);
+            respond(conn, out).await?;
+        }
+        return Ok(());
+    }
+    // reverse pings only follow pings on their own bidirectional stream
+    let request = PingRequest {
+        flags: request.flags & !FLAG_REVERSE_PING,
+        ..request
+    };
+
+    out.put_u8(version);
+    out.put_slice(b"PONG");
+    ping.encode_pong(&request, node_id, received, received_at_us, out);
+    respond(conn, out).await?;
+    ping.metrics.pings_recv.inc();
+    Ok(())
+}
+
+/// Sends `out` on a fresh unidirectional stream.
+async fn respond(conn: &Connection, out: &[u8]) -> Result<(), AcceptError> {
+    let mut send = conn.open_uni().await?;
+    match send.write_all(out).await {
+        Ok(()) => {}
+        // the connection is going away, nothing left to answer
+        Err(WriteError::ConnectionLost(_)) => return Ok(()),
+        Err(err) => return Err(AcceptError::from_err(err)),
+    }
+    send.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, ALPN_V1};
+
+    #[tokio::test]
+    async fn test_ping_uni() -> anyhow::Result<()> {
+        let server = Ping::new();
+        let (router, addr, client) = test_utils::local_pair_with(server.clone()).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+        let ping = Ping::new();
+        for _ in 0..10 {
+            let rtt = ping.ping_uni(&conn).await?;
+            assert!(rtt > Duration::ZERO);
+        }
+        assert_eq!(ping.metrics().pings_sent.get(), 10);
+        assert_eq!(server.metrics().pings_recv.get(), 10);
+
+        // bidirectional pings still work on the same connection
+        ping.ping_on_conn(&conn).await?;
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+        router.shutdown().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ping_uni_old_server() -> anyhow::Result<()> {
+        let server = Ping::new().with_max_version(UNI_VERSION - 1);
+        let (_router, addr, client) = test_utils::local_pair_with(server).await?;
+        let conn = client.connect(addr, ALPN_V1).await?;
+
+        let err = Ping::new().ping_uni(&conn).await.unwrap_err();
+        assert!(
+            matches!(err, PingError::UniUnsupported { version } if version == UNI_VERSION - 1),
+            "{err:?}"
+        );
+
+        conn.close(0u32.into(), b"bye!");
+        client.close().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! Property tests of invariants that should hold for any input, not just the happy path.
+//!
+//! The inputs come from proptest strategies, which weigh in the edge cases (no input, a
+//! single value, zero and the largest values) and shrink any failure to a minimal input.
+
+use std::{panic::AssertUnwindSafe, time::Duration};
+
+use iroh_ping::{test_utils, Ping, PingServerMode, PingStats, ALPN_V1, DEFAULT_MAX_PAYLOAD};
+use proptest::{prelude::*, strategy::ValueTree, test_runner::TestRunner};
+
+/// Round trip times from nothing up to a minute, with runs of equal ones.
+fn rtts() -> impl Strategy<Value = Vec<Duration>> {
+    let rtt = prop_oneof![0..=1_000u64, 0..=1_000_000u64, 0..=60_000_000_000u64]
+        .prop_map(Duration::from_nanos);
+    prop::collection::vec((rtt, 1..=4usize), 0..100).prop_map(|runs| {
+        runs.into_iter()
+            .flat_map(|(rtt, repeat)| std::iter::repeat_n(rtt, repeat))
+            .collect()
+    })
+}
+
+fn server_mode() -> impl Strategy<Value = PingServerMode> {
+    prop_oneof![Just(PingServerMode::Standard), Just(PingServerMode::Echo)]
+}
+
+proptest! {
+    #[test]
+    fn test_stats_invariants(rtts in rtts()) {
+        let stats = PingStats::from_rtts(&rtts);
+        prop_assert_eq!(stats.received(), rtts.len() as u64);
+        if rtts.is_empty() {
+            prop_assert_eq!(stats.p50(), Duration::ZERO);
+            prop_assert_eq!(stats.mdev(), Duration::ZERO);
+            return Ok(());
+        }
+        prop_assert_eq!(stats.min(), *rtts.iter().min().unwrap());
+        prop_assert_eq!(stats.max(), *rtts.iter().max().unwrap());
+        prop_assert!(stats.min() <= stats.avg(), "{:?}", stats);
+        prop_assert!(stats.avg() <= stats.max(), "{:?}", stats);
+        prop_assert!(stats.mdev() <= stats.max() - stats.min(), "{:?}", stats);
+        prop_assert!(stats.min() <= stats.p50(), "{:?}", stats);
+        prop_assert!(stats.p50() <= stats.p99(), "{:?}", stats);
+        prop_assert!(stats.p99() <= stats.max(), "{:?}", stats);
+    }
+
+    /// The builder takes any input, and only panics on the ones its docs say it does.
+    #[test]
+    fn test_builder(
+        timeout in any::<u64>(),
+        max_payload in any::<usize>(),
+        max_transfer in any::<u64>(),
+        mode in server_mode(),
+        alpn in prop::collection::vec(any::<u8>(), 0..64),
+        log_connections in any::<bool>(),
+        max_pings_per_second in prop_oneof![Just(0), any::<u32>()],
+        server_rate_limit in prop_oneof![Just(0), any::<u32>()],
+        max_concurrent_streams in prop_oneof![Just(0), any::<usize>()],
+    ) {
+        let build = || {
+            Ping::new()
+                .with_timeout(Duration::from_nanos(timeout))
+                .with_max_payload(max_payload)
+                .with_max_transfer(max_transfer)
+                .with_server_mode(mode)
+                .with_alpn(alpn.clone())
+                .with_log_connections(log_connections)
+                .with_max_pings_per_second(max_pings_per_second)
+                .with_server_rate_limit(server_rate_limit)
+                .with_max_concurrent_streams(max_concurrent_streams)
+        };
+        let invalid = alpn.is_empty()
+            || max_pings_per_second == 0
+            || server_rate_limit == 0
+            || max_concurrent_streams == 0;
+        match std::panic::catch_unwind(AssertUnwindSafe(build)) {
+            Ok(ping) => {
+                prop_assert!(!invalid);
+                prop_assert_eq!(ping.alpn(), &alpn[..]);
+            }
+            Err(_) => prop_assert!(invalid),
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "the ALPN must not be empty")]
+fn test_builder_empty_alpn() {
+    let _ = Ping::new().with_alpn(Vec::new());
+}
+
+#[test]
+#[should_panic(expected = "the rate limit must be positive")]
+fn test_builder_zero_rate_limit() {
+    let _ = Ping::new().with_max_pings_per_second(0);
+}
+
+#[test]
+#[should_panic(expected = "the rate limit must be positive")]
+fn test_builder_zero_server_rate_limit() {
+    let _ = Ping::new().with_server_rate_limit(0);
+}
+
+#[test]
+#[should_panic(expected = "at least one stream must be answered at a time")]
+fn test_builder_zero_concurrent_streams() {
+    let _ = Ping::new().with_max_concurrent_streams(0);
+}
+
+#[tokio::test]
+async fn test_echo_any_payload_size() -> anyhow::Result<()> {
+    let server = Ping::new().with_server_mode(PingServerMode::Echo);
+    let (router, addr) = test_utils::local_router(ALPN_V1, server).await?;
+
+    let client = test_utils::local_endpoint().await?;
+    let conn = client.connect(addr, ALPN_V1).await?;
+    // the edge sizes, then random ones, all with random bytes; the payloads are drawn by
+    // hand since a single connection serves them all
+    let size = prop_oneof![
+        Just(0),
+        Just(1),
+        Just(DEFAULT_MAX_PAYLOAD - 1),
+        Just(DEFAULT_MAX_PAYLOAD),
+        0..DEFAULT_MAX_PAYLOAD,
+    ];
+    let payloads = size.prop_flat_map(|size| prop::collection::vec(any::<u8>(), size));
+    let mut runner = TestRunner::deterministic();
+    for _ in 0..36 {
+        let payload = payloads
+            .new_tree(&mut runner)
+            .expect("draw a payload")
+            .current();
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&payload).await?;
+        send.finish()?;
+        let echoed = recv.read_to_end(DEFAULT_MAX_PAYLOAD).await?;
+        assert!(
+            echoed == payload,
+            "payload of {} bytes not echoed",
+            payload.len()
+        );
+    }
+
+    conn.close(0u32.into(), b"bye!");
+    client.close().await;
+    router.shutdown().await?;
+
+    Ok(())
+}